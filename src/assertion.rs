@@ -0,0 +1,98 @@
+use std::fmt;
+use std::io::Error as IOError;
+
+use chrono::Utc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+
+use crate::config::{read_secret_file, PrivateKeyJwt};
+
+type DynErr = Box<dyn std::error::Error>;
+
+pub const ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// Assertions are signed for every request, a short lifetime is enough
+const LIFETIME_SECS: i64 = 60;
+
+// Signs the `private_key_jwt` client assertions (RFC 7523) authenticating the client
+// instead of the client secret
+#[derive(Clone)]
+pub struct ClientAssertion {
+    client_id: String,
+    audience: String,
+    header: Header,
+    key: EncodingKey,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    jti: String,
+    iat: i64,
+    exp: i64,
+}
+
+impl ClientAssertion {
+    // The `audience` defaults to the token endpoint
+    pub fn new(client_id: &str, token_url: &str, c: &PrivateKeyJwt) -> Result<Self, DynErr> {
+        let pem = read_secret_file(&c.key_file)?;
+        let key = match c.algorithm {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => EncodingKey::from_rsa_pem(pem.as_bytes()),
+            Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(pem.as_bytes()),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(pem.as_bytes()),
+            algorithm => {
+                return Err(format!(
+                    "Unsupported private_key_jwt algorithm {algorithm:?}, use an asymmetric one"
+                )
+                .into())
+            }
+        }
+        .map_err(|e| format!("Invalid private key {}: {e}", c.key_file.display()))?;
+        let mut header = Header::new(c.algorithm);
+        header.kid = c.kid.clone();
+        Ok(Self {
+            client_id: client_id.to_string(),
+            audience: c.audience.clone().unwrap_or_else(|| token_url.to_string()),
+            header,
+            key,
+        })
+    }
+
+    // A fresh assertion with a unique `jti`, servers may reject assertions used twice
+    pub fn sign(&self) -> Result<String, DynErr> {
+        let mut jti = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut jti)
+            .map_err(|_| IOError::other("Failed to generate jti"))?;
+        let iat = Utc::now().timestamp();
+        let claims = Claims {
+            iss: &self.client_id,
+            sub: &self.client_id,
+            aud: &self.audience,
+            jti: jti.iter().map(|b| format!("{b:02x}")).collect(),
+            iat,
+            exp: iat + LIFETIME_SECS,
+        };
+        Ok(encode(&self.header, &claims, &self.key)?)
+    }
+}
+
+// Leaves out the signing key: the HTTP client holding the assertion is part of the
+// `OAuth Client` debug log of the module
+impl fmt::Debug for ClientAssertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientAssertion")
+            .field("client_id", &self.client_id)
+            .field("audience", &self.audience)
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
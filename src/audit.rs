@@ -0,0 +1,180 @@
+use std::fs::OpenOptions;
+use std::io::{Error as IOError, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use pam::constants::PamResultCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::group_sync::GroupChanges;
+use crate::logger::correlation_id;
+
+// Audit trail of the authentication attempts, one JSON object per line.
+// Unlike the debug log it is always written, never rotated by the module and
+// never has its usernames masked, so it can be shipped to a SIEM as is.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditResult {
+    Success,
+    Failure,
+    // The module didn't apply to the attempt and returned `PAM_IGNORE`
+    Ignored,
+    // Allowed without the Authorization Server, see `offline_ttl`
+    Offline,
+}
+
+// Why an authentication attempt failed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    // The config or the provider selection is invalid
+    Config,
+    // Talking to the user failed
+    Conversation,
+    // No provider issued a device code
+    DeviceCode,
+    // No token was issued, e.g. the token request failed
+    Token,
+    // The user denied the authorization
+    AccessDenied,
+    // The device code expired before the user authorized the device
+    ExpiredToken,
+    // The token was rejected by the validation
+    Denied,
+    // The token doesn't show the multi-factor authentication required by `require_mfa`
+    InsufficientAuthentication,
+    // The token could not be validated
+    ValidationUnavailable,
+    // The remote identity doesn't match the subject pinned for the local user
+    SubjectMismatch,
+    // The local user is not covered by `allowed_users`, listed in `denied_users` or a member
+    // of one of the `skip_if_local_groups`
+    ExemptUser,
+    // The login matches one of the `bypass` rules
+    Bypassed,
+    // The Authorization Server couldn't be reached, see `on_unreachable`
+    Unreachable,
+    // The user went away while the login was pending, e.g. with Ctrl-C
+    Aborted,
+    // The local user or the remote host started too many device flows, see
+    // `rate_limit_per_minute`
+    RateLimited,
+    // The local user is locked out after too many denied logins, see `lockout_threshold`
+    LockedOut,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    // Random ID of the attempt, also in its log lines
+    pub correlation_id: String,
+    pub service: String,
+    pub local_user: String,
+    pub remote_user: Option<String>,
+    pub rhost: String,
+    pub tty: String,
+    pub provider: Option<String>,
+    // SHA-256 of the `client_id`, so events can be told apart per client without exposing it
+    pub client_id_hash: String,
+    // See `logger::token_fingerprint`, of the access token issued during the attempt
+    pub token_fingerprint: Option<String>,
+    // Reused from the login cache
+    pub cached: bool,
+    // Allowed by an offline entry while the Authorization Server was unreachable
+    pub offline: bool,
+    // Local groups changed by the `group_sync` after the login
+    pub group_changes: Option<GroupChanges>,
+    pub result: AuditResult,
+    pub error_class: Option<ErrorClass>,
+    // Time the user took to authorize the device flow, only reported to the metrics
+    #[serde(skip)]
+    pub device_flow: Option<Duration>,
+}
+
+impl AuditEvent {
+    // Attempt that has not finished yet, see `finish`
+    pub fn new(service: &str, local_user: &str, rhost: &str, tty: &str, client_id: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            correlation_id: correlation_id(),
+            service: service.to_string(),
+            local_user: local_user.to_string(),
+            remote_user: None,
+            rhost: rhost.to_string(),
+            tty: tty.to_string(),
+            provider: None,
+            client_id_hash: client_id_hash(client_id),
+            token_fingerprint: None,
+            cached: false,
+            offline: false,
+            group_changes: None,
+            result: AuditResult::Failure,
+            error_class: None,
+            device_flow: None,
+        }
+    }
+
+    pub fn set_provider(&mut self, name: &str, client_id: &str) {
+        self.provider = Some(name.to_string());
+        self.client_id_hash = client_id_hash(client_id);
+    }
+
+    // Records the outcome of the attempt, returning the PAM result code
+    pub fn finish(&mut self, result: Result<(), (PamResultCode, ErrorClass)>) -> PamResultCode {
+        match result {
+            Ok(()) => {
+                self.result = if self.offline {
+                    AuditResult::Offline
+                } else {
+                    AuditResult::Success
+                };
+                self.error_class = None;
+                PamResultCode::PAM_SUCCESS
+            }
+            Err((code, class)) => {
+                self.result = if code == PamResultCode::PAM_IGNORE {
+                    AuditResult::Ignored
+                } else {
+                    AuditResult::Failure
+                };
+                self.error_class = Some(class);
+                code
+            }
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    // Appends the event with a single write, so concurrent writers never interleave lines
+    pub fn record(&self, event: &AuditEvent) -> Result<(), IOError> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+fn client_id_hash(client_id: &str) -> String {
+    Sha256::digest(client_id.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,214 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use oauth2::http::header::CONTENT_TYPE;
+use oauth2::http::{Method, Request};
+use oauth2::SyncHttpClient;
+use url::Url;
+
+use crate::audit::AuditEvent;
+use crate::config::{AuditExportConfig, Config, RetryConfig};
+use crate::http::{HttpClient, Timeouts};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Time to wait for the listener of the socket, and for each request to the webhook
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Ships the audit events to a local Unix socket and/or an HTTPS webhook as they happen.
+// Every event is spooled first, one file per event and sink, then the spool is delivered in
+// order with a single attempt per event, so a sink that is down never holds up the login
+// for long. Undelivered events are sent again with the next event.
+pub struct AuditExport<'a> {
+    config: &'a AuditExportConfig,
+    spool_dir: PathBuf,
+    allow_insecure_http: bool,
+    http_client: HttpClient,
+}
+
+// Where the events are delivered to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sink {
+    Socket,
+    Webhook,
+}
+
+impl Sink {
+    fn name(self) -> &'static str {
+        match self {
+            Sink::Socket => "socket",
+            Sink::Webhook => "webhook",
+        }
+    }
+}
+
+impl<'a> AuditExport<'a> {
+    // None without `audit_export`. Webhook requests are not retried, the spool does.
+    pub fn new(config: &'a Config) -> Option<Self> {
+        let export = config.audit_export.as_ref()?;
+        let mut http_client = HttpClient::new(config);
+        http_client.set_retry(RetryConfig {
+            max_attempts: 1,
+            ..RetryConfig::default()
+        });
+        http_client.set_timeouts(Timeouts {
+            connect: WEBHOOK_TIMEOUT,
+            read: WEBHOOK_TIMEOUT,
+            total: WEBHOOK_TIMEOUT,
+        });
+        Some(Self {
+            config: export,
+            spool_dir: export
+                .spool_dir
+                .clone()
+                .unwrap_or_else(|| config.cache_dir.join("audit_spool")),
+            allow_insecure_http: config.allow_insecure_http,
+            http_client,
+        })
+    }
+
+    pub fn export(&self, event: &AuditEvent) -> Result<(), DynErr> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut errors = Vec::new();
+        for sink in [Sink::Socket, Sink::Webhook] {
+            if self.enabled(sink) {
+                if let Err(e) = self.deliver(sink, &line) {
+                    errors.push(format!("{}: {e}", sink.name()));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(format!("Audit event spooled, {}", errors.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    fn enabled(&self, sink: Sink) -> bool {
+        match sink {
+            Sink::Socket => self.config.socket.is_some(),
+            Sink::Webhook => self.config.webhook.is_some(),
+        }
+    }
+
+    // Spools `line`, then sends the spooled events oldest first until a send fails. The
+    // remaining events stay spooled, in order. A concurrent login already delivering the
+    // spool of the sink is left to it, the event goes with the next one at the latest.
+    fn deliver(&self, sink: Sink, line: &[u8]) -> Result<(), DynErr> {
+        let dir = self.spool_dir.join(sink.name());
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        spool(&dir, line, self.config.max_spooled)?;
+
+        // Concurrent logins must not deliver the same spooled event twice
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(dir.join(".lock"))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = IOError::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                log::debug!("Audit spool {} is being delivered", dir.display());
+                return Ok(());
+            }
+            return Err(err.into());
+        }
+
+        for path in spooled(&dir)? {
+            let spooled_line = match fs::read(&path) {
+                Ok(spooled_line) => spooled_line,
+                // Dropped beyond `max_spooled` by a concurrent login
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.send(sink, &spooled_line)?;
+            fs::remove_file(&path)?;
+            log::debug!("Delivered audit event {}", path.display());
+        }
+        Ok(())
+    }
+
+    fn send(&self, sink: Sink, line: &[u8]) -> Result<(), DynErr> {
+        match sink {
+            Sink::Socket => match &self.config.socket {
+                Some(path) => send_socket(path, line),
+                None => Ok(()),
+            },
+            Sink::Webhook => match &self.config.webhook {
+                Some(url) => self.send_webhook(url, line),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn send_webhook(&self, url: &Url, line: &[u8]) -> Result<(), DynErr> {
+        if url.scheme() != "https" && !self.allow_insecure_http {
+            return Err(format!("Webhook {url} is not an https URL").into());
+        }
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(line.to_vec())?;
+        let response = self.http_client.call(request)?;
+        if !response.status().is_success() {
+            return Err(format!("POST {url} failed with status {}", response.status()).into());
+        }
+        Ok(())
+    }
+}
+
+// One JSON line per connection, so the listener knows when an event is complete
+fn send_socket(path: &Path, line: &[u8]) -> Result<(), DynErr> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+    stream.write_all(line)?;
+    Ok(())
+}
+
+// Spooled events, oldest first
+fn spooled(dir: &Path) -> Result<Vec<PathBuf>, IOError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    // Names start with the zero padded time they were spooled at
+    paths.sort();
+    Ok(paths)
+}
+
+// Adds `line` to the spool, dropping the oldest events beyond `max_spooled`
+fn spool(dir: &Path, line: &[u8], max_spooled: usize) -> Result<(), IOError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let path = dir.join(format!("{nanos:020}-{}.json", std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    file.write_all(line)?;
+    file.sync_data()?;
+
+    let paths = spooled(dir)?;
+    let excess = paths.len().saturating_sub(max_spooled);
+    if excess > 0 {
+        log::warn!(
+            "Audit spool {} is full, dropping the {excess} oldest events",
+            dir.display()
+        );
+    }
+    for path in &paths[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use oauth2::Scope;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::config::{AzureAdOptions, AzureEndpointVersion};
+use crate::discovery::ProviderMetadata;
+
+const LOGIN: &str = "https://login.microsoftonline.com";
+
+// Claims holding the username, in order of preference. Member accounts have a `upn`,
+// v1 tokens of guests only a `unique_name` and v2 tokens a `preferred_username`.
+const USERNAME_CLAIMS: &[&str] = &["upn", "unique_name", "preferred_username"];
+
+// Scopes of the id_token and refresh token that never show up in the `scp` of access tokens
+const OIDC_SCOPES: &[&str] = &["openid", "profile", "email", "offline_access"];
+
+// Endpoints of the tenant, Azure AD's discovery documents can't be used as the issuer they
+// name depends on the version of the tokens rather than on the endpoints
+pub fn metadata(options: &AzureAdOptions) -> ProviderMetadata {
+    let tenant = &options.tenant;
+    let oauth2 = match options.endpoint_version {
+        AzureEndpointVersion::V1 => format!("{LOGIN}/{tenant}/oauth2"),
+        AzureEndpointVersion::V2 => format!("{LOGIN}/{tenant}/oauth2/v2.0"),
+    };
+    let jwks = match options.endpoint_version {
+        AzureEndpointVersion::V1 => format!("{LOGIN}/{tenant}/discovery/keys"),
+        AzureEndpointVersion::V2 => format!("{LOGIN}/{tenant}/discovery/v2.0/keys"),
+    };
+    let url = |url: String| Url::parse(&url).ok();
+    ProviderMetadata {
+        issuer: format!("{LOGIN}/{tenant}/v2.0"),
+        authorization_endpoint: url(format!("{oauth2}/authorize")),
+        device_authorization_endpoint: url(format!("{oauth2}/devicecode")),
+        token_endpoint: url(format!("{oauth2}/token")),
+        introspection_endpoint: None,
+        jwks_uri: url(jwks),
+        userinfo_endpoint: None,
+        revocation_endpoint: None,
+        backchannel_authentication_endpoint: None,
+    }
+}
+
+// Issuers of the v1 and v2 tokens of the tenant. Both endpoint versions may issue both token
+// versions, depending on the `accessTokenAcceptedVersion` of the API the token is issued for.
+pub fn issuers(tenant: &str) -> Result<Vec<Url>, url::ParseError> {
+    Ok(vec![
+        Url::parse(&format!("https://sts.windows.net/{tenant}/"))?,
+        Url::parse(&format!("{LOGIN}/{tenant}/v2.0"))?,
+    ])
+}
+
+// Scopes of the access token when `requested` were requested. Its `scp` leaves out the OpenID
+// Connect scopes and the resource, e.g. `login` for `api://pam/login`, and `.default` requests
+// whatever scopes were consented to.
+pub fn expected_scopes(requested: &[Scope]) -> Vec<Scope> {
+    requested
+        .iter()
+        .filter(|scope| !OIDC_SCOPES.contains(&scope.as_str()))
+        .map(|scope| {
+            scope
+                .rsplit_once('/')
+                .map_or(scope.as_str(), |(_, name)| name)
+        })
+        .filter(|scope| *scope != ".default")
+        .map(|scope| Scope::new(scope.to_string()))
+        .collect()
+}
+
+pub fn username(claims: &Map<String, Value>) -> Option<&str> {
+    USERNAME_CLAIMS
+        .iter()
+        .find_map(|claim| claims.get(*claim).and_then(Value::as_str))
+}
+
+// Address of a user as known to their home directory. Azure AD mangles the names of guests,
+// e.g. `alice_contoso.com#EXT#@fabrikam.onmicrosoft.com` for `alice@contoso.com`, and
+// prefixes those of other identity providers, e.g. `live.com#alice@outlook.com`.
+pub fn demangle(name: &str) -> String {
+    if let Some((mangled, _)) = name.split_once("#EXT#") {
+        // The `@` of the address was replaced with the last `_`
+        return match mangled.rsplit_once('_') {
+            Some((user, domain)) => format!("{user}@{domain}"),
+            None => mangled.to_string(),
+        };
+    }
+    match name.rsplit_once('#') {
+        Some((_, name)) => name.to_string(),
+        None => name.to_string(),
+    }
+}
@@ -0,0 +1,197 @@
+// Checks a config file from a terminal without going through PAM: parses it, builds the
+// OAuth client of every provider (including discovery) and runs the device flow (or the
+// `ciba` flow) end-to-end, so a broken setup is found before anyone gets locked out of SSH.
+
+use std::io::{BufRead, Write};
+use std::process::ExitCode;
+
+use log::LevelFilter;
+use pam_oauth2_device::config::{read_config, read_strict_config};
+use pam_oauth2_device::flow::DeviceFlow;
+use pam_oauth2_device::oauth_device::{Authorization, OAuthClient, Validation};
+use simplelog::{ConfigBuilder, WriteLogger};
+
+type DynErr = Box<dyn std::error::Error>;
+
+const USAGE: &str = "Usage: pam-oauth2-device-check [OPTIONS]
+
+Options:
+    --config <PATH>     Config file to check [default: /etc/pam_oauth2_device/config.json]
+    --user <NAME>       Local username the login is checked for [default: $USER]
+    --provider <NAME>   Only check the provider of that name, like the `provider` PAM arg
+    --no-device-flow    Stop after building the OAuth clients
+    --strict            Reject unknown keys and invalid values, like `strict_config`
+    -v, --verbose       Print the module's debug log to stderr (secrets are redacted)
+    -h, --help          Print this help";
+
+struct Args {
+    config: String,
+    user: String,
+    provider: Option<String>,
+    device_flow: bool,
+    strict: bool,
+    verbose: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            config: "/etc/pam_oauth2_device/config.json".to_string(),
+            user: std::env::var("USER").unwrap_or_default(),
+            provider: None,
+            device_flow: true,
+            strict: false,
+            verbose: false,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            let mut value = || argv.next().ok_or(format!("Missing value of {arg}"));
+            match arg.as_str() {
+                "--config" => args.config = value()?,
+                "--user" => args.user = value()?,
+                "--provider" => args.provider = Some(value()?),
+                "--no-device-flow" => args.device_flow = false,
+                "--strict" => args.strict = true,
+                "-v" | "--verbose" => args.verbose = true,
+                "-h" | "--help" => return Err(String::new()),
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{e}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let level = if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Warn
+    };
+    let log_config = ConfigBuilder::new().build();
+    let _ = log::set_boxed_logger(WriteLogger::new(level, log_config, std::io::stderr()));
+    log::set_max_level(level);
+
+    match check(&args) {
+        Ok(()) => {
+            println!("\nAll checks passed");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("{}", failure(&*e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn check(args: &Args) -> Result<(), DynErr> {
+    let read = if args.strict {
+        read_strict_config
+    } else {
+        read_config
+    };
+    let config =
+        read(&args.config).map_err(|e| format!("Failed to parse config {}: {e}", args.config))?;
+    println!("✔ Config {} parsed", args.config);
+
+    let providers = config.provider_configs(args.provider.as_deref())?;
+    let mut failed = 0;
+    for provider in &providers {
+        match OAuthClient::new(provider) {
+            Ok(_) => println!("✔ Provider {}: OAuth client built", provider.provider_name),
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "{}",
+                    failure_of(&format!("Provider {}", provider.provider_name), &e)
+                );
+            }
+        }
+    }
+    if failed == providers.len() {
+        return Err("No usable provider".into());
+    }
+    if !args.device_flow {
+        return Ok(());
+    }
+    if args.user.is_empty() {
+        return Err("Unknown local user, set it with --user".into());
+    }
+    let mut flow = DeviceFlow::new(config);
+    if let Some(provider) = &args.provider {
+        flow = flow.with_provider(provider);
+    }
+    device_flow(&flow, &args.user)
+}
+
+fn device_flow(flow: &DeviceFlow, local_user: &str) -> Result<(), DynErr> {
+    let pending = flow.start(local_user)?;
+    let provider_name = &pending.provider().provider_name;
+    let expires_in = pending.expires_in().as_secs();
+    match pending.authorization() {
+        Authorization::Device(_) => {
+            println!(
+                "✔ Provider {provider_name}: device code issued, expires in {expires_in} seconds"
+            );
+            print!("{}", pending.prompt());
+            std::io::stdout().flush()?;
+            std::io::stdin().lock().read_line(&mut String::new())?;
+        }
+        Authorization::Ciba(_) => {
+            println!(
+                "✔ Provider {provider_name}: backchannel authentication requested, expires in {expires_in} seconds"
+            );
+            println!("{}", pending.prompt());
+        }
+    }
+    let token = pending.token()?;
+    println!("✔ Token issued");
+
+    match pending.validate(&token) {
+        Validation::Valid(validated) => {
+            println!("✔ Token valid for local user {local_user}");
+            println!("    remote user:  {}", validated.username);
+            println!("    display name: {}", validated.display_name);
+            if let Some(subject) = &validated.subject {
+                println!("    subject:      {subject}");
+            }
+            if let Some(expires_at) = &validated.expires_at {
+                println!("    expires at:   {expires_at}");
+            }
+            Ok(())
+        }
+        Validation::Denied => Err(format!(
+            "Token rejected for local user {local_user}, run with --verbose for the reason"
+        )
+        .into()),
+        Validation::InsufficientAuthentication => Err(format!(
+            "Token of local user {local_user} doesn't show multi-factor authentication (require_mfa)"
+        )
+        .into()),
+        Validation::Unavailable(e) => Err(format!("Failed to validate token: {e}").into()),
+    }
+}
+
+fn failure(e: &dyn std::error::Error) -> String {
+    failure_of("Check failed", e)
+}
+
+// Error with its chain of causes, like `Logger::handle_error`
+fn failure_of(what: &str, e: &dyn std::error::Error) -> String {
+    let mut msg = format!("✘ {what}: {e}");
+    let mut cause = e.source();
+    while let Some(e) = cause {
+        msg += &format!("\n    caused by: {e}");
+        cause = e.source();
+    }
+    msg
+}
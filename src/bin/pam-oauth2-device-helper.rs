@@ -0,0 +1,101 @@
+// Helper daemon keeping the login cache and the refresh tokens on behalf of the module, see
+// `helper_socket`. Meant to be started by systemd socket activation, binds the socket itself
+// otherwise.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use log::LevelFilter;
+use pam_oauth2_device::config::read_config;
+use pam_oauth2_device::helper::{listen, HelperServer};
+use pam_oauth2_device::state::ensure_state_dir;
+use simplelog::{ConfigBuilder, WriteLogger};
+
+const USAGE: &str = "Usage: pam-oauth2-device-helper [OPTIONS]
+
+Options:
+    --config <PATH>     Config file of the module [default: /etc/pam_oauth2_device/config.json]
+    --socket <PATH>     Socket to listen on, unless passed by systemd [default: `helper_socket`]
+    -v, --verbose       Log every request to stderr
+    -h, --help          Print this help";
+
+struct Args {
+    config: String,
+    socket: Option<PathBuf>,
+    verbose: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            config: "/etc/pam_oauth2_device/config.json".to_string(),
+            socket: None,
+            verbose: false,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            let mut value = || argv.next().ok_or(format!("Missing value of {arg}"));
+            match arg.as_str() {
+                "--config" => args.config = value()?,
+                "--socket" => args.socket = Some(value()?.into()),
+                "-v" | "--verbose" => args.verbose = true,
+                "-h" | "--help" => return Err(String::new()),
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{e}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let level = if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    let log_config = ConfigBuilder::new().build();
+    let _ = log::set_boxed_logger(WriteLogger::new(level, log_config, std::io::stderr()));
+    log::set_max_level(level);
+
+    let config = match read_config(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("✘ Failed to parse config {}: {e}", args.config);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = ensure_state_dir(&config) {
+        eprintln!("✘ {e}");
+        return ExitCode::FAILURE;
+    }
+    let Some(socket) = args.socket.or(config.helper_socket.clone()) else {
+        eprintln!(
+            "✘ No socket given and `helper_socket` is not set in {}",
+            args.config
+        );
+        return ExitCode::FAILURE;
+    };
+    let listener = match listen(&socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("✘ Failed to listen on {}: {e}", socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    log::info!("Listening on {}", socket.display());
+    if let Err(e) = HelperServer::new(&config).serve(&listener) {
+        eprintln!("✘ {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,179 @@
+// Generates a commented config for a kind of Authorization Server, either from the options
+// (`init`) or by asking for them on the terminal (`wizard`). The config is only written once
+// the provider is reachable with it, unless `--no-verify` is given.
+
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use pam_oauth2_device::setup::{install, Preset, Setup};
+
+type DynErr = Box<dyn std::error::Error>;
+
+const USAGE: &str = "Usage: pam-oauth2-device-setup <COMMAND> [OPTIONS]
+
+Commands:
+    init                    Generate the config from the options
+    wizard                  Ask for the provider type, issuer and client, then generate the config
+
+Options:
+    --output <PATH>         Config file to write, must end with .toml [default: /etc/pam_oauth2_device/config.toml]
+    --provider-type <TYPE>  keycloak, azuread, google or oidc [default: oidc]
+    --issuer <URL>          Issuer or Keycloak realm URL, the tenant id for azuread
+    --client-id <ID>        Client id registered at the provider
+    --client-secret <SECRET>
+                            Client secret, left out for public clients
+    --no-verify             Write the config without checking the provider is reachable
+    --force                 Overwrite an existing config
+    -h, --help              Print this help";
+
+#[derive(PartialEq)]
+enum Command {
+    Init,
+    Wizard,
+}
+
+struct Args {
+    command: Command,
+    output: PathBuf,
+    setup: Setup,
+    verify: bool,
+    force: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut argv = std::env::args().skip(1);
+        let command = match argv.next().as_deref() {
+            Some("init") => Command::Init,
+            Some("wizard") => Command::Wizard,
+            Some("-h" | "--help") | None => return Err(String::new()),
+            Some(other) => return Err(format!("Unknown command: {other}")),
+        };
+        let mut args = Args {
+            command,
+            output: PathBuf::from("/etc/pam_oauth2_device/config.toml"),
+            setup: Setup::default(),
+            verify: true,
+            force: false,
+        };
+        while let Some(arg) = argv.next() {
+            let mut value = || argv.next().ok_or(format!("Missing value of {arg}"));
+            match arg.as_str() {
+                "--output" => args.output = value()?.into(),
+                "--provider-type" => {
+                    let value = value()?;
+                    args.setup.preset = value
+                        .parse()
+                        .map_err(|_| format!("Unknown provider type: {value}"))?;
+                }
+                "--issuer" => args.setup.issuer = Some(value()?),
+                "--client-id" => args.setup.client_id = value()?,
+                "--client-secret" => args.setup.client_secret = Some(value()?),
+                "--no-verify" => args.verify = false,
+                "--force" => args.force = true,
+                "-h" | "--help" => return Err(String::new()),
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{e}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match setup(&mut args) {
+        Ok(()) => {
+            println!("✔ Config {} written", args.output.display());
+            println!(
+                "Check the login with: pam-oauth2-device-check --config {}",
+                args.output.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("✘ Setup failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn setup(args: &mut Args) -> Result<(), DynErr> {
+    if args.output.exists() && !args.force {
+        return Err(format!(
+            "Config {} already exists, overwrite it with --force",
+            args.output.display()
+        )
+        .into());
+    }
+    if args.command == Command::Wizard {
+        ask(&mut args.setup)?;
+    }
+    let text = args.setup.render()?;
+    if args.verify {
+        println!("Checking the provider is reachable...");
+    }
+    install(&args.output, &text, args.verify)
+}
+
+// Asks for every answer on the terminal, the given options are the defaults
+fn ask(setup: &mut Setup) -> Result<(), DynErr> {
+    let presets = [
+        Preset::Keycloak,
+        Preset::Azuread,
+        Preset::Google,
+        Preset::Oidc,
+    ];
+    for (i, preset) in presets.iter().enumerate() {
+        println!("  {}) {}", i + 1, preset.description());
+    }
+    let current = presets.iter().position(|p| *p == setup.preset).unwrap_or(3);
+    loop {
+        let answer = prompt("Provider type", Some(&(current + 1).to_string()))?;
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=presets.len()).contains(&n) => {
+                setup.preset = presets[n - 1];
+                break;
+            }
+            _ => println!("Enter a number from 1 to {}", presets.len()),
+        }
+    }
+    if let Some(what) = setup.preset.issuer_prompt() {
+        setup.issuer = Some(prompt(what, setup.issuer.as_deref())?);
+    }
+    setup.client_id = prompt("Client id", Some(&setup.client_id))?;
+    let secret = prompt(
+        "Client secret (empty for a public client)",
+        setup.client_secret.as_deref(),
+    )?;
+    setup.client_secret = Some(secret).filter(|s| !s.is_empty());
+    Ok(())
+}
+
+fn prompt(what: &str, default: Option<&str>) -> Result<String, DynErr> {
+    let default = default.filter(|d| !d.is_empty());
+    match default {
+        Some(default) => print!("{what} [{default}]: "),
+        None => print!("{what}: "),
+    }
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err("Setup aborted".into());
+    }
+    let answer = line.trim();
+    Ok(match default {
+        Some(default) if answer.is_empty() => default.to_string(),
+        _ => answer.to_string(),
+    })
+}
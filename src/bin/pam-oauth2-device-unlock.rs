@@ -0,0 +1,89 @@
+// Lifts the lockout of local users locked out after too many denied logins, see
+// `lockout_threshold`, and forgets their failed logins.
+
+use std::process::ExitCode;
+
+use pam_oauth2_device::config::read_config;
+use pam_oauth2_device::lockout::LockoutStore;
+
+const USAGE: &str = "Usage: pam-oauth2-device-unlock [OPTIONS] <USER>...
+
+Options:
+    --config <PATH>     Config file of the module [default: /etc/pam_oauth2_device/config.json]
+    --status            Only print whether the users are locked out
+    -h, --help          Print this help";
+
+struct Args {
+    config: String,
+    users: Vec<String>,
+    status: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            config: "/etc/pam_oauth2_device/config.json".to_string(),
+            users: Vec::new(),
+            status: false,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--config" => args.config = argv.next().ok_or("Missing value of --config")?,
+                "--status" => args.status = true,
+                "-h" | "--help" => return Err(String::new()),
+                _ if arg.starts_with('-') => return Err(format!("Unknown argument: {arg}")),
+                _ => args.users.push(arg),
+            }
+        }
+        if args.users.is_empty() {
+            return Err("No user given".to_string());
+        }
+        Ok(args)
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{e}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let config = match read_config(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("✘ Failed to parse config {}: {e}", args.config);
+            return ExitCode::FAILURE;
+        }
+    };
+    // The threshold and the duration only matter to new failures
+    let store = LockoutStore::new(
+        &config.lockout_store,
+        config.lockout_threshold.unwrap_or(1),
+        config.lockout_duration,
+    );
+
+    let mut code = ExitCode::SUCCESS;
+    for user in &args.users {
+        let res = if args.status {
+            store.locked_until(user).map(|until| match until {
+                Some(until) => println!("{user}: locked out until {until}"),
+                None => println!("{user}: not locked out"),
+            })
+        } else {
+            store
+                .reset(user)
+                .map(|()| println!("✔ {user}: lockout lifted"))
+        };
+        if let Err(e) = res {
+            eprintln!("✘ {user}: {e}");
+            code = ExitCode::FAILURE;
+        }
+    }
+    code
+}
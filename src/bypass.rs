@@ -0,0 +1,104 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::BypassRule;
+use crate::glob;
+
+// IPv4 or IPv6 network, e.g. `10.20.0.0/16` or `fd00::/8`. A plain address is a single host.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        // sshd reports IPv4 clients of a dual-stack socket as `::ffff:a.b.c.d`
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+    if net[..bytes] != addr[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ addr[bytes]) & (0xff << (8 - bits)) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid network {s}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length of network {s}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl BypassRule {
+    // Whether the login of `service` on `tty` from `rhost` skips the module. Every condition
+    // set must match, a rule without any never applies.
+    pub fn applies(&self, service: &str, tty: &str, rhost: &str) -> bool {
+        if self.rhosts.is_empty() && self.ttys.is_empty() && self.services.is_empty() {
+            return false;
+        }
+        (self.services.is_empty() || glob::matches_any(&self.services, service))
+            && (self.ttys.is_empty() || glob::matches_any(&self.ttys, tty))
+            && (self.rhosts.is_empty() || self.matches_rhost(rhost))
+    }
+
+    // Host names (e.g. sshd with `UseDNS yes`) never match, they can't be trusted
+    fn matches_rhost(&self, rhost: &str) -> bool {
+        match rhost.parse::<IpAddr>() {
+            Ok(addr) => self.rhosts.iter().any(|cidr| cidr.contains(&addr)),
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::helper::HelperClient;
+use crate::logger::LogUser;
+use crate::oauth_device::ValidatedToken;
+use crate::seal;
+
+// Last successful authentication of every local user, so repeated logins within
+// `cache_ttl` (e.g. `sudo` or a screen unlock) skip the device flow.
+// One file per local user, only the hash of the token is stored.
+pub struct TokenCache {
+    dir: PathBuf,
+    ttl: Duration,
+    // Entries are kept by the helper daemon instead of in `dir`
+    helper: Option<HelperClient>,
+    // Entries are encrypted with this key, see `cache_key`
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub token_hash: String,
+    // Service, tty, remote user and host of the login, a cached login is only reused for the same one
+    pub context: String,
+    pub username: String,
+    pub subject: Option<String>,
+    pub display_name: String,
+    // Groups of the token and whether it proved multiple factors, so `allowed_groups` and
+    // `require_mfa` are checked again when the login is reused. Unknown for older entries.
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
+    #[serde(default)]
+    pub multi_factor: bool,
+    pub cached_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl CacheEntry {
+    pub fn validated(&self) -> ValidatedToken {
+        ValidatedToken {
+            username: self.username.clone(),
+            subject: self.subject.clone(),
+            display_name: self.display_name.clone(),
+            expires_at: Some(self.expires_at),
+            groups: self.groups.clone(),
+            email: None,
+            uid: None,
+        }
+    }
+}
+
+impl TokenCache {
+    pub fn new(dir: &Path, ttl: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            ttl,
+            helper: None,
+            key_file: None,
+        }
+    }
+
+    pub fn encrypted(mut self, key_file: &Path) -> Self {
+        self.key_file = Some(key_file.to_path_buf());
+        self
+    }
+
+    pub fn via_helper(mut self, socket: &Path) -> Self {
+        self.helper = Some(HelperClient::new(socket));
+        self
+    }
+
+    // Remembers a successful login until the ttl elapses or the token expires, whichever is
+    // first. `multi_factor` if the token was checked for multiple factors, see `require_mfa`.
+    pub fn store(
+        &self,
+        local_user: &str,
+        context: &str,
+        token: &str,
+        validated: &ValidatedToken,
+        multi_factor: bool,
+    ) -> Result<CacheEntry, IOError> {
+        let cached_at = Utc::now();
+        let ttl = chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::MAX);
+        let mut expires_at = cached_at
+            .checked_add_signed(ttl)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        if let Some(token_exp) = validated.expires_at {
+            expires_at = expires_at.min(token_exp);
+        }
+        let entry = CacheEntry {
+            token_hash: hash(token),
+            context: context.to_string(),
+            username: validated.username.clone(),
+            subject: validated.subject.clone(),
+            display_name: validated.display_name.clone(),
+            groups: validated.groups.clone(),
+            multi_factor,
+            cached_at,
+            expires_at,
+        };
+        self.put(local_user, &entry)?;
+        Ok(entry)
+    }
+
+    // Writes a complete entry, as received by the helper daemon
+    pub fn put(&self, local_user: &str, entry: &CacheEntry) -> Result<(), IOError> {
+        if let Some(helper) = &self.helper {
+            return helper.cache_store(local_user, entry);
+        }
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let path = self.entry(local_user)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let tmp = path.with_file_name(format!(".{local_user}.{}.{nanos}", std::process::id()));
+        // Never follows or reuses a file someone else put there
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        let mut data = serde_json::to_vec(entry)?;
+        if let Some(key_file) = &self.key_file {
+            data = seal::seal(&seal::load_key(key_file)?, local_user.as_bytes(), &data)?;
+        }
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        self.cleanup();
+        Ok(())
+    }
+
+    // Cached login of `local_user` for the same context, if it hasn't expired
+    pub fn lookup(&self, local_user: &str, context: &str) -> Result<Option<CacheEntry>, IOError> {
+        self.lookup_in(local_user, Some(context))
+    }
+
+    // Cached login of `local_user` for any context, if it hasn't expired
+    pub fn lookup_any_context(&self, local_user: &str) -> Result<Option<CacheEntry>, IOError> {
+        self.lookup_in(local_user, None)
+    }
+
+    fn lookup_in(
+        &self,
+        local_user: &str,
+        context: Option<&str>,
+    ) -> Result<Option<CacheEntry>, IOError> {
+        if let Some(helper) = &self.helper {
+            return helper.cache_lookup(local_user, context);
+        }
+        let path = self.entry(local_user)?;
+        let Some(entry) = read_entry(&path, self.key_file.as_deref())? else {
+            return Ok(None);
+        };
+        if entry.expires_at <= Utc::now() {
+            log::debug!("Cached login of {} expired", LogUser(local_user));
+            remove(&path)?;
+            return Ok(None);
+        }
+        if context.is_some_and(|context| entry.context != context) {
+            log::debug!(
+                "Cached login of {} is for another context",
+                LogUser(local_user)
+            );
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    pub fn remove(&self, local_user: &str) -> Result<(), IOError> {
+        if let Some(helper) = &self.helper {
+            return helper.cache_remove(local_user);
+        }
+        remove(&self.entry(local_user)?)
+    }
+
+    // Drops the expired entries of all users
+    fn cleanup(&self) {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        // Temporary files of concurrent writers start with a dot
+        let entries = dir
+            .flatten()
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'));
+        for path in entries.map(|e| e.path()) {
+            let expired = match read_entry(&path, self.key_file.as_deref()) {
+                Ok(Some(entry)) => entry.expires_at <= Utc::now(),
+                Ok(None) => false,
+                Err(_) => true,
+            };
+            if expired {
+                if let Err(e) = remove(&path) {
+                    log::warn!(
+                        "Failed to remove expired cache entry {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn entry(&self, local_user: &str) -> Result<PathBuf, IOError> {
+        if local_user.is_empty() || local_user.starts_with('.') || local_user.contains('/') {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid local username: {}", LogUser(local_user)),
+            ));
+        }
+        Ok(self.dir.join(local_user))
+    }
+}
+
+// Entries not owned by us or accessible by others may have been planted and are ignored.
+// Encrypted entries are authenticated with the local username, their file name.
+fn read_entry(path: &Path, key_file: Option<&Path>) -> Result<Option<CacheEntry>, IOError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let euid = unsafe { libc::geteuid() };
+    if !metadata.is_file() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+        log::warn!("Ignoring insecure cache entry {}", path.display());
+        return Ok(None);
+    }
+    let mut data = fs::read(path)?;
+    if let Some(key_file) = key_file {
+        let local_user = path.file_name().unwrap_or_default().as_encoded_bytes();
+        data = seal::open(&seal::load_key(key_file)?, local_user, &data)?;
+    }
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+fn remove(path: &Path) -> Result<(), IOError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,70 @@
+use serde_json::Value;
+
+use crate::config::{ClaimOperator, ClaimRule};
+use crate::logger::LogUser;
+
+// Whether `claims` (the token introspection, JWT claims or userinfo response) satisfy every
+// rule. A missing claim only satisfies `exists` with a `false` value.
+pub fn required_claims_met(rules: &[ClaimRule], claims: &Value, user: &str) -> bool {
+    let failed: Vec<&ClaimRule> = rules
+        .iter()
+        .filter(|rule| !rule_met(rule, lookup(claims, &rule.claim)))
+        .collect();
+    for rule in &failed {
+        log::warn!(
+            "Required claim {} {:?} {} not met for user {}",
+            rule.claim,
+            rule.operator,
+            rule.value,
+            LogUser(user)
+        );
+    }
+    failed.is_empty()
+}
+
+// Looks up a nested claim by its dot separated path, e.g. `realm_access.roles`
+pub fn lookup<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(claims, |value, part| value.get(part))
+        .filter(|value| !value.is_null())
+}
+
+fn rule_met(rule: &ClaimRule, claim: Option<&Value>) -> bool {
+    let claim = match (rule.operator, claim) {
+        (ClaimOperator::Exists, claim) => {
+            return claim.is_some() != (rule.value == Value::Bool(false))
+        }
+        (_, None) => return false,
+        (_, Some(claim)) => claim,
+    };
+    match rule.operator {
+        ClaimOperator::Eq => *claim == rule.value,
+        ClaimOperator::Ne => *claim != rule.value,
+        ClaimOperator::In => elements(claim).iter().any(|c| one_of(c, &rule.value)),
+        ClaimOperator::NotIn => !elements(claim).iter().any(|c| one_of(c, &rule.value)),
+        ClaimOperator::Contains => match claim {
+            Value::Array(values) => values.contains(&rule.value),
+            Value::String(values) => rule
+                .value
+                .as_str()
+                .is_some_and(|value| values.split_whitespace().any(|v| v == value)),
+            _ => false,
+        },
+        ClaimOperator::Exists => true,
+    }
+}
+
+// The elements of a list claim, or the claim itself
+fn elements(claim: &Value) -> Vec<&Value> {
+    match claim {
+        Value::Array(values) => values.iter().collect(),
+        claim => vec![claim],
+    }
+}
+
+fn one_of(claim: &Value, values: &Value) -> bool {
+    match values {
+        Value::Array(values) => values.contains(claim),
+        value => value == claim,
+    }
+}
@@ -0,0 +1,159 @@
+use serde::Deserialize;
+use url::Url;
+
+use crate::azuread;
+use crate::config::{Config, Flow, ProviderType};
+use crate::http::{get_json, HttpClient};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Subset of the OpenID Provider Metadata used by this module
+// (see https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata)
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: Option<Url>,
+    pub device_authorization_endpoint: Option<Url>,
+    pub token_endpoint: Option<Url>,
+    pub introspection_endpoint: Option<Url>,
+    pub jwks_uri: Option<Url>,
+    pub userinfo_endpoint: Option<Url>,
+    pub revocation_endpoint: Option<Url>,
+    pub backchannel_authentication_endpoint: Option<Url>,
+}
+
+// Endpoints used by the OAuth client, either configured or discovered
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub auth: Url,
+    // Only the endpoint of the configured `flow` is required
+    pub device: Option<Url>,
+    pub backchannel: Option<Url>,
+    pub token: Url,
+    // Not used by providers of opaque tokens
+    pub introspection: Option<Url>,
+    pub jwks: Option<Url>,
+    pub userinfo: Option<Url>,
+    pub revocation: Option<Url>,
+}
+
+impl Endpoints {
+    // Manually configured endpoints take precedence over the discovered ones
+    pub fn resolve(c: &Config, http_client: &HttpClient) -> Result<Self, DynErr> {
+        let metadata = match &c.issuer {
+            Some(issuer) => Some(discover(issuer, http_client)?),
+            None => preset_metadata(c),
+        };
+        let pick = |configured: &Option<Url>,
+                    discovered: fn(&ProviderMetadata) -> &Option<Url>,
+                    name: &str|
+         -> Result<Url, DynErr> {
+            configured
+                .clone()
+                .or_else(|| metadata.as_ref().and_then(|m| discovered(m).clone()))
+                .ok_or_else(|| format!("No {name} configured or discovered").into())
+        };
+
+        Ok(Self {
+            auth: pick(
+                &c.oauth_auth_url,
+                |m| &m.authorization_endpoint,
+                "oauth_auth_url",
+            )?,
+            device: match c.flow {
+                Flow::Device => Some(pick(
+                    &c.oauth_device_url,
+                    |m| &m.device_authorization_endpoint,
+                    "oauth_device_url",
+                )?),
+                Flow::Ciba => None,
+            },
+            backchannel: match c.flow {
+                Flow::Ciba => Some(pick(
+                    &c.oauth_backchannel_auth_url,
+                    |m| &m.backchannel_authentication_endpoint,
+                    "oauth_backchannel_auth_url",
+                )?),
+                Flow::Device => None,
+            },
+            token: pick(&c.oauth_token_url, |m| &m.token_endpoint, "oauth_token_url")?,
+            introspection: c.oauth_token_introspect_url.clone().or_else(|| {
+                metadata
+                    .as_ref()
+                    .and_then(|m| m.introspection_endpoint.clone())
+            }),
+            // Only needed by the `jwks` validation mode
+            jwks: c
+                .jwks_uri
+                .clone()
+                .or_else(|| metadata.as_ref().and_then(|m| m.jwks_uri.clone())),
+            // Only needed when the `username_claim` is missing from the token
+            userinfo: c
+                .oauth_userinfo_url
+                .clone()
+                .or_else(|| metadata.as_ref().and_then(|m| m.userinfo_endpoint.clone())),
+            // Only needed with `revoke_on_logout`
+            revocation: c.oauth_revocation_url.clone().or_else(|| {
+                metadata
+                    .as_ref()
+                    .and_then(|m| m.revocation_endpoint.clone())
+            }),
+        })
+    }
+}
+
+// Endpoints of the well-known providers without a discovery document
+fn preset_metadata(c: &Config) -> Option<ProviderMetadata> {
+    let (issuer, auth, device, token) = match c.provider_type {
+        ProviderType::Github => (
+            "https://github.com",
+            "https://github.com/login/oauth/authorize",
+            "https://github.com/login/device/code",
+            "https://github.com/login/oauth/access_token",
+        ),
+        ProviderType::Gitlab => (
+            "https://gitlab.com",
+            "https://gitlab.com/oauth/authorize",
+            "https://gitlab.com/oauth/authorize_device",
+            "https://gitlab.com/oauth/token",
+        ),
+        ProviderType::Azuread => return c.azuread.as_ref().map(azuread::metadata),
+        ProviderType::Oidc | ProviderType::Rest => return None,
+    };
+    Some(ProviderMetadata {
+        issuer: issuer.to_string(),
+        authorization_endpoint: Url::parse(auth).ok(),
+        device_authorization_endpoint: Url::parse(device).ok(),
+        token_endpoint: Url::parse(token).ok(),
+        introspection_endpoint: None,
+        jwks_uri: None,
+        userinfo_endpoint: None,
+        revocation_endpoint: None,
+        backchannel_authentication_endpoint: None,
+    })
+}
+
+pub fn discovery_url(issuer: &Url) -> Result<Url, DynErr> {
+    let base = issuer.as_str().trim_end_matches('/');
+    Ok(Url::parse(&format!(
+        "{base}/.well-known/openid-configuration"
+    ))?)
+}
+
+// Fetches the discovery document of `issuer`
+pub fn discover(issuer: &Url, http_client: &HttpClient) -> Result<ProviderMetadata, DynErr> {
+    let url = discovery_url(issuer)?;
+    log::debug!("Fetching OpenID Provider Metadata from {url}");
+
+    let metadata: ProviderMetadata = get_json(http_client, &url)?;
+    // The issuer in the document must match the configured one (OIDC Discovery 4.3)
+    if metadata.issuer.trim_end_matches('/') != issuer.as_str().trim_end_matches('/') {
+        return Err(format!(
+            "Discovered issuer {} doesn't match configured issuer {}",
+            metadata.issuer, issuer
+        )
+        .into());
+    }
+    log::debug!("Discovered metadata: {:#?}", metadata);
+    Ok(metadata)
+}
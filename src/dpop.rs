@@ -0,0 +1,107 @@
+use std::fmt;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk,
+};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Serialize;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// P-256 key proving possession of DPoP-bound tokens (RFC 9449). Tokens are bound to the key
+// they were requested with, so a refresh token must be redeemed with the key it was issued to.
+#[derive(Clone)]
+pub struct DpopKey {
+    pkcs8: Vec<u8>,
+    key: EncodingKey,
+    jwk: Jwk,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    jti: String,
+    htm: &'a str,
+    htu: &'a str,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+}
+
+impl DpopKey {
+    pub fn generate() -> Result<Self, DynErr> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| "Failed to generate DPoP key")?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, DynErr> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| "Invalid DPoP key")?;
+        // Uncompressed point: 0x04, x and y
+        let point = key_pair.public_key().as_ref();
+        let jwk = Jwk {
+            common: CommonParameters::default(),
+            algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&point[33..65]),
+            }),
+        };
+        Ok(Self {
+            pkcs8: pkcs8.to_vec(),
+            key: EncodingKey::from_ec_der(pkcs8),
+            jwk,
+        })
+    }
+
+    // PKCS#8 encoding of the private key, to be stored along with the refresh token
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    pub fn jwk(&self) -> &Jwk {
+        &self.jwk
+    }
+
+    // Proof of the `method` request to `url`, with the `nonce` the server asked for if any
+    pub fn proof(&self, method: &str, url: &str, nonce: Option<&str>) -> Result<String, DynErr> {
+        let mut jti = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut jti)
+            .map_err(|_| "Failed to generate jti")?;
+        let mut header = Header::new(Algorithm::ES256);
+        header.typ = Some("dpop+jwt".to_string());
+        header.jwk = Some(self.jwk.clone());
+        let claims = Claims {
+            jti: URL_SAFE_NO_PAD.encode(jti),
+            htm: method,
+            // Without query and fragment (RFC 9449, section 4.2)
+            htu: url.split(['?', '#']).next().unwrap_or(url),
+            iat: Utc::now().timestamp(),
+            nonce,
+        };
+        Ok(encode(&header, &claims, &self.key)?)
+    }
+}
+
+// The public JWK only. The key is kept by the HTTP client, whose `Debug` is logged with the
+// OAuth client.
+impl fmt::Debug for DpopKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DpopKey")
+            .field("jwk", &self.jwk)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,76 @@
+use std::ffi::{CStr, CString};
+
+use libc::{c_char, c_int};
+use pam::constants::PamResultCode;
+use pam::module::PamHandle;
+
+// pam-bindings doesn't wrap the PAM environment functions
+#[link(name = "pam")]
+extern "C" {
+    fn pam_putenv(pamh: *mut PamHandle, name_value: *const c_char) -> c_int;
+    fn pam_getenv(pamh: *const PamHandle, name: *const c_char) -> *const c_char;
+}
+
+// Variables selecting the language of the messages, by precedence
+const LOCALE_VARS: &[&str] = &["LC_ALL", "LC_MESSAGES", "LANG"];
+
+// Sets `name` in the PAM environment, which is passed on to the session
+pub fn put_env(pamh: &mut PamHandle, name: &str, value: &str) -> Result<(), PamResultCode> {
+    putenv(pamh, &format!("{name}={value}"))
+}
+
+// Removes `name` from the PAM environment
+pub fn unset_env(pamh: &mut PamHandle, name: &str) -> Result<(), PamResultCode> {
+    match putenv(pamh, name) {
+        // Removing a variable that isn't set
+        Err(PamResultCode::PAM_BAD_ITEM) => Ok(()),
+        res => res,
+    }
+}
+
+// Value of `name` in the PAM environment, None if not set
+pub fn get_env(pamh: &PamHandle, name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    let value = unsafe { pam_getenv(pamh, name.as_ptr()) };
+    if value.is_null() {
+        return None;
+    }
+    // The value is owned by the PAM handle
+    Some(
+        unsafe { CStr::from_ptr(value) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+// Locale of the user, e.g. `de_DE.UTF-8`, from the PAM environment (e.g. set by
+// pam_env or passed on by sshd) or else the environment of the process
+pub fn locale(pamh: &PamHandle) -> Option<String> {
+    LOCALE_VARS
+        .iter()
+        .find_map(|name| get_env(pamh, name).filter(|value| !value.is_empty()))
+        .or_else(|| {
+            LOCALE_VARS
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        })
+}
+
+// Value of `name` in the PAM environment or else the environment of the process, None if
+// empty in both
+pub fn login_var(pamh: &PamHandle, name: &str) -> Option<String> {
+    get_env(pamh, name)
+        .filter(|value| !value.is_empty())
+        .or_else(|| std::env::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+fn putenv(pamh: &mut PamHandle, name_value: &str) -> Result<(), PamResultCode> {
+    let name_value = CString::new(name_value).map_err(|_| PamResultCode::PAM_BUF_ERR)?;
+    // pam_putenv copies the string
+    let res = unsafe { pam_putenv(pamh, name_value.as_ptr()) };
+    match PamResultCode::try_from(res) {
+        Ok(PamResultCode::PAM_SUCCESS) => Ok(()),
+        Ok(err) => Err(err),
+        Err(_) => Err(PamResultCode::PAM_SYSTEM_ERR),
+    }
+}
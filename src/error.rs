@@ -0,0 +1,172 @@
+use std::fmt;
+
+use oauth2::basic::BasicErrorResponse;
+use oauth2::{ConfigurationError, DeviceCodeErrorResponse, ErrorResponse, RequestTokenError};
+
+use crate::http::{is_unreachable, FormError, HttpError};
+use crate::interrupt::is_interrupted;
+
+type DynErr = Box<dyn std::error::Error>;
+
+/// Failure of the device flow (or the `ciba` flow), by class, so callers can react to each of
+/// them, e.g. the PAM module maps them onto PAM result codes. The failures wrapping an error
+/// display it as is.
+#[derive(Debug)]
+pub enum OAuthDeviceError {
+    /// The Authorization Server couldn't be reached, after retries
+    Network(DynErr),
+    /// Error response of the Authorization Server (RFC 6749, section 5.2)
+    Protocol {
+        error: ProtocolError,
+        source: DynErr,
+    },
+    /// The config or the provider selection is invalid
+    Config(DynErr),
+    /// The token was rejected by the validation
+    Denied,
+    /// The token doesn't show the multi-factor authentication required by `require_mfa`
+    InsufficientAuthentication,
+    /// The token could not be validated
+    Validation(DynErr),
+    /// A signal interrupted the polling, see `interrupt`
+    Interrupted,
+    /// Any other failure, e.g. a response that can't be parsed
+    Other(DynErr),
+}
+
+/// `error` code of an error response
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// The user has not authorized the login yet (RFC 8628, section 3.5)
+    AuthorizationPending,
+    /// Polling too fast (RFC 8628, section 3.5)
+    SlowDown,
+    /// The user denied the authorization
+    AccessDenied,
+    /// The device code or the backchannel request expired before the user authorized it,
+    /// reported by the server or after polling for `expires_in`
+    ExpiredToken,
+    Other(String),
+}
+
+impl OAuthDeviceError {
+    /// Classifies an error of the HTTP client, oauth2 or the module's own requests
+    pub fn classify(err: DynErr) -> Self {
+        if is_interrupted(&*err) {
+            return Self::Interrupted;
+        }
+        if is_unreachable(&*err) {
+            return Self::Network(err);
+        }
+        match error_code(&*err) {
+            Some(code) => Self::Protocol {
+                error: ProtocolError::from(code),
+                source: err,
+            },
+            None => Self::Other(err),
+        }
+    }
+
+    // Like `classify`, errors not sent by a server are config errors
+    pub(crate) fn config(err: DynErr) -> Self {
+        match Self::classify(err) {
+            Self::Other(err) => Self::Config(err),
+            classified => classified,
+        }
+    }
+
+    pub fn protocol_error(&self) -> Option<&ProtocolError> {
+        match self {
+            Self::Protocol { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn is_expired_token(&self) -> bool {
+        self.protocol_error() == Some(&ProtocolError::ExpiredToken)
+    }
+}
+
+impl From<&str> for ProtocolError {
+    fn from(code: &str) -> Self {
+        match code {
+            "authorization_pending" => Self::AuthorizationPending,
+            "slow_down" => Self::SlowDown,
+            "access_denied" => Self::AccessDenied,
+            "expired_token" => Self::ExpiredToken,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+// `error` of the error response `err` or one of its sources is about
+fn error_code<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a str> {
+    std::iter::successors(Some(err), |e| e.source()).find_map(|e| {
+        if let Some(RequestTokenError::ServerResponse(resp)) =
+            e.downcast_ref::<RequestTokenError<HttpError, DeviceCodeErrorResponse>>()
+        {
+            return Some(resp.error().as_ref());
+        }
+        if let Some(RequestTokenError::ServerResponse(resp)) =
+            e.downcast_ref::<RequestTokenError<HttpError, BasicErrorResponse>>()
+        {
+            return Some(resp.error().as_ref());
+        }
+        e.downcast_ref::<FormError>()?.error.as_deref()
+    })
+}
+
+impl fmt::Display for OAuthDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(err)
+            | Self::Protocol { source: err, .. }
+            | Self::Config(err)
+            | Self::Validation(err)
+            | Self::Other(err) => write!(f, "{err}"),
+            Self::Denied => write!(f, "Token rejected"),
+            Self::InsufficientAuthentication => {
+                write!(f, "Token doesn't show multi-factor authentication")
+            }
+            Self::Interrupted => write!(f, "Token polling interrupted"),
+        }
+    }
+}
+
+// The wrapped error is displayed in place, its causes are the causes of the failure
+impl std::error::Error for OAuthDeviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(err)
+            | Self::Protocol { source: err, .. }
+            | Self::Config(err)
+            | Self::Validation(err)
+            | Self::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<DynErr> for OAuthDeviceError {
+    fn from(err: DynErr) -> Self {
+        Self::classify(err)
+    }
+}
+
+impl<RE: ErrorResponse + 'static> From<RequestTokenError<HttpError, RE>> for OAuthDeviceError {
+    fn from(err: RequestTokenError<HttpError, RE>) -> Self {
+        Self::classify(Box::new(err))
+    }
+}
+
+impl From<ConfigurationError> for OAuthDeviceError {
+    fn from(err: ConfigurationError) -> Self {
+        Self::Config(Box::new(err))
+    }
+}
+
+impl From<serde_json::Error> for OAuthDeviceError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Other(Box::new(err))
+    }
+}
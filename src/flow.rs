@@ -0,0 +1,187 @@
+//! Device flow without PAM, for CLIs and daemons sharing the config and the token validation
+//! of the module.
+//!
+//! ```no_run
+//! use pam_oauth2_device::flow::DeviceFlow;
+//!
+//! let flow = DeviceFlow::from_file("/etc/pam_oauth2_device/config.json")?;
+//! let pending = flow.start("alice")?;
+//! println!("{}", pending.prompt());
+//! let login = pending.finish()?;
+//! println!("Logged in as {}", login.validated.username);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::Error as IOError;
+use std::time::Duration;
+
+use crate::config::{read_config, Config};
+use crate::error::OAuthDeviceError;
+use crate::oauth_device::{
+    authorize_with_fallback, Authorization, CibaAuthorization, DeviceTokenResponse, OAuthClient,
+    ValidatedToken, Validation,
+};
+use crate::prompt::UserPrompt;
+use oauth2::StandardDeviceAuthorizationResponse;
+
+/// Logins of local users with the providers of a config, tried in order like the PAM module
+/// does.
+pub struct DeviceFlow {
+    config: Config,
+    provider: Option<String>,
+}
+
+/// Authorization started by [`DeviceFlow::start`], waiting for the user.
+pub struct PendingLogin {
+    provider: Config,
+    oauth_client: OAuthClient,
+    authorization: Authorization,
+    local_user: String,
+}
+
+/// Token of a completed login, validated for the local user.
+#[derive(Debug)]
+pub struct Login {
+    /// Name of the provider that issued the token
+    pub provider: String,
+    pub token: DeviceTokenResponse,
+    pub validated: ValidatedToken,
+}
+
+impl DeviceFlow {
+    /// Flow of an already parsed config, e.g. one adjusted with [`Config::for_service`].
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            provider: None,
+        }
+    }
+
+    /// Flow of a config file, read like the PAM module reads it.
+    pub fn from_file(path: &str) -> Result<Self, IOError> {
+        Ok(Self::new(read_config(path)?))
+    }
+
+    /// Only uses the provider of that name, like the `provider` PAM argument.
+    pub fn with_provider(mut self, name: &str) -> Self {
+        self.provider = Some(name.to_string());
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Requests a device code (or a backchannel authentication with the `ciba` flow) for
+    /// `local_user`, falling back to the next provider when one fails.
+    pub fn start(&self, local_user: &str) -> Result<PendingLogin, OAuthDeviceError> {
+        let providers = self
+            .config
+            .provider_configs(self.provider.as_deref())
+            .map_err(|e| OAuthDeviceError::Config(e.into()))?;
+        let (provider, oauth_client, authorization) =
+            authorize_with_fallback(&providers, local_user)?;
+        Ok(PendingLogin {
+            provider: provider.clone(),
+            oauth_client,
+            authorization,
+            local_user: local_user.to_string(),
+        })
+    }
+}
+
+impl PendingLogin {
+    /// Config of the provider the authorization was started with.
+    pub fn provider(&self) -> &Config {
+        &self.provider
+    }
+
+    pub fn authorization(&self) -> &Authorization {
+        &self.authorization
+    }
+
+    pub fn oauth_client(&self) -> &OAuthClient {
+        &self.oauth_client
+    }
+
+    /// Time left to the user to authorize the login.
+    pub fn expires_in(&self) -> Duration {
+        match &self.authorization {
+            Authorization::Device(details) => details.expires_in(),
+            Authorization::Ciba(ciba) => Duration::from_secs(ciba.expires_in),
+        }
+    }
+
+    /// What the user is asked to do, rendered from the `messages` of the config. The device
+    /// prompt ends with `messages.prompt_enter`, see [`PendingLogin::notification`] for one
+    /// without it.
+    pub fn prompt(&self) -> String {
+        match &self.authorization {
+            Authorization::Device(details) => self.user_prompt(details).to_string(),
+            Authorization::Ciba(ciba) => self.ciba_prompt(ciba),
+        }
+    }
+
+    /// Same as [`PendingLogin::prompt`], without asking to press Enter.
+    pub fn notification(&self) -> String {
+        match &self.authorization {
+            Authorization::Device(details) => self.user_prompt(details).notification(),
+            Authorization::Ciba(ciba) => self.ciba_prompt(ciba),
+        }
+    }
+
+    /// Polls the token endpoint until the user authorized the login, the authorization expired
+    /// or `oauth_device_token_polling_timeout` elapsed.
+    pub fn token(&self) -> Result<DeviceTokenResponse, OAuthDeviceError> {
+        let timeout = self.provider.oauth_device_token_polling_timeout;
+        match &self.authorization {
+            Authorization::Device(details) => self.oauth_client.get_token(details, timeout),
+            Authorization::Ciba(ciba) => self.oauth_client.ciba_token(ciba, timeout),
+        }
+    }
+
+    /// Validates `token` for the local user, with the `validation_mode` of the provider.
+    pub fn validate(&self, token: &DeviceTokenResponse) -> Validation {
+        self.oauth_client.validate(token, &self.local_user)
+    }
+
+    /// Waits for the token and validates it.
+    pub fn finish(self) -> Result<Login, OAuthDeviceError> {
+        let token = self.token()?;
+        match self.validate(&token) {
+            Validation::Valid(validated) => Ok(Login {
+                provider: self.provider.provider_name,
+                token,
+                validated,
+            }),
+            Validation::Denied => Err(OAuthDeviceError::Denied),
+            Validation::InsufficientAuthentication => {
+                Err(OAuthDeviceError::InsufficientAuthentication)
+            }
+            Validation::Unavailable(e) => Err(OAuthDeviceError::Validation(e)),
+        }
+    }
+
+    fn user_prompt(&self, details: &StandardDeviceAuthorizationResponse) -> UserPrompt {
+        let config = &self.provider;
+        let mut prompt = UserPrompt::new(details, &config.messages);
+        prompt.set_username(&self.local_user);
+        if !config.prefer_verification_uri_complete {
+            prompt.ignore_verification_uri_complete();
+        }
+        if config.qr_enabled {
+            prompt.generate_qr(&config.qr);
+        }
+        if config.prompt_colors {
+            prompt.enable_styling();
+        }
+        prompt
+    }
+
+    fn ciba_prompt(&self, ciba: &CibaAuthorization) -> String {
+        self.provider.messages.prompt_ciba.replace(
+            "{binding_message}",
+            ciba.binding_message.as_deref().unwrap_or_default(),
+        )
+    }
+}
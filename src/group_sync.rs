@@ -0,0 +1,138 @@
+use std::io::Error as IOError;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, GroupSyncConfig};
+use crate::groups::local_groups;
+use crate::logger::LogUser;
+
+// Local group membership following the IdP groups of the user: after a login the user is
+// added to the local groups mapped from their IdP groups, and with `remove` taken out of the
+// mapped local groups of IdP groups they left. Local groups not in the map are never touched.
+pub struct GroupSync<'a> {
+    config: &'a GroupSyncConfig,
+}
+
+// Local groups a user was added to and removed from, or would have been in a dry run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GroupChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub dry_run: bool,
+}
+
+impl GroupChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl<'a> GroupSync<'a> {
+    // None without `group_sync`
+    pub fn new(config: &'a Config) -> Option<Self> {
+        Some(Self {
+            config: config.group_sync.as_ref()?,
+        })
+    }
+
+    // Changes bringing the local groups `current` in line with the IdP groups `groups`
+    pub fn plan(&self, groups: &[String], current: &[String]) -> GroupChanges {
+        let mut wanted: Vec<&String> = self
+            .config
+            .map
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .flat_map(|(_, local)| local)
+            .collect();
+        wanted.sort();
+        wanted.dedup();
+        let added = wanted
+            .iter()
+            .filter(|group| !current.contains(group))
+            .map(|group| group.to_string())
+            .collect();
+
+        let mut removed = Vec::new();
+        if self.config.remove {
+            let mut mapped: Vec<&String> = self.config.map.values().flatten().collect();
+            mapped.sort();
+            mapped.dedup();
+            removed = mapped
+                .into_iter()
+                .filter(|group| current.contains(group) && !wanted.contains(group))
+                .cloned()
+                .collect();
+        }
+        GroupChanges {
+            added,
+            removed,
+            dry_run: self.config.dry_run,
+        }
+    }
+
+    // Applies the changes for the local `user`, only logging them in a dry run. A change that
+    // fails is logged and left out of the result, the others are still made.
+    pub fn sync(&self, user: &str, groups: &[String]) -> Result<GroupChanges, IOError> {
+        let mut changes = self.plan(groups, &local_groups(user)?);
+        if changes.dry_run {
+            for group in &changes.added {
+                log::info!("Dry run, would add user {} to group {group}", LogUser(user));
+            }
+            for group in &changes.removed {
+                log::info!(
+                    "Dry run, would remove user {} from group {group}",
+                    LogUser(user)
+                );
+            }
+            return Ok(changes);
+        }
+
+        changes
+            .added
+            .retain(|group| match self.gpasswd("-a", user, group) {
+                Ok(()) => {
+                    log::info!("Added user {} to group {group}", LogUser(user));
+                    true
+                }
+                Err(e) => {
+                    log::error!("Failed to add user {} to group {group}: {e}", LogUser(user));
+                    false
+                }
+            });
+        changes
+            .removed
+            .retain(|group| match self.gpasswd("-d", user, group) {
+                Ok(()) => {
+                    log::info!("Removed user {} from group {group}", LogUser(user));
+                    true
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to remove user {} from group {group}: {e}",
+                        LogUser(user)
+                    );
+                    false
+                }
+            });
+        Ok(changes)
+    }
+
+    // `gpasswd -a|-d <user> -- <group>`, which edits `/etc/group` and `/etc/gshadow` with the
+    // usual locking. The user is the argument of the flag and the group follows `--`, so
+    // neither is taken for an option.
+    fn gpasswd(&self, flag: &str, user: &str, group: &str) -> Result<(), IOError> {
+        let output = Command::new(&self.config.gpasswd)
+            .args([flag, user, "--", group])
+            .output()?;
+        if !output.status.success() {
+            return Err(IOError::other(format!(
+                "{} {}: {}",
+                self.config.gpasswd.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
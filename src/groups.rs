@@ -0,0 +1,164 @@
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{Error as IOError, ErrorKind};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+// Initial size of the string buffers of the `*_r` lookups, doubled while too small
+const BUFFER_SIZE: usize = 1024;
+// Upper bound of the buffers, so a broken NSS module can't make the lookups grow forever
+const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Whether the local `user` is a member of one of `groups`, by its primary or a supplementary group
+pub fn member_of_any(user: &str, groups: &[String]) -> Result<bool, IOError> {
+    Ok(local_groups(user)?
+        .iter()
+        .any(|group| groups.contains(group)))
+}
+
+// Names of the local groups of `user` as resolved by NSS, e.g. from `/etc/group` or SSSD
+pub fn local_groups(user: &str) -> Result<Vec<String>, IOError> {
+    let name = CString::new(user).map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    let (_, gid) = passwd_ids(&name)?;
+
+    let mut gids: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = gids.len() as libc::c_int;
+        let found =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            gids.truncate(count as usize);
+            break;
+        }
+        // Too small, `count` holds the number of groups
+        gids.resize((count as usize).max(gids.len() * 2), 0);
+    }
+
+    let mut names = Vec::with_capacity(gids.len());
+    for gid in gids {
+        match group_name(gid)? {
+            Some(name) => names.push(name),
+            None => log::debug!("No name for local group {gid}"),
+        }
+    }
+    Ok(names)
+}
+
+// Uid and primary gid of the local `user`
+pub fn user_ids(user: &str) -> Result<(libc::uid_t, libc::gid_t), IOError> {
+    let name = CString::new(user).map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    passwd_ids(&name)
+}
+
+// Home directory of the local `user`
+pub fn home_dir(user: &str) -> Result<PathBuf, IOError> {
+    let name = CString::new(user).map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    passwd_entry(&name, |passwd| {
+        let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        PathBuf::from(OsStr::from_bytes(dir.to_bytes()))
+    })
+}
+
+// Full name of the local `user`, the first field of its GECOS. None if it is empty.
+pub fn full_name(user: &str) -> Result<Option<String>, IOError> {
+    let name = CString::new(user).map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    passwd_entry(&name, |passwd| {
+        if passwd.pw_gecos.is_null() {
+            return None;
+        }
+        let gecos = unsafe { CStr::from_ptr(passwd.pw_gecos) }.to_string_lossy();
+        let full_name = gecos.split(',').next().unwrap_or_default().trim();
+        (!full_name.is_empty()).then(|| full_name.to_string())
+    })
+}
+
+fn passwd_ids(name: &CStr) -> Result<(libc::uid_t, libc::gid_t), IOError> {
+    passwd_entry(name, |passwd| (passwd.pw_uid, passwd.pw_gid))
+}
+
+// `read` of the passwd entry of `name`, while its strings are still in the buffer
+fn passwd_entry<T>(name: &CStr, read: impl FnOnce(&libc::passwd) -> T) -> Result<T, IOError> {
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        let err = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                passwd.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => {
+                return Err(IOError::new(
+                    ErrorKind::NotFound,
+                    format!("Unknown local user {}", name.to_string_lossy()),
+                ))
+            }
+            0 => {
+                let passwd = unsafe { passwd.assume_init() };
+                return Ok(read(&passwd));
+            }
+            libc::ERANGE if buffer.len() < MAX_BUFFER_SIZE => buffer.resize(buffer.len() * 2, 0),
+            err => return Err(IOError::from_raw_os_error(err)),
+        }
+    }
+}
+
+fn group_name(gid: libc::gid_t) -> Result<Option<String>, IOError> {
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut group = MaybeUninit::<libc::group>::uninit();
+        let mut result = std::ptr::null_mut();
+        let err = unsafe {
+            libc::getgrgid_r(
+                gid,
+                group.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let group = unsafe { group.assume_init() };
+                let name = unsafe { CStr::from_ptr(group.gr_name) };
+                return Ok(Some(name.to_string_lossy().into_owned()));
+            }
+            libc::ERANGE if buffer.len() < MAX_BUFFER_SIZE => buffer.resize(buffer.len() * 2, 0),
+            err => return Err(IOError::from_raw_os_error(err)),
+        }
+    }
+}
+
+// Name of the local user with `uid`, None if there is none
+pub fn user_name(uid: libc::uid_t) -> Result<Option<String>, IOError> {
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        let err = unsafe {
+            libc::getpwuid_r(
+                uid,
+                passwd.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let passwd = unsafe { passwd.assume_init() };
+                let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+                return Ok(Some(name.to_string_lossy().into_owned()));
+            }
+            libc::ERANGE if buffer.len() < MAX_BUFFER_SIZE => buffer.resize(buffer.len() * 2, 0),
+            err => return Err(IOError::from_raw_os_error(err)),
+        }
+    }
+}
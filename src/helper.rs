@@ -0,0 +1,464 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use oauth2::RefreshToken;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{CacheEntry, TokenCache};
+use crate::config::Config;
+use crate::groups::user_ids;
+use crate::logger::LogUser;
+use crate::refresh::{BoundRefreshToken, RefreshStore};
+
+// A connection stalling longer than this is dropped, so it can't hold up other logins
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+// Connections read at once, further ones wait in the listen backlog. Any local user may
+// connect, so they must not be able to exhaust the threads of the daemon.
+const MAX_CONNECTIONS: usize = 32;
+// First file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+// Request of the module to the helper daemon, a single JSON line per connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    CacheStore {
+        local_user: String,
+        entry: CacheEntry,
+    },
+    // A cached login for the context, for any context if not set
+    CacheLookup {
+        local_user: String,
+        context: Option<String>,
+    },
+    CacheRemove {
+        local_user: String,
+    },
+    RefreshStore {
+        local_user: String,
+        context: String,
+        refresh_token: String,
+        dpop_key: Option<String>,
+    },
+    // The refresh token granted in the context, in any context if not set
+    RefreshLoad {
+        local_user: String,
+        #[serde(default)]
+        context: Option<String>,
+    },
+    RefreshRemove {
+        local_user: String,
+    },
+}
+
+impl Request {
+    // Stores write what later logins trust and refresh tokens (with their DPoP key) are
+    // secrets of the module, so only root, i.e. the module, may send these requests
+    fn root_only(&self) -> bool {
+        matches!(
+            self,
+            Request::CacheStore { .. } | Request::RefreshStore { .. } | Request::RefreshLoad { .. }
+        )
+    }
+
+    fn local_user(&self) -> &str {
+        match self {
+            Request::CacheStore { local_user, .. }
+            | Request::CacheLookup { local_user, .. }
+            | Request::CacheRemove { local_user }
+            | Request::RefreshStore { local_user, .. }
+            | Request::RefreshLoad { local_user, .. }
+            | Request::RefreshRemove { local_user } => local_user,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    CacheEntry {
+        entry: Option<CacheEntry>,
+    },
+    RefreshToken {
+        refresh_token: Option<String>,
+        dpop_key: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// Connection of the module to the helper daemon, see `helper_socket`
+#[derive(Debug, Clone)]
+pub struct HelperClient {
+    socket: PathBuf,
+}
+
+impl HelperClient {
+    pub fn new(socket: &Path) -> Self {
+        Self {
+            socket: socket.to_path_buf(),
+        }
+    }
+
+    pub fn cache_store(&self, local_user: &str, entry: &CacheEntry) -> Result<(), IOError> {
+        self.call(&Request::CacheStore {
+            local_user: local_user.to_string(),
+            entry: entry.clone(),
+        })
+        .map(drop)
+    }
+
+    pub fn cache_lookup(
+        &self,
+        local_user: &str,
+        context: Option<&str>,
+    ) -> Result<Option<CacheEntry>, IOError> {
+        let request = Request::CacheLookup {
+            local_user: local_user.to_string(),
+            context: context.map(str::to_string),
+        };
+        match self.call(&request)? {
+            Response::CacheEntry { entry } => Ok(entry),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn cache_remove(&self, local_user: &str) -> Result<(), IOError> {
+        self.call(&Request::CacheRemove {
+            local_user: local_user.to_string(),
+        })
+        .map(drop)
+    }
+
+    pub fn refresh_store(
+        &self,
+        local_user: &str,
+        context: &str,
+        token: &RefreshToken,
+        dpop_key: Option<&[u8]>,
+    ) -> Result<(), IOError> {
+        self.call(&Request::RefreshStore {
+            local_user: local_user.to_string(),
+            context: context.to_string(),
+            refresh_token: token.secret().clone(),
+            dpop_key: dpop_key.map(|key| URL_SAFE_NO_PAD.encode(key)),
+        })
+        .map(drop)
+    }
+
+    pub fn refresh_load(
+        &self,
+        local_user: &str,
+        context: Option<&str>,
+    ) -> Result<Option<BoundRefreshToken>, IOError> {
+        let request = Request::RefreshLoad {
+            local_user: local_user.to_string(),
+            context: context.map(str::to_string),
+        };
+        match self.call(&request)? {
+            Response::RefreshToken {
+                refresh_token: Some(refresh_token),
+                dpop_key,
+            } => {
+                let dpop_key = dpop_key
+                    .map(|key| URL_SAFE_NO_PAD.decode(key))
+                    .transpose()
+                    .map_err(|e| IOError::new(ErrorKind::InvalidData, e))?;
+                Ok(Some((RefreshToken::new(refresh_token), dpop_key)))
+            }
+            Response::RefreshToken { .. } => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn refresh_remove(&self, local_user: &str) -> Result<(), IOError> {
+        self.call(&Request::RefreshRemove {
+            local_user: local_user.to_string(),
+        })
+        .map(drop)
+    }
+
+    fn call(&self, request: &Request) -> Result<Response, IOError> {
+        let mut stream = UnixStream::connect(&self.socket).map_err(|e| {
+            IOError::new(
+                e.kind(),
+                format!("Failed to connect to helper {}: {e}", self.socket.display()),
+            )
+        })?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        stream.write_all(&line)?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let mut buff = Vec::new();
+        stream.read_to_end(&mut buff)?;
+        match serde_json::from_slice(&buff)? {
+            Response::Error { message } => Err(IOError::other(message)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> IOError {
+    IOError::new(
+        ErrorKind::InvalidData,
+        format!("Unexpected response of helper: {response:?}"),
+    )
+}
+
+// Helper daemon owning the login cache and the refresh tokens. Every connection is read on
+// its own thread, so a stalled peer can't hold up other logins, and requests are answered
+// one at a time, so concurrent logins never race on an entry. Only root may store entries
+// and load refresh tokens, other peers may only look up cached logins and remove the
+// entries of their own local user.
+pub struct HelperServer {
+    cache: TokenCache,
+    refresh: RefreshStore,
+    lock: Mutex<()>,
+}
+
+impl HelperServer {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            cache: token_cache(config),
+            refresh: RefreshStore::new(&config.refresh_token_store, &config.refresh_token_key),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn serve(&self, listener: &UnixListener) -> Result<(), IOError> {
+        let connections = Slots::new(MAX_CONNECTIONS);
+        std::thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let slot = connections.acquire();
+                scope.spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = stream.and_then(|stream| self.handle(stream)) {
+                        log::warn!("Failed to handle helper request: {e}");
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    pub fn handle(&self, stream: UnixStream) -> Result<(), IOError> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let uid = peer_uid(&stream)?;
+
+        let mut line = String::new();
+        BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => self.respond(uid, request),
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {e}"),
+            },
+        };
+        let mut stream = stream;
+        stream.write_all(&serde_json::to_vec(&response)?)
+    }
+
+    pub fn respond(&self, uid: libc::uid_t, request: Request) -> Response {
+        if let Err(e) = authorize(uid, &request) {
+            log::warn!(
+                "Refused helper request of uid {uid} for user {}: {e}",
+                LogUser(request.local_user())
+            );
+            return Response::Error {
+                message: e.to_string(),
+            };
+        }
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let res: Result<Response, Box<dyn std::error::Error>> = match request {
+            Request::CacheStore { local_user, entry } => self
+                .cache
+                .put(&local_user, &entry)
+                .map(|()| Response::Ok)
+                .map_err(Into::into),
+            Request::CacheLookup {
+                local_user,
+                context: Some(context),
+            } => self
+                .cache
+                .lookup(&local_user, &context)
+                .map(|entry| Response::CacheEntry { entry })
+                .map_err(Into::into),
+            Request::CacheLookup {
+                local_user,
+                context: None,
+            } => self
+                .cache
+                .lookup_any_context(&local_user)
+                .map(|entry| Response::CacheEntry { entry })
+                .map_err(Into::into),
+            Request::CacheRemove { local_user } => self
+                .cache
+                .remove(&local_user)
+                .map(|()| Response::Ok)
+                .map_err(Into::into),
+            Request::RefreshStore {
+                local_user,
+                context,
+                refresh_token,
+                dpop_key,
+            } => dpop_key
+                .map(|key| URL_SAFE_NO_PAD.decode(key))
+                .transpose()
+                .map_err(Into::into)
+                .and_then(|dpop_key| {
+                    self.refresh.store_with_dpop_key(
+                        &local_user,
+                        &context,
+                        &RefreshToken::new(refresh_token),
+                        dpop_key.as_deref(),
+                    )
+                })
+                .map(|()| Response::Ok),
+            Request::RefreshLoad {
+                local_user,
+                context,
+            } => match &context {
+                Some(context) => self.refresh.load_for_context(&local_user, context),
+                None => self.refresh.load_with_dpop_key(&local_user),
+            }
+            .map(|token| match token {
+                Some((refresh_token, dpop_key)) => Response::RefreshToken {
+                    refresh_token: Some(refresh_token.secret().clone()),
+                    dpop_key: dpop_key.map(|key| URL_SAFE_NO_PAD.encode(key)),
+                },
+                None => Response::RefreshToken {
+                    refresh_token: None,
+                    dpop_key: None,
+                },
+            }),
+            Request::RefreshRemove { local_user } => self
+                .refresh
+                .remove(&local_user)
+                .map(|()| Response::Ok)
+                .map_err(Into::into),
+        };
+        res.unwrap_or_else(|e| Response::Error {
+            message: e.to_string(),
+        })
+    }
+}
+
+// Counting semaphore bounding the connections handled at once
+struct Slots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+// Frees its slot when dropped
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    fn new(count: usize) -> Self {
+        Self {
+            free: Mutex::new(count),
+            released: Condvar::new(),
+        }
+    }
+
+    // Waits until a slot is free
+    fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        while *free == 0 {
+            free = self.released.wait(free).unwrap_or_else(|e| e.into_inner());
+        }
+        *free -= 1;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.released.notify_one();
+    }
+}
+
+// Socket passed by systemd socket activation, or `path` bound by the daemon itself. Any local
+// user may connect, requests are checked against the peer's credentials.
+pub fn listen(path: &Path) -> Result<UnixListener, IOError> {
+    let activated = std::env::var("LISTEN_PID").ok() == Some(std::process::id().to_string())
+        && std::env::var("LISTEN_FDS").ok().as_deref() == Some("1");
+    if activated {
+        return Ok(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) });
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+fn authorize(uid: libc::uid_t, request: &Request) -> Result<(), IOError> {
+    let local_user = request.local_user();
+    if uid == 0 || (!request.root_only() && user_ids(local_user)?.0 == uid) {
+        return Ok(());
+    }
+    Err(IOError::new(
+        ErrorKind::PermissionDenied,
+        format!("Permission denied for user {}", LogUser(local_user)),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, IOError> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let err = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if err != 0 {
+        return Err(IOError::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, IOError> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(IOError::last_os_error());
+    }
+    Ok(uid)
+}
+
+fn token_cache(config: &Config) -> TokenCache {
+    // Entries are stored with their expiry, the ttl is applied by the module
+    let cache = TokenCache::new(&config.cache_dir, config.cache_ttl.unwrap_or_default());
+    match &config.cache_key {
+        Some(key_file) => cache.encrypted(key_file),
+        None => cache,
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::{self, Error as IOError, ErrorKind};
+use std::os::unix::fs::{lchown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::groups::{home_dir, user_ids};
+
+// Creates the home directory of the local `user` with a copy of `skel`, like pam_mkhomedir.
+// False if it already exists.
+pub fn create_homedir(user: &str, skel: &Path, mode: u32) -> Result<bool, IOError> {
+    let (uid, gid) = user_ids(user)?;
+    let home = home_dir(user)?;
+    if !home.is_absolute() {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            format!("Home directory {} is not absolute", home.display()),
+        ));
+    }
+    create_home_at(&home, skel, uid, gid, mode)
+}
+
+// `home` with a copy of `skel`, owned by `uid` and `gid`. The copy is made in a directory next
+// to `home`, private to root until it is complete, so the user can't redirect the copy with
+// symlinks of their own, and only renamed to `home` once complete. A failed copy is removed,
+// the next session tries again.
+pub fn create_home_at(
+    home: &Path,
+    skel: &Path,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+) -> Result<bool, IOError> {
+    if fs::symlink_metadata(home).is_ok() {
+        return Ok(false);
+    }
+    let (Some(parent), Some(name)) = (home.parent(), home.file_name()) else {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid home directory {}", home.display()),
+        ));
+    };
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(parent)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let staging = parent.join(format!(
+        ".{}.{}.{nanos}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let res = populate(&staging, skel, uid, gid, mode).and_then(|()| fs::rename(&staging, home));
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                log::warn!("Failed to remove {}: {e}", staging.display());
+            }
+            // Created by a concurrent session
+            if fs::symlink_metadata(home).is_ok() {
+                return Ok(false);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn populate(dir: &Path, skel: &Path, uid: u32, gid: u32, mode: u32) -> Result<(), IOError> {
+    if skel.is_dir() {
+        copy_dir(skel, dir, uid, gid)?;
+    } else {
+        log::warn!("Skeleton directory {} not found", skel.display());
+    }
+    lchown(dir, Some(uid), Some(gid))?;
+    fs::set_permissions(dir, Permissions::from_mode(mode & 0o7777))
+}
+
+// Copies the directories, files and symlinks of `from` into `to`, owned by `uid` and `gid`
+// with the permissions of the originals. Other kinds of files are skipped.
+fn copy_dir(from: &Path, to: &Path, uid: u32, gid: u32) -> Result<(), IOError> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&source)?;
+        let file_type = metadata.file_type();
+        let permissions = Permissions::from_mode(metadata.mode() & 0o777);
+        if file_type.is_dir() {
+            DirBuilder::new().mode(0o700).create(&target)?;
+            copy_dir(&source, &target, uid, gid)?;
+            lchown(&target, Some(uid), Some(gid))?;
+            fs::set_permissions(&target, permissions)?;
+        } else if file_type.is_file() {
+            let mut reader = fs::File::open(&source)?;
+            let mut writer = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&target)?;
+            io::copy(&mut reader, &mut writer)?;
+            lchown(&target, Some(uid), Some(gid))?;
+            fs::set_permissions(&target, permissions)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+            lchown(&target, Some(uid), Some(gid))?;
+        } else {
+            log::debug!("Skipping special file {} of skeleton", source.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,769 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error as IOError, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use curl::easy::{Easy, List};
+use oauth2::http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER,
+};
+use oauth2::http::{Method, Request, StatusCode};
+use oauth2::{HttpClientError, HttpRequest, HttpResponse, SyncHttpClient};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use url::{form_urlencoded, Url};
+
+use crate::assertion::{ClientAssertion, ASSERTION_TYPE};
+use crate::config::{
+    AzureEndpointVersion, Config, HttpEndpoint, HttpTimeouts, ProviderType, RetryConfig,
+};
+use crate::dpop::DpopKey;
+use crate::logger::{is_secret_field, redact_json, REDACTED};
+use crate::mtls::ClientCertificate;
+use crate::vault::VAULT_TOKEN;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Usual locations of the system CA bundle
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+    "/usr/local/share/certs/ca-root-nss.crt",
+];
+
+// Token response fields defined as numbers, all other fields are strings
+const NUMERIC_FIELDS: &[&str] = &["expires_in", "interval"];
+
+const DPOP: HeaderName = HeaderName::from_static("dpop");
+const DPOP_NONCE: HeaderName = HeaderName::from_static("dpop-nonce");
+
+// HTTP client used for every request to the Authorization Server.
+// With `wire_debug` enabled, requests and responses are logged at trace level with secrets redacted.
+// With `tolerate_form_encoded_token` enabled, form-encoded responses are converted to JSON.
+// Transient failures are retried according to `http_retry`.
+#[derive(Debug, Default)]
+pub struct HttpClient {
+    wire_debug: bool,
+    tolerate_form_encoded: bool,
+    // GitHub answers token requests failing with e.g. `authorization_pending` with `200 OK`
+    errors_with_ok_status: bool,
+    // The v1 endpoints of Azure AD send numbers like `expires_in` as strings
+    numeric_strings: bool,
+    retry: RetryConfig,
+    timeouts: Timeouts,
+    endpoint_timeouts: HashMap<HttpEndpoint, HttpTimeouts>,
+    // Timeouts of the registered endpoints with an override, by URL without the query
+    overrides: Vec<(String, Timeouts)>,
+    tls: Tls,
+    proxy: Option<Url>,
+    // Signs the `private_key_jwt` assertion added to requests to the client authenticating endpoints
+    client_assertion: Option<ClientAssertion>,
+    // URLs of the device, token, introspection and revocation endpoints, without the query
+    client_auth_urls: Vec<String>,
+    // Signs the DPoP proofs of requests to the token and introspection endpoints
+    dpop_key: Option<DpopKey>,
+    dpop_urls: Vec<String>,
+    // Last `DPoP-Nonce` sent by the server, included in the following proofs
+    dpop_nonce: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Tls {
+    ca_bundle: Option<PathBuf>,
+    system_roots: bool,
+    pinned_public_key: Option<String>,
+    // Presented to the client authenticating endpoints only
+    client_certificate: Option<ClientCertificate>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    // Zero disables the timeout
+    pub read: Duration,
+    // Zero disables the timeout
+    pub total: Duration,
+}
+
+impl HttpClient {
+    pub fn new(c: &Config) -> Self {
+        Self {
+            wire_debug: c.wire_debug,
+            tolerate_form_encoded: c.tolerate_form_encoded_token,
+            errors_with_ok_status: c.provider_type == ProviderType::Github,
+            numeric_strings: c.provider_type == ProviderType::Azuread
+                && c.azuread
+                    .as_ref()
+                    .is_some_and(|o| o.endpoint_version == AzureEndpointVersion::V1),
+            retry: c.http_retry,
+            timeouts: Timeouts {
+                connect: c.http_connect_timeout,
+                read: c.http_read_timeout,
+                total: c.http_total_timeout,
+            },
+            endpoint_timeouts: c.http_endpoint_timeouts.clone(),
+            overrides: Vec::new(),
+            tls: Tls {
+                ca_bundle: c.ca_bundle_path.clone(),
+                system_roots: c.tls_system_roots,
+                pinned_public_key: c.tls_pinned_public_key.clone(),
+                client_certificate: None,
+            },
+            proxy: c.proxy_url.clone(),
+            client_assertion: None,
+            client_auth_urls: Vec::new(),
+            dpop_key: None,
+            dpop_urls: Vec::new(),
+            dpop_nonce: Mutex::new(None),
+        }
+    }
+
+    pub fn set_retry(&mut self, retry: RetryConfig) {
+        self.retry = retry;
+    }
+
+    // Replaces the timeouts of requests to endpoints without an override
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_dpop_key(&mut self, dpop_key: DpopKey) {
+        self.dpop_key = Some(dpop_key);
+    }
+
+    pub fn dpop_key(&self) -> Option<&DpopKey> {
+        self.dpop_key.as_ref()
+    }
+
+    pub fn set_client_certificate(&mut self, client_certificate: ClientCertificate) {
+        self.tls.client_certificate = Some(client_certificate);
+    }
+
+    pub fn set_client_assertion(&mut self, client_assertion: ClientAssertion) {
+        self.client_assertion = Some(client_assertion);
+    }
+
+    // Applies the `http_endpoint_timeouts` of `endpoint` to requests to `url`
+    pub fn register_endpoint(&mut self, endpoint: HttpEndpoint, url: &Url) {
+        if matches!(
+            endpoint,
+            HttpEndpoint::Device
+                | HttpEndpoint::Token
+                | HttpEndpoint::Introspection
+                | HttpEndpoint::Revocation
+                | HttpEndpoint::Backchannel
+        ) {
+            self.client_auth_urls
+                .push(without_query(url.as_str()).to_string());
+        }
+        if matches!(endpoint, HttpEndpoint::Token | HttpEndpoint::Introspection) {
+            self.dpop_urls.push(without_query(url.as_str()).to_string());
+        }
+        if let Some(o) = self.endpoint_timeouts.get(&endpoint) {
+            let timeouts = Timeouts {
+                connect: o.connect.unwrap_or(self.timeouts.connect),
+                read: o.read.unwrap_or(self.timeouts.read),
+                total: o.total.unwrap_or(self.timeouts.total),
+            };
+            self.overrides
+                .push((without_query(url.as_str()).to_string(), timeouts));
+        }
+    }
+
+    pub fn timeouts(&self, url: &str) -> Timeouts {
+        let url = without_query(url);
+        self.overrides
+            .iter()
+            .find(|(endpoint, _)| endpoint == url)
+            .map_or(self.timeouts, |(_, timeouts)| *timeouts)
+    }
+
+    fn call_once(&self, mut request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let client_auth = self.authenticates_client(&request);
+        if client_auth {
+            self.add_client_assertion(&mut request)?;
+        }
+        let dpop = self.add_dpop_proof(&mut request)?;
+        if self.wire_debug {
+            log_request(&request);
+        }
+        let timeouts = self.timeouts(&request.uri().to_string());
+        let client_certificate = self.tls.client_certificate.as_ref().filter(|_| client_auth);
+        let response = perform(
+            &request,
+            timeouts,
+            &self.tls,
+            client_certificate,
+            self.proxy.as_ref(),
+        );
+        if self.wire_debug {
+            match &response {
+                Ok(response) => log_response(response),
+                Err(e) => log::trace!("HTTP <- error: {e}"),
+            }
+        }
+        let mut response = response?;
+        if dpop {
+            self.save_dpop_nonce(&response);
+        }
+        if self.tolerate_form_encoded && is_form_encoded(&response) {
+            response = form_to_json(response);
+        }
+        if self.numeric_strings {
+            response = numeric_strings_to_numbers(response);
+        }
+        if self.errors_with_ok_status && is_error_response(&response) {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+        }
+        Ok(response)
+    }
+
+    // Whether `request` goes to the device, token, introspection or revocation endpoint
+    fn authenticates_client(&self, request: &HttpRequest) -> bool {
+        let url = request.uri().to_string();
+        request.method() == Method::POST
+            && self
+                .client_auth_urls
+                .iter()
+                .any(|endpoint| endpoint == without_query(&url))
+    }
+
+    // Every attempt gets a fresh assertion, the `jti` of a retried or polling request must differ
+    fn add_client_assertion(&self, request: &mut HttpRequest) -> Result<(), HttpError> {
+        let Some(client_assertion) = &self.client_assertion else {
+            return Ok(());
+        };
+        let assertion = client_assertion.sign().map_err(|e| {
+            HttpClientError::Other(format!("Failed to sign the client assertion: {e}"))
+        })?;
+        let params = form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_assertion_type", ASSERTION_TYPE)
+            .append_pair("client_assertion", &assertion)
+            .finish();
+        let body = request.body_mut();
+        if !body.is_empty() {
+            body.push(b'&');
+        }
+        body.extend_from_slice(params.as_bytes());
+        Ok(())
+    }
+
+    // Adds the `DPoP` header to requests to the token and introspection endpoints
+    fn add_dpop_proof(&self, request: &mut HttpRequest) -> Result<bool, HttpError> {
+        let Some(dpop_key) = &self.dpop_key else {
+            return Ok(false);
+        };
+        let url = request.uri().to_string();
+        if !self
+            .dpop_urls
+            .iter()
+            .any(|endpoint| endpoint == without_query(&url))
+        {
+            return Ok(false);
+        }
+        let nonce = self.dpop_nonce.lock().map_or(None, |nonce| nonce.clone());
+        let proof = dpop_key
+            .proof(request.method().as_str(), &url, nonce.as_deref())
+            .map_err(|e| HttpClientError::Other(format!("Failed to sign the DPoP proof: {e}")))?;
+        let proof = HeaderValue::from_str(&proof)
+            .map_err(|_| HttpClientError::Other("invalid DPoP proof".to_string()))?;
+        request.headers_mut().insert(DPOP, proof);
+        Ok(true)
+    }
+
+    fn save_dpop_nonce(&self, response: &HttpResponse) {
+        let nonce = response
+            .headers()
+            .get(DPOP_NONCE)
+            .and_then(|nonce| nonce.to_str().ok());
+        if let (Some(nonce), Ok(mut saved)) = (nonce, self.dpop_nonce.lock()) {
+            *saved = Some(nonce.to_string());
+        }
+    }
+
+    // The server rejected the proof for lacking its nonce (RFC 9449, section 8), which
+    // `save_dpop_nonce` picked up for the next attempt
+    fn dpop_nonce_required(&self, response: &HttpResponse) -> bool {
+        self.dpop_key.is_some()
+            && matches!(
+                response.status(),
+                StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED
+            )
+            && response.headers().contains_key(DPOP_NONCE)
+            && serde_json::from_slice::<Value>(response.body())
+                .is_ok_and(|body| body.get("error").is_some_and(|e| e == "use_dpop_nonce"))
+    }
+}
+
+pub type HttpError = HttpClientError<curl::Error>;
+
+impl SyncHttpClient for HttpClient {
+    type Error = HttpError;
+
+    fn call(&self, request: HttpRequest) -> Result<HttpResponse, Self::Error> {
+        let mut attempt = 1;
+        loop {
+            let mut response = self.call_once(copy_request(&request));
+            if response
+                .as_ref()
+                .is_ok_and(|response| self.dpop_nonce_required(response))
+            {
+                log::debug!("{} requires a DPoP nonce, retrying", request.uri());
+                response = self.call_once(copy_request(&request));
+            }
+            let (failure, retry_after) = match &response {
+                Ok(response) if is_transient_status(response.status()) => (
+                    format!("status {}", response.status()),
+                    retry_after(response),
+                ),
+                Err(e) if is_transient_error(e) => (e.to_string(), None),
+                _ => return response,
+            };
+            if attempt >= self.retry.max_attempts {
+                return response;
+            }
+            let backoff = self.retry.backoff(attempt, retry_after);
+            attempt += 1;
+            log::warn!(
+                "{} {} failed with {}, retrying in {:?} (attempt {}/{})",
+                request.method(),
+                request.uri(),
+                failure,
+                backoff,
+                attempt,
+                self.retry.max_attempts
+            );
+            std::thread::sleep(backoff);
+        }
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Network failures that may well succeed when tried again, unlike e.g. TLS errors
+fn is_transient_error(e: &HttpError) -> bool {
+    match e {
+        HttpClientError::Reqwest(e) => {
+            e.is_couldnt_connect()
+                || e.is_couldnt_resolve_host()
+                || e.is_operation_timedout()
+                || e.is_send_error()
+                || e.is_recv_error()
+                || e.is_got_nothing()
+                || e.is_partial_file()
+        }
+        HttpClientError::Io(_) => true,
+        _ => false,
+    }
+}
+
+// Whether `err` or one of its sources is a network failure reaching the server, after retries
+pub fn is_unreachable(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |e| e.source()).any(|e| {
+        e.downcast_ref::<HttpError>()
+            .is_some_and(is_transient_error)
+    })
+}
+
+// `Retry-After` in seconds, HTTP dates are not supported
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+// Same as oauth2's `CurlHttpClient`, with timeouts, TLS and proxy options and all response headers.
+// Without a proxy set, curl picks it from the environment
+fn perform(
+    request: &HttpRequest,
+    timeouts: Timeouts,
+    tls: &Tls,
+    client_certificate: Option<&ClientCertificate>,
+    proxy: Option<&Url>,
+) -> Result<HttpResponse, HttpError> {
+    let mut easy = Easy::new();
+    easy.url(&request.uri().to_string()).map_err(Box::new)?;
+    // Required by some APIs, e.g. the one of GitHub
+    easy.useragent(concat!("pam_oauth2_device/", env!("CARGO_PKG_VERSION")))
+        .map_err(Box::new)?;
+    if let Some(proxy) = proxy {
+        // Credentials of the URL are sent with basic auth
+        easy.proxy(proxy.as_str()).map_err(Box::new)?;
+    }
+    if let Some(cas) = trusted_cas(tls.ca_bundle.as_deref(), tls.system_roots)? {
+        easy.ssl_cainfo_blob(&cas).map_err(Box::new)?;
+    }
+    if let Some(pin) = &tls.pinned_public_key {
+        easy.pinned_public_key(pin).map_err(Box::new)?;
+    }
+    if let Some(client_certificate) = client_certificate {
+        easy.ssl_cert_blob(&client_certificate.cert)
+            .map_err(Box::new)?;
+        easy.ssl_key_blob(&client_certificate.key)
+            .map_err(Box::new)?;
+    }
+    easy.connect_timeout(timeouts.connect).map_err(Box::new)?;
+    if !timeouts.read.is_zero() {
+        // Aborts when less than a byte per second arrived during the read timeout
+        easy.low_speed_limit(1).map_err(Box::new)?;
+        easy.low_speed_time(timeouts.read).map_err(Box::new)?;
+    }
+    if !timeouts.total.is_zero() {
+        // A server sending a byte now and then would get past the read timeout
+        easy.timeout(timeouts.total).map_err(Box::new)?;
+    }
+
+    let mut headers = List::new();
+    for (name, value) in request.headers() {
+        let value = value.to_str().map_err(|_| {
+            HttpClientError::Other(format!("invalid `{name}` header value {value:?}"))
+        })?;
+        headers
+            .append(&format!("{name}: {value}"))
+            .map_err(Box::new)?;
+    }
+    easy.http_headers(headers).map_err(Box::new)?;
+
+    match *request.method() {
+        Method::POST => {
+            easy.post(true).map_err(Box::new)?;
+            easy.post_field_size(request.body().len() as u64)
+                .map_err(Box::new)?;
+        }
+        Method::GET => (),
+        ref method => {
+            return Err(HttpClientError::Other(format!(
+                "unsupported method {method}"
+            )))
+        }
+    }
+
+    let mut body = &request.body()[..];
+    let mut data = Vec::new();
+    let mut response_headers = HeaderMap::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .read_function(|buf| Ok(body.read(buf).unwrap_or(0)))
+            .map_err(Box::new)?;
+        transfer
+            .write_function(|new_data| {
+                data.extend_from_slice(new_data);
+                Ok(new_data.len())
+            })
+            .map_err(Box::new)?;
+        transfer
+            .header_function(|line| {
+                let line = String::from_utf8_lossy(line);
+                // Status line of a new response, e.g. after `100 Continue`
+                if line.starts_with("HTTP/") {
+                    response_headers.clear();
+                } else if let Some((name, value)) = line.split_once(':') {
+                    if let (Ok(name), Ok(value)) = (
+                        HeaderName::from_bytes(name.trim().as_bytes()),
+                        HeaderValue::from_str(value.trim()),
+                    ) {
+                        response_headers.append(name, value);
+                    }
+                }
+                true
+            })
+            .map_err(Box::new)?;
+        transfer.perform().map_err(Box::new)?;
+    }
+
+    let status = StatusCode::from_u16(easy.response_code().map_err(Box::new)? as u16)
+        .map_err(oauth2::http::Error::from)?;
+    let mut response = HttpResponse::new(data);
+    *response.status_mut() = status;
+    *response.headers_mut() = response_headers;
+    Ok(response)
+}
+
+// PEM bundle of the CAs to trust, `None` keeps curl's defaults. The system bundle is
+// prepended to `ca_bundle` as curl only accepts a single one.
+pub fn trusted_cas(
+    ca_bundle: Option<&Path>,
+    system_roots: bool,
+) -> Result<Option<Vec<u8>>, IOError> {
+    let Some(ca_bundle) = ca_bundle else {
+        return Ok(None);
+    };
+    let mut cas = Vec::new();
+    if system_roots {
+        match SYSTEM_CA_BUNDLES.iter().map(Path::new).find(|p| p.exists()) {
+            Some(system) => {
+                cas = fs::read(system)?;
+                cas.push(b'\n');
+            }
+            None => log::warn!(
+                "No system CA bundle found, only trusting {}",
+                ca_bundle.display()
+            ),
+        }
+    }
+    let custom = fs::read(ca_bundle).map_err(|e| {
+        IOError::new(
+            e.kind(),
+            format!("Failed to read CA bundle {}: {e}", ca_bundle.display()),
+        )
+    })?;
+    cas.extend_from_slice(&custom);
+    Ok(Some(cas))
+}
+
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+// `HttpRequest` doesn't implement `Clone`
+fn copy_request(request: &HttpRequest) -> HttpRequest {
+    let mut copy = HttpRequest::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+// Fetches a JSON document such as the discovery document or the JWKS
+pub fn get_json<T: DeserializeOwned>(http_client: &HttpClient, url: &Url) -> Result<T, DynErr> {
+    get(http_client, url, None)
+}
+
+// Fetches a JSON document of a protected resource such as the userinfo endpoint
+pub fn get_json_authorized<T: DeserializeOwned>(
+    http_client: &HttpClient,
+    url: &Url,
+    access_token: &str,
+) -> Result<T, DynErr> {
+    get(http_client, url, Some(access_token))
+}
+
+// Posts the `access_token` to a protected resource and returns the body of the response,
+// e.g. the ccache of a Kerberos gateway
+pub fn post_authorized(
+    http_client: &HttpClient,
+    url: &Url,
+    access_token: &str,
+) -> Result<Vec<u8>, DynErr> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url.as_str())
+        .header(AUTHORIZATION, format!("Bearer {access_token}"))
+        .body(Vec::new())?;
+    let response = http_client.call(request)?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("POST {url} failed with status {}", response.status()).into());
+    }
+    Ok(response.into_body())
+}
+
+// Posts a form to an endpoint oauth2 has no request for, e.g. the revocation endpoint, and
+// returns the body of the response. `client_secret` authenticates the client with basic auth, like oauth2 does (RFC 6749,
+// section 2.3.1). Without it, the client is identified by the `client_id` in the form.
+pub fn post_form(
+    http_client: &HttpClient,
+    url: &Url,
+    params: &[(&str, &str)],
+    client_id: &str,
+    client_secret: Option<&str>,
+) -> Result<Vec<u8>, DynErr> {
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.extend_pairs(params);
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(url.as_str())
+        .header(ACCEPT, "application/json")
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+    match client_secret {
+        Some(client_secret) => {
+            let encode =
+                |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+            let credentials = format!("{}:{}", encode(client_id), encode(client_secret));
+            request = request.header(
+                AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            );
+        }
+        None => {
+            form.append_pair("client_id", client_id);
+        }
+    }
+    let request = request.body(form.finish().into_bytes())?;
+    let response = http_client.call(request)?;
+    if response.status() != StatusCode::OK {
+        let error = serde_json::from_slice::<Value>(response.body())
+            .ok()
+            .and_then(|body| {
+                body.get("error")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            });
+        return Err(Box::new(FormError {
+            url: url.to_string(),
+            status: response.status(),
+            error,
+        }));
+    }
+    Ok(response.into_body())
+}
+
+// Error response to `post_form`, with the OAuth `error` code if the body has one
+#[derive(Debug)]
+pub struct FormError {
+    pub url: String,
+    pub status: StatusCode,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "POST {} failed with status {}", self.url, self.status)?;
+        match &self.error {
+            Some(error) => write!(f, ": {error}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+fn get<T: DeserializeOwned>(
+    http_client: &HttpClient,
+    url: &Url,
+    access_token: Option<&str>,
+) -> Result<T, DynErr> {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri(url.as_str())
+        .header(ACCEPT, "application/json");
+    if let Some(access_token) = access_token {
+        request = request.header(AUTHORIZATION, format!("Bearer {access_token}"));
+    }
+    let request = request.body(Vec::new())?;
+    let response = http_client.call(request)?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("GET {url} failed with status {}", response.status()).into());
+    }
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+fn is_form_encoded(response: &HttpResponse) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"))
+}
+
+// `200 OK` response with an OAuth error body, e.g. `{"error": "authorization_pending"}`
+fn is_error_response(response: &HttpResponse) -> bool {
+    response.status() == StatusCode::OK
+        && serde_json::from_slice::<Value>(response.body())
+            .is_ok_and(|body| body.get("error").is_some_and(Value::is_string))
+}
+
+// Converts the `NUMERIC_FIELDS` of a JSON response sent as strings, e.g. `"expires_in": "3599"`
+fn numeric_strings_to_numbers(mut response: HttpResponse) -> HttpResponse {
+    let Ok(Value::Object(mut fields)) = serde_json::from_slice::<Value>(response.body()) else {
+        return response;
+    };
+    let mut converted = false;
+    for field in NUMERIC_FIELDS {
+        if let Some(Ok(n)) = fields
+            .get(*field)
+            .and_then(Value::as_str)
+            .map(str::parse::<u64>)
+        {
+            fields.insert(field.to_string(), Value::from(n));
+            converted = true;
+        }
+    }
+    if converted {
+        *response.body_mut() = Value::Object(fields).to_string().into_bytes();
+    }
+    response
+}
+
+// Some legacy token endpoints answer with `application/x-www-form-urlencoded` bodies
+pub fn form_to_json(mut response: HttpResponse) -> HttpResponse {
+    log::debug!("Converting form-encoded response to JSON");
+    let fields = form_urlencoded::parse(response.body())
+        .map(|(k, v)| {
+            let value = match v.parse::<u64>() {
+                Ok(n) if NUMERIC_FIELDS.contains(&k.as_ref()) => Value::from(n),
+                _ => Value::from(v.into_owned()),
+            };
+            (k.into_owned(), value)
+        })
+        .collect::<Map<String, Value>>();
+    *response.body_mut() = Value::Object(fields).to_string().into_bytes();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn log_request(request: &HttpRequest) {
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION || name == DPOP || name == VAULT_TOKEN {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or(REDACTED)
+            };
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<String>>();
+    log::trace!(
+        "HTTP -> {} {}\n    headers: {:?}\n    body: {}",
+        request.method(),
+        request.uri(),
+        headers,
+        redact_body(request.body())
+    );
+}
+
+fn log_response(response: &HttpResponse) {
+    log::trace!(
+        "HTTP <- {}\n    content-type: {:?}\n    body: {}",
+        response.status(),
+        response.headers().get(CONTENT_TYPE),
+        redact_body(response.body())
+    );
+}
+
+// Renders a JSON or form-encoded body with all secret fields replaced
+pub fn redact_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+        redact_json(&mut json);
+        return json.to_string();
+    }
+    match std::str::from_utf8(body) {
+        Ok(form) if !form.contains(char::is_whitespace) => form_urlencoded::parse(form.as_bytes())
+            .map(|(k, v)| {
+                if is_secret_field(&k) {
+                    format!("{k}={REDACTED}")
+                } else {
+                    format!("{k}={v}")
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("&"),
+        _ => format!("[unparsable body, {} bytes]", body.len()),
+    }
+}
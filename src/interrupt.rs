@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Signals ending a login while the token endpoint is polled, e.g. sshd terminating the PAM
+// process once the client went away
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+// Sleeps are cut into slices, a handled signal doesn't interrupt `thread::sleep`
+const SLICE: Duration = Duration::from_millis(100);
+
+// The signal caught, 0 if none
+static CAUGHT: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    CAUGHT.store(signal, Ordering::SeqCst);
+}
+
+// The login was interrupted by a signal before a token was issued
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token polling interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// Catches the `SIGNALS` while it lives and restores their dispositions when dropped. Only
+// signals which would terminate the process are caught, handlers and ignored signals of the
+// application are left alone. A signal caught meanwhile is raised again once the default
+// disposition is back, so the process still terminates, only after the polling stopped.
+pub struct InterruptGuard {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl InterruptGuard {
+    pub fn install() -> Self {
+        CAUGHT.store(0, Ordering::SeqCst);
+        let mut previous = Vec::new();
+        for signal in SIGNALS {
+            unsafe {
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, std::ptr::null(), &mut old) != 0
+                    || old.sa_sigaction != libc::SIG_DFL
+                {
+                    continue;
+                }
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) == 0 {
+                    previous.push((signal, old));
+                }
+            }
+        }
+        Self { previous }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for (signal, old) in &self.previous {
+            unsafe {
+                libc::sigaction(*signal, old, std::ptr::null_mut());
+            }
+        }
+        let caught = CAUGHT.swap(0, Ordering::SeqCst);
+        if self.previous.iter().any(|(signal, _)| *signal == caught) {
+            log::warn!("Login interrupted by signal {caught}, terminating");
+            unsafe {
+                libc::raise(caught);
+            }
+        }
+    }
+}
+
+pub fn interrupted() -> bool {
+    CAUGHT.load(Ordering::SeqCst) != 0
+}
+
+// Sleeps for `duration` unless a signal arrives first, false if it did
+pub fn sleep(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if interrupted() {
+            return false;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        thread::sleep(remaining.min(SLICE));
+    }
+}
+
+// Whether `err` or one of its sources is an interrupted login
+pub fn is_interrupted(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |e| e.source()).any(|e| e.is::<Interrupted>())
+}
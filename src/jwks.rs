@@ -0,0 +1,213 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::http::{get_json, HttpClient};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Why a JWT couldn't be validated locally.
+// `Unsupported` means this mode can't decide (the token is not a JWT, the key is missing
+// or the JWKS can't be fetched), `Invalid` means the token was checked and must be rejected.
+#[derive(Debug)]
+pub enum JwtError {
+    Unsupported(DynErr),
+    Invalid(DynErr),
+}
+
+// Validates JWT access tokens with the keys published by the provider
+#[derive(Debug)]
+pub struct JwksValidator {
+    jwks_uri: Url,
+    // Accepted `iss` values
+    issuers: Vec<Url>,
+    // Accepted `aud` values, the token must name at least one of them
+    audiences: Vec<String>,
+    // Clock skew tolerated by the `exp` and `nbf` checks, in seconds
+    leeway: u64,
+    // File the key set is kept in between logins, fetched at every login if None
+    cache: Option<PathBuf>,
+    cache_ttl: Duration,
+}
+
+impl JwksValidator {
+    pub fn new(jwks_uri: Url, issuers: Vec<Url>, audiences: Vec<String>, leeway: u64) -> Self {
+        Self {
+            jwks_uri,
+            issuers,
+            audiences,
+            leeway,
+            cache: None,
+            cache_ttl: Duration::ZERO,
+        }
+    }
+
+    // Keeps the key set in `dir` for `ttl`, a zero `ttl` disables the cache
+    pub fn cached(mut self, dir: &Path, ttl: Duration) -> Self {
+        if !ttl.is_zero() {
+            let digest = Sha256::digest(self.jwks_uri.as_str().as_bytes());
+            let name: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            self.cache = Some(dir.join(name));
+            self.cache_ttl = ttl;
+        }
+        self
+    }
+
+    // Checks the signature, `exp`, `nbf`, `iss` and `aud` of `token` and returns its claims
+    pub fn claims(
+        &self,
+        token: &str,
+        http_client: &HttpClient,
+    ) -> Result<Map<String, Value>, JwtError> {
+        let header = decode_header(token)
+            .map_err(|e| JwtError::Unsupported(format!("Token is not a JWT: {e}").into()))?;
+
+        let (jwks, fresh) = match self.load_cached() {
+            Some(jwks) => (jwks, false),
+            None => (self.fetch(http_client)?, true),
+        };
+        let jwk = match find_key(&jwks, header.kid.as_deref()) {
+            Some(jwk) => jwk.clone(),
+            // The provider rotated its keys since the key set was cached
+            None if !fresh => find_key(&self.fetch(http_client)?, header.kid.as_deref())
+                .cloned()
+                .ok_or_else(|| missing_key(&header.kid))?,
+            None => return Err(missing_key(&header.kid)),
+        };
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| JwtError::Unsupported(e.into()))?;
+
+        // The `alg` of the header only picks among the algorithms of the key, so `none`, HMAC
+        // with a public key or another hash than the published one are refused
+        let algorithms = key_algorithms(&jwk);
+        if !algorithms.contains(&header.alg) {
+            return Err(JwtError::Invalid(
+                format!(
+                    "Token algorithm {:?} is not allowed for key {:?}",
+                    header.alg, header.kid
+                )
+                .into(),
+            ));
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        let issuers: Vec<&str> = self
+            .issuers
+            .iter()
+            .flat_map(|issuer| [issuer.as_str(), issuer.as_str().trim_end_matches('/')])
+            .collect();
+        validation.set_issuer(&issuers);
+        validation.set_audience(&self.audiences);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway;
+
+        decode::<Map<String, Value>>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| JwtError::Invalid(e.into()))
+    }
+
+    fn fetch(&self, http_client: &HttpClient) -> Result<JwkSet, JwtError> {
+        log::debug!("Fetching JWKS from {}", self.jwks_uri);
+        let jwks: JwkSet = get_json(http_client, &self.jwks_uri).map_err(JwtError::Unsupported)?;
+        if let Some(cache) = &self.cache {
+            if let Err(e) = store(cache, &jwks) {
+                log::warn!("Failed to cache the JWKS in {}: {e}", cache.display());
+            }
+        }
+        Ok(jwks)
+    }
+
+    // The cached key set unless it is older than `cache_ttl`. Files not owned by us or
+    // accessible by others may have been planted and are ignored.
+    fn load_cached(&self) -> Option<JwkSet> {
+        let cache = self.cache.as_ref()?;
+        let metadata = fs::symlink_metadata(cache).ok()?;
+        let euid = unsafe { libc::geteuid() };
+        if !metadata.is_file() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+            log::warn!("Ignoring insecure cached JWKS {}", cache.display());
+            return None;
+        }
+        let age = metadata.modified().ok()?.elapsed().ok()?;
+        if age >= self.cache_ttl {
+            return None;
+        }
+        serde_json::from_slice(&fs::read(cache).ok()?).ok()
+    }
+}
+
+fn store(cache: &Path, jwks: &JwkSet) -> Result<(), IOError> {
+    let dir = cache.parent().unwrap_or(Path::new("."));
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let tmp = dir.join(format!(".jwks.{}.{nanos}", std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(&serde_json::to_vec(jwks)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, cache)
+}
+
+fn find_key<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        // Without a `kid` the key is only unambiguous if the provider publishes a single one
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+fn missing_key(kid: &Option<String>) -> JwtError {
+    JwtError::Unsupported(format!("No key {kid:?} found in JWKS").into())
+}
+
+// The `alg` published with the key, or the signature algorithms of its type
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(alg) = jwk.common.key_algorithm {
+        return Algorithm::from_str(&alg.to_string()).into_iter().collect();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+            vec![Algorithm::EdDSA]
+        }
+        // Symmetric keys are never published
+        _ => Vec::new(),
+    }
+}
+
+// Claims of a JWT received directly from the token endpoint, e.g. the id_token.
+// The TLS connection authenticates the issuer instead of the signature (OIDC Core 3.1.3.7).
+pub fn unverified_claims(token: &str) -> Result<Map<String, Value>, DynErr> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+    Ok(decode::<Map<String, Value>>(token, &DecodingKey::from_secret(&[]), &validation)?.claims)
+}
@@ -0,0 +1,112 @@
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{chown, DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::config::{Kerberos, KerberosSource};
+use crate::groups::user_ids;
+use crate::http::{post_authorized, HttpClient};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// First byte of every ccache file, followed by the format version
+const CCACHE_MAGIC: u8 = 0x05;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Acquires Kerberos credentials of `local_user` with `token` and returns the `KRB5CCNAME` of
+// the ccache. The ccache is first written to a private directory, then moved in place once it
+// belongs to the user, so a failed acquisition never leaves a partial one behind.
+pub fn acquire(
+    c: &Kerberos,
+    http_client: &HttpClient,
+    token: &str,
+    local_user: &str,
+) -> Result<String, DynErr> {
+    let (uid, gid) = user_ids(local_user)?;
+    let name = format!("krb5cc_{uid}_oauth2");
+    let path = c.ccache_dir.join(&name);
+    let staging = c.ccache_dir.join(format!(".{name}.{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(&name);
+
+    let res = match &c.source {
+        KerberosSource::Endpoint(url) => fetch(http_client, url, token, &staged),
+        KerberosSource::Command(command) => run(command, token, &staged, c.timeout),
+    }
+    .and_then(|()| {
+        fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        chown(&staged, Some(uid), Some(gid))?;
+        fs::rename(&staged, &path)?;
+        Ok(())
+    });
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        log::warn!("Failed to remove {}: {}", staging.display(), e);
+    }
+    res?;
+    Ok(format!("FILE:{}", path.display()))
+}
+
+fn fetch(http_client: &HttpClient, url: &Url, token: &str, ccache: &Path) -> Result<(), DynErr> {
+    let body = post_authorized(http_client, url, token)?;
+    if body.first() != Some(&CCACHE_MAGIC) {
+        return Err(format!("Response of {url} is not a ccache").into());
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(ccache)?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn run(command: &[String], token: &str, ccache: &Path, timeout: Duration) -> Result<(), DynErr> {
+    let (program, args) = command.split_first().ok_or("Empty kerberos command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .env("KRB5CCNAME", format!("FILE:{}", ccache.display()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+    // Dropping stdin closes it, the token is the whole input. A command exiting without
+    // reading it is reported by its status.
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(token.as_bytes()) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{program} timed out after {}s", timeout.as_secs()).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    if !status.success() {
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        return Err(format!("{program} failed with {status}: {}", stderr.trim()).into());
+    }
+    if !ccache.is_file() {
+        return Err(format!("{program} wrote no ccache").into());
+    }
+    Ok(())
+}
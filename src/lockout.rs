@@ -0,0 +1,127 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+// Consecutive failed logins of every local user, and the users locked out after too many.
+// One file per user (named after the hash of the username), holding the number of failures,
+// the time the lockout ends (0 if the user is not locked out) and the time of the last failure.
+// Failures are forgotten once none followed for the lockout duration.
+pub struct LockoutStore {
+    dir: PathBuf,
+    threshold: u32,
+    duration: Duration,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Lockout {
+    failures: u32,
+    locked_until: i64,
+    // Missing in the files of older versions
+    last_failure: Option<i64>,
+}
+
+impl LockoutStore {
+    pub fn new(dir: &Path, threshold: u32, duration: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            threshold,
+            duration,
+        }
+    }
+
+    // End of the lockout of `local_user`, None if the user may log in
+    pub fn locked_until(&self, local_user: &str) -> Result<Option<DateTime<Utc>>, IOError> {
+        let mut buff = String::new();
+        match File::open(self.entry(local_user)) {
+            Ok(mut file) => file.read_to_string(&mut buff)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let lockout = parse(&buff);
+        Ok((lockout.locked_until > Utc::now().timestamp())
+            .then(|| DateTime::from_timestamp(lockout.locked_until, 0))
+            .flatten())
+    }
+
+    // Counts a failed login, the user is locked out once `threshold` failures followed each
+    // other. Returns the end of the lockout if this failure started it.
+    pub fn record_failure(&self, local_user: &str) -> Result<Option<DateTime<Utc>>, IOError> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(self.entry(local_user))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(IOError::last_os_error());
+        }
+        let mut buff = String::new();
+        file.read_to_string(&mut buff)?;
+        let mut lockout = parse(&buff);
+
+        let now = Utc::now().timestamp();
+        let window = self.duration.as_secs() as i64;
+        if lockout
+            .last_failure
+            .is_some_and(|last| now - last >= window)
+        {
+            lockout.failures = 0;
+        }
+        lockout.failures = lockout.failures.saturating_add(1);
+        lockout.last_failure = Some(now);
+        let mut locked_until = None;
+        if lockout.failures >= self.threshold && lockout.locked_until <= now {
+            lockout.failures = 0;
+            lockout.locked_until = now + window;
+            locked_until = DateTime::from_timestamp(lockout.locked_until, 0);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(
+            format!("{} {} {now}\n", lockout.failures, lockout.locked_until).as_bytes(),
+        )?;
+        Ok(locked_until)
+    }
+
+    // Forgets the failures of `local_user` and lifts its lockout, after a successful login or
+    // by an admin
+    pub fn reset(&self, local_user: &str) -> Result<(), IOError> {
+        match fs::remove_file(self.entry(local_user)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn entry(&self, local_user: &str) -> PathBuf {
+        let digest = Sha256::digest(local_user.as_bytes());
+        let name: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+}
+
+// An entry that can't be read counts as no failures
+fn parse(buff: &str) -> Lockout {
+    let mut fields = buff.split_whitespace();
+    let failures = fields.next().map(str::parse::<u32>);
+    let locked_until = fields.next().map(str::parse::<i64>);
+    match (failures, locked_until) {
+        (Some(Ok(failures)), Some(Ok(locked_until))) => Lockout {
+            failures,
+            locked_until,
+            last_failure: fields.next().and_then(|last| last.parse().ok()),
+        },
+        _ => Lockout::default(),
+    }
+}
@@ -1,14 +1,70 @@
 use dtor::dtor;
 use log::LevelFilter;
-use log::Log;
+use log::{Level, Log, Metadata, Record};
 
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use simplelog::{ConfigBuilder, WriteLogger};
-use std::fs::OpenOptions;
-use std::sync::Once;
+use std::cmp::Reverse;
+use std::ffi::CString;
+use std::fmt::{Debug, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IOError, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 type DynErr = Box<dyn std::error::Error>;
 
+// Tag of the messages sent to syslog and the journal
+const IDENTIFIER: &str = "pam_oauth2_device";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+pub const REDACTED: &str = "[redacted]";
+
+// Claims naming the user, masked like usernames when `mask_username` is enabled
+const USER_FIELDS: &[&str] = &[
+    "username",
+    "preferred_username",
+    "email",
+    "upn",
+    "unique_name",
+    "login",
+    "name",
+    "sub",
+];
+
+// Request parameters and response fields that must never end up in the log
+const SECRET_FIELDS: &[&str] = &[
+    "client_secret",
+    "client_assertion",
+    "device_code",
+    "auth_req_id",
+    "user_code",
+    "verification_uri_complete",
+    "token",
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "code",
+    "password",
+    "secret_id",
+    "client_token",
+    "subject_token",
+    "actor_token",
+];
+
 static INIT: Once = Once::new();
+static MASK_USERNAMES: AtomicBool = AtomicBool::new(false);
+static MASK_KEY: Mutex<Option<hmac::Key>> = Mutex::new(None);
+static CONTEXT: Mutex<Option<LogContext>> = Mutex::new(None);
 
 pub struct DefaultLogger;
 
@@ -22,35 +78,205 @@ pub trait Logger {
         }
         log::error!("{}", err_msg);
     }
+
+    // Sets the PAM context prefixed to every following log line
+    fn set_context(context: LogContext) {
+        *CONTEXT.lock().unwrap_or_else(PoisonError::into_inner) = Some(context);
+    }
+
+    // Tags the following log lines with the correlation ID of the authentication attempt
+    fn set_correlation_id(id: &str) {
+        CONTEXT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(LogContext::default)
+            .correlation_id = id.to_string();
+    }
+}
+
+// PAM items of the current call, so the lines of simultaneous logins can be told apart
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogContext {
+    pub service: String,
+    pub rhost: String,
+    pub tty: String,
+    // See `correlation_id`
+    pub correlation_id: String,
+}
+
+impl Display for LogContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (name, value) in [
+            ("service", &self.service),
+            ("rhost", &self.rhost),
+            ("tty", &self.tty),
+            ("id", &self.correlation_id),
+        ] {
+            if !value.is_empty() {
+                write!(f, "{name}={value} ")?;
+            }
+        }
+        write!(f, "pid={}", std::process::id())
+    }
 }
 
 impl Logger for DefaultLogger {}
 
+// Size based rotation of the log file
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+// Level of every target, from a comma separated list of a default level and `target=level`
+// directives, e.g. `info,pam_oauth2_device::oauth_device=debug`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelFilters {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LevelFilters {
+    pub fn parse(spec: &str) -> Self {
+        let mut filters = Self {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filters
+                    .targets
+                    .push((target.to_string(), parse_level(level))),
+                None => filters.default = parse_level(directive),
+            }
+        }
+        // The most specific target wins
+        filters
+            .targets
+            .sort_by_key(|(target, _)| Reverse(target.len()));
+        filters
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+fn parse_level(level: &str) -> LevelFilter {
+    match level.trim() {
+        "info" => LevelFilter::Info,
+        "warn" => LevelFilter::Warn,
+        "error" => LevelFilter::Error,
+        "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
+        "none" | "off" => LevelFilter::Off,
+        _ => LevelFilter::Info,
+    }
+}
+
+// Drops the records of the targets filtered out before they reach the backend, and prefixes
+// the others with the `LogContext`
+pub struct ModuleLogger {
+    filters: LevelFilters,
+    inner: Box<dyn Log>,
+}
+
+impl ModuleLogger {
+    pub fn new(filters: LevelFilters, inner: Box<dyn Log>) -> Self {
+        Self { filters, inner }
+    }
+}
+
+impl Log for ModuleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filters.level(metadata.target()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let context = CONTEXT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match context {
+            Some(context) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{context}] {}", record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
 impl DefaultLogger {
-    pub fn init(log_path: &str, log_level: &str) {
+    // `log_path` is either a file or one of the `syslog` and `journald` backends, and
+    // `log_level` is parsed by `LevelFilters`
+    pub fn init(log_path: &str, log_level: &str, rotation: Rotation) {
         INIT.call_once(|| {
-            let log_level = match log_level {
-                "info" => LevelFilter::Info,
-                "warn" => LevelFilter::Warn,
-                "error" => LevelFilter::Error,
-                "debug" => LevelFilter::Debug,
-                "trace" => LevelFilter::Trace,
-                _ => LevelFilter::Info,
-            };
+            let filters = LevelFilters::parse(log_level);
+            let log_level = filters.max();
 
-            let log_file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(log_path)
-                .expect("Failed to open log file");
-
-            let config = ConfigBuilder::new().set_time_format_rfc2822().build();
-            let logger = WriteLogger::new(log_level, config, log_file);
+            let logger: Box<dyn Log> = match log_path {
+                "syslog" => Box::new(SyslogLogger::new(log_level)),
+                "journald" => Box::new(JournaldLogger::new(log_level, Path::new(JOURNALD_SOCKET))),
+                path => {
+                    let log_file = RotatingFile::open(Path::new(path), rotation)
+                        .expect("Failed to open log file");
+                    let config = ConfigBuilder::new().set_time_format_rfc2822().build();
+                    WriteLogger::new(log_level, config, log_file)
+                }
+            };
+            let logger = Box::new(ModuleLogger::new(filters, logger));
             log::set_boxed_logger(logger).expect("Failed to init logger!");
             log::set_max_level(log_level);
         });
     }
 
+    // Enables masking of usernames printed with `LogUser`. Without a `key`, usernames are
+    // masked with a random key and can only be correlated within this process.
+    pub fn mask_usernames(enabled: bool, key: Option<&[u8]>) {
+        if let Some(key) = key {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+            *MASK_KEY.lock().unwrap_or_else(PoisonError::into_inner) = Some(key);
+        }
+        MASK_USERNAMES.store(enabled, Ordering::Relaxed);
+    }
+
     // Shutdowns global logger
     pub unsafe fn shutdown() {
         let logger_ptr = log::logger() as *const dyn Log;
@@ -61,6 +287,324 @@ impl DefaultLogger {
     }
 }
 
+// Logs to syslog with the auth facility. `openlog` is deliberately not called, so the
+// ident of the host process (e.g. sshd) is kept and the module tags its messages instead.
+pub struct SyslogLogger {
+    level: LevelFilter,
+}
+
+impl SyslogLogger {
+    pub fn new(level: LevelFilter) -> Self {
+        Self { level }
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = format!("{IDENTIFIER}: {}", record.args()).replace('\0', "");
+        let Ok(msg) = CString::new(msg) else {
+            return;
+        };
+        unsafe {
+            libc::syslog(
+                libc::LOG_AUTH | syslog_priority(record.level()),
+                c"%s".as_ptr(),
+                msg.as_ptr(),
+            )
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+// Logs to systemd-journald with its native protocol, so multi-line messages are kept intact
+pub struct JournaldLogger {
+    level: LevelFilter,
+    socket_path: PathBuf,
+    socket: Option<UnixDatagram>,
+}
+
+impl JournaldLogger {
+    pub fn new(level: LevelFilter, socket_path: &Path) -> Self {
+        Self {
+            level,
+            socket_path: socket_path.to_path_buf(),
+            socket: UnixDatagram::unbound().ok(),
+        }
+    }
+
+    // Newline separated fields, the message is length prefixed as it may contain newlines
+    pub fn entry(record: &Record) -> Vec<u8> {
+        let msg = record.args().to_string();
+        let mut entry = Vec::with_capacity(msg.len() + 128);
+        entry.extend_from_slice(b"MESSAGE\n");
+        entry.extend_from_slice(&(msg.len() as u64).to_le_bytes());
+        entry.extend_from_slice(msg.as_bytes());
+        entry.push(b'\n');
+        for (field, value) in [
+            ("PRIORITY", syslog_priority(record.level()).to_string()),
+            ("SYSLOG_FACILITY", (libc::LOG_AUTH >> 3).to_string()),
+            ("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+            ("SYSLOG_PID", std::process::id().to_string()),
+        ] {
+            entry.extend_from_slice(format!("{field}={value}\n").as_bytes());
+        }
+        entry
+    }
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Nowhere to report a failure to log to
+        if let Some(socket) = &self.socket {
+            let _ = socket.send_to(&Self::entry(record), &self.socket_path);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn syslog_priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+// Log file rotated to `<path>.1`, `<path>.2`, ... once it grows over `max_bytes`.
+// Several processes (e.g. sshd workers) may append to the same file, so the rotation
+// is serialized with an flock on `<path>.lock` and every writer reopens a rotated file.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation) -> Result<Self, IOError> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file: open_append(path)?,
+        })
+    }
+
+    fn rotate(&mut self, incoming: u64) -> Result<(), IOError> {
+        let lock = open_append(&self.generation_path("lock"))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(IOError::last_os_error());
+        }
+
+        // Another writer may have rotated the file already while we were waiting for the lock
+        if !self.replaced()? && self.full(incoming)? {
+            // `init` rejects `log_max_files=0`, but `Rotation` may be built directly: keep the
+            // live file as `.1` then rather than deleting it
+            let max_files = self.rotation.max_files.max(1);
+            let _ = fs::remove_file(self.generation_path(&max_files.to_string()));
+            for i in (1..max_files).rev() {
+                let from = self.generation_path(&i.to_string());
+                if from.exists() {
+                    fs::rename(&from, self.generation_path(&(i + 1).to_string()))?;
+                }
+            }
+            fs::rename(&self.path, self.generation_path("1"))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        // The lock is released when `lock` is closed
+        Ok(())
+    }
+
+    // Whether `incoming` bytes don't fit anymore. An empty file is never rotated, a single
+    // record larger than `max_bytes` is written to it instead
+    fn full(&self, incoming: u64) -> Result<bool, IOError> {
+        let len = self.file.metadata()?.len();
+        Ok(len > 0 && len + incoming > self.rotation.max_bytes)
+    }
+
+    // Whether the file at `path` is not the one we write to anymore, because another writer
+    // rotated it or `logrotate` moved it away
+    fn replaced(&self) -> Result<bool, IOError> {
+        let ours = self.file.metadata()?;
+        Ok(fs::metadata(&self.path)
+            .map_or(true, |m| m.ino() != ours.ino() || m.dev() != ours.dev()))
+    }
+
+    fn generation_path(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        if self.rotation.max_bytes > 0 && self.full(buf.len() as u64)? {
+            self.rotate(buf.len() as u64)?;
+        } else if self.replaced()? {
+            // Moved away by `logrotate`, long-lived processes (e.g. sshd) would otherwise
+            // keep writing to the rotated file
+            self.file = open_append(&self.path)?;
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        self.file.flush()
+    }
+}
+
+// Log files other local users could tamper with, e.g. by planting a symlink to another file
+// under the name of the log in `/tmp`
+pub fn insecure_log_path(log_path: &str) -> Option<String> {
+    if log_path == "syslog" || log_path == "journald" {
+        return None;
+    }
+    let path = Path::new(log_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if fs::metadata(dir).is_ok_and(|m| m.mode() & 0o002 != 0) {
+        return Some(format!(
+            "Log file {log_path} is in {}, which everyone can write. Set the logs argument to a directory only root can write, or to syslog",
+            dir.display()
+        ));
+    }
+    if fs::symlink_metadata(path).is_ok_and(|m| m.mode() & 0o002 != 0) {
+        return Some(format!(
+            "Log file {log_path} can be written by everyone, run chmod 600 on it"
+        ));
+    }
+    None
+}
+
+fn open_append(path: &Path) -> Result<File, IOError> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub fn is_secret_field(name: &str) -> bool {
+    SECRET_FIELDS.contains(&name)
+}
+
+// Replaces the values of all secret fields, at any depth.
+// Usernames and the other `USER_FIELDS` are masked as well when `mask_username` is enabled.
+pub fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret_field(key) {
+                    *value = Value::String(REDACTED.to_string());
+                } else if USER_FIELDS.contains(&key.as_str())
+                    && MASK_USERNAMES.load(Ordering::Relaxed)
+                {
+                    if let Value::String(username) = value {
+                        *username = mask_username(username);
+                    }
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => (),
+    }
+}
+
+// Debug representation of a serializable value (e.g. a token response) with all secret
+// fields redacted, keeping the rest of the structure: `log::debug!("{:#?}", Redacted(&token))`
+pub struct Redacted<'a, T: Serialize>(pub &'a T);
+
+impl<T: Serialize> Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut value = match serde_json::to_value(self.0) {
+            Ok(value) => value,
+            Err(_) => return write!(f, "{REDACTED}"),
+        };
+        redact_json(&mut value);
+        let rendered = if f.alternate() {
+            serde_json::to_string_pretty(&value)
+        } else {
+            serde_json::to_string(&value)
+        };
+        write!(f, "{}", rendered.map_err(|_| std::fmt::Error)?)
+    }
+}
+
+// Username as it should appear in the log, masked when `mask_username` is enabled
+pub struct LogUser<'a>(pub &'a str);
+
+impl Display for LogUser<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if MASK_USERNAMES.load(Ordering::Relaxed) {
+            write!(f, "{}", mask_username(self.0))
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+// Keeps (at most) the first two characters and a short keyed hash, so log lines of
+// the same user can still be correlated: `alice` -> `al***#2bd806c9`. A plain hash of
+// a username could be reversed by hashing a list of candidate names.
+pub fn mask_username(username: &str) -> String {
+    let visible = username.chars().count().saturating_sub(1).min(2);
+    let prefix: String = username.chars().take(visible).collect();
+    let mut key = MASK_KEY.lock().unwrap_or_else(PoisonError::into_inner);
+    if key.is_none() {
+        *key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).ok();
+    }
+    // Without any key, the username is left without a tag rather than a reversible one
+    let Some(key) = key.as_ref() else {
+        return format!("{prefix}***");
+    };
+    let digest = hmac::sign(key, username.as_bytes());
+    let tag: String = digest.as_ref()[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("{prefix}***#{tag}")
+}
+
+// Random ID of an authentication attempt, in its log lines and its audit event
+pub fn correlation_id() -> String {
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        // Unique enough to tell the attempts of a host apart
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        bytes = ((std::process::id() as u64) << 32 | nanos as u64).to_be_bytes();
+    }
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Non-reversible tag of a token, logged and audited instead of the token itself so it can be
+// matched with the logs of the Authorization Server: the hex of the first 8 bytes of its SHA-256
+pub fn token_fingerprint(token: &str) -> String {
+    Sha256::digest(token.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 // Runs just before unloading the .so module
 #[dtor]
 unsafe fn shutdown() {
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::net::UdpSocket;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::audit::AuditEvent;
+use crate::config::MetricsConfig;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Counts the authentication attempts by result and error class, and the time the device
+// flows took until the user authorized them
+pub struct Metrics<'a> {
+    config: &'a MetricsConfig,
+}
+
+impl<'a> Metrics<'a> {
+    pub fn new(config: &'a MetricsConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn record(&self, event: &AuditEvent) -> Result<(), DynErr> {
+        let result = label(&event.result)?;
+        let error_class = event.error_class.as_ref().map(label).transpose()?;
+        let prefix = &self.config.prefix;
+
+        if let Some(addr) = &self.config.statsd {
+            let mut lines = vec![
+                format!("{prefix}.attempts:1|c"),
+                format!("{prefix}.result.{result}:1|c"),
+            ];
+            if let Some(class) = &error_class {
+                lines.push(format!("{prefix}.error_class.{class}:1|c"));
+            }
+            if let Some(device_flow) = event.device_flow {
+                lines.push(format!(
+                    "{prefix}.device_flow:{}|ms",
+                    device_flow.as_millis()
+                ));
+            }
+            // Several metrics per datagram, separated by newlines
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.send_to(lines.join("\n").as_bytes(), addr.as_str())?;
+        }
+
+        if let Some(path) = &self.config.textfile {
+            let mut increments = vec![
+                (format!("{prefix}_attempts_total"), 1.0),
+                (
+                    format!("{prefix}_results_total{{result=\"{result}\"}}"),
+                    1.0,
+                ),
+            ];
+            if let Some(class) = &error_class {
+                increments.push((
+                    format!("{prefix}_errors_total{{error_class=\"{class}\"}}"),
+                    1.0,
+                ));
+            }
+            if let Some(device_flow) = event.device_flow {
+                increments.push((
+                    format!("{prefix}_device_flow_seconds_sum"),
+                    device_flow.as_secs_f64(),
+                ));
+                increments.push((format!("{prefix}_device_flow_seconds_count"), 1.0));
+            }
+            update_textfile(path, &increments)?;
+        }
+        Ok(())
+    }
+}
+
+// Name of a result or an error class as in the audit log, e.g. `expired_token`
+fn label<T: serde::Serialize>(value: &T) -> Result<String, DynErr> {
+    match serde_json::to_value(value)? {
+        Value::String(label) => Ok(label),
+        other => Err(format!("Unexpected metric label: {other}").into()),
+    }
+}
+
+// Adds `increments` to the series of the textfile. Concurrent logins are serialized by a lock
+// file, and the textfile is replaced at once so the collector never reads a partial one.
+fn update_textfile(path: &Path, increments: &[(String, f64)]) -> Result<(), DynErr> {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.with_extension("lock"))?;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(IOError::last_os_error().into());
+    }
+
+    let mut series = match fs::read_to_string(path) {
+        Ok(text) => parse_textfile(&text),
+        Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    for (name, increment) in increments {
+        *series.entry(name.clone()).or_insert(0.0) += increment;
+    }
+
+    // Not ending with `.prom`, so the collector ignores it until it is renamed
+    let staged = path.with_extension("prom.tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&staged)?;
+    file.write_all(render_textfile(&series).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&staged, path)?;
+    Ok(())
+}
+
+fn parse_textfile(text: &str) -> BTreeMap<String, f64> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, value) = line.rsplit_once(' ')?;
+            Some((name.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+// Prometheus text format, with the type of every metric family
+fn render_textfile(series: &BTreeMap<String, f64>) -> String {
+    let mut text = String::new();
+    let mut family = "";
+    for (name, value) in series {
+        let base = name.split('{').next().unwrap_or(name);
+        let (current, kind) = match base
+            .strip_suffix("_sum")
+            .or_else(|| base.strip_suffix("_count"))
+        {
+            Some(summary) => (summary, "summary"),
+            None => (base, "counter"),
+        };
+        if current != family {
+            text += &format!("# TYPE {current} {kind}\n");
+            family = current;
+        }
+        text += &format!("{name} {value}\n");
+    }
+    text
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::read_secret_file;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Client certificate presented to the Authorization Server for mutual TLS (RFC 8705)
+#[derive(Clone)]
+pub struct ClientCertificate {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    // `x5t#S256` of the certificate, the base64url SHA-256 of its DER encoding
+    pub thumbprint: String,
+}
+
+impl ClientCertificate {
+    // PEM files of the certificate and its private key, the key must not be world-readable
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, DynErr> {
+        let cert = fs::read(cert_path)
+            .map_err(|e| format!("Client certificate {}: {e}", cert_path.display()))?;
+        let thumbprint = thumbprint(&cert)
+            .map_err(|e| format!("Client certificate {}: {e}", cert_path.display()))?;
+        let key = read_secret_file(key_path)?.into_bytes();
+        Ok(Self {
+            cert,
+            key,
+            thumbprint,
+        })
+    }
+}
+
+// Just the thumbprint, so the private key stays out of the `OAuth Client` debug log
+impl fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("thumbprint", &self.thumbprint)
+            .finish_non_exhaustive()
+    }
+}
+
+// `x5t#S256` of the first certificate of a PEM file
+pub fn thumbprint(pem: &[u8]) -> Result<String, DynErr> {
+    let pem = std::str::from_utf8(pem)?;
+    let (_, rest) = pem
+        .split_once("-----BEGIN CERTIFICATE-----")
+        .ok_or("No PEM certificate found")?;
+    let (body, _) = rest
+        .split_once("-----END CERTIFICATE-----")
+        .ok_or("Unterminated PEM certificate")?;
+    let der = STANDARD.decode(body.split_whitespace().collect::<String>())?;
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(der)))
+}
+
+// `x5t#S256` of the `cnf` claim binding the token to a client certificate
+pub fn bound_thumbprint(claims: &Map<String, Value>) -> Option<&str> {
+    claims.get("cnf")?.get("x5t#S256")?.as_str()
+}
@@ -0,0 +1,26 @@
+use crate::config::{UsernameCase, UsernameNormalization};
+use crate::glob;
+
+// Remote username as compared with the local user and logged: the domain is stripped if it
+// matches one of `strip_domains`, then the case is folded and the substitutions applied in
+// order. The domain is what follows the last `@`, matched case-insensitively.
+pub fn normalize_username(rules: &UsernameNormalization, username: &str) -> String {
+    let mut name = match username.rsplit_once('@') {
+        Some((name, domain)) if glob::matches_any(&rules.strip_domains, &domain.to_lowercase()) => {
+            name
+        }
+        _ => username,
+    }
+    .to_string();
+    name = match rules.case {
+        UsernameCase::Keep => name,
+        UsernameCase::Lower => name.to_lowercase(),
+        UsernameCase::Upper => name.to_uppercase(),
+    };
+    for substitution in &rules.substitutions {
+        if !substitution.from.is_empty() {
+            name = name.replace(&substitution.from, &substitution.to);
+        }
+    }
+    name
+}
@@ -0,0 +1,148 @@
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{chown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::config::NoConv;
+use crate::groups::user_ids;
+use crate::logger::LogUser;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Delivers the messages of a login whose PAM service has no conversation, see `NoConv`
+#[derive(Debug)]
+pub struct Notifier {
+    fallback: NoConv,
+    notify_dir: PathBuf,
+    user: String,
+}
+
+impl Notifier {
+    // None with the `fail` fallback
+    pub fn new(fallback: NoConv, notify_dir: &Path, user: &str) -> Option<Self> {
+        (fallback != NoConv::Fail).then(|| Self {
+            fallback,
+            notify_dir: notify_dir.to_path_buf(),
+            user: user.to_string(),
+        })
+    }
+
+    pub fn send(&self, message: &str) -> Result<(), DynErr> {
+        match self.fallback {
+            NoConv::Terminals => write_terminals(&self.user, message),
+            NoConv::File => write_file(&self.notify_dir, &self.user, message),
+            NoConv::Fail => Err("No conversation available".into()),
+        }
+    }
+}
+
+// Writes `message` to every terminal of `user` in utmp. Terminals which don't belong to the
+// user are skipped, in case utmp is stale.
+fn write_terminals(user: &str, message: &str) -> Result<(), DynErr> {
+    let (uid, _) = user_ids(user)?;
+    // Terminals in raw mode don't translate newlines
+    let text = format!("\nMessage from pam_oauth2_device:\n\n{message}\n").replace('\n', "\r\n");
+    let mut written = 0;
+    for tty in user_terminals(user) {
+        match fs::metadata(&tty) {
+            Ok(metadata) if metadata.uid() == uid => (),
+            Ok(_) => {
+                log::debug!("Skipping {}, it belongs to another user", tty.display());
+                continue;
+            }
+            Err(e) => {
+                log::debug!("Skipping {}: {}", tty.display(), e);
+                continue;
+            }
+        }
+        // Never block on a terminal whose output is suspended
+        let res = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&tty)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+        match res {
+            Ok(()) => written += 1,
+            Err(e) => log::warn!("Failed to write to {}: {}", tty.display(), e),
+        }
+    }
+    if written == 0 {
+        return Err(format!("No terminal of user {} to write to", LogUser(user)).into());
+    }
+    Ok(())
+}
+
+// `/dev` paths of the login terminals of `user`
+fn user_terminals(user: &str) -> Vec<PathBuf> {
+    let mut ttys = Vec::new();
+    unsafe {
+        libc::setutxent();
+        loop {
+            let entry = libc::getutxent();
+            if entry.is_null() {
+                break;
+            }
+            let entry = &*entry;
+            if entry.ut_type != libc::USER_PROCESS || utmp_field(&entry.ut_user) != user {
+                continue;
+            }
+            let line = utmp_field(&entry.ut_line);
+            // Lines are relative to `/dev`, e.g. `pts/3`
+            if line.is_empty() || line.contains("..") {
+                continue;
+            }
+            let tty = Path::new("/dev").join(line);
+            if !ttys.contains(&tty) {
+                ttys.push(tty);
+            }
+        }
+        libc::endutxent();
+    }
+    ttys
+}
+
+// utmp fields are only NUL terminated when shorter than the field
+fn utmp_field(field: &[libc::c_char]) -> String {
+    let bytes: Vec<u8> = field
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Replaces the notification file of `user` with `message`. The file is only readable by the
+// user, it holds the code of their login.
+fn write_file(notify_dir: &Path, user: &str, message: &str) -> Result<(), DynErr> {
+    let (uid, gid) = user_ids(user)?;
+    if user.contains('/') || user.starts_with('.') {
+        return Err(format!(
+            "Invalid username for a notification file: {}",
+            LogUser(user)
+        )
+        .into());
+    }
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(notify_dir)?;
+    let path = notify_dir.join(user);
+    let staged = notify_dir.join(format!(".{user}.{}", std::process::id()));
+    let res = (|| -> Result<(), DynErr> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staged)?;
+        file.write_all(format!("{message}\n").as_bytes())?;
+        file.sync_all()?;
+        fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        chown(&staged, Some(uid), Some(gid))?;
+        fs::rename(&staged, &path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    res.map_err(|e| format!("Notification file {}: {e}", path.display()).into())
+}
@@ -1,94 +1,1364 @@
-use std::time::Duration;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use chrono::{DateTime, Utc};
-use oauth2::basic::{BasicClient, BasicTokenResponse};
+use crate::assertion::ClientAssertion;
+use crate::azuread;
+use crate::claims::{lookup, required_claims_met};
+use crate::config::{
+    AuthMode, AzureEndpointVersion, CibaOptions, ClaimRule, Config, Flow, HttpEndpoint,
+    IdentityEndpoint, Kerberos, KerberosSource, KeycloakRoles, ProviderType, SubjectTokenType,
+    TokenExchange, UsernameCase, UsernameNormalization, ValidationMode,
+};
+use crate::discovery::{discovery_url, Endpoints};
+use crate::dpop::DpopKey;
+use crate::error::{OAuthDeviceError, ProtocolError};
+use crate::glob;
+use crate::http::{get_json_authorized, post_form};
+use crate::http::{FormError, HttpClient};
+use crate::interrupt::{self, InterruptGuard};
+use crate::jwks::{unverified_claims, JwksValidator, JwtError};
+use crate::kerberos;
+use crate::logger::{DefaultLogger, LogUser, Logger, Redacted, REDACTED};
+use crate::mtls::{bound_thumbprint, ClientCertificate};
+use crate::normalize::normalize_username;
+use crate::usermap::UserMap;
+use chrono::{DateTime, TimeDelta, Utc};
+use oauth2::basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenType};
+use oauth2::http::StatusCode;
 use oauth2::{
-    AccessToken, AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, IntrospectionUrl,
-    RedirectUrl, Scope, TokenIntrospectionResponse, TokenUrl,
+    AccessToken, AuthUrl, Client, ClientId, ClientSecret, DeviceAuthorizationUrl, ExtraTokenFields,
+    IntrospectionUrl, RedirectUrl, RefreshToken, RevocableToken, Scope, StandardRevocableToken,
+    StandardTokenIntrospectionResponse, StandardTokenResponse, TokenIntrospectionResponse,
+    TokenResponse, TokenUrl,
 };
-use oauth2::{CurlHttpClient as http_client, EndpointSet};
-use oauth2::{EndpointNotSet, StandardDeviceAuthorizationResponse};
+use oauth2::{EndpointMaybeSet, EndpointNotSet, EndpointSet, StandardDeviceAuthorizationResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use url::Url;
 
 type DynErr = Box<dyn std::error::Error>;
+type SendErr = Box<dyn std::error::Error + Send + Sync>;
+
+// Claims of the introspection response not defined by RFC 7662 (e.g. `name`, `groups`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtraClaims {
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+impl ExtraTokenFields for ExtraClaims {}
+
+impl ExtraClaims {
+    pub fn get_str(&self, claim: &str) -> Option<&str> {
+        self.claims.get(claim).and_then(Value::as_str)
+    }
+
+    // Looks up a nested claim by its dot separated path, e.g. `realm_access.roles`
+    pub fn get_path(&self, path: &str) -> Option<&Value> {
+        let mut parts = path.split('.');
+        let first = self.claims.get(parts.next()?)?;
+        parts.try_fold(first, |value, part| value.get(part))
+    }
+
+    pub fn get_path_str(&self, path: &str) -> Option<&str> {
+        self.get_path(path).and_then(Value::as_str)
+    }
+
+    // Numeric id held by `claim`, as a number or a string of digits, e.g. `uidNumber`
+    pub fn uid(&self, claim: &str) -> Option<u32> {
+        match self.get_path(claim)? {
+            Value::Number(uid) => uid.as_u64().and_then(|uid| u32::try_from(uid).ok()),
+            Value::String(uid) => uid.parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Groups held by `claim`, either a list of strings or a single space separated string
+    pub fn groups(&self, claim: &str) -> Option<Vec<&str>> {
+        match self.get_path(claim)? {
+            Value::Array(groups) => Some(groups.iter().filter_map(Value::as_str).collect()),
+            Value::String(groups) => Some(groups.split_whitespace().collect()),
+            _ => None,
+        }
+    }
+
+    // Keycloak realm roles and `<client>:<role>` client roles selected by `roles`,
+    // `None` if the token holds none of the selected role claims
+    pub fn keycloak_roles(&self, roles: &KeycloakRoles) -> Option<Vec<String>> {
+        let role_names = |path: &str| {
+            self.get_path(path)
+                .and_then(Value::as_array)
+                .map(|roles| roles.iter().filter_map(Value::as_str))
+        };
+        let realm = Some("realm_access.roles")
+            .filter(|_| roles.realm)
+            .and_then(role_names)
+            .map(|roles| roles.map(str::to_string).collect::<Vec<String>>());
+        let clients: Vec<Vec<String>> = roles
+            .clients
+            .iter()
+            .filter_map(|client| {
+                let path = format!("resource_access.{client}.roles");
+                role_names(&path)
+                    .map(|roles| roles.map(|role| format!("{client}:{role}")).collect())
+            })
+            .collect();
+        if realm.is_none() && clients.is_empty() {
+            return None;
+        }
+        Some(realm.into_iter().chain(clients).flatten().collect())
+    }
+}
+
+pub type IntrospectionResponse = StandardTokenIntrospectionResponse<ExtraClaims, BasicTokenType>;
+
+// Token response fields not defined by RFC 6749, i.e. the OpenID Connect `id_token`
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct IdTokenFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// The id_token is a bearer credential just like the access token
+impl std::fmt::Debug for IdTokenFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdTokenFields")
+            .field("id_token", &self.id_token.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+pub type DeviceTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+// Results of introspecting the access token and (optionally) the refresh token.
+// Each result is kept separately so a failure of one doesn't hide the other.
+#[derive(Debug)]
+pub struct IntrospectedTokens<T> {
+    pub access: Result<T, DynErr>,
+    pub refresh: Option<Result<T, DynErr>>,
+}
+
+// Interval of the CIBA token polling if the server doesn't set one (OpenID CIBA, section 7.3)
+const CIBA_INTERVAL: u64 = 5;
+
+const CIBA_GRANT_TYPE: &str = "urn:openid:params:grant-type:ciba";
+
+// Pending authorization of the login by the user, depending on the `flow`
+#[derive(Debug)]
+pub enum Authorization {
+    Device(StandardDeviceAuthorizationResponse),
+    Ciba(CibaAuthorization),
+}
+
+// Response to the backchannel authentication request (OpenID CIBA, section 7.3)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CibaAuthorization {
+    pub auth_req_id: String,
+    pub expires_in: u64,
+    #[serde(default)]
+    pub interval: Option<u64>,
+    // Sent with the request and shown to the user, not part of the response
+    #[serde(skip)]
+    pub binding_message: Option<String>,
+}
+
+// Downstream token issued by the token exchange (RFC 8693, section 2.2.1)
+#[derive(Deserialize, Debug)]
+pub struct ExchangedToken {
+    pub access_token: AccessToken,
+    #[serde(default)]
+    pub issued_token_type: Option<String>,
+    pub token_type: String,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+// Identity of a user whose token passed validation
+#[derive(Debug)]
+pub struct ValidatedToken {
+    pub username: String,
+    pub subject: Option<String>,
+    pub display_name: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    // Groups and roles of `groups_claim` and `keycloak_roles`. None if the token holds neither,
+    // which says nothing about the groups of the user, and for logins cached by older versions.
+    pub groups: Option<Vec<String>>,
+    // Profile of the local account created by the `provisioning`, not known for cached logins
+    pub email: Option<String>,
+    pub uid: Option<u32>,
+}
+
+// Outcome of a single validation mode.
+// `Denied` is definitive and stops the validation chain, `Unavailable` means the mode
+// couldn't decide (e.g. the endpoint failed) and the next mode in the chain is tried.
+#[derive(Debug)]
+pub enum Validation {
+    Valid(ValidatedToken),
+    Denied,
+    // Valid token of a user who didn't authenticate with multiple factors, like `Denied`
+    InsufficientAuthentication,
+    Unavailable(DynErr),
+}
+
+// Outcome of the account checks of an authenticated user
+#[derive(Debug)]
+pub enum AccountStatus {
+    Active,
+    Expired,
+    Denied,
+    Unavailable(DynErr),
+}
+
+// Where the identity of the user is taken from
+#[derive(Debug)]
+enum ProviderProfile {
+    // Claims of the token, introspected or verified by the `validation_mode`
+    Oidc,
+    // Like `Oidc`, with the username taken from Azure AD's claims and demangled
+    AzureAd,
+    // Response of a REST endpoint the opaque token is sent to, e.g. GitHub's `/user`
+    Rest(IdentityEndpoint),
+}
+
+impl ProviderProfile {
+    // A configured `identity_endpoint` overrides the one of the well-known providers
+    fn new(c: &Config) -> Result<Self, DynErr> {
+        let preset = |url: &str, username: &str| IdentityEndpoint {
+            url: Url::parse(url).expect("valid preset URL"),
+            username_pointer: username.to_string(),
+            subject_pointer: Some("/id".to_string()),
+        };
+        let identity_endpoint = match c.provider_type {
+            ProviderType::Oidc => return Ok(Self::Oidc),
+            ProviderType::Azuread => return Ok(Self::AzureAd),
+            ProviderType::Github => c
+                .identity_endpoint
+                .clone()
+                .unwrap_or_else(|| preset("https://api.github.com/user", "/login")),
+            ProviderType::Gitlab => c
+                .identity_endpoint
+                .clone()
+                .unwrap_or_else(|| preset("https://gitlab.com/api/v4/user", "/username")),
+            ProviderType::Rest => c
+                .identity_endpoint
+                .clone()
+                .ok_or("The rest provider type requires the identity_endpoint option")?,
+        };
+        Ok(Self::Rest(identity_endpoint))
+    }
+}
 
 #[derive(Debug)]
 pub struct OAuthClient {
-    client: BasicClient<
-        EndpointSet,    //HasAuthUrl
-        EndpointSet,    //HasDeviceAuthUrl
-        EndpointSet,    //HasIntrospectionUrl
-        EndpointNotSet, //HasRevocationUrl
-        EndpointSet,    //HasTokenUrl
+    client: Client<
+        BasicErrorResponse,
+        DeviceTokenResponse,
+        IntrospectionResponse,
+        StandardRevocableToken,
+        BasicRevocationErrorResponse,
+        EndpointSet,      //HasAuthUrl
+        EndpointMaybeSet, //HasDeviceAuthUrl
+        EndpointMaybeSet, //HasIntrospectionUrl
+        EndpointNotSet,   //HasRevocationUrl
+        EndpointSet,      //HasTokenUrl
     >,
     scopes: Vec<Scope>,
+    // Scopes the token must hold, the `required_scopes` or the requested ones, see
+    // `azuread::expected_scopes`
+    expected_scopes: Vec<Scope>,
+    http_client: HttpClient,
+    profile: ProviderProfile,
+    // Requested instead of scopes by the v1 endpoints of Azure AD
+    resource: Option<String>,
+    validation_mode: ValidationMode,
+    introspect_refresh_token: bool,
+    display_name_claim: String,
+    username_claim: String,
+    username_normalization: UsernameNormalization,
+    // `uid_claim` of the `provisioning`
+    uid_claim: Option<String>,
+    userinfo_url: Option<Url>,
+    revocation_url: Option<Url>,
+    // Backchannel authentication endpoint of the `ciba` flow
+    backchannel_url: Option<Url>,
+    flow: Flow,
+    ciba: CibaOptions,
+    // Kept for the requests oauth2 does not make, e.g. the token revocation
+    client_secret: Option<ClientSecret>,
+    merge_userinfo: bool,
+    allowed_groups: Vec<String>,
+    groups_claim: String,
+    keycloak_roles: KeycloakRoles,
+    allowed_audiences: Vec<String>,
+    allowed_authorized_parties: Vec<String>,
+    required_claims: Vec<ClaimRule>,
+    require_mfa: bool,
+    mfa_acr_values: Vec<String>,
+    mfa_amr_values: Vec<String>,
+    users: UserMatch,
+    token_exchange: Option<TokenExchange>,
+    kerberos: Option<Kerberos>,
+    // `x5t#S256` of the `tls_client_cert`
+    client_cert_thumbprint: Option<String>,
+    jwks: Option<JwksValidator>,
+    // Verifies the `acr` and `amr` of the id_token, see `multi_factor`
+    id_token_jwks: Option<JwksValidator>,
+    // Tolerated clock drift of the Authorization Server, see `valid_times`
+    clock_skew: TimeDelta,
+    max_token_lifetime: Option<TimeDelta>,
 }
 
 impl OAuthClient {
-    pub fn new(c: &Config) -> Result<Self, DynErr> {
+    // Failures are config errors, unless discovery couldn't reach the Authorization Server
+    pub fn new(c: &Config) -> Result<Self, OAuthDeviceError> {
+        Self::build(c).map_err(OAuthDeviceError::config)
+    }
+
+    fn build(c: &Config) -> Result<Self, DynErr> {
+        if c.wire_debug {
+            log::warn!("WIRE DEBUG ENABLED: HTTP exchanges with the Authorization Server are logged at trace level (secrets redacted). Disable it once done debugging!");
+        }
+        if !c.tls_system_roots && c.ca_bundle_path.is_none() {
+            return Err("tls_system_roots can only be disabled along with a ca_bundle_path".into());
+        }
+        let mut http_client = HttpClient::new(c);
+
+        if let Some(issuer) = &c.issuer {
+            require_https(&[("issuer", issuer)], c.allow_insecure_http)?;
+            http_client.register_endpoint(HttpEndpoint::Discovery, &discovery_url(issuer)?);
+        }
+        let endpoints = Endpoints::resolve(c, &http_client)?;
+        let profile = ProviderProfile::new(c)?;
+        if endpoints.introspection.is_none() {
+            match profile {
+                ProviderProfile::Oidc => {
+                    return Err("No oauth_token_introspect_url configured or discovered".into())
+                }
+                ProviderProfile::AzureAd if c.validation_mode.uses(ValidationMode::Introspection) => {
+                    return Err("Azure AD doesn't support token introspection, set the validation_mode to jwks".into())
+                }
+                _ => (),
+            }
+        }
+        if let Some(device_url) = &endpoints.device {
+            http_client.register_endpoint(HttpEndpoint::Device, device_url);
+        }
+        if let Some(backchannel_url) = &endpoints.backchannel {
+            http_client.register_endpoint(HttpEndpoint::Backchannel, backchannel_url);
+        }
+        http_client.register_endpoint(HttpEndpoint::Token, &endpoints.token);
+        if let Some(introspection_url) = &endpoints.introspection {
+            http_client.register_endpoint(HttpEndpoint::Introspection, introspection_url);
+        }
+        if let ProviderProfile::Rest(identity_endpoint) = &profile {
+            http_client.register_endpoint(HttpEndpoint::Identity, &identity_endpoint.url);
+        }
+        if let Some(userinfo_url) = &endpoints.userinfo {
+            http_client.register_endpoint(HttpEndpoint::Userinfo, userinfo_url);
+        }
+        if let Some(jwks_uri) = &endpoints.jwks {
+            http_client.register_endpoint(HttpEndpoint::Jwks, jwks_uri);
+        }
+        if let Some(revocation_url) = &endpoints.revocation {
+            http_client.register_endpoint(HttpEndpoint::Revocation, revocation_url);
+        }
+        if let Some(exchange_url) = c.token_exchange.as_ref().and_then(|e| e.endpoint.as_ref()) {
+            http_client.register_endpoint(HttpEndpoint::Token, exchange_url);
+            require_https(
+                &[("token_exchange.endpoint", exchange_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        if let Some(KerberosSource::Endpoint(kerberos_url)) = c.kerberos.as_ref().map(|k| &k.source)
+        {
+            require_https(
+                &[("kerberos.endpoint", kerberos_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        require_https(
+            &[
+                ("oauth_auth_url", &endpoints.auth),
+                ("oauth_token_url", &endpoints.token),
+            ],
+            c.allow_insecure_http,
+        )?;
+        if let Some(device_url) = &endpoints.device {
+            require_https(&[("oauth_device_url", device_url)], c.allow_insecure_http)?;
+        }
+        if let Some(backchannel_url) = &endpoints.backchannel {
+            require_https(
+                &[("oauth_backchannel_auth_url", backchannel_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        if let Some(introspection_url) = &endpoints.introspection {
+            require_https(
+                &[("oauth_token_introspect_url", introspection_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        if let ProviderProfile::Rest(identity_endpoint) = &profile {
+            require_https(
+                &[("identity_endpoint", &identity_endpoint.url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        if let Some(jwks_uri) = &endpoints.jwks {
+            require_https(&[("jwks_uri", jwks_uri)], c.allow_insecure_http)?;
+        }
+        if let Some(userinfo_url) = &endpoints.userinfo {
+            require_https(
+                &[("oauth_userinfo_url", userinfo_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+        if let Some(revocation_url) = &endpoints.revocation {
+            require_https(
+                &[("oauth_revocation_url", revocation_url)],
+                c.allow_insecure_http,
+            )?;
+        }
+
+        let issuers = match (&profile, &c.azuread) {
+            (ProviderProfile::AzureAd, Some(azuread)) if c.issuer.is_none() => {
+                Some(azuread::issuers(&azuread.tenant)?)
+            }
+            _ => c.issuer.clone().map(|issuer| vec![issuer]),
+        };
+        let jwks_validator = |audiences: Vec<String>| -> Option<JwksValidator> {
+            let validator = JwksValidator::new(
+                endpoints.jwks.clone()?,
+                issuers.clone()?,
+                audiences,
+                c.allowed_clock_skew_secs.as_secs(),
+            );
+            Some(validator.cached(&c.cache_dir.join("jwks"), c.jwks_cache_ttl))
+        };
+        let jwks = if c.validation_mode.uses(ValidationMode::Jwks) {
+            if endpoints.jwks.is_none() {
+                return Err("No jwks_uri configured or discovered".into());
+            }
+            if issuers.is_none() {
+                return Err("The jwks validation mode requires the issuer option".into());
+            }
+            let audiences = if c.allowed_audiences.is_empty() {
+                vec![c
+                    .jwt_audience
+                    .clone()
+                    .unwrap_or_else(|| c.client_id.clone())]
+            } else {
+                c.allowed_audiences.clone()
+            };
+            jwks_validator(audiences)
+        } else {
+            None
+        };
+        // The id_token is issued to the client itself
+        let id_token_jwks = if c.require_mfa {
+            jwks_validator(vec![c.client_id.clone()])
+        } else {
+            None
+        };
+
+        let resource = c
+            .azuread
+            .as_ref()
+            .filter(|azuread| {
+                matches!(profile, ProviderProfile::AzureAd)
+                    && azuread.endpoint_version == AzureEndpointVersion::V1
+            })
+            .and_then(|azuread| azuread.resource.clone());
+
+        if let Some(private_key_jwt) = &c.private_key_jwt {
+            http_client.set_client_assertion(ClientAssertion::new(
+                &c.client_id,
+                endpoints.token.as_str(),
+                private_key_jwt,
+            )?);
+        }
+
+        let client_certificate = match (&c.tls_client_cert, &c.tls_client_key) {
+            (Some(cert), Some(key)) => Some(ClientCertificate::load(cert, key)?),
+            (None, None) => None,
+            _ => return Err("tls_client_cert and tls_client_key must be set together".into()),
+        };
+        let client_cert_thumbprint = client_certificate.as_ref().map(|c| c.thumbprint.clone());
+        if let Some(client_certificate) = client_certificate {
+            http_client.set_client_certificate(client_certificate);
+        }
+
+        if c.dpop {
+            http_client.set_dpop_key(DpopKey::generate()?);
+        }
+
         let client_id = ClientId::new(c.client_id.clone());
-        let client_secret = ClientSecret::new(c.client_secret.clone());
-        let auth_url = AuthUrl::from_url(c.oauth_auth_url.clone());
-        let token_url = TokenUrl::from_url(c.oauth_token_url.clone());
-        let device_url = DeviceAuthorizationUrl::from_url(c.oauth_device_url.clone());
-        let introspect_url = IntrospectionUrl::from_url(c.oauth_token_introspect_url.clone());
+        let auth_url = AuthUrl::from_url(endpoints.auth);
+        let token_url = TokenUrl::from_url(endpoints.token);
+        let device_url = endpoints.device.map(DeviceAuthorizationUrl::from_url);
+        let introspect_url = endpoints.introspection.map(IntrospectionUrl::from_url);
         let redirect_url = RedirectUrl::new("urn:ietf:wg:oauth:2.0:oob".to_string())?;
-        let scopes = c
+        let scopes: Vec<Scope> = c
             .scopes
             .split_whitespace()
             .map(|s| Scope::new(s.to_string()))
             .collect();
 
-        let client = BasicClient::new(client_id)
-            .set_client_secret(client_secret)
+        let mut client = Client::new(client_id)
             .set_auth_uri(auth_url)
             .set_token_uri(token_url)
-            .set_device_authorization_url(device_url)
-            .set_introspection_url(introspect_url)
+            .set_device_authorization_url_option(device_url)
+            .set_introspection_url_option(introspect_url)
             .set_redirect_uri(redirect_url);
+        // Without a secret, the client_id is sent in the body next to the assertion or
+        // authenticated by the client certificate
+        let client_secret = match &c.client_secret_source {
+            Some(source) => source.resolver().resolve(&http_client)?,
+            None => c.client_secret.clone(),
+        };
+        let client_secret = Some(ClientSecret::new(client_secret))
+            .filter(|s| c.private_key_jwt.is_none() && !s.secret().is_empty());
+        if let Some(client_secret) = &client_secret {
+            client = client.set_client_secret(client_secret.clone());
+        }
 
-        Ok(Self { client, scopes })
+        Ok(Self {
+            client,
+            expected_scopes: match (&c.required_scopes, &profile) {
+                (Some(required), _) => required.iter().map(|s| Scope::new(s.clone())).collect(),
+                (None, ProviderProfile::AzureAd) => azuread::expected_scopes(&scopes),
+                (None, _) => scopes.clone(),
+            },
+            scopes,
+            http_client,
+            profile,
+            resource,
+            validation_mode: c.validation_mode,
+            introspect_refresh_token: c.introspect_refresh_token,
+            display_name_claim: c.display_name_claim.clone(),
+            username_claim: c.username_claim.clone(),
+            username_normalization: c.username_normalization.clone(),
+            uid_claim: c
+                .provisioning
+                .as_ref()
+                .and_then(|provisioning| provisioning.uid_claim.clone()),
+            userinfo_url: endpoints.userinfo,
+            revocation_url: endpoints.revocation,
+            backchannel_url: endpoints.backchannel,
+            flow: c.flow,
+            ciba: c.ciba.clone(),
+            client_secret,
+            merge_userinfo: c.merge_userinfo,
+            allowed_groups: c.allowed_groups.clone(),
+            groups_claim: c.groups_claim.clone(),
+            keycloak_roles: c.keycloak_roles.clone(),
+            allowed_audiences: c.allowed_audiences.clone(),
+            allowed_authorized_parties: c.allowed_authorized_parties.clone(),
+            required_claims: c.required_claims.clone(),
+            require_mfa: c.require_mfa,
+            mfa_acr_values: c.mfa_acr_values.clone(),
+            mfa_amr_values: c.mfa_amr_values.clone(),
+            users: UserMatch::new(c),
+            token_exchange: c.token_exchange.clone(),
+            kerberos: c.kerberos.clone(),
+            client_cert_thumbprint,
+            jwks,
+            id_token_jwks,
+            clock_skew: TimeDelta::from_std(c.allowed_clock_skew_secs)?,
+            max_token_lifetime: c.max_token_lifetime.map(TimeDelta::from_std).transpose()?,
+        })
+    }
+
+    // Replaces the generated DPoP key, e.g. with the one a stored refresh token is bound to
+    pub fn set_dpop_key(&mut self, dpop_key: DpopKey) {
+        self.http_client.set_dpop_key(dpop_key);
+    }
+
+    pub fn dpop_key(&self) -> Option<&DpopKey> {
+        self.http_client.dpop_key()
     }
 
     pub fn scopes(&self) -> &[Scope] {
         &self.scopes
     }
 
-    pub fn device_code(&self) -> Result<StandardDeviceAuthorizationResponse, DynErr> {
-        let details: StandardDeviceAuthorizationResponse = self
+    pub fn device_code(&self) -> Result<StandardDeviceAuthorizationResponse, OAuthDeviceError> {
+        let mut request = self
             .client
-            .exchange_device_code()
-            .add_scopes(self.scopes.clone())
-            .request(&http_client)?;
+            .exchange_device_code()?
+            .add_scopes(self.scopes.clone());
+        if let Some(resource) = &self.resource {
+            request = request.add_extra_param("resource", resource.as_str());
+        }
+        let details: StandardDeviceAuthorizationResponse = request.request(&self.http_client)?;
         Ok(details)
     }
 
+    // Authorization request of the configured `flow` for `local_user`
+    pub fn authorize(&self, local_user: &str) -> Result<Authorization, OAuthDeviceError> {
+        match self.flow {
+            Flow::Device => Ok(Authorization::Device(self.device_code()?)),
+            Flow::Ciba => Ok(Authorization::Ciba(
+                self.backchannel_authentication(local_user)?,
+            )),
+        }
+    }
+
+    // Asks the Authorization Server to have `local_user` approve the login on their
+    // authenticator (OpenID CIBA, section 7.1)
+    pub fn backchannel_authentication(
+        &self,
+        local_user: &str,
+    ) -> Result<CibaAuthorization, OAuthDeviceError> {
+        let url = self.backchannel_url.as_ref().ok_or_else(|| {
+            OAuthDeviceError::Config(
+                "No oauth_backchannel_auth_url configured or discovered".into(),
+            )
+        })?;
+        // CIBA is an OpenID Connect flow, the `openid` scope is required
+        let mut scopes: Vec<&str> = self.scopes.iter().map(|s| s.as_str()).collect();
+        if !scopes.contains(&"openid") {
+            scopes.insert(0, "openid");
+        }
+        let scope = scopes.join(" ");
+        let login_hint = self.ciba.login_hint.replace("{username}", local_user);
+        let binding_message = match self.ciba.binding_message {
+            true => Some(binding_message().map_err(OAuthDeviceError::Other)?),
+            false => None,
+        };
+        let mut params = vec![
+            ("scope", scope.as_str()),
+            ("login_hint", login_hint.as_str()),
+        ];
+        if let Some(binding_message) = &binding_message {
+            params.push(("binding_message", binding_message));
+        }
+        let body = post_form(
+            &self.http_client,
+            url,
+            &params,
+            self.client.client_id(),
+            self.client_secret.as_ref().map(|s| s.secret().as_str()),
+        )?;
+        let mut authorization: CibaAuthorization = serde_json::from_slice(&body)?;
+        authorization.binding_message = binding_message;
+        log::debug!(
+            "Backchannel authentication response: {:#?}",
+            Redacted(&authorization)
+        );
+        Ok(authorization)
+    }
+
+    // Polls the token endpoint until the user approved the backchannel authentication request,
+    // like `get_token` does for the device code (OpenID CIBA, section 10.1)
+    pub fn ciba_token(
+        &self,
+        authorization: &CibaAuthorization,
+        timeout: Option<Duration>,
+    ) -> Result<DeviceTokenResponse, OAuthDeviceError> {
+        let expires_in = Duration::from_secs(authorization.expires_in);
+        let deadline = Instant::now() + timeout.map_or(expires_in, |t| t.min(expires_in));
+        let mut interval = Duration::from_secs(authorization.interval.unwrap_or(CIBA_INTERVAL));
+        let token_url = self.client.token_uri().url();
+        let params = [
+            ("grant_type", CIBA_GRANT_TYPE),
+            ("auth_req_id", authorization.auth_req_id.as_str()),
+        ];
+        let _guard = InterruptGuard::install();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                // Reported like oauth2 does when the device code runs out of time
+                return Err(OAuthDeviceError::classify(Box::new(FormError {
+                    url: token_url.to_string(),
+                    status: StatusCode::BAD_REQUEST,
+                    error: Some("expired_token".to_string()),
+                })));
+            }
+            if !interrupt::sleep(interval.min(remaining)) {
+                return Err(OAuthDeviceError::Interrupted);
+            }
+            let res = post_form(
+                &self.http_client,
+                token_url,
+                &params,
+                self.client.client_id(),
+                self.client_secret.as_ref().map(|s| s.secret().as_str()),
+            );
+            let e = match res {
+                Ok(body) => return Ok(serde_json::from_slice(&body)?),
+                Err(e) => OAuthDeviceError::classify(e),
+            };
+            match e.protocol_error() {
+                Some(ProtocolError::AuthorizationPending) => (),
+                Some(ProtocolError::SlowDown) => {
+                    interval += Duration::from_secs(5);
+                    log::info!(
+                        "Polling the token endpoint every {} seconds",
+                        interval.as_secs()
+                    );
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
     pub fn get_token(
         &self,
         details: &StandardDeviceAuthorizationResponse,
         timeout: Option<Duration>,
-    ) -> Result<BasicTokenResponse, DynErr> {
-        let token = self.client.exchange_device_access_token(details).request(
-            &http_client,
-            std::thread::sleep,
-            timeout,
-        )?;
+    ) -> Result<DeviceTokenResponse, OAuthDeviceError> {
+        self.get_token_with_progress(details, timeout, &|_| ())
+    }
+
+    // Like `get_token`, calling `progress` with the time left before every poll
+    pub fn get_token_with_progress(
+        &self,
+        details: &StandardDeviceAuthorizationResponse,
+        timeout: Option<Duration>,
+        progress: &dyn Fn(Duration),
+    ) -> Result<DeviceTokenResponse, OAuthDeviceError> {
+        self.poll(details, timeout, None, progress)?.ok_or_else(|| {
+            OAuthDeviceError::Other("Token polling stopped without a response".into())
+        })
+    }
+
+    // Like `get_token`, but gives up after `max_polls` polls of the token endpoint and
+    // returns `None` if the user has not authorized the device by then
+    pub fn poll_token(
+        &self,
+        details: &StandardDeviceAuthorizationResponse,
+        timeout: Option<Duration>,
+        max_polls: Option<u32>,
+    ) -> Result<Option<DeviceTokenResponse>, OAuthDeviceError> {
+        self.poll(details, timeout, max_polls, &|_| ())
+    }
+
+    fn poll(
+        &self,
+        details: &StandardDeviceAuthorizationResponse,
+        timeout: Option<Duration>,
+        max_polls: Option<u32>,
+        progress: &dyn Fn(Duration),
+    ) -> Result<Option<DeviceTokenResponse>, OAuthDeviceError> {
+        // Polling starts at the `interval` of the device authorization response, is 5 seconds
+        // slower after every `slow_down` error (RFC 8628, section 3.5) and never outlives the code
+        let timeout = timeout.map_or(details.expires_in(), |t| t.min(details.expires_in()));
+        let started = Instant::now();
+        let interval = Cell::new(details.interval());
+        let polls = Cell::new(0u32);
+        let exhausted = AtomicBool::new(false);
+        // A signal ends the polling like `max_polls` does, but fails it
+        let _guard = InterruptGuard::install();
+        let sleep = |next: Duration| {
+            polls.set(polls.get() + 1);
+            if max_polls.is_some_and(|max| polls.get() >= max) {
+                exhausted.store(true, Ordering::Relaxed);
+                return;
+            }
+            if next > interval.get() {
+                log::info!(
+                    "Polling the token endpoint every {} seconds",
+                    next.as_secs()
+                );
+            }
+            interval.set(next);
+            progress(timeout.saturating_sub(started.elapsed()));
+            if !interrupt::sleep(next) {
+                exhausted.store(true, Ordering::Relaxed);
+            }
+        };
+        // The clock is checked before every poll, jumping past the timeout stops polling
+        let now = || {
+            if exhausted.load(Ordering::Relaxed) {
+                DateTime::<Utc>::MAX_UTC
+            } else {
+                Utc::now()
+            }
+        };
+        let mut request = self
+            .client
+            .exchange_device_access_token(details)
+            .set_time_fn(now);
+        if let Some(resource) = &self.resource {
+            // The v1 endpoints still know the device code by its pre-RFC 8628 name
+            request = request
+                .add_extra_param("resource", resource.as_str())
+                .add_extra_param("code", details.device_code().secret().as_str());
+        }
+        let token = request.request(&self.http_client, sleep, Some(timeout));
+        match token {
+            Ok(token) => Ok(Some(token)),
+            Err(_) if interrupt::interrupted() => Err(OAuthDeviceError::Interrupted),
+            Err(_) if exhausted.load(Ordering::Relaxed) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Client credentials grant of a service account (RFC 6749, section 4.4)
+    pub fn client_credentials(&self) -> Result<DeviceTokenResponse, DynErr> {
+        let mut request = self
+            .client
+            .exchange_client_credentials()
+            .add_scopes(self.scopes.clone());
+        if let Some(resource) = &self.resource {
+            request = request.add_extra_param("resource", resource.as_str());
+        }
+        let token = request.request(&self.http_client)?;
         Ok(token)
     }
 
-    pub fn introspect(
+    // Exchanges a refresh token obtained by an earlier login for a new token
+    pub fn refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<DeviceTokenResponse, DynErr> {
+        let mut request = self
+            .client
+            .exchange_refresh_token(refresh_token)
+            .add_scopes(self.scopes.clone());
+        if let Some(resource) = &self.resource {
+            request = request.add_extra_param("resource", resource.as_str());
+        }
+        let token = request.request(&self.http_client)?;
+        Ok(token)
+    }
+
+    pub fn introspect(&self, token: &AccessToken) -> Result<IntrospectionResponse, DynErr> {
+        let introspect = self
+            .introspect_with_hint(token, "access_token")
+            .map_err(|err| err as DynErr)?;
+        Ok(introspect)
+    }
+
+    // Introspects both tokens concurrently, the refresh token only if one was granted.
+    pub fn introspect_tokens(
+        &self,
+        access_token: &AccessToken,
+        refresh_token: Option<&RefreshToken>,
+    ) -> IntrospectedTokens<IntrospectionResponse> {
+        let Some(refresh_token) = refresh_token else {
+            return IntrospectedTokens {
+                access: self
+                    .introspect_with_hint(access_token, "access_token")
+                    .map_err(|err| err as DynErr),
+                refresh: None,
+            };
+        };
+
+        // The refresh token is sent as the `token` parameter just like an access token is,
+        // the hint tells the server which one it is.
+        let refresh_token = AccessToken::new(refresh_token.secret().to_string());
+        let (access, refresh) = std::thread::scope(|s| {
+            let refresh = s.spawn(|| self.introspect_with_hint(&refresh_token, "refresh_token"));
+            let access = self.introspect_with_hint(access_token, "access_token");
+            let refresh = refresh
+                .join()
+                .unwrap_or_else(|_| Err("Refresh token introspection panicked".into()));
+            (access, refresh)
+        });
+
+        IntrospectedTokens {
+            access: access.map_err(|err| err as DynErr),
+            refresh: Some(refresh.map_err(|err| err as DynErr)),
+        }
+    }
+
+    fn introspect_with_hint(
         &self,
         token: &AccessToken,
-    ) -> Result<impl TokenIntrospectionResponse, DynErr> {
-        let introspect = self.client.introspect(token).request(&http_client)?;
+        hint: &'static str,
+    ) -> Result<IntrospectionResponse, SendErr> {
+        let introspect = self
+            .client
+            .introspect(token)?
+            .set_token_type_hint(hint)
+            .request(&self.http_client)?;
         Ok(introspect)
     }
 
-    pub fn validate_token(
+    // Revokes `token` at the revocation endpoint (RFC 7009). Revoking a refresh token usually
+    // revokes the access tokens issued with it as well.
+    pub fn revoke(&self, token: StandardRevocableToken) -> Result<(), DynErr> {
+        let revocation_url = self
+            .revocation_url
+            .as_ref()
+            .ok_or("No oauth_revocation_url configured or discovered")?;
+        let mut params = vec![("token", token.secret())];
+        if let Some(hint) = token.type_hint() {
+            params.push(("token_type_hint", hint));
+        }
+        post_form(
+            &self.http_client,
+            revocation_url,
+            &params,
+            self.client.client_id(),
+            self.client_secret.as_ref().map(|s| s.secret().as_str()),
+        )?;
+        Ok(())
+    }
+
+    // Exchanges the access or id token of `token` for a token of the downstream `audience`
+    // of the `token_exchange`
+    pub fn exchange_token(&self, token: &DeviceTokenResponse) -> Result<ExchangedToken, DynErr> {
+        let exchange = self
+            .token_exchange
+            .as_ref()
+            .ok_or("No token_exchange configured")?;
+        let subject_token = match exchange.subject_token_type {
+            SubjectTokenType::AccessToken => token.access_token().secret(),
+            SubjectTokenType::IdToken => token
+                .extra_fields()
+                .id_token
+                .as_ref()
+                .ok_or("No id_token granted to exchange")?,
+        };
+        let mut params = vec![
+            (
+                "grant_type",
+                "urn:ietf:params:oauth:grant-type:token-exchange",
+            ),
+            ("subject_token", subject_token.as_str()),
+            ("subject_token_type", exchange.subject_token_type.urn()),
+            ("audience", exchange.audience.as_str()),
+        ];
+        if let Some(resource) = &exchange.resource {
+            params.push(("resource", resource));
+        }
+        if let Some(scopes) = &exchange.scopes {
+            params.push(("scope", scopes));
+        }
+        if let Some(requested_token_type) = &exchange.requested_token_type {
+            params.push(("requested_token_type", requested_token_type));
+        }
+        let endpoint = exchange
+            .endpoint
+            .as_ref()
+            .unwrap_or(self.client.token_uri().url());
+        let body = post_form(
+            &self.http_client,
+            endpoint,
+            &params,
+            self.client.client_id(),
+            self.client_secret.as_ref().map(|s| s.secret().as_str()),
+        )?;
+        let exchanged: ExchangedToken = serde_json::from_slice(&body)?;
+        log::debug!("Token exchange response: {:#?}", exchanged);
+        Ok(exchanged)
+    }
+
+    // Acquires the `kerberos` credentials of `local_user` with `token`, returns the ccache
+    pub fn acquire_kerberos(&self, token: &str, local_user: &str) -> Result<String, DynErr> {
+        let kerberos = self.kerberos.as_ref().ok_or("No kerberos configured")?;
+        kerberos::acquire(kerberos, &self.http_client, token, local_user)
+    }
+
+    // Claims of the user returned by the OpenID Connect UserInfo endpoint
+    pub fn get_userinfo(&self, access_token: &AccessToken) -> Result<ExtraClaims, DynErr> {
+        let userinfo_url = self
+            .userinfo_url
+            .as_ref()
+            .ok_or("No userinfo endpoint configured or discovered")?;
+        let userinfo = get_json_authorized(&self.http_client, userinfo_url, access_token.secret())?;
+        log::debug!("Userinfo response: {:#?}", Redacted(&userinfo));
+        Ok(userinfo)
+    }
+
+    // Validates the token with every mode of the configured chain until one of them decides.
+    // Opaque tokens of REST providers are validated by resolving their identity instead.
+    pub fn validate(&self, token: &DeviceTokenResponse, local_user: &str) -> Validation {
+        if let ProviderProfile::Rest(identity_endpoint) = &self.profile {
+            return self.validate_identity(identity_endpoint, token, local_user);
+        }
+        self.validate_chain(token, local_user, &|introspection| {
+            self.validated(introspection, token, local_user)
+        })
+    }
+
+    // Validates the token of the client credentials grant of a service account logging in as
+    // `local_user`. There is no remote user, the token must have been issued to `client_id`.
+    pub fn validate_service_account(
         &self,
-        token: &impl TokenIntrospectionResponse,
+        token: &DeviceTokenResponse,
+        client_id: &str,
+        local_user: &str,
+    ) -> Validation {
+        if let ProviderProfile::Rest(_) = &self.profile {
+            return Validation::Unavailable(
+                "Service accounts require the introspection or jwks validation mode".into(),
+            );
+        }
+        self.validate_chain(token, local_user, &|introspection| {
+            self.validated_service_account(introspection, client_id, local_user)
+        })
+    }
+
+    // Runs the modes of the `validation_mode` chain until one of them decides, `validated`
+    // checks the claims each mode obtained
+    fn validate_chain(
+        &self,
+        token: &DeviceTokenResponse,
+        local_user: &str,
+        validated: &dyn Fn(IntrospectionResponse) -> Validation,
+    ) -> Validation {
+        let chain = self.validation_mode.chain();
+        let mut validation = Validation::Unavailable("No validation mode configured".into());
+        for (i, mode) in chain.iter().enumerate() {
+            validation = match mode {
+                ValidationMode::Introspection => {
+                    self.validate_introspection(token, local_user, validated)
+                }
+                ValidationMode::Jwks => self.validate_jwks(token, local_user, validated),
+                // `chain` only yields single modes
+                chained => Validation::Unavailable(format!("{chained:?} is chained").into()),
+            };
+            match (&validation, chain.get(i + 1)) {
+                (Validation::Unavailable(e), Some(next)) => log::warn!(
+                    "{:?} validation unavailable ({}), falling back to {:?}",
+                    mode,
+                    e,
+                    next
+                ),
+                _ => break,
+            }
+        }
+        validation
+    }
+
+    fn validate_introspection(
+        &self,
+        token: &DeviceTokenResponse,
+        local_user: &str,
+        validated: &dyn Fn(IntrospectionResponse) -> Validation,
+    ) -> Validation {
+        let refresh_token = token
+            .refresh_token()
+            .filter(|_| self.introspect_refresh_token);
+        let introspected = self.introspect_tokens(token.access_token(), refresh_token);
+        match introspected.refresh {
+            Some(Ok(refresh)) => {
+                log::debug!(
+                    "Refresh token introspect response: {:#?}",
+                    Redacted(&refresh)
+                );
+                if !refresh.active() {
+                    log::warn!("Refresh token inactive for user: {}", LogUser(local_user));
+                }
+            }
+            Some(Err(e)) => DefaultLogger::handle_error(e, "Failed to introspect refresh token"),
+            None => (),
+        }
+
+        let introspection = match introspected.access {
+            Ok(introspection) => introspection,
+            Err(e) => return Validation::Unavailable(e),
+        };
+        log::debug!("Introspect response: {:#?}", Redacted(&introspection));
+        validated(introspection)
+    }
+
+    fn validate_jwks(
+        &self,
+        token: &DeviceTokenResponse,
+        local_user: &str,
+        validated: &dyn Fn(IntrospectionResponse) -> Validation,
+    ) -> Validation {
+        let Some(jwks) = &self.jwks else {
+            return Validation::Unavailable("No JWKS validator configured".into());
+        };
+        let claims = match jwks.claims(token.access_token().secret(), &self.http_client) {
+            Ok(claims) => claims,
+            Err(JwtError::Unsupported(e)) => return Validation::Unavailable(e),
+            Err(JwtError::Invalid(e)) => {
+                log::warn!("Invalid JWT for user {}: {}", LogUser(local_user), e);
+                return Validation::Denied;
+            }
+        };
+        let introspection = introspection_from_claims(claims);
+        log::debug!("JWT claims: {:#?}", Redacted(&introspection));
+        validated(introspection)
+    }
+
+    fn validate_identity(
+        &self,
+        identity_endpoint: &IdentityEndpoint,
+        token: &DeviceTokenResponse,
+        local_user: &str,
+    ) -> Validation {
+        let identity = match self.get_identity(identity_endpoint, token.access_token()) {
+            Ok(identity) => identity,
+            Err(e) => return Validation::Unavailable(e),
+        };
+        let introspection =
+            introspection_from_identity(identity_endpoint, identity, token, &self.scopes);
+        self.validated(introspection, token, local_user)
+    }
+
+    // User the opaque token was issued to, according to the provider API
+    fn get_identity(
+        &self,
+        identity_endpoint: &IdentityEndpoint,
+        access_token: &AccessToken,
+    ) -> Result<Map<String, Value>, DynErr> {
+        let identity = get_json_authorized(
+            &self.http_client,
+            &identity_endpoint.url,
+            access_token.secret(),
+        )?;
+        log::debug!("Identity response: {:#?}", Redacted(&identity));
+        Ok(identity)
+    }
+
+    // Shared by all validation modes once the token claims are known
+    fn validated(
+        &self,
+        mut introspection: IntrospectionResponse,
+        token: &DeviceTokenResponse,
+        local_user: &str,
+    ) -> Validation {
+        self.add_userinfo_claims(&mut introspection, token);
+        if self.username_claim != "username"
+            || (self.merge_userinfo && introspection.username().is_none())
+        {
+            introspection.set_username(self.remote_username(&introspection, token));
+        }
+        if matches!(self.profile, ProviderProfile::AzureAd) {
+            let username = if self.username_claim == "username" {
+                azuread_username(&introspection, token)
+            } else {
+                introspection.username().map(str::to_string)
+            };
+            introspection.set_username(username.as_deref().map(azuread::demangle));
+        }
+        if !self.validate_token(&introspection, local_user) {
+            return Validation::Denied;
+        }
+        if !self.certificate_bound(&introspection, local_user) {
+            return Validation::Denied;
+        }
+        if self.require_mfa && !self.multi_factor(&introspection, token, local_user) {
+            return Validation::InsufficientAuthentication;
+        }
+        //it is safe cause of token validatiaon
+        let username = self.normalized_username(introspection.username().unwrap());
+        let display_name = introspection
+            .extra_fields()
+            .get_str(&self.display_name_claim)
+            .map_or_else(|| username.clone(), |name| name.to_string());
+        Validation::Valid(ValidatedToken {
+            username,
+            subject: introspection.sub().map(|s| s.to_string()),
+            display_name,
+            expires_at: introspection.exp(),
+            groups: self.token_groups(&introspection),
+            email: introspection
+                .extra_fields()
+                .get_str("email")
+                .map(str::to_string),
+            uid: self
+                .uid_claim
+                .as_ref()
+                .and_then(|claim| introspection.extra_fields().uid(claim)),
+        })
+    }
+
+    fn validated_service_account(
+        &self,
+        introspection: IntrospectionResponse,
+        client_id: &str,
+        local_user: &str,
+    ) -> Validation {
+        if !introspection.active() {
+            log::warn!("Token of service account {} inactive", LogUser(local_user));
+            return Validation::Denied;
+        }
+        let Some(exp) = introspection.exp() else {
+            log::warn!("No expiration time provided in token");
+            return Validation::Denied;
+        };
+        if !valid_times(exp, &introspection, self.clock_skew, local_user) {
+            return Validation::Denied;
+        }
+        if authorized_party(&introspection) != Some(client_id) {
+            log::warn!(
+                "Token of service account {} was issued to another client: {:?}",
+                LogUser(local_user),
+                authorized_party(&introspection)
+            );
+            return Validation::Denied;
+        }
+        if let Some(token_scopes) = introspection.scopes() {
+            if !valid_scopes(&self.expected_scopes, token_scopes, local_user) {
+                return Validation::Denied;
+            }
+        }
+        if !self.certificate_bound(&introspection, local_user) {
+            return Validation::Denied;
+        }
+        Validation::Valid(ValidatedToken {
+            username: client_id.to_string(),
+            subject: introspection.sub().map(|s| s.to_string()),
+            display_name: client_id.to_string(),
+            expires_at: Some(exp),
+            groups: None,
+            email: None,
+            uid: None,
+        })
+    }
+
+    // A token bound to a client certificate (`cnf` claim, RFC 8705) must be bound to ours,
+    // it was issued to another client otherwise
+    fn certificate_bound(&self, introspection: &IntrospectionResponse, local_user: &str) -> bool {
+        let Some(bound) = bound_thumbprint(&introspection.extra_fields().claims) else {
+            return true;
+        };
+        if self.client_cert_thumbprint.as_deref() == Some(bound) {
+            return true;
+        }
+        log::warn!(
+            "Token of user {} is bound to another client certificate",
+            LogUser(local_user)
+        );
+        false
+    }
+
+    // Whether the `acr` or `amr` claims indicate multi-factor authentication. They are taken
+    // from the validated token claims if present, otherwise from the id_token, whose signature
+    // must then check out with the JWKS of the provider. Without a JWKS its claims aren't used.
+    fn multi_factor(
+        &self,
+        introspection: &IntrospectionResponse,
+        token: &DeviceTokenResponse,
         local_user: &str,
     ) -> bool {
+        let id_token_claims = token.extra_fields().id_token.as_ref().and_then(|id_token| {
+            let Some(jwks) = &self.id_token_jwks else {
+                log::debug!("No JWKS to verify the id_token with, ignoring its acr and amr");
+                return None;
+            };
+            match jwks.claims(id_token, &self.http_client) {
+                Ok(claims) => Some(claims),
+                Err(JwtError::Unsupported(e) | JwtError::Invalid(e)) => {
+                    DefaultLogger::handle_error(e, "Failed to verify id_token");
+                    None
+                }
+            }
+        });
+        let sources = std::iter::once(&introspection.extra_fields().claims).chain(&id_token_claims);
+        for claims in sources {
+            let acr = claims.get("acr").and_then(Value::as_str);
+            let amr: Vec<&str> = claims
+                .get("amr")
+                .and_then(Value::as_array)
+                .map(|amr| amr.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            if acr.is_none() && amr.is_empty() {
+                continue;
+            }
+            if acr.is_some_and(|acr| self.mfa_acr_values.iter().any(|v| v == acr))
+                || amr
+                    .iter()
+                    .any(|m| self.mfa_amr_values.iter().any(|v| v == m))
+            {
+                return true;
+            }
+            log::warn!(
+                "Insufficient authentication strength for user {}: acr {:?}, amr {:?}",
+                LogUser(local_user),
+                acr,
+                amr
+            );
+            return false;
+        }
+        log::warn!(
+            "No acr or amr claim provided in token, multi-factor authentication required for user {}",
+            LogUser(local_user)
+        );
+        false
+    }
+
+    // Value of `username_claim`, taken from the validated token claims if present, otherwise
+    // from the id_token and finally from the userinfo response
+    fn remote_username(
+        &self,
+        introspection: &IntrospectionResponse,
+        token: &DeviceTokenResponse,
+    ) -> Option<String> {
+        let claim = self.username_claim.as_str();
+        if claim == "sub" {
+            return introspection.sub().map(str::to_string);
+        }
+        if let Some(username) = introspection.extra_fields().get_path_str(claim) {
+            return Some(username.to_string());
+        }
+
+        if let Some(id_token) = &token.extra_fields().id_token {
+            match unverified_claims(id_token) {
+                Ok(claims) => {
+                    if let Some(username) = (ExtraClaims { claims }).get_path_str(claim) {
+                        return Some(username.to_string());
+                    }
+                }
+                Err(e) => DefaultLogger::handle_error(e, "Failed to decode id_token"),
+            }
+        }
+
+        // Already merged into the token claims
+        if self.merge_userinfo || self.userinfo_url.is_none() {
+            return None;
+        }
+        log::debug!("No {claim} claim in token, requesting userinfo");
+        match self.get_userinfo(token.access_token()) {
+            Ok(userinfo) => userinfo.get_path_str(claim).map(str::to_string),
+            Err(e) => {
+                DefaultLogger::handle_error(e, "Failed to fetch userinfo");
+                None
+            }
+        }
+    }
+
+    // Claims of the userinfo response missing from the token are added to its claims with
+    // `merge_userinfo`, or otherwise if some required claims are missing from the token
+    fn add_userinfo_claims(
+        &self,
+        introspection: &mut IntrospectionResponse,
+        token: &DeviceTokenResponse,
+    ) {
+        if self.userinfo_url.is_none() {
+            return;
+        }
+        let claims = match serde_json::to_value(&*introspection) {
+            Ok(claims) => claims,
+            Err(e) => return DefaultLogger::handle_error(e.into(), "Failed to serialize claims"),
+        };
+        if !self.merge_userinfo
+            && self
+                .required_claims
+                .iter()
+                .all(|rule| lookup(&claims, &rule.claim).is_some())
+        {
+            return;
+        }
+        log::debug!("Requesting userinfo to complete the token claims");
+        let userinfo = match self.get_userinfo(token.access_token()) {
+            Ok(userinfo) => userinfo,
+            Err(e) => return DefaultLogger::handle_error(e, "Failed to fetch userinfo"),
+        };
+        // Claims of the token take precedence
+        let mut extra = introspection.extra_fields().clone();
+        for (name, value) in userinfo.claims {
+            if claims.get(&name).is_none() {
+                extra.claims.entry(name).or_insert(value);
+            }
+        }
+        introspection.set_extra_fields(extra);
+    }
+
+    pub fn validate_token(&self, token: &IntrospectionResponse, local_user: &str) -> bool {
         if !token.active() {
             log::warn!("User token inactive!");
             return false;
@@ -99,7 +1369,10 @@ impl OAuthClient {
                 log::warn!("No username provided in token");
                 false
             },
-            |remote_username| valid_user(remote_username, local_user),
+            |remote_username| {
+                let remote_username = self.normalized_username(remote_username);
+                self.users.allows(&remote_username, token.sub(), local_user)
+            },
         );
 
         let scope_valid = token.scopes().map_or_else(
@@ -107,21 +1380,408 @@ impl OAuthClient {
                 log::warn!("No scope provided in token");
                 false
             },
-            |token_scopes| valid_scopes(&self.scopes, &token_scopes, &local_user),
+            |token_scopes| valid_scopes(&self.expected_scopes, token_scopes, local_user),
         );
 
         let exp_valid = token.exp().map_or_else(
             || {
+                // Opaque tokens may never expire, e.g. those of GitHub OAuth apps
+                if matches!(self.profile, ProviderProfile::Rest(_)) {
+                    return true;
+                }
                 log::warn!("No expiration time provided in token");
                 false
             },
-            |exp| valid_exp(exp, local_user),
+            |exp| valid_times(exp, token, self.clock_skew, local_user),
+        );
+
+        let lifetime_valid = self
+            .max_token_lifetime
+            .is_none_or(|max| valid_lifetime(token, max, local_user));
+
+        let groups_valid = self.member_of_allowed_groups(token, local_user);
+
+        let aud_valid = self.allowed_audiences.is_empty()
+            || token.aud().map_or_else(
+                || {
+                    log::warn!("No audience provided in token");
+                    false
+                },
+                |aud| valid_audience(&self.allowed_audiences, aud, local_user),
+            );
+
+        let azp_valid = self.allowed_authorized_parties.is_empty()
+            || authorized_party(token).map_or_else(
+                || {
+                    log::warn!("No authorized party provided in token");
+                    false
+                },
+                |azp| valid_authorized_party(&self.allowed_authorized_parties, azp, local_user),
+            );
+
+        let claims_valid = self.required_claims.is_empty()
+            || match serde_json::to_value(token) {
+                Ok(claims) => required_claims_met(&self.required_claims, &claims, local_user),
+                Err(e) => {
+                    DefaultLogger::handle_error(e.into(), "Failed to serialize claims");
+                    false
+                }
+            };
+
+        username_valid
+            && scope_valid
+            && exp_valid
+            && lifetime_valid
+            && groups_valid
+            && aud_valid
+            && azp_valid
+            && claims_valid
+    }
+
+    // Re-validates the token of an already authenticated user, used by the account checks
+    pub fn check_account(&self, access_token: &AccessToken, local_user: &str) -> AccountStatus {
+        if let ProviderProfile::Rest(identity_endpoint) = &self.profile {
+            return self.check_identity(identity_endpoint, access_token, local_user);
+        }
+        let introspection = match self.introspect(access_token) {
+            Ok(introspection) => introspection,
+            Err(e) => return AccountStatus::Unavailable(e),
+        };
+        log::debug!(
+            "Account check introspect response: {:#?}",
+            Redacted(&introspection)
+        );
+
+        let expired = introspection
+            .exp()
+            .is_some_and(|exp| exp + self.clock_skew <= Utc::now());
+        if !introspection.active() || expired {
+            log::warn!("Token of user {} is no longer active", LogUser(local_user));
+            return AccountStatus::Expired;
+        }
+        if !self.member_of_allowed_groups(&introspection, local_user) {
+            return AccountStatus::Denied;
+        }
+        AccountStatus::Active
+    }
+
+    // The identity endpoint only answers while the opaque token is still valid
+    fn check_identity(
+        &self,
+        identity_endpoint: &IdentityEndpoint,
+        access_token: &AccessToken,
+        local_user: &str,
+    ) -> AccountStatus {
+        let identity = match self.get_identity(identity_endpoint, access_token) {
+            Ok(identity) => identity,
+            Err(e) => return AccountStatus::Unavailable(e),
+        };
+        let mut introspection = IntrospectionResponse::new(true, ExtraClaims::default());
+        introspection.set_extra_fields(ExtraClaims { claims: identity });
+        if !self.member_of_allowed_groups(&introspection, local_user) {
+            return AccountStatus::Denied;
+        }
+        AccountStatus::Active
+    }
+
+    // Group membership is only required if some groups are configured
+    fn member_of_allowed_groups(&self, token: &IntrospectionResponse, local_user: &str) -> bool {
+        if self.allowed_groups.is_empty() {
+            return true;
+        }
+        let Some(groups) = self.token_groups(token) else {
+            log::warn!("No {} claim provided in token", self.groups_claim);
+            return false;
+        };
+        valid_groups(&self.allowed_groups, &groups, local_user)
+    }
+
+    // Groups and selected Keycloak roles of the user, None if the token holds neither
+    fn token_groups(&self, token: &IntrospectionResponse) -> Option<Vec<String>> {
+        let claims = token.extra_fields();
+        let groups = claims
+            .groups(&self.groups_claim)
+            .map(|groups| groups.into_iter().map(str::to_string).collect());
+        let roles = claims.keycloak_roles(&self.keycloak_roles);
+        if groups.is_none() && roles.is_none() {
+            return None;
+        }
+        Some(groups.into_iter().chain(roles).flatten().collect())
+    }
+
+    fn normalized_username(&self, remote_username: &str) -> String {
+        let normalized = normalize_username(&self.username_normalization, remote_username);
+        if normalized != remote_username {
+            log::debug!(
+                "Normalized remote username {} to {}",
+                LogUser(remote_username),
+                LogUser(&normalized)
+            );
+        }
+        normalized
+    }
+}
+
+// Which remote users may log in as which local user: `allowed_remote_users`, the `user_map`
+// and the match of the names. Checked for every token and again when a cached login is reused.
+#[derive(Debug)]
+pub struct UserMatch {
+    user_map: Option<UserMap>,
+    allowed_remote_users: Vec<String>,
+    // Second factor mode, see `valid_user_relaxed`
+    relaxed: bool,
+}
+
+impl UserMatch {
+    pub fn new(c: &Config) -> Self {
+        Self {
+            user_map: c.user_map.as_deref().map(UserMap::new),
+            allowed_remote_users: c.allowed_remote_users.clone(),
+            relaxed: c.mode == AuthMode::Mfa,
+        }
+    }
+
+    // Whether the already normalized `remote_username` may log in as `local_username`
+    pub fn allows(&self, remote_username: &str, sub: Option<&str>, local_username: &str) -> bool {
+        self.valid_remote_user(remote_username, local_username)
+            && self.valid_user(remote_username, sub, local_username)
+    }
+
+    fn valid_remote_user(&self, remote_username: &str, local_user: &str) -> bool {
+        if self.allowed_remote_users.is_empty()
+            || glob::matches_any(&self.allowed_remote_users, remote_username)
+        {
+            return true;
+        }
+        log::warn!(
+            "Remote user {} not in allowed_remote_users, denied for local user {}",
+            LogUser(remote_username),
+            LogUser(local_user)
         );
+        false
+    }
+
+    // Remote users listed in the user map may log in as any of their mapped local accounts,
+    // other users only as the local account of the same name
+    fn valid_user(&self, remote_username: &str, sub: Option<&str>, local_username: &str) -> bool {
+        let Some(user_map) = &self.user_map else {
+            return self.valid_unmapped_user(remote_username, local_username);
+        };
+        let remote_ids: Vec<&str> = std::iter::once(remote_username).chain(sub).collect();
+        match user_map.local_users(&remote_ids) {
+            Ok(Some(local_users)) => {
+                if local_username != "root" && local_users.iter().any(|u| u == local_username) {
+                    return true;
+                }
+                log::warn!(
+                    "Local user {} not mapped to remote user: {}",
+                    LogUser(local_username),
+                    LogUser(remote_username)
+                );
+                false
+            }
+            Ok(None) => self.valid_unmapped_user(remote_username, local_username),
+            Err(e) => {
+                DefaultLogger::handle_error(e, "Failed to read user map");
+                false
+            }
+        }
+    }
+
+    fn valid_unmapped_user(&self, remote_username: &str, local_username: &str) -> bool {
+        if self.relaxed {
+            valid_user_relaxed(remote_username, local_username)
+        } else {
+            valid_user(remote_username, local_username)
+        }
+    }
+}
 
-        username_valid && scope_valid && exp_valid
+// Device authorization with the first of `providers` issuing a device code, along with
+// the config and client of that provider. The error of the last provider is returned.
+pub fn device_code_with_fallback(
+    providers: &[Config],
+) -> Result<(&Config, OAuthClient, StandardDeviceAuthorizationResponse), OAuthDeviceError> {
+    with_fallback(providers, OAuthClient::device_code)
+}
+
+// Like `device_code_with_fallback`, with the authorization request of the `flow` of each provider
+pub fn authorize_with_fallback<'a>(
+    providers: &'a [Config],
+    local_user: &str,
+) -> Result<(&'a Config, OAuthClient, Authorization), OAuthDeviceError> {
+    with_fallback(providers, |oauth_client| oauth_client.authorize(local_user))
+}
+
+fn with_fallback<T>(
+    providers: &[Config],
+    request: impl Fn(&OAuthClient) -> Result<T, OAuthDeviceError>,
+) -> Result<(&Config, OAuthClient, T), OAuthDeviceError> {
+    let authorize = |provider: &Config| {
+        let oauth_client = OAuthClient::new(provider)?;
+        let details = request(&oauth_client)?;
+        Ok::<_, OAuthDeviceError>((oauth_client, details))
+    };
+    let Some((last, fallbacks)) = providers.split_last() else {
+        return Err(OAuthDeviceError::Config("No provider configured".into()));
+    };
+    for provider in fallbacks {
+        match authorize(provider) {
+            Ok((oauth_client, details)) => return Ok((provider, oauth_client, details)),
+            Err(e) => {
+                log::warn!(
+                    "Provider {} failed to start the authorization, trying the next one",
+                    provider.provider_name
+                );
+                DefaultLogger::handle_error(Box::new(e), "Failed to start authorization");
+            }
+        }
+    }
+    let (oauth_client, details) = authorize(last)?;
+    Ok((last, oauth_client, details))
+}
+
+// Maps the claims of a verified JWT (RFC 9068) onto an introspection response,
+// so both validation modes apply the same checks
+fn introspection_from_claims(claims: Map<String, Value>) -> IntrospectionResponse {
+    let str_claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+
+    let mut introspection = IntrospectionResponse::new(true, ExtraClaims::default());
+    introspection.set_username(str_claim("username").or_else(|| str_claim("preferred_username")));
+    introspection.set_sub(str_claim("sub"));
+    introspection.set_iss(str_claim("iss"));
+    // Azure AD names the scopes `scp`
+    introspection.set_scopes(
+        str_claim("scope")
+            .or_else(|| str_claim("scp"))
+            .map(|scope| {
+                scope
+                    .split_whitespace()
+                    .map(|s| Scope::new(s.to_string()))
+                    .collect()
+            }),
+    );
+    introspection.set_aud(claims.get("aud").and_then(|aud| {
+        match aud {
+            Value::String(aud) => Some(vec![aud.clone()]),
+            Value::Array(aud) => Some(
+                aud.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }));
+    // `appid` of Azure AD v1 tokens
+    introspection.set_client_id(
+        str_claim("client_id")
+            .or_else(|| str_claim("appid"))
+            .map(ClientId::new),
+    );
+    let time_claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(Value::as_i64)
+            .and_then(|time| DateTime::from_timestamp(time, 0))
+    };
+    introspection.set_exp(time_claim("exp"));
+    introspection.set_iat(time_claim("iat"));
+    introspection.set_nbf(time_claim("nbf"));
+    introspection.set_extra_fields(ExtraClaims { claims });
+    introspection
+}
+
+// Maps the identity of an opaque token onto an introspection response. The token is
+// active as the provider accepted it, its scopes are those granted or otherwise the
+// requested ones (RFC 6749, section 5.1).
+fn introspection_from_identity(
+    identity_endpoint: &IdentityEndpoint,
+    identity: Map<String, Value>,
+    token: &DeviceTokenResponse,
+    requested_scopes: &[Scope],
+) -> IntrospectionResponse {
+    let identity = Value::Object(identity);
+    // Ids are often numbers, e.g. GitHub's
+    let pointed = |pointer: &str| match identity.pointer(pointer)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+
+    let mut introspection = IntrospectionResponse::new(true, ExtraClaims::default());
+    introspection.set_username(pointed(&identity_endpoint.username_pointer));
+    introspection.set_sub(
+        identity_endpoint
+            .subject_pointer
+            .as_deref()
+            .and_then(pointed),
+    );
+    introspection.set_scopes(Some(
+        token
+            .scopes()
+            .cloned()
+            .unwrap_or_else(|| requested_scopes.to_vec()),
+    ));
+    introspection.set_exp(
+        token
+            .expires_in()
+            .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+            .map(|expires_in| Utc::now() + expires_in),
+    );
+    if let Value::Object(claims) = identity {
+        introspection.set_extra_fields(ExtraClaims { claims });
+    }
+    introspection
+}
+
+// Username of an Azure AD token, from the token claims if present, otherwise from the id_token
+fn azuread_username(
+    introspection: &IntrospectionResponse,
+    token: &DeviceTokenResponse,
+) -> Option<String> {
+    if let Some(username) = azuread::username(&introspection.extra_fields().claims) {
+        return Some(username.to_string());
+    }
+    let id_token = token.extra_fields().id_token.as_ref()?;
+    match unverified_claims(id_token) {
+        Ok(claims) => azuread::username(&claims).map(str::to_string),
+        Err(e) => {
+            DefaultLogger::handle_error(e, "Failed to decode id_token");
+            None
+        }
     }
 }
 
+// Random code of the CIBA `binding_message`, short enough to compare at a glance
+fn binding_message() -> Result<String, DynErr> {
+    let mut bytes = [0u8; 4];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to generate binding message")?;
+    Ok(format!("{:06}", u32::from_be_bytes(bytes) % 1_000_000))
+}
+
+// Refuses to send credentials or tokens in cleartext unless explicitly allowed
+fn require_https(endpoints: &[(&str, &Url)], allow_insecure_http: bool) -> Result<(), DynErr> {
+    for (name, url) in endpoints {
+        match url.scheme() {
+            "https" => (),
+            "http" if allow_insecure_http => {
+                log::warn!("Insecure plain http endpoint allowed for {name}: {url}");
+            }
+            "http" => {
+                return Err(format!(
+                "Insecure plain http endpoint {name}: {url} (set allow_insecure_http to allow it)"
+            )
+                .into())
+            }
+            scheme => return Err(format!("Unsupported scheme {scheme} for {name}: {url}").into()),
+        }
+    }
+    Ok(())
+}
+
 fn valid_user(remote_username: &str, local_username: &str) -> bool {
     //remote user cannot be root
     if remote_username == local_username && remote_username != "root" {
@@ -129,8 +1789,30 @@ fn valid_user(remote_username: &str, local_username: &str) -> bool {
     }
     log::warn!(
         "Invalid username: remote: {} -> local: {}",
-        remote_username,
-        &local_username
+        LogUser(remote_username),
+        LogUser(local_username)
+    );
+    false
+}
+
+// The local user already authenticated in the `mfa` mode, so the remote user only has to name
+// the same person, ignoring case. Only the domains of `username_normalization` were stripped
+// from it, e.g. `Alice@example.org` matches `alice` with `example.org` among them, any other
+// domain doesn't.
+fn valid_user_relaxed(remote_username: &str, local_username: &str) -> bool {
+    let folded = UsernameNormalization {
+        case: UsernameCase::Lower,
+        ..Default::default()
+    };
+    if normalize_username(&folded, remote_username) == normalize_username(&folded, local_username)
+        && local_username != "root"
+    {
+        return true;
+    }
+    log::warn!(
+        "Invalid username for second factor: remote: {} -> local: {}",
+        LogUser(remote_username),
+        LogUser(local_username)
     );
     false
 }
@@ -146,16 +1828,103 @@ fn valid_scopes(required_scopes: &[Scope], token_scopes: &[Scope], user: &str) -
         .collect::<Vec<String>>();
     log::warn!(
         "Insuficient scopes for user {}: {:?}",
-        &user,
+        LogUser(user),
         display_scopes
     );
     false
 }
 
-fn valid_exp(exp: DateTime<Utc>, user: &str) -> bool {
-    if exp <= Utc::now() {
-        log::warn!("Token has expired for user {}", &user);
+pub fn valid_groups(allowed_groups: &[String], token_groups: &[String], user: &str) -> bool {
+    if token_groups
+        .iter()
+        .any(|group| allowed_groups.iter().any(|allowed| allowed == group))
+    {
+        return true;
+    }
+    log::warn!(
+        "User {} is not a member of any allowed group: {:?}",
+        LogUser(user),
+        token_groups
+    );
+    false
+}
+
+// The client the token was issued to, the `azp` claim of OpenID Connect or the `client_id`
+// of introspection responses and JWT access tokens (RFC 9068)
+fn authorized_party(token: &IntrospectionResponse) -> Option<&str> {
+    token
+        .extra_fields()
+        .get_str("azp")
+        .or_else(|| token.client_id().map(|client_id| client_id.as_str()))
+}
+
+fn valid_audience(allowed_audiences: &[String], token_aud: &[String], user: &str) -> bool {
+    if token_aud
+        .iter()
+        .any(|aud| allowed_audiences.iter().any(|allowed| allowed == aud))
+    {
+        return true;
+    }
+    log::warn!(
+        "Token of user {} was not issued for an allowed audience: {:?}",
+        LogUser(user),
+        token_aud
+    );
+    false
+}
+
+fn valid_authorized_party(allowed_parties: &[String], azp: &str, user: &str) -> bool {
+    if allowed_parties.iter().any(|allowed| allowed == azp) {
+        return true;
+    }
+    log::warn!(
+        "Token of user {} was issued to a client that is not allowed: {}",
+        LogUser(user),
+        azp
+    );
+    false
+}
+
+// The token was issued for at most `max`, e.g. not a long-lived one minted for automation.
+// A token without `exp` or `iat` has no known lifetime.
+fn valid_lifetime(token: &IntrospectionResponse, max: TimeDelta, user: &str) -> bool {
+    let (Some(exp), Some(iat)) = (token.exp(), token.iat()) else {
+        log::warn!(
+            "Token of user {} has no exp or iat, its lifetime is unknown",
+            LogUser(user)
+        );
+        return false;
+    };
+    if exp - iat > max {
+        log::warn!(
+            "Token of user {} is valid for {}s, longer than the max_token_lifetime",
+            LogUser(user),
+            (exp - iat).num_seconds()
+        );
+        return false;
     }
+    true
+}
 
-    exp > Utc::now()
+// `exp` is not past and neither `nbf` nor `iat` are ahead, give or take `skew`
+fn valid_times(
+    exp: DateTime<Utc>,
+    token: &IntrospectionResponse,
+    skew: TimeDelta,
+    user: &str,
+) -> bool {
+    let now = Utc::now();
+    if exp + skew <= now {
+        log::warn!("Token has expired for user {}", LogUser(user));
+        return false;
+    }
+    if token.nbf().is_some_and(|nbf| nbf - skew > now) {
+        log::warn!("Token of user {} is not valid yet", LogUser(user));
+        return false;
+    }
+    if token.iat().is_some_and(|iat| iat - skew > now) {
+        log::warn!("Token of user {} was issued in the future", LogUser(user));
+        return false;
+    }
+    true
 }
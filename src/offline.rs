@@ -0,0 +1,170 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::logger::LogUser;
+
+const SALT_LEN: usize = 16;
+
+// Recent online logins of every local user, allowing them to log in offline while the
+// Authorization Server is unreachable. One file per local user holding a salted hash of the
+// local user and the subject of the token, so the files don't reveal who logged in.
+pub struct OfflineStore {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OfflineEntry {
+    pub salt: String,
+    pub hash: String,
+    pub stored_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OfflineEntry {
+    // Whether the entry was stored for `local_user` logging in with `subject`
+    pub fn matches(&self, local_user: &str, subject: &str) -> bool {
+        let Ok(salt) = from_hex(&self.salt) else {
+            return false;
+        };
+        hash(&salt, local_user, subject) == self.hash
+    }
+}
+
+impl OfflineStore {
+    pub fn new(dir: &Path, ttl: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            ttl,
+        }
+    }
+
+    // Allows `local_user` to log in offline for the ttl after an online login with `subject`
+    pub fn store(&self, local_user: &str, subject: &str) -> Result<OfflineEntry, IOError> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| IOError::other("Failed to generate salt"))?;
+        let stored_at = Utc::now();
+        let ttl = chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::MAX);
+        let entry = OfflineEntry {
+            salt: to_hex(&salt),
+            hash: hash(&salt, local_user, subject),
+            stored_at,
+            expires_at: stored_at
+                .checked_add_signed(ttl)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        };
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let path = self.entry(local_user)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let tmp = path.with_file_name(format!(".{local_user}.{}.{nanos}", std::process::id()));
+        // Never follows or reuses a file someone else put there
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&serde_json::to_vec(&entry)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(entry)
+    }
+
+    // Unexpired entry of `local_user`, which must have been stored for `pinned_subject`, so
+    // entries stop working once the subject pinned for the user is reset
+    pub fn lookup(
+        &self,
+        local_user: &str,
+        pinned_subject: &str,
+    ) -> Result<Option<OfflineEntry>, IOError> {
+        let path = self.entry(local_user)?;
+        let Some(entry) = read_entry(&path)? else {
+            return Ok(None);
+        };
+        if entry.expires_at <= Utc::now() {
+            log::debug!("Offline entry of {} expired", LogUser(local_user));
+            remove(&path)?;
+            return Ok(None);
+        }
+        if !entry.matches(local_user, pinned_subject) {
+            log::warn!(
+                "Offline entry of {} doesn't match the pinned subject",
+                LogUser(local_user)
+            );
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    pub fn remove(&self, local_user: &str) -> Result<(), IOError> {
+        remove(&self.entry(local_user)?)
+    }
+
+    fn entry(&self, local_user: &str) -> Result<PathBuf, IOError> {
+        if local_user.is_empty() || local_user.starts_with('.') || local_user.contains('/') {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid local username: {}", LogUser(local_user)),
+            ));
+        }
+        Ok(self.dir.join(local_user))
+    }
+}
+
+// Entries not owned by us or accessible by others may have been planted and are ignored
+fn read_entry(path: &Path) -> Result<Option<OfflineEntry>, IOError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let euid = unsafe { libc::geteuid() };
+    if !metadata.is_file() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+        log::warn!("Ignoring insecure offline entry {}", path.display());
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+fn remove(path: &Path) -> Result<(), IOError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// The NUL separates the local user from the subject, neither can contain one
+fn hash(salt: &[u8], local_user: &str, subject: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(local_user.as_bytes());
+    hasher.update([0]);
+    hasher.update(subject.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, std::num::ParseIntError> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+        .collect()
+}
@@ -0,0 +1,1746 @@
+// PAM glue of the module: the hooks, the conversation with the user and the PAM data and
+// environment of the login
+
+use crate::audit::{AuditEvent, AuditLog, ErrorClass};
+use crate::audit_export::AuditExport;
+use crate::cache::{CacheEntry, TokenCache};
+use crate::config::{
+    cached_config, AccountCheck, AuthMode, Config, OnUnreachable, PromptEcho, PromptMode, QrMode,
+    Reload, ServiceAccount,
+};
+use crate::dpop::DpopKey;
+use crate::env::{locale, login_var, put_env, unset_env};
+use crate::error::{OAuthDeviceError, ProtocolError};
+use crate::group_sync::GroupSync;
+use crate::homedir::create_homedir;
+use crate::http::is_unreachable;
+use crate::lockout::LockoutStore;
+use crate::notify::Notifier;
+use crate::oauth_device::*;
+use crate::offline::OfflineStore;
+use crate::{glob, groups};
+use pam::constants::{
+    PamFlag, PamMessageStyle, PamResultCode, PAM_DELETE_CRED, PAM_ERROR_MSG, PAM_PROMPT_ECHO_OFF,
+    PAM_PROMPT_ECHO_ON, PAM_TEXT_INFO,
+};
+
+use crate::logger::{
+    insecure_log_path, token_fingerprint, DefaultLogger, LogContext, LogUser, Logger, Redacted,
+    Rotation,
+};
+use crate::metrics::Metrics;
+use crate::prompt::{success_message, supports_ansi, supports_images, waiting_message, UserPrompt};
+use crate::provision::Provisioner;
+use crate::ratelimit::RateLimiter;
+use crate::refresh::RefreshStore;
+use crate::seal;
+use crate::session::SessionStore;
+use crate::shared::{set_c_string_data, SharedToken};
+use crate::shortener::UrlShortener;
+use crate::state::ensure_state_dir;
+use crate::subject::SubjectStore;
+use chrono::{DateTime, Utc};
+use oauth2::{
+    AccessToken, RefreshToken, StandardDeviceAuthorizationResponse, StandardRevocableToken,
+    TokenResponse,
+};
+use pam::conv::Conv;
+use pam::items::{Item, RHost, RUser, Service, Tty};
+use pam::module::{PamHandle, PamHooks};
+use pam::pam_try;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+pub struct PamOAuth2Device;
+pam::pam_hooks!(PamOAuth2Device);
+
+macro_rules! try_or_handle {
+    ($res:expr, $error_message:expr, $pam_error:expr) => {
+        match $res {
+            Ok(o) => o,
+            Err(e) => {
+                DefaultLogger::handle_error(e, $error_message);
+                return $pam_error;
+            }
+        }
+    };
+}
+
+// PAM data key under which the authenticated remote identity is kept for the session hooks
+const IDENTITY_DATA_KEY: &str = "pam_oauth2_device_identity";
+
+/// PAM data key under which the token response is shared with the other modules of the stack.
+///
+/// The item is a NUL terminated `char *` holding the JSON serialized [`SharedToken`], e.g.
+/// `{"version":1,"access_token":"...","token_type":"bearer","expires_at":1713953169,"username":"alice"}`.
+/// Read it with `pam_get_data(pamh, "pam_oauth2_device_token_response", &data)` after this
+/// module's `auth` succeeded. It is only set for logins that went through the token endpoint,
+/// not for logins reused from the cache.
+pub const TOKEN_RESPONSE_DATA_KEY: &str = "pam_oauth2_device_token_response";
+
+// PAM data key under which the token of the authenticated user is kept for the
+// account checks and the session environment
+const TOKEN_DATA_KEY: &str = "pam_oauth2_device_token";
+
+struct TokenData {
+    // Name of the provider that issued the token
+    provider: String,
+    // Not known for logins reused from the cache
+    access_token: Option<AccessToken>,
+    // Only kept to be revoked when the session is closed
+    refresh_token: Option<RefreshToken>,
+    id_token: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    // Obtained with the `token_exchange`
+    downstream_token: Option<AccessToken>,
+    // `KRB5CCNAME` of the `kerberos` credentials
+    ccache: Option<String>,
+    // Profile of the account created by the `provisioning`
+    display_name: String,
+    email: Option<String>,
+    uid: Option<u32>,
+}
+
+impl PamHooks for PamOAuth2Device {
+    fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let (args, config) = match init(pamh, &args) {
+            Ok(init) => init,
+            Err(code) => return code,
+        };
+
+        let local_username = pam_try!(pamh.get_user(None));
+
+        let mut event = AuditEvent::new(
+            &item::<Service>(pamh),
+            &local_username,
+            &item::<RHost>(pamh),
+            &item::<Tty>(pamh),
+            &config.client_id,
+        );
+        DefaultLogger::set_correlation_id(&event.correlation_id);
+        let result = authenticate(pamh, &args, &config, &local_username, &mut event);
+        if let Err(failure) = &result {
+            send_failure(pamh, &config, failure);
+        }
+        update_lockout(&config, &local_username, &result);
+        let code = event.finish(result);
+        if let Some(audit_log) = &config.audit_log {
+            if let Err(e) = AuditLog::new(audit_log).record(&event) {
+                DefaultLogger::handle_error(e.into(), "Failed to write audit log");
+            }
+        }
+        if let Some(export) = AuditExport::new(&config) {
+            if let Err(e) = export.export(&event) {
+                DefaultLogger::handle_error(e, "Failed to export audit event");
+            }
+        }
+        if let Some(metrics) = &config.metrics {
+            if let Err(e) = Metrics::new(metrics).record(&event) {
+                DefaultLogger::handle_error(e, "Failed to record metrics");
+            }
+        }
+        code
+    }
+
+    fn sm_setcred(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        let config = match init(pamh, &args) {
+            Ok((_, config)) => config,
+            Err(code) => return code,
+        };
+        if exports_env(&config) {
+            let res = if flags & PAM_DELETE_CRED != 0 {
+                unset_token_env(pamh, &config)
+            } else {
+                export_token_env(pamh, &config)
+            };
+            pam_try!(res);
+        }
+        PamResultCode::PAM_SUCCESS
+    }
+
+    fn acct_mgmt(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let config = match init(pamh, &args) {
+            Ok((_, config)) => config,
+            Err(code) => return code,
+        };
+        if let Some(provisioner) = Provisioner::new(&config) {
+            pam_try!(provision_account(pamh, &provisioner));
+        }
+        if config.account_check == AccountCheck::Disabled {
+            return PamResultCode::PAM_SUCCESS;
+        }
+
+        let local_username = pam_try!(pamh.get_user(None));
+        let Ok(account) = (unsafe { pamh.get_data::<TokenData>(TOKEN_DATA_KEY) }) else {
+            log::debug!(
+                "User {} not authenticated by this module, skipping account checks",
+                LogUser(&local_username)
+            );
+            return PamResultCode::PAM_IGNORE;
+        };
+
+        let skew = config.allowed_clock_skew_secs;
+        if account
+            .expires_at
+            .is_some_and(|exp| exp + skew <= Utc::now())
+        {
+            log::warn!("Token of user {} has expired", LogUser(&local_username));
+            return PamResultCode::PAM_ACCT_EXPIRED;
+        }
+        if config.account_check == AccountCheck::Local {
+            return PamResultCode::PAM_SUCCESS;
+        }
+        let Some(access_token) = &account.access_token else {
+            log::debug!("Cached login, only the token expiry is checked");
+            return PamResultCode::PAM_SUCCESS;
+        };
+
+        let provider = try_or_handle!(
+            config
+                .provider_config(&account.provider)
+                .map_err(|err| err.into()),
+            "Failed to select provider",
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+        let oauth_client = try_or_handle!(
+            OAuthClient::new(&provider).map_err(|err| err.into()),
+            "Failed to build OAuth client",
+            PamResultCode::PAM_SYSTEM_ERR
+        );
+        match oauth_client.check_account(access_token, &local_username) {
+            AccountStatus::Active => PamResultCode::PAM_SUCCESS,
+            AccountStatus::Expired => PamResultCode::PAM_ACCT_EXPIRED,
+            AccountStatus::Denied => {
+                log::warn!(
+                    "Account check failed for user: {}",
+                    LogUser(&local_username)
+                );
+                PamResultCode::PAM_PERM_DENIED
+            }
+            AccountStatus::Unavailable(e) => {
+                DefaultLogger::handle_error(e, "Failed to check account");
+                PamResultCode::PAM_AUTH_ERR
+            }
+        }
+    }
+
+    fn sm_chauthtok(_pamh: &mut PamHandle, _args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_IGNORE
+    }
+    fn sm_open_session(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let config = match init(pamh, &args) {
+            Ok((_, config)) => config,
+            Err(code) => return code,
+        };
+        if config.create_homedir {
+            pam_try!(create_home(pamh, &config));
+        }
+        if exports_env(&config) {
+            pam_try!(export_token_env(pamh, &config));
+        }
+        let Some(max_sessions) = config.max_sessions_per_user else {
+            return if exports_env(&config) || config.create_homedir {
+                PamResultCode::PAM_SUCCESS
+            } else {
+                PamResultCode::PAM_IGNORE
+            };
+        };
+
+        let identity = pam_try!(session_identity(pamh));
+        let store = SessionStore::new(&config.session_store, config.session_stale_timeout);
+        match store.open(&identity, std::process::id(), max_sessions) {
+            Ok(true) => PamResultCode::PAM_SUCCESS,
+            Ok(false) => {
+                log::warn!(
+                    "Session limit of {} reached for remote identity: {}",
+                    max_sessions,
+                    LogUser(&identity)
+                );
+                PamResultCode::PAM_PERM_DENIED
+            }
+            Err(e) => {
+                DefaultLogger::handle_error(e.into(), "Failed to register session");
+                PamResultCode::PAM_SESSION_ERR
+            }
+        }
+    }
+
+    fn sm_close_session(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let config = match init(pamh, &args) {
+            Ok((_, config)) => config,
+            Err(code) => return code,
+        };
+        if config.revoke_on_logout {
+            revoke_session_tokens(pamh, &config);
+        }
+        if config.max_sessions_per_user.is_none() {
+            return if config.revoke_on_logout {
+                PamResultCode::PAM_SUCCESS
+            } else {
+                PamResultCode::PAM_IGNORE
+            };
+        }
+
+        let identity = pam_try!(session_identity(pamh));
+        let store = SessionStore::new(&config.session_store, config.session_stale_timeout);
+        try_or_handle!(
+            store
+                .close(&identity, std::process::id())
+                .map_err(|err| err.into()),
+            "Failed to unregister session",
+            PamResultCode::PAM_SESSION_ERR
+        );
+        PamResultCode::PAM_SUCCESS
+    }
+}
+
+// Tells the user why the login failed. Nothing is sent for logins left to the rest of the
+// stack, when the user went away, or if the message is empty. Expired codes are reported as
+// soon as they expire.
+fn send_failure(pamh: &PamHandle, config: &Config, (code, class): &(PamResultCode, ErrorClass)) {
+    let message = match class {
+        _ if *code == PamResultCode::PAM_IGNORE => return,
+        ErrorClass::Denied
+        | ErrorClass::InsufficientAuthentication
+        | ErrorClass::SubjectMismatch => &config.messages.denied,
+        ErrorClass::Unreachable | ErrorClass::ValidationUnavailable => &config.messages.unavailable,
+        ErrorClass::RateLimited => &config.messages.rate_limited,
+        ErrorClass::LockedOut => &config.messages.locked_out,
+        ErrorClass::Config
+        | ErrorClass::DeviceCode
+        | ErrorClass::Token
+        | ErrorClass::AccessDenied => &config.messages.failed,
+        _ => return,
+    };
+    if message.is_empty() {
+        return;
+    }
+    if let Ok(Some(conv)) = pamh.get_item::<Conv>() {
+        if let Err(e) = Prompter::Conv(conv).send(PAM_ERROR_MSG, message) {
+            log::warn!("Failed to send failure message: {:?}", e);
+        }
+    }
+}
+
+// Authentication of `local_username`, recording what is learned about the attempt in `event`
+fn authenticate(
+    pamh: &mut PamHandle,
+    args: &HashMap<String, String>,
+    config: &Config,
+    local_username: &str,
+    event: &mut AuditEvent,
+) -> Result<(), (PamResultCode, ErrorClass)> {
+    if !covers_user(config, local_username) {
+        log::info!(
+            "User {} not covered by allowed_users and denied_users, ignoring",
+            LogUser(local_username)
+        );
+        return Err((PamResultCode::PAM_IGNORE, ErrorClass::ExemptUser));
+    }
+    if !config.skip_if_local_groups.is_empty() {
+        match groups::member_of_any(local_username, &config.skip_if_local_groups) {
+            Ok(true) => {
+                log::info!(
+                    "User {} is a member of a skip_if_local_groups group, ignoring",
+                    LogUser(local_username)
+                );
+                return Err((PamResultCode::PAM_IGNORE, ErrorClass::ExemptUser));
+            }
+            Ok(false) => (),
+            // The user is not exempt then, as nothing shows they are
+            Err(e) => DefaultLogger::handle_error(e.into(), "Failed to look up local groups"),
+        }
+    }
+    let (service, tty, rhost) = (
+        item::<Service>(pamh),
+        item::<Tty>(pamh),
+        item::<RHost>(pamh),
+    );
+    if let Some(index) = config
+        .bypass
+        .iter()
+        .position(|rule| rule.applies(&service, &tty, &rhost))
+    {
+        log::info!(
+            "Login of {} ({service} on {tty} from {rhost}) matches bypass rule {index}, ignoring",
+            LogUser(local_username)
+        );
+        return Err((PamResultCode::PAM_IGNORE, ErrorClass::Bypassed));
+    }
+
+    let prompter = match pamh.get_item::<Conv>() {
+        Ok(Some(conv)) => Prompter::Conv(conv),
+        Ok(None) => {
+            if let Some(account) = config.service_accounts.get(local_username) {
+                return service_account_login(pamh, config, local_username, account, event);
+            }
+            match Notifier::new(config.no_conv, &config.notify_dir, local_username) {
+                Some(notifier) => {
+                    log::info!(
+                        "No conv available, notifying user {} with the {:?} fallback",
+                        LogUser(local_username),
+                        config.no_conv
+                    );
+                    Prompter::Notify(notifier)
+                }
+                None => {
+                    log::error!("No conv available");
+                    return Err((PamResultCode::PAM_CONV_ERR, ErrorClass::Conversation));
+                }
+            }
+        }
+        Err(err) => {
+            log::error!("Couldn't get pam_conv");
+            return Err((err, ErrorClass::Conversation));
+        }
+    };
+
+    log::info!("Trying to authenticate user: {}", LogUser(local_username));
+
+    if let Some(lockout) = lockout_store(config) {
+        match lockout.locked_until(local_username) {
+            Ok(Some(until)) => {
+                log::warn!(
+                    "User {} is locked out until {}, login refused",
+                    LogUser(local_username),
+                    until
+                );
+                return Err((PamResultCode::PAM_MAXTRIES, ErrorClass::LockedOut));
+            }
+            Ok(None) => (),
+            Err(e) => DefaultLogger::handle_error(e.into(), "Failed to read lockout"),
+        }
+    }
+
+    let cache = config.cache_ttl.map(|ttl| token_cache(config, ttl));
+    let context = login_context(pamh);
+    if let Some(cache) = &cache {
+        match cache.lookup(local_username, &context) {
+            Ok(Some(entry)) if cached_login_allowed(config, local_username, &entry) => {
+                greet(&prompter, config, &entry.validated(), local_username);
+                reuse_cached(pamh, config, local_username, event, &entry);
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(e) => DefaultLogger::handle_error(e.into(), "Failed to read cached login"),
+        }
+    }
+
+    // `provider` PAM arg selects a single provider instead of trying all of them
+    let providers = try_or_handle!(
+        config
+            .provider_configs(args.get("provider").map(String::as_str))
+            .map_err(|err| err.into()),
+        "Failed to select provider",
+        Err((PamResultCode::PAM_SYSTEM_ERR, ErrorClass::Config))
+    );
+    let primary = &providers[0];
+
+    // Refresh tokens are only stored for and redeemed with the first provider
+    let refresh_store = config.refresh_token_reauth.then(|| refresh_store(config));
+    let refreshed = refresh_store
+        .as_ref()
+        .and_then(|store| silent_reauth(store, primary, &prompter, local_username, &context));
+    let (provider, oauth_client, token) = match refreshed {
+        Some((oauth_client, token)) => {
+            event.set_provider(&primary.provider_name, &primary.client_id);
+            (primary, oauth_client, token)
+        }
+        None => {
+            let mut retries = 0;
+            loop {
+                if !within_rate_limit(config, local_username, &rhost) {
+                    return Err((PamResultCode::PAM_MAXTRIES, ErrorClass::RateLimited));
+                }
+                let (provider, oauth_client, authorization) =
+                    match authorize_with_fallback(&providers, local_username) {
+                        Ok(authorization) => authorization,
+                        Err(e) => {
+                            let (code, class) = error_result(&e);
+                            DefaultLogger::handle_error(e.into(), "Failed to start authorization");
+                            return match class {
+                                ErrorClass::Unreachable => unreachable_login(
+                                    pamh,
+                                    config,
+                                    cache.as_ref(),
+                                    local_username,
+                                    event,
+                                ),
+                                ErrorClass::Token => Err((code, ErrorClass::DeviceCode)),
+                                _ => Err((code, class)),
+                            };
+                        }
+                    };
+                event.set_provider(&provider.provider_name, &provider.client_id);
+                let started = Instant::now();
+                let token = match &authorization {
+                    Authorization::Device(device_code_resp) => device_flow(
+                        &oauth_client,
+                        device_code_resp,
+                        &prompter,
+                        config,
+                        local_username,
+                    ),
+                    Authorization::Ciba(ciba) => ciba_flow(&oauth_client, ciba, &prompter, config),
+                };
+                match token {
+                    Ok(token) => {
+                        event.device_flow = Some(started.elapsed());
+                        break (provider, oauth_client, token);
+                    }
+                    // A fresh code or backchannel request is shown with a new prompt
+                    Err((_, ErrorClass::ExpiredToken)) if retries < config.max_prompt_retries => {
+                        retries += 1;
+                        log::info!(
+                            "Requesting a new device code for user: {} (retry {}/{})",
+                            LogUser(local_username),
+                            retries,
+                            config.max_prompt_retries
+                        );
+                    }
+                    Err((_, ErrorClass::Unreachable)) => {
+                        return unreachable_login(
+                            pamh,
+                            config,
+                            cache.as_ref(),
+                            local_username,
+                            event,
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    };
+    log::debug!("OAuth Client: {:#?}", oauth_client);
+    record_token(
+        event,
+        &provider.provider_name,
+        token.access_token().secret(),
+    );
+    log::debug!("Token response: {:#?}", Redacted(&token));
+
+    let validated = match oauth_client.validate(&token, local_username) {
+        Validation::Valid(validated) => validated,
+        Validation::Denied => {
+            log::warn!("Login failed for user: {}", LogUser(local_username));
+            return Err((PamResultCode::PAM_PERM_DENIED, ErrorClass::Denied));
+        }
+        Validation::InsufficientAuthentication => {
+            log::warn!(
+                "Login failed for user {}: multi-factor authentication required",
+                LogUser(local_username)
+            );
+            return Err((
+                PamResultCode::PAM_PERM_DENIED,
+                ErrorClass::InsufficientAuthentication,
+            ));
+        }
+        Validation::Unavailable(e) => {
+            let unreachable = is_unreachable(&*e);
+            DefaultLogger::handle_error(e, "Failed to validate user token");
+            if unreachable {
+                return unreachable_login(pamh, config, cache.as_ref(), local_username, event);
+            }
+            return Err((
+                PamResultCode::PAM_AUTHINFO_UNAVAIL,
+                ErrorClass::ValidationUnavailable,
+            ));
+        }
+    };
+    event.remote_user = Some(validated.username.clone());
+
+    if config.pin_subject
+        && !SubjectStore::new(&config.subject_store)
+            .validate(validated.subject.as_deref(), local_username)
+    {
+        log::warn!("Login failed for user: {}", LogUser(local_username));
+        return Err((PamResultCode::PAM_PERM_DENIED, ErrorClass::SubjectMismatch));
+    }
+
+    let refresh_store = refresh_store.filter(|_| provider.provider_name == primary.provider_name);
+    if let (Some(store), Some(refresh_token)) = (&refresh_store, token.refresh_token()) {
+        let dpop_key = oauth_client.dpop_key().map(DpopKey::pkcs8);
+        if let Err(e) = store.store_with_dpop_key(local_username, &context, refresh_token, dpop_key)
+        {
+            DefaultLogger::handle_error(e, "Failed to store refresh token");
+        }
+    }
+
+    if let Some(ttl) = config.offline_ttl {
+        match &validated.subject {
+            Some(subject) => {
+                if let Err(e) =
+                    OfflineStore::new(&config.offline_store, ttl).store(local_username, subject)
+                {
+                    DefaultLogger::handle_error(e.into(), "Failed to store offline entry");
+                }
+            }
+            None => log::warn!("No subject in token, offline login not enabled"),
+        }
+    }
+
+    if let Some(cache) = &cache {
+        if let Err(e) = cache.store(
+            local_username,
+            &context,
+            token.access_token().secret(),
+            &validated,
+            provider.require_mfa,
+        ) {
+            DefaultLogger::handle_error(e.into(), "Failed to cache login");
+        }
+    }
+
+    // A failed exchange only leaves the downstream token unset, the login stands
+    let downstream_token = provider
+        .token_exchange
+        .as_ref()
+        .and_then(|exchange| match oauth_client.exchange_token(&token) {
+            Ok(exchanged) => {
+                log::info!(
+                    "Exchanged token of user {} for audience {}",
+                    LogUser(local_username),
+                    exchange.audience
+                );
+                Some(exchanged.access_token)
+            }
+            Err(e) => {
+                DefaultLogger::handle_error(e, "Token exchange failed");
+                None
+            }
+        });
+
+    // The downstream token is meant for other services, e.g. a Kerberos gateway. Failing to
+    // acquire credentials doesn't fail the login either.
+    let ccache = provider.kerberos.as_ref().and_then(|_| {
+        let kerberos_token = downstream_token.as_ref().unwrap_or(token.access_token());
+        match oauth_client.acquire_kerberos(kerberos_token.secret(), local_username) {
+            Ok(ccache) => {
+                log::info!(
+                    "Acquired Kerberos credentials of user {} in {}",
+                    LogUser(local_username),
+                    ccache
+                );
+                Some(ccache)
+            }
+            Err(e) => {
+                DefaultLogger::handle_error(e, "Failed to acquire Kerberos credentials");
+                None
+            }
+        }
+    });
+
+    if let Some(sync) = GroupSync::new(provider) {
+        match &validated.groups {
+            // Not a member of nothing, the local groups are left as they are
+            None => log::warn!(
+                "No groups in the token of user {}, local groups not synced",
+                LogUser(local_username)
+            ),
+            Some(groups) => match sync.sync(local_username, groups) {
+                Ok(changes) if changes.is_empty() => {}
+                Ok(changes) => event.group_changes = Some(changes),
+                Err(e) => DefaultLogger::handle_error(e.into(), "Failed to sync local groups"),
+            },
+        }
+    }
+
+    greet(&prompter, provider, &validated, local_username);
+    store_identity(pamh, &validated);
+    store_token(
+        pamh,
+        &provider.provider_name,
+        Some(&token),
+        &validated,
+        downstream_token,
+        ccache,
+    );
+    share_token(pamh, &token, &validated);
+
+    log::info!(
+        "Authentication successful for remote user: {} -> local user: {} (provider {})",
+        LogUser(&validated.username),
+        LogUser(local_username),
+        provider.provider_name
+    );
+    Ok(())
+}
+
+// Logs and audits the fingerprint of the issued access token, never the token itself
+fn record_token(event: &mut AuditEvent, provider_name: &str, access_token: &str) {
+    let fingerprint = token_fingerprint(access_token);
+    log::info!("Token issued by provider {provider_name} (fingerprint {fingerprint})");
+    event.token_fingerprint = Some(fingerprint);
+}
+
+// Login reused from the cache instead of going through the device flow, after greeting the user
+fn reuse_cached(
+    pamh: &mut PamHandle,
+    config: &Config,
+    local_username: &str,
+    event: &mut AuditEvent,
+    entry: &CacheEntry,
+) {
+    let validated = entry.validated();
+    event.remote_user = Some(validated.username.clone());
+    event.cached = true;
+    store_identity(pamh, &validated);
+    store_token(pamh, &config.provider_name, None, &validated, None, None);
+    log::info!(
+        "Authentication successful for remote user: {} -> local user: {} (cached until {})",
+        LogUser(&validated.username),
+        LogUser(local_username),
+        entry.expires_at
+    );
+}
+
+// The local policy may have changed since the login was cached, e.g. the user map or the
+// allowed groups, so it's checked again before the login is reused. Otherwise the device
+// flow decides.
+fn cached_login_allowed(config: &Config, local_username: &str, entry: &CacheEntry) -> bool {
+    let allowed =
+        UserMatch::new(config).allows(&entry.username, entry.subject.as_deref(), local_username)
+            && (config.allowed_groups.is_empty()
+                || entry.groups.as_ref().is_some_and(|groups| {
+                    valid_groups(&config.allowed_groups, groups, local_username)
+                }))
+            && (!config.require_mfa || entry.multi_factor)
+            && (!config.pin_subject
+                || SubjectStore::new(&config.subject_store)
+                    .validate(entry.subject.as_deref(), local_username));
+    if !allowed {
+        log::info!(
+            "Cached login of user {} no longer allowed, not reused",
+            LogUser(local_username)
+        );
+    }
+    allowed
+}
+
+fn lockout_store(config: &Config) -> Option<LockoutStore> {
+    config.lockout_threshold.map(|threshold| {
+        LockoutStore::new(&config.lockout_store, threshold, config.lockout_duration)
+    })
+}
+
+// Counts the logins the user was denied, see `lockout_threshold`. A successful login starts
+// the count over, other failures (e.g. an unreachable server) don't count.
+fn update_lockout(
+    config: &Config,
+    local_username: &str,
+    result: &Result<(), (PamResultCode, ErrorClass)>,
+) {
+    let Some(lockout) = lockout_store(config) else {
+        return;
+    };
+    let res = match result {
+        Ok(()) => lockout.reset(local_username),
+        Err((
+            _,
+            ErrorClass::AccessDenied
+            | ErrorClass::Denied
+            | ErrorClass::InsufficientAuthentication
+            | ErrorClass::SubjectMismatch,
+        )) => lockout.record_failure(local_username).map(|locked_until| {
+            if let Some(until) = locked_until {
+                log::warn!(
+                    "User {} locked out until {} after too many denied logins",
+                    LogUser(local_username),
+                    until
+                );
+            }
+        }),
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = res {
+        DefaultLogger::handle_error(e.into(), "Failed to update lockout");
+    }
+}
+
+// Whether the local user and the remote host may start another device flow, see
+// `rate_limit_per_minute`. Logins are not held up by a failing limiter.
+fn within_rate_limit(config: &Config, local_username: &str, rhost: &str) -> bool {
+    let Some(max) = config.rate_limit_per_minute else {
+        return true;
+    };
+    let user_key = format!("user:{local_username}");
+    let rhost_key = format!("rhost:{rhost}");
+    let mut keys = vec![user_key.as_str()];
+    if !rhost.is_empty() {
+        keys.push(&rhost_key);
+    }
+    let limiter = RateLimiter::new(
+        &config.cache_dir.join("rate_limit"),
+        max,
+        Duration::from_secs(60),
+    );
+    match limiter.allow(&keys) {
+        Ok(true) => true,
+        Ok(false) => {
+            log::warn!(
+                "Too many device flows started by user {} or from {}, login refused",
+                LogUser(local_username),
+                rhost
+            );
+            false
+        }
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to update rate limit");
+            true
+        }
+    }
+}
+
+// Login for which the Authorization Server couldn't be reached: an offline login if the user
+// logged in online recently, otherwise as set by `on_unreachable`
+fn unreachable_login(
+    pamh: &mut PamHandle,
+    config: &Config,
+    cache: Option<&TokenCache>,
+    local_username: &str,
+    event: &mut AuditEvent,
+) -> Result<(), (PamResultCode, ErrorClass)> {
+    if let Some(subject) = offline_login(config, local_username) {
+        event.offline = true;
+        set_identity(pamh, subject);
+        return Ok(());
+    }
+    let entry = unreachable_fallback(config, cache, local_username, &login_context(pamh))?;
+    if let Ok(Some(conv)) = pamh.get_item::<Conv>() {
+        greet(
+            &Prompter::Conv(conv),
+            config,
+            &entry.validated(),
+            local_username,
+        );
+    }
+    reuse_cached(pamh, config, local_username, event, &entry);
+    Ok(())
+}
+
+// Break-glass login of a user with an unexpired offline entry, see `offline_ttl`. The entry
+// must match the subject pinned for the user, which becomes the identity of the session.
+fn offline_login(config: &Config, local_username: &str) -> Option<String> {
+    let ttl = config.offline_ttl?;
+    // Without a pinned subject, the mere existence of an entry would let the user in
+    if !config.pin_subject {
+        log::warn!(
+            "offline_ttl requires pin_subject, offline login of user {} denied",
+            LogUser(local_username)
+        );
+        return None;
+    }
+    // A reset pinned subject revokes the offline entry along with it
+    let pinned = match SubjectStore::new(&config.subject_store).pinned(local_username) {
+        Ok(Some(pinned)) => pinned,
+        Ok(None) => {
+            log::warn!(
+                "No pinned subject for user {}, offline login denied",
+                LogUser(local_username)
+            );
+            return None;
+        }
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to read pinned subject");
+            return None;
+        }
+    };
+    match OfflineStore::new(&config.offline_store, ttl).lookup(local_username, &pinned) {
+        Ok(Some(entry)) => {
+            log::warn!(
+                "OFFLINE login of user {} while the Authorization Server is unreachable (online login at {})",
+                LogUser(local_username),
+                entry.stored_at
+            );
+            Some(pinned)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to read offline entry");
+            None
+        }
+    }
+}
+
+// Cached login of the same context to fall back to when the Authorization Server couldn't be
+// reached, or the outcome of the login without one, see `on_unreachable`
+fn unreachable_fallback(
+    config: &Config,
+    cache: Option<&TokenCache>,
+    local_username: &str,
+    context: &str,
+) -> Result<CacheEntry, (PamResultCode, ErrorClass)> {
+    match config.on_unreachable {
+        OnUnreachable::Deny => Err((PamResultCode::PAM_AUTHINFO_UNAVAIL, ErrorClass::Unreachable)),
+        OnUnreachable::Ignore => {
+            log::warn!(
+                "Authorization Server unreachable, ignoring login of user: {}",
+                LogUser(local_username)
+            );
+            Err((PamResultCode::PAM_IGNORE, ErrorClass::Unreachable))
+        }
+        OnUnreachable::Cached => {
+            let Some(cache) = cache else {
+                log::warn!("on_unreachable is cached, but no cache_ttl is configured");
+                return Err((PamResultCode::PAM_AUTHINFO_UNAVAIL, ErrorClass::Unreachable));
+            };
+            match cache.lookup(local_username, context) {
+                Ok(Some(entry)) if cached_login_allowed(config, local_username, &entry) => {
+                    log::warn!(
+                        "Authorization Server unreachable, falling back to the cached login of user: {}",
+                        LogUser(local_username)
+                    );
+                    Ok(entry)
+                }
+                Ok(_) => {
+                    log::warn!(
+                        "Authorization Server unreachable and no cached login of user: {}",
+                        LogUser(local_username)
+                    );
+                    Err((PamResultCode::PAM_AUTHINFO_UNAVAIL, ErrorClass::Unreachable))
+                }
+                Err(e) => {
+                    DefaultLogger::handle_error(e.into(), "Failed to read cached login");
+                    Err((PamResultCode::PAM_AUTHINFO_UNAVAIL, ErrorClass::Unreachable))
+                }
+            }
+        }
+    }
+}
+
+// Whether the module authenticates `local_username`, other users are left to the rest of the stack
+fn covers_user(config: &Config, local_username: &str) -> bool {
+    (config.allowed_users.is_empty() || glob::matches_any(&config.allowed_users, local_username))
+        && !glob::matches_any(&config.denied_users, local_username)
+}
+
+fn device_flow(
+    oauth_client: &OAuthClient,
+    device_code_resp: &StandardDeviceAuthorizationResponse,
+    prompter: &Prompter,
+    config: &Config,
+    local_username: &str,
+) -> Result<DeviceTokenResponse, (PamResultCode, ErrorClass)> {
+    log::debug!("Device Code response: {:#?}", Redacted(device_code_resp));
+
+    // The second factor prompt is a single line without the QR code, unless customized
+    let mut messages = config.messages.clone();
+    let short_prompt = config.mode == AuthMode::Mfa && messages.prompt_template.is_none();
+    if short_prompt {
+        messages.prompt_template = Some(messages.prompt_mfa.clone());
+    }
+    let mut user_prompt = UserPrompt::new(device_code_resp, &messages);
+    user_prompt.set_username(local_username);
+    if !config.prefer_verification_uri_complete {
+        user_prompt.ignore_verification_uri_complete();
+    }
+    // Before the QR code, which gets smaller along with the link
+    if let Some(shortener) = UrlShortener::new(config)
+        .filter(|shortener| shortener.applies(user_prompt.verification_uri()))
+    {
+        match shortener.shorten(user_prompt.verification_uri()) {
+            Ok(short_url) => {
+                log::debug!("Shortened verification URI");
+                user_prompt.replace_verification_uri(short_url);
+            }
+            Err(e) => DefaultLogger::handle_error(e, "Failed to shorten verification URI"),
+        }
+    }
+    if config.qr_enabled && !short_prompt {
+        log::debug!("Generating QR code...");
+        user_prompt.generate_qr(&config.qr);
+    }
+    // Notifications may end up in files
+    if config.prompt_colors && matches!(prompter, Prompter::Conv(_)) {
+        user_prompt.enable_styling();
+    }
+    log::debug!("User prompt: {:#?}", user_prompt);
+
+    send_prompt(prompter, config, &user_prompt).map_err(|code| (code, ErrorClass::Conversation))?;
+
+    // Nobody can press Enter without a conversation or an input prompt
+    let prompt_mode = match prompter {
+        Prompter::Conv(_) if config.prompt_echo.expects_input() => config.prompt_mode,
+        _ => PromptMode::Poll,
+    };
+    let token = match prompt_mode {
+        PromptMode::Poll => {
+            // Updates are only sent in a conversation, notifications would pile up
+            let every = config
+                .progress_interval
+                .filter(|_| matches!(prompter, Prompter::Conv(_)));
+            let last = Cell::new(Instant::now());
+            // The token holding the display name claim is what is waited for
+            let display_name = groups::full_name(local_username)
+                .unwrap_or_else(|e| {
+                    log::debug!("No full name of {}: {e}", LogUser(local_username));
+                    None
+                })
+                .unwrap_or_else(|| local_username.to_string());
+            let progress = |remaining: Duration| {
+                if every.is_none_or(|every| last.get().elapsed() < every) {
+                    return;
+                }
+                last.set(Instant::now());
+                let message =
+                    waiting_message(&config.messages, remaining, local_username, &display_name);
+                if let Err(e) = prompter.send(PAM_TEXT_INFO, &message) {
+                    log::warn!("Failed to send waiting message: {:?}", e);
+                }
+            };
+            oauth_client.get_token_with_progress(
+                device_code_resp,
+                config.oauth_device_token_polling_timeout,
+                &progress,
+            )
+        }
+        // The conversation fails once the client went away, e.g. after Ctrl-C
+        PromptMode::Enter => poll_after_enter(oauth_client, device_code_resp, prompter, config)
+            .map_err(|code| {
+                log::warn!("Conversation failed while polling: {:?}", code);
+                (PamResultCode::PAM_ABORT, ErrorClass::Aborted)
+            })?,
+    };
+    token_result(token, prompter, config)
+}
+
+// Backchannel authentication: the user approves the login on their authenticator while the
+// token endpoint is polled, there is nothing to answer in the prompt
+fn ciba_flow(
+    oauth_client: &OAuthClient,
+    authorization: &CibaAuthorization,
+    prompter: &Prompter,
+    config: &Config,
+) -> Result<DeviceTokenResponse, (PamResultCode, ErrorClass)> {
+    let prompt = config.messages.prompt_ciba.replace(
+        "{binding_message}",
+        authorization.binding_message.as_deref().unwrap_or_default(),
+    );
+    prompter
+        .send(PAM_TEXT_INFO, &prompt)
+        .map_err(|code| (code, ErrorClass::Conversation))?;
+    let token = oauth_client.ciba_token(authorization, config.oauth_device_token_polling_timeout);
+    token_result(token, prompter, config)
+}
+
+fn token_result(
+    token: Result<DeviceTokenResponse, OAuthDeviceError>,
+    prompter: &Prompter,
+    config: &Config,
+) -> Result<DeviceTokenResponse, (PamResultCode, ErrorClass)> {
+    let e = match token {
+        Ok(token) => return Ok(token),
+        Err(e) => e,
+    };
+    let failure = error_result(&e);
+    match failure.1 {
+        ErrorClass::Aborted => {
+            DefaultLogger::handle_error(e.into(), "Login interrupted before the user authorized it")
+        }
+        ErrorClass::ExpiredToken => {
+            DefaultLogger::handle_error(
+                e.into(),
+                "Device code expired before the user authorized it",
+            );
+            if let Err(e) = prompter.send(PAM_ERROR_MSG, &config.messages.expired) {
+                log::warn!("Failed to send expired code message: {:?}", e);
+            }
+        }
+        _ => DefaultLogger::handle_error(e.into(), "Failed to recive user token"),
+    }
+    Err(failure)
+}
+
+// PAM result and audit class of a failed login. Only failures of the user to authenticate are
+// `PAM_AUTH_ERR`, so stacks can e.g. skip the module with `[authinfo_unavail=ignore]`.
+fn error_result(e: &OAuthDeviceError) -> (PamResultCode, ErrorClass) {
+    match e {
+        OAuthDeviceError::Interrupted => (PamResultCode::PAM_ABORT, ErrorClass::Aborted),
+        OAuthDeviceError::Network(_) => {
+            (PamResultCode::PAM_AUTHINFO_UNAVAIL, ErrorClass::Unreachable)
+        }
+        OAuthDeviceError::Config(_) => (PamResultCode::PAM_SYSTEM_ERR, ErrorClass::Config),
+        OAuthDeviceError::Denied => (PamResultCode::PAM_PERM_DENIED, ErrorClass::Denied),
+        OAuthDeviceError::InsufficientAuthentication => (
+            PamResultCode::PAM_PERM_DENIED,
+            ErrorClass::InsufficientAuthentication,
+        ),
+        OAuthDeviceError::Validation(_) => (
+            PamResultCode::PAM_AUTHINFO_UNAVAIL,
+            ErrorClass::ValidationUnavailable,
+        ),
+        e if e.is_expired_token() => (PamResultCode::PAM_CRED_EXPIRED, ErrorClass::ExpiredToken),
+        e if e.protocol_error() == Some(&ProtocolError::AccessDenied) => {
+            (PamResultCode::PAM_AUTH_ERR, ErrorClass::AccessDenied)
+        }
+        _ => (PamResultCode::PAM_AUTH_ERR, ErrorClass::Token),
+    }
+}
+
+// Renders the user prompt, either as one message or split into info messages and the input
+// prompt with `split_prompt`
+fn send_prompt(
+    prompter: &Prompter,
+    config: &Config,
+    user_prompt: &UserPrompt,
+) -> Result<(), PamResultCode> {
+    if let Prompter::Notify(_) = prompter {
+        return prompter.send(PAM_TEXT_INFO, &user_prompt.notification());
+    }
+    let style = input_style(config.prompt_echo);
+    if !config.split_prompt {
+        return prompter.send(style, &user_prompt.to_string());
+    }
+    let (info, prompt) = user_prompt.split();
+    for message in info {
+        prompter.send(PAM_TEXT_INFO, &message)?;
+    }
+    prompter.send(style, &prompt)
+}
+
+fn input_style(echo: PromptEcho) -> PamMessageStyle {
+    match echo {
+        PromptEcho::Off => PAM_PROMPT_ECHO_OFF,
+        PromptEcho::On => PAM_PROMPT_ECHO_ON,
+        PromptEcho::Info => PAM_TEXT_INFO,
+    }
+}
+
+// Polls the token endpoint `enter_polls` times every time the user pressed Enter, until the
+// device is authorized or the code expired. The outer error is a failed conversation.
+fn poll_after_enter(
+    oauth_client: &OAuthClient,
+    device_code_resp: &StandardDeviceAuthorizationResponse,
+    prompter: &Prompter,
+    config: &Config,
+) -> Result<Result<DeviceTokenResponse, OAuthDeviceError>, PamResultCode> {
+    let expires_in = device_code_resp.expires_in();
+    let timeout = config
+        .oauth_device_token_polling_timeout
+        .map_or(expires_in, |t| t.min(expires_in));
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match oauth_client.poll_token(
+            device_code_resp,
+            Some(remaining),
+            Some(config.enter_polls.max(1)),
+        ) {
+            Ok(Some(token)) => return Ok(Ok(token)),
+            Ok(None) => {
+                log::debug!("Device not authorized yet, waiting for the user to press Enter");
+                prompter.send(
+                    input_style(config.prompt_echo),
+                    &config.messages.not_authorized,
+                )?;
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+}
+
+// Creates the local account of a user authenticated by this module who doesn't have one yet.
+// Users authenticated by other modules are left to the rest of the stack.
+fn provision_account(pamh: &mut PamHandle, provisioner: &Provisioner) -> Result<(), PamResultCode> {
+    let local_username = pamh.get_user(None)?;
+    match groups::user_ids(&local_username) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to look up local user");
+            return Err(PamResultCode::PAM_SYSTEM_ERR);
+        }
+    }
+    let Ok(token) = (unsafe { pamh.get_data::<TokenData>(TOKEN_DATA_KEY) }) else {
+        log::debug!(
+            "User {} not authenticated by this module, not provisioning",
+            LogUser(&local_username)
+        );
+        return Ok(());
+    };
+
+    let account = provisioner
+        .plan(
+            &local_username,
+            &token.display_name,
+            token.email.as_deref(),
+            token.uid,
+        )
+        .and_then(|account| {
+            provisioner.create(&local_username, &account)?;
+            Ok(account)
+        })
+        .map_err(|e| {
+            DefaultLogger::handle_error(e.into(), "Failed to provision local user");
+            PamResultCode::PAM_SYSTEM_ERR
+        })?;
+    log::info!(
+        "Created local user {} with uid {}",
+        LogUser(&local_username),
+        account.uid
+    );
+    Ok(())
+}
+
+// Home directory of the session user from `skel_dir`, if they don't have one yet
+fn create_home(pamh: &mut PamHandle, config: &Config) -> Result<(), PamResultCode> {
+    let local_username = pamh.get_user(None)?;
+    match create_homedir(&local_username, &config.skel_dir, config.homedir_mode) {
+        Ok(true) => log::info!(
+            "Created home directory of user {}",
+            LogUser(&local_username)
+        ),
+        Ok(false) => {}
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to create home directory");
+            return Err(PamResultCode::PAM_SESSION_ERR);
+        }
+    }
+    Ok(())
+}
+
+fn token_cache(config: &Config, ttl: Duration) -> TokenCache {
+    let cache = TokenCache::new(&config.cache_dir, ttl);
+    match (&config.helper_socket, &config.cache_key) {
+        (Some(socket), _) => cache.via_helper(socket),
+        (None, Some(key_file)) => cache.encrypted(key_file),
+        (None, None) => cache,
+    }
+}
+
+fn refresh_store(config: &Config) -> RefreshStore {
+    let store = RefreshStore::new(&config.refresh_token_store, &config.refresh_token_key);
+    match &config.helper_socket {
+        Some(socket) => store.via_helper(socket),
+        None => store,
+    }
+}
+
+// Refresh token grant with the refresh token stored by an earlier login in the same context,
+// once the user confirmed it in the conversation. Services without one never redeem it.
+// A refresh token that stopped working is dropped, so the device flow is used instead.
+fn silent_reauth(
+    store: &RefreshStore,
+    provider: &Config,
+    prompter: &Prompter,
+    local_username: &str,
+    context: &str,
+) -> Option<(OAuthClient, DeviceTokenResponse)> {
+    let Prompter::Conv(conv) = prompter else {
+        return None;
+    };
+    let (refresh_token, dpop_key) = match store.load_for_context(local_username, context) {
+        Ok(stored) => stored?,
+        Err(e) => {
+            DefaultLogger::handle_error(e, "Failed to load refresh token");
+            return None;
+        }
+    };
+    match conv.send(PAM_PROMPT_ECHO_ON, &provider.messages.reauth_confirm) {
+        Ok(answer) => {
+            let answer = answer.map(|answer| {
+                String::from_utf8_lossy(answer.as_bytes())
+                    .trim()
+                    .to_lowercase()
+            });
+            if !matches!(answer.as_deref(), None | Some("" | "y" | "yes")) {
+                log::info!(
+                    "User {} declined the silent re-authentication",
+                    LogUser(local_username)
+                );
+                return None;
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to confirm silent re-authentication: {:?}", e);
+            return None;
+        }
+    }
+    let mut oauth_client = match OAuthClient::new(provider) {
+        Ok(oauth_client) => oauth_client,
+        Err(e) => {
+            DefaultLogger::handle_error(e.into(), "Failed to build OAuth client");
+            return None;
+        }
+    };
+    // A DPoP-bound refresh token only works with the key it was issued to
+    if let Some(dpop_key) = dpop_key.filter(|_| provider.dpop) {
+        match DpopKey::from_pkcs8(&dpop_key) {
+            Ok(dpop_key) => oauth_client.set_dpop_key(dpop_key),
+            Err(e) => DefaultLogger::handle_error(e, "Failed to load stored DPoP key"),
+        }
+    }
+    match oauth_client.refresh_token(&refresh_token) {
+        Ok(token) => {
+            log::info!(
+                "Refreshed token of user: {}, skipping device flow",
+                LogUser(local_username)
+            );
+            Some((oauth_client, token))
+        }
+        Err(e) => {
+            DefaultLogger::handle_error(
+                e,
+                "Refresh token grant failed, falling back to device flow",
+            );
+            if let Err(e) = store.remove(local_username) {
+                log::warn!("Failed to remove refresh token: {}", e);
+            }
+            None
+        }
+    }
+}
+
+// Where the messages of a login go: the conversation, or the `no_conv` fallback of services
+// without one
+enum Prompter<'a> {
+    Conv(Conv<'a>),
+    Notify(Notifier),
+}
+
+impl Prompter<'_> {
+    fn send(&self, style: PamMessageStyle, message: &str) -> Result<(), PamResultCode> {
+        match self {
+            Prompter::Conv(conv) => conv.send(style, message).map(|_| ()),
+            Prompter::Notify(notifier) => notifier.send(message).map_err(|e| {
+                DefaultLogger::handle_error(e, "Failed to notify user");
+                PamResultCode::PAM_CONV_ERR
+            }),
+        }
+    }
+}
+
+// `{issuer}` is the one of `provider`, or its name without an issuer. Cached logins don't
+// remember their provider, they are greeted with the first one.
+fn greet(prompter: &Prompter, provider: &Config, validated: &ValidatedToken, local_username: &str) {
+    let issuer = provider
+        .issuer
+        .as_ref()
+        .map_or(provider.provider_name.as_str(), |issuer| issuer.as_str());
+    let greeting = success_message(&provider.messages, validated, local_username, issuer);
+    if !greeting.is_empty() {
+        if let Err(e) = prompter.send(PAM_TEXT_INFO, &greeting) {
+            log::warn!("Failed to send success message: {:?}", e);
+        }
+    }
+}
+
+// Login of a configured service account without a conversation, e.g. a cron job, with a
+// client credentials grant of the account's own client
+fn service_account_login(
+    pamh: &mut PamHandle,
+    config: &Config,
+    local_username: &str,
+    account: &ServiceAccount,
+    event: &mut AuditEvent,
+) -> Result<(), (PamResultCode, ErrorClass)> {
+    log::info!(
+        "Trying to authenticate service account: {}",
+        LogUser(local_username)
+    );
+    let provider = try_or_handle!(
+        config
+            .service_account_config(account)
+            .map_err(|err| err.into()),
+        "Failed to configure service account",
+        Err((PamResultCode::PAM_SYSTEM_ERR, ErrorClass::Config))
+    );
+    event.set_provider(&provider.provider_name, &provider.client_id);
+    let oauth_client = try_or_handle!(
+        OAuthClient::new(&provider).map_err(|err| err.into()),
+        "Failed to build OAuth client",
+        Err((PamResultCode::PAM_SYSTEM_ERR, ErrorClass::Config))
+    );
+    let token = match oauth_client.client_credentials() {
+        Ok(token) => token,
+        Err(e) => {
+            let e = OAuthDeviceError::classify(e);
+            let failure = error_result(&e);
+            DefaultLogger::handle_error(e.into(), "Client credentials grant failed");
+            return Err(failure);
+        }
+    };
+    record_token(
+        event,
+        &provider.provider_name,
+        token.access_token().secret(),
+    );
+    log::debug!("Token response: {:#?}", Redacted(&token));
+
+    let validated =
+        match oauth_client.validate_service_account(&token, &account.client_id, local_username) {
+            Validation::Valid(validated) => validated,
+            Validation::Denied | Validation::InsufficientAuthentication => {
+                log::warn!(
+                    "Login failed for service account: {}",
+                    LogUser(local_username)
+                );
+                return Err((PamResultCode::PAM_PERM_DENIED, ErrorClass::Denied));
+            }
+            Validation::Unavailable(e) => {
+                DefaultLogger::handle_error(e, "Failed to validate service account token");
+                return Err((
+                    PamResultCode::PAM_AUTHINFO_UNAVAIL,
+                    ErrorClass::ValidationUnavailable,
+                ));
+            }
+        };
+    event.remote_user = Some(validated.username.clone());
+    store_identity(pamh, &validated);
+    store_token(
+        pamh,
+        &provider.provider_name,
+        Some(&token),
+        &validated,
+        None,
+        None,
+    );
+
+    log::info!(
+        "Authentication successful for service account: {} (client {}, provider {})",
+        LogUser(local_username),
+        account.client_id,
+        provider.provider_name
+    );
+    Ok(())
+}
+
+// Sessions are keyed by the stable `sub`, falling back to the remote username
+fn store_identity(pamh: &mut PamHandle, validated: &ValidatedToken) {
+    let identity = validated
+        .subject
+        .clone()
+        .unwrap_or_else(|| validated.username.clone());
+    set_identity(pamh, identity);
+}
+
+fn set_identity(pamh: &mut PamHandle, identity: String) {
+    if let Err(e) = pamh.set_data(IDENTITY_DATA_KEY, Box::new(identity)) {
+        log::warn!("Failed to store identity in PAM data: {:?}", e);
+    }
+}
+
+fn store_token(
+    pamh: &mut PamHandle,
+    provider: &str,
+    token: Option<&DeviceTokenResponse>,
+    validated: &ValidatedToken,
+    downstream_token: Option<AccessToken>,
+    ccache: Option<String>,
+) {
+    let token_data = TokenData {
+        provider: provider.to_string(),
+        access_token: token.map(|t| t.access_token().clone()),
+        refresh_token: token.and_then(|t| t.refresh_token().cloned()),
+        id_token: token.and_then(|t| t.extra_fields().id_token.clone()),
+        expires_at: validated.expires_at,
+        downstream_token,
+        ccache,
+        display_name: validated.display_name.clone(),
+        email: validated.email.clone(),
+        uid: validated.uid,
+    };
+    if let Err(e) = pamh.set_data(TOKEN_DATA_KEY, Box::new(token_data)) {
+        log::warn!("Failed to store token in PAM data: {:?}", e);
+    }
+}
+
+fn share_token(pamh: &mut PamHandle, token: &DeviceTokenResponse, validated: &ValidatedToken) {
+    let shared = match SharedToken::new(token, validated).to_c_string() {
+        Ok(shared) => shared,
+        Err(e) => return DefaultLogger::handle_error(e, "Failed to serialize shared token"),
+    };
+    if let Err(e) = set_c_string_data(pamh, TOKEN_RESPONSE_DATA_KEY, shared) {
+        log::warn!("Failed to share token in PAM data: {:?}", e);
+    }
+}
+
+// The ccache of the `kerberos` credentials is exported even without `export_env`, the
+// credentials are of no use to the session otherwise
+fn exports_env(config: &Config) -> bool {
+    config.export_env || config.kerberos.is_some()
+}
+
+// Exports the tokens obtained by `sm_authenticate` to the PAM environment
+fn export_token_env(pamh: &mut PamHandle, config: &Config) -> Result<(), PamResultCode> {
+    let token = match unsafe { pamh.get_data::<TokenData>(TOKEN_DATA_KEY) } {
+        Ok(token) => token,
+        Err(_) => {
+            log::debug!("No token in PAM data, nothing to export");
+            return Ok(());
+        }
+    };
+    let names = &config.env_names;
+    let mut vars = vec![(&names.ccache, token.ccache.clone())];
+    if config.export_env {
+        vars.extend([
+            (
+                &names.access_token,
+                token.access_token.as_ref().map(|t| t.secret().clone()),
+            ),
+            (&names.id_token, token.id_token.clone()),
+            (
+                &names.expires_at,
+                token.expires_at.map(|exp| exp.timestamp().to_string()),
+            ),
+            (
+                &names.downstream_token,
+                token.downstream_token.as_ref().map(|t| t.secret().clone()),
+            ),
+        ]);
+    }
+    for (name, value) in vars {
+        match value {
+            Some(value) if !name.is_empty() => {
+                log::debug!("Exporting {} to the PAM environment", name);
+                put_env(pamh, name, &value)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn unset_token_env(pamh: &mut PamHandle, config: &Config) -> Result<(), PamResultCode> {
+    let names = &config.env_names;
+    for name in [
+        &names.access_token,
+        &names.id_token,
+        &names.expires_at,
+        &names.downstream_token,
+        &names.ccache,
+    ] {
+        if !name.is_empty() {
+            unset_env(pamh, name)?;
+        }
+    }
+    Ok(())
+}
+
+// Service, tty, remote user and remote host of the current login
+fn login_context(pamh: &PamHandle) -> String {
+    [
+        item::<Service>(pamh),
+        item::<Tty>(pamh),
+        item::<RUser>(pamh),
+        item::<RHost>(pamh),
+    ]
+    .join("|")
+}
+
+fn item<'a, T>(pamh: &'a PamHandle) -> String
+where
+    T: Item<'a> + std::ops::Deref<Target = &'a CStr>,
+{
+    match pamh.get_item::<T>() {
+        Ok(Some(item)) => item.to_string_lossy().into_owned(),
+        _ => String::new(),
+    }
+}
+
+// Revokes the tokens of the login, failures are logged but never fail the logout.
+// The tokens are taken from the PAM data, or when the session hooks run in another process
+// than the authentication, the refresh token stored for silent re-authentication is used.
+fn revoke_session_tokens(pamh: &mut PamHandle, config: &Config) {
+    let local_username = match pamh.get_user(None) {
+        Ok(local_username) => local_username,
+        Err(e) => return log::warn!("Failed to get local username: {:?}", e),
+    };
+    let (provider, mut tokens) = match unsafe { pamh.get_data::<TokenData>(TOKEN_DATA_KEY) } {
+        Ok(token) => {
+            let mut tokens = Vec::new();
+            // The refresh token first, servers revoke the access tokens issued with it as well
+            if let Some(refresh_token) = &token.refresh_token {
+                tokens.push(StandardRevocableToken::RefreshToken(refresh_token.clone()));
+            }
+            if let Some(access_token) = &token.access_token {
+                tokens.push(StandardRevocableToken::AccessToken(access_token.clone()));
+            }
+            (token.provider.clone(), tokens)
+        }
+        Err(_) => (config.provider_name.clone(), Vec::new()),
+    };
+
+    // Refresh tokens are only stored for the first provider, see `silent_reauth`
+    if config.refresh_token_reauth && provider == config.provider_name {
+        let store = refresh_store(config);
+        match store.load(&local_username) {
+            Ok(Some(refresh_token)) if tokens.is_empty() => {
+                tokens.push(StandardRevocableToken::RefreshToken(refresh_token))
+            }
+            Ok(_) => (),
+            Err(e) => DefaultLogger::handle_error(e, "Failed to load refresh token"),
+        }
+        if let Err(e) = store.remove(&local_username) {
+            log::warn!("Failed to remove refresh token: {}", e);
+        }
+    }
+    if tokens.is_empty() {
+        log::debug!("No tokens of user {} to revoke", LogUser(&local_username));
+        return;
+    }
+
+    let oauth_client = match config
+        .provider_config(&provider)
+        .map_err(|e| e.into())
+        .and_then(|provider| OAuthClient::new(&provider).map_err(|e| e.into()))
+    {
+        Ok(oauth_client) => oauth_client,
+        Err(e) => return DefaultLogger::handle_error(e, "Failed to build OAuth client"),
+    };
+    let mut revoked = 0;
+    for token in tokens {
+        match oauth_client.revoke(token) {
+            Ok(()) => revoked += 1,
+            Err(e) => DefaultLogger::handle_error(e, "Failed to revoke token"),
+        }
+    }
+    if revoked > 0 {
+        log::info!(
+            "Revoked {} token(s) of user: {}",
+            revoked,
+            LogUser(&local_username)
+        );
+    }
+}
+
+// Remote identity stored by `sm_authenticate`. When the session hooks run in another
+// process than the authentication (e.g. privilege separated sshd), the local username is used.
+fn session_identity(pamh: &mut PamHandle) -> Result<String, PamResultCode> {
+    match unsafe { pamh.get_data::<String>(IDENTITY_DATA_KEY) } {
+        Ok(identity) => Ok(identity.clone()),
+        Err(_) => {
+            log::debug!("No identity in PAM data, using local username");
+            pamh.get_user(None)
+        }
+    }
+}
+
+// Initializes the logger and reads the config file, shared by all hooks. The overrides of the
+// PAM service apply on top of the config file, the PAM args on top of both.
+fn init(
+    pamh: &PamHandle,
+    args: &[&CStr],
+) -> Result<(HashMap<String, String>, Config), PamResultCode> {
+    let args = parse_args(args);
+    let default_log_path = "/tmp/pam_oauth2_device.log".to_string();
+    let default_log_level = "info".to_string();
+    let log_path = args.get("logs").unwrap_or(&default_log_path);
+    let log_level = args.get("log_level").unwrap_or(&default_log_level);
+    let mut rotation = Rotation::default();
+    if let Some(max_bytes) = args.get("log_max_bytes").and_then(|v| v.parse().ok()) {
+        rotation.max_bytes = max_bytes;
+    }
+    // `log_max_files=0` would leave no room for the rotated log file, so it is rejected
+    let max_files = args
+        .get("log_max_files")
+        .map(|v| v.parse().ok().filter(|&n| n > 0));
+    if let Some(Some(max_files)) = max_files {
+        rotation.max_files = max_files;
+    }
+    DefaultLogger::init(log_path, log_level, rotation);
+    if let Some(None) = max_files {
+        log::warn!(
+            "Invalid log_max_files, keeping {} rotated log files",
+            rotation.max_files
+        );
+    }
+    DefaultLogger::set_context(LogContext {
+        service: item::<Service>(pamh),
+        rhost: item::<RHost>(pamh),
+        tty: item::<Tty>(pamh),
+        ..Default::default()
+    });
+
+    let default_config_path = "/etc/pam_oauth2_device/config.json".to_string();
+    let config_path = args.get("config").unwrap_or(&default_config_path);
+    let reload = match args.get("reload").map(|reload| reload.parse()) {
+        Some(Ok(reload)) => reload,
+        Some(Err(_)) => {
+            log::warn!("Ignoring invalid reload argument: {}", args["reload"]);
+            Reload::default()
+        }
+        None => Reload::default(),
+    };
+    let config = try_or_handle!(
+        cached_config(config_path, reload).map_err(|err| err.into()),
+        "Failed to parse config file",
+        Err(PamResultCode::PAM_SYSTEM_ERR)
+    );
+    let mut config = try_or_handle!(
+        config
+            .for_service(&item::<Service>(pamh))
+            .map_err(|err| err.into()),
+        "Failed to apply service overrides",
+        Err(PamResultCode::PAM_SYSTEM_ERR)
+    );
+    if let Some(locale) = locale(pamh) {
+        config = try_or_handle!(
+            config.for_locale(&locale).map_err(|err| err.into()),
+            "Failed to apply translation",
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+    }
+    config.apply_args(&args);
+    if config.prompt_colors
+        && !supports_ansi(
+            login_var(pamh, "TERM").as_deref(),
+            login_var(pamh, "NO_COLOR").is_some(),
+        )
+    {
+        log::debug!("Terminal doesn't support ANSI escapes, prompt not styled");
+        config.prompt_colors = false;
+    }
+    if config.qr.mode.is_image() && !supports_images(login_var(pamh, "TERM").as_deref()) {
+        log::debug!("Terminal doesn't display images, drawing the QR code with unicode blocks");
+        config.qr.mode = QrMode::Unicode;
+    }
+
+    let mask_key = config
+        .mask_username
+        .then(|| seal::load_secret(&config.mask_username_key))
+        .transpose()
+        .unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to load {}, masked usernames can't be correlated across logins: {}",
+                config.mask_username_key.display(),
+                e
+            );
+            None
+        });
+    DefaultLogger::mask_usernames(config.mask_username, mask_key.as_deref());
+    if let Some(problem) = insecure_log_path(log_path) {
+        try_or_handle!(
+            config
+                .permission_check
+                .apply(problem)
+                .map_err(|err| err.into()),
+            "Insecure log file",
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        );
+    }
+    try_or_handle!(
+        ensure_state_dir(&config).map_err(|err| err.into()),
+        "Failed to check state dir",
+        Err(PamResultCode::PAM_SYSTEM_ERR)
+    );
+
+    Ok((args, config))
+}
+
+fn parse_args(args: &[&CStr]) -> HashMap<String, String> {
+    args.iter()
+        .map(|&s| {
+            let s = s.to_string_lossy().into_owned();
+            let mut parts = s.splitn(2, '=');
+            (
+                parts.next().unwrap_or_default().to_string(),
+                parts.next().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
@@ -1,11 +1,20 @@
 use std::fmt::{Debug, Display};
+use std::time::Duration;
 
 use oauth2::StandardDeviceAuthorizationResponse;
 use oauth2::{UserCode, VerificationUriComplete};
-use qrcode::render::unicode;
-use qrcode::QrCode;
+use qrcode::{Color, EcLevel, QrCode};
 
-use crate::config::Messages;
+use crate::config::{Messages, QrErrorCorrection, QrMode, QrOptions};
+use crate::logger::LogUser;
+use crate::oauth_device::ValidatedToken;
+use crate::qr_image;
+
+// ANSI escape sequences of the prompt styling
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
 
 struct QrString(String);
 
@@ -25,13 +34,31 @@ impl Debug for QrString {
     }
 }
 
-#[derive(Debug)]
 pub struct UserPrompt {
     qrcode: Option<QrString>,
     verification_uri_complete: Option<VerificationUriComplete>,
     verification_uri: String,
     user_code: UserCode,
+    expires_in: Duration,
+    username: String,
     messages: Messages,
+    styled: bool,
+}
+
+// Masks the username when `mask_username` is enabled, see `LogUser`
+impl Debug for UserPrompt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserPrompt")
+            .field("qrcode", &self.qrcode)
+            .field("verification_uri_complete", &self.verification_uri_complete)
+            .field("verification_uri", &self.verification_uri)
+            .field("user_code", &self.user_code)
+            .field("expires_in", &self.expires_in)
+            .field("username", &LogUser(&self.username).to_string())
+            .field("messages", &self.messages)
+            .field("styled", &self.styled)
+            .finish()
+    }
 }
 
 impl UserPrompt {
@@ -44,82 +71,307 @@ impl UserPrompt {
             verification_uri_complete: device_code_resp.verification_uri_complete().cloned(),
             verification_uri: device_code_resp.verification_uri().to_string(),
             user_code: device_code_resp.user_code().to_owned(),
+            expires_in: device_code_resp.expires_in(),
+            username: String::new(),
             messages: messages.clone(),
+            styled: false,
         }
     }
 
-    pub fn generate_qr(&mut self) {
-        let qrcode: Option<QrString>;
+    // Shows the `verification_uri` and `user_code` even if the server returned a
+    // `verification_uri_complete`, so users have to type the code themselves
+    pub fn ignore_verification_uri_complete(&mut self) {
+        self.verification_uri_complete = None;
+    }
 
-        if let Some(verification_uri_complete) = &self.verification_uri_complete {
-            qrcode = match qr_code(&verification_uri_complete.secret()) {
-                Err(e) => {
-                    log::warn!("Failed to create QR code: {e}");
-                    None
-                }
-                Ok(qr) => Some(QrString::new(qr)),
-            };
+    // Local username for the `{username}` placeholder of `messages.prompt_template`
+    pub fn set_username(&mut self, username: &str) {
+        self.username = username.to_string();
+    }
+
+    // Bold user code, colored link and dim hints, for terminals understanding ANSI escapes.
+    // The QR code is never styled.
+    pub fn enable_styling(&mut self) {
+        self.styled = true;
+    }
+
+    // The link shown and encoded in the QR code, the `verification_uri_complete` if one is used
+    pub fn verification_uri(&self) -> &str {
+        match &self.verification_uri_complete {
+            Some(verification_uri_complete) => verification_uri_complete.secret(),
+            None => &self.verification_uri,
+        }
+    }
+
+    // Shows `url` in place of the verification URI, e.g. a short URL redirecting to it
+    pub fn replace_verification_uri(&mut self, url: String) {
+        match &mut self.verification_uri_complete {
+            Some(verification_uri_complete) => {
+                *verification_uri_complete = VerificationUriComplete::new(url)
+            }
+            None => self.verification_uri = url,
+        }
+    }
+
+    pub fn generate_qr(&mut self, options: &QrOptions) {
+        self.qrcode = match qr_code(&self.verification_uri().to_string(), options) {
+            Err(e) => {
+                log::warn!("Failed to create QR code: {e}");
+                None
+            }
+            Ok(qr) => Some(QrString::new(qr)),
+        };
+    }
+}
+
+impl UserPrompt {
+    // `messages.prompt_template` with its placeholders filled in.
+    // `{verification_uri}` is the `verification_uri_complete` if one is used, `{qr}` is
+    // empty if no QR code was generated.
+    fn render_template(&self, template: &str) -> String {
+        let verification_uri = self.verification_uri();
+        let qr = self.qrcode.as_ref().map(|qr| qr.secret().as_str());
+        template
+            .replace("{verification_uri}", &self.link(verification_uri))
+            .replace("{user_code}", &self.code(self.user_code.secret()))
+            .replace(
+                "{expires_in_minutes}",
+                &self.expires_in.as_secs().div_ceil(60).to_string(),
+            )
+            .replace("{username}", &self.username)
+            // Last, so the placeholders are not looked up in the QR code
+            .replace("{qr}", qr.unwrap_or_default())
+    }
+}
+
+impl UserPrompt {
+    fn style(&self, style: &str, text: &str) -> String {
+        if self.styled && !text.is_empty() {
+            format!("{style}{text}{RESET}")
         } else {
-            qrcode = match qr_code(&self.verification_uri) {
-                Err(e) => {
-                    log::warn!("Failed to create QR code: {e}");
-                    None
-                }
-                Ok(qr) => Some(QrString::new(qr)),
+            text.to_string()
+        }
+    }
+
+    fn code(&self, code: &str) -> String {
+        self.style(BOLD, code)
+    }
+
+    fn link(&self, url: &str) -> String {
+        self.style(CYAN, url)
+    }
+
+    fn hint(&self, message: &str) -> String {
+        self.style(DIM, message)
+    }
+}
+
+impl UserPrompt {
+    // The prompt as separate conversation messages: the informational lines (QR code, link
+    // and code) shown with `PAM_TEXT_INFO`, then the input prompt. A template is split at its
+    // last line, which becomes the input prompt.
+    pub fn split(&self) -> (Vec<String>, String) {
+        if let Some(template) = &self.messages.prompt_template {
+            let rendered = self.render_template(template);
+            return match rendered.rsplit_once('\n') {
+                Some((info, prompt)) => (vec![info.to_string()], prompt.to_string()),
+                None => (Vec::new(), rendered),
+            };
+        }
+        let mut info = Vec::new();
+        if let Some(qr) = &self.qrcode {
+            info.push(qr.secret().clone());
+        }
+        match (&self.qrcode, &self.verification_uri_complete) {
+            (Some(_), Some(url)) => {
+                info.push(self.hint(&self.messages.prompt_complete));
+                info.push(self.link(url.secret()));
+            }
+            (None, Some(url)) => {
+                info.push(self.hint(&self.messages.prompt_no_qr_complete));
+                info.push(self.link(url.secret()));
+            }
+            (qr, None) => {
+                info.push(self.hint(if qr.is_some() {
+                    &self.messages.prompt_incomplete
+                } else {
+                    &self.messages.prompt_no_qr_incomplete
+                }));
+                info.push(self.link(&self.verification_uri));
+                info.push(self.hint(&self.messages.prompt_code));
+                info.push(self.code(self.user_code.secret()));
             }
         }
-        self.qrcode = qrcode;
+        (info, self.hint(&self.messages.prompt_enter))
+    }
+
+    // The prompt for users who can't answer it, without the input prompt asking to press
+    // Enter. A template is kept whole.
+    pub fn notification(&self) -> String {
+        match &self.messages.prompt_template {
+            Some(template) => self.render_template(template),
+            None => self.split().0.join("\n"),
+        }
     }
 }
 
 impl Display for UserPrompt {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(template) = &self.messages.prompt_template {
+            return write!(f, "{}", self.render_template(template));
+        }
         match (&self.qrcode, &self.verification_uri_complete) {
             (Some(qr), Some(url)) => write!(
                 f,
                 "\n{}\n{}\n{}\n{}",
                 qr.secret(),
-                &self.messages.prompt_complete,
-                url.secret(),
-                &self.messages.prompt_enter
+                self.hint(&self.messages.prompt_complete),
+                self.link(url.secret()),
+                self.hint(&self.messages.prompt_enter)
             ),
             (None, Some(url)) => write!(
                 f,
                 "\n{}\n{}\n{}",
-                &self.messages.prompt_no_qr_complete,
-                url.secret(),
-                &self.messages.prompt_enter
+                self.hint(&self.messages.prompt_no_qr_complete),
+                self.link(url.secret()),
+                self.hint(&self.messages.prompt_enter)
             ),
             (Some(qr), None) => write!(
                 f,
                 "\n{}\n{}\n{}\n{}\n{}\n{}",
                 qr.secret(),
-                &self.messages.prompt_incomplete,
-                self.verification_uri,
-                &self.messages.prompt_code,
-                self.user_code.secret(),
-                &self.messages.prompt_enter
+                self.hint(&self.messages.prompt_incomplete),
+                self.link(&self.verification_uri),
+                self.hint(&self.messages.prompt_code),
+                self.code(self.user_code.secret()),
+                self.hint(&self.messages.prompt_enter)
             ),
             (None, None) => write!(
                 f,
                 "\n{}\n{}\n{}\n{}\n{}",
-                &self.messages.prompt_no_qr_incomplete,
-                self.verification_uri,
-                &self.messages.prompt_code,
-                self.user_code.secret(),
-                &self.messages.prompt_enter
+                self.hint(&self.messages.prompt_no_qr_incomplete),
+                self.link(&self.verification_uri),
+                self.hint(&self.messages.prompt_code),
+                self.code(self.user_code.secret()),
+                self.hint(&self.messages.prompt_enter)
             ),
         }
     }
 }
-pub fn qr_code(url: &String) -> Result<String, Box<dyn std::error::Error>> {
-    let qr = QrCode::new(&url)?;
 
-    let qr_text = qr
-        .render::<unicode::Dense1x2>()
-        .dark_color(unicode::Dense1x2::Light)
-        .light_color(unicode::Dense1x2::Dark)
-        .build();
+// Best effort check whether the terminal of the login understands ANSI escapes. The `TERM`
+// of the user is often unknown while authenticating (sshd only sets it for the session), so
+// only a dumb terminal or `NO_COLOR` (https://no-color.org) rule the styling out.
+pub fn supports_ansi(term: Option<&str>, no_color: bool) -> bool {
+    !no_color && term.is_none_or(|term| term != "dumb" && !term.is_empty())
+}
+
+// Best effort check whether the terminal of the login displays inline images. Like for
+// `supports_ansi`, only terminals known not to (the console, multiplexers) rule them out.
+pub fn supports_images(term: Option<&str>) -> bool {
+    term.is_none_or(|term| {
+        !term.is_empty()
+            && !["dumb", "linux", "vt100", "vt220"].contains(&term)
+            && !term.starts_with("screen")
+            && !term.starts_with("tmux")
+    })
+}
+
+pub fn qr_code(url: &String, options: &QrOptions) -> Result<String, Box<dyn std::error::Error>> {
+    let ec_level = match options.error_correction {
+        QrErrorCorrection::Low => EcLevel::L,
+        QrErrorCorrection::Medium => EcLevel::M,
+        QrErrorCorrection::Quartile => EcLevel::Q,
+        QrErrorCorrection::High => EcLevel::H,
+    };
+    let qr = QrCode::with_error_correction_level(url, ec_level)?;
+
+    // Whether the module at `x`, `y` of the code surrounded by the quiet zone is dark.
+    // Modules outside of the code are light.
+    let width = qr.width();
+    let colors = qr.to_colors();
+    let size = width + 2 * options.quiet_zone;
+    let dark = |x: usize, y: usize| {
+        let color = match (
+            x.checked_sub(options.quiet_zone),
+            y.checked_sub(options.quiet_zone),
+        ) {
+            (Some(x), Some(y)) if x < width && y < width => colors[y * width + x],
+            _ => Color::Light,
+        };
+        color == Color::Dark
+    };
+    // Characters draw the light modules on dark backgrounds, unless inverted
+    let drawn = |x: usize, y: usize| dark(x, y) == options.invert;
+
+    // Images are black on white whatever the background
+    if options.mode.is_image() {
+        let modules: Vec<bool> = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .map(|(x, y)| dark(x, y))
+            .collect();
+        let scale = options.scale.max(1);
+        return Ok(match options.mode {
+            QrMode::Sixel => qr_image::sixel(&modules, size, scale),
+            QrMode::Kitty => qr_image::kitty(&modules, size, scale),
+            _ => qr_image::iterm2(&modules, size, scale),
+        });
+    }
+
+    let rows: Vec<String> = match options.mode {
+        QrMode::Unicode => (0..size)
+            .step_by(2)
+            .map(|y| {
+                (0..size)
+                    .map(|x| match (drawn(x, y), y + 1 < size && drawn(x, y + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect()
+            })
+            .collect(),
+        _ => (0..size)
+            .map(|y| {
+                (0..size)
+                    .map(|x| if drawn(x, y) { "##" } else { "  " })
+                    .collect()
+            })
+            .collect(),
+    };
+    Ok(rows.join("\n"))
+}
+
+// Message shown after successful authentication, empty if `messages.success` is not set.
+// Supports the `{display_name}`, `{username}` (the local username, as in the other messages),
+// `{remote_username}` and `{issuer}` placeholders.
+pub fn success_message(
+    messages: &Messages,
+    validated: &ValidatedToken,
+    local_username: &str,
+    issuer: &str,
+) -> String {
+    messages
+        .success
+        .replace("{display_name}", &validated.display_name)
+        .replace("{remote_username}", &validated.username)
+        .replace("{username}", local_username)
+        .replace("{issuer}", issuer)
+}
 
-    Ok(qr_text)
+// Message sent while the token endpoint is polled, `{remaining}` as minutes and seconds.
+// No token is there yet, `display_name` is the one known to the local account.
+pub fn waiting_message(
+    messages: &Messages,
+    remaining: Duration,
+    local_username: &str,
+    display_name: &str,
+) -> String {
+    let secs = remaining.as_secs();
+    messages
+        .waiting
+        .replace("{remaining}", &format!("{}:{:02}", secs / 60, secs % 60))
+        .replace("{display_name}", display_name)
+        .replace("{username}", local_username)
 }
@@ -0,0 +1,121 @@
+use std::io::{Error as IOError, ErrorKind};
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::config::{Config, ProvisioningConfig};
+use crate::groups::user_name;
+
+// Local accounts created for authenticated users who don't have one yet, like SSSD does for
+// LDAP users. The account is added with `useradd`, its home directory is left to
+// `create_homedir` or pam_mkhomedir.
+pub struct Provisioner<'a> {
+    config: &'a ProvisioningConfig,
+}
+
+// Account about to be created for a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewAccount {
+    pub uid: u32,
+    pub gecos: String,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+impl<'a> Provisioner<'a> {
+    // None without `provisioning`
+    pub fn new(config: &'a Config) -> Option<Self> {
+        Some(Self {
+            config: config.provisioning.as_ref()?,
+        })
+    }
+
+    // Account of the local `user` with the profile of their token. The uid is the claimed
+    // one if the token has the `uid_claim`, otherwise the lowest free one of `uid_range`.
+    pub fn plan(
+        &self,
+        user: &str,
+        display_name: &str,
+        email: Option<&str>,
+        claimed_uid: Option<u32>,
+    ) -> Result<NewAccount, IOError> {
+        let (min, max) = self.config.uid_range;
+        let uid = match claimed_uid {
+            // Never root or another system account, whatever the token says
+            Some(uid) if uid == 0 || !(min..=max).contains(&uid) => {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    format!("Claimed uid {uid} is outside uid_range {min}-{max}"),
+                ))
+            }
+            Some(uid) => match user_name(uid)? {
+                Some(owner) => {
+                    return Err(IOError::new(
+                        ErrorKind::AlreadyExists,
+                        format!("Claimed uid {uid} belongs to local user {owner}"),
+                    ))
+                }
+                None => uid,
+            },
+            None => free_uid(min.max(1), max)?,
+        };
+        Ok(NewAccount {
+            uid,
+            gecos: gecos(display_name, email),
+            home: self.config.home_base.join(user),
+            shell: self.config.shell.clone(),
+        })
+    }
+
+    // `useradd` of the account, without its home directory
+    pub fn create(&self, user: &str, account: &NewAccount) -> Result<(), IOError> {
+        let mut command = Command::new(&self.config.useradd);
+        command
+            .arg("-M")
+            .args(["-u", &account.uid.to_string()])
+            .arg("-c")
+            .arg(&account.gecos)
+            .arg("-d")
+            .arg(&account.home)
+            .arg("-s")
+            .arg(&account.shell);
+        if let Some(group) = &self.config.primary_group {
+            command.args(["-g", group]);
+        }
+        let output = command.args(["--", user]).output()?;
+        if !output.status.success() {
+            return Err(IOError::other(format!(
+                "{} {}: {}",
+                self.config.useradd.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn free_uid(min: u32, max: u32) -> Result<u32, IOError> {
+    for uid in min..=max {
+        if user_name(uid)?.is_none() {
+            return Ok(uid);
+        }
+    }
+    Err(IOError::new(
+        ErrorKind::NotFound,
+        format!("No free uid left in uid_range {min}-{max}"),
+    ))
+}
+
+// GECOS field of the full name and, in the field for other information, the email address.
+// The separators of `/etc/passwd` and the GECOS fields are left out.
+pub fn gecos(display_name: &str, email: Option<&str>) -> String {
+    let clean = |text: &str| -> String {
+        text.chars()
+            .filter(|c| !matches!(c, ':' | ',' | '=') && !c.is_control())
+            .collect()
+    };
+    match email {
+        Some(email) => format!("{},,,,{}", clean(display_name), clean(email)),
+        None => clean(display_name),
+    }
+}
@@ -0,0 +1,131 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+// Inline image renderings of a QR code for terminals with a graphics protocol. `dark` holds
+// the modules of the code, quiet zone included, row by row, `size` per row. Every module is
+// drawn as a square of `scale` pixels, dark modules black and light ones white.
+
+// Payload bytes of a kitty graphics escape, before the base64 encoding
+const KITTY_CHUNK: usize = 3072;
+
+// DEC sixel graphics, six rows of pixels per line of sixels
+pub fn sixel(dark: &[bool], size: usize, scale: usize) -> String {
+    let pixels = size * scale;
+    let is_dark = |x: usize, y: usize| dark[(y / scale) * size + x / scale];
+    // 1:1 pixel aspect ratio, a white background and two color registers
+    let mut out = format!("\x1bP0;1q\"1;1;{pixels};{pixels}#0;2;100;100;100#1;2;0;0;0");
+    for band in (0..pixels).step_by(6) {
+        for (register, color) in [(0, false), (1, true)] {
+            out += &format!("#{register}");
+            let sixels: Vec<u8> = (0..pixels)
+                .map(|x| {
+                    let bits = (0..6)
+                        .filter(|row| band + row < pixels && is_dark(x, band + row) == color)
+                        .fold(0, |bits, row| bits | (1 << row));
+                    63 + bits
+                })
+                .collect();
+            push_run_length(&mut out, &sixels);
+            // Back to the start of the line for the other color
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out += "\x1b\\";
+    out
+}
+
+// Repeated sixels as `!<count><sixel>`
+fn push_run_length(out: &mut String, sixels: &[u8]) {
+    let mut i = 0;
+    while i < sixels.len() {
+        let run = sixels[i..].iter().take_while(|&&s| s == sixels[i]).count();
+        if run > 3 {
+            out.push_str(&format!("!{run}"));
+        } else {
+            out.extend(std::iter::repeat_n(sixels[i] as char, run - 1));
+        }
+        out.push(sixels[i] as char);
+        i += run;
+    }
+}
+
+// kitty graphics protocol with raw RGB data, split into chunks. `q=2` keeps the terminal
+// from answering, its reply would end up in the input of the prompt.
+pub fn kitty(dark: &[bool], size: usize, scale: usize) -> String {
+    let pixels = size * scale;
+    let data = STANDARD.encode(rgb(dark, size, scale));
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK / 3 * 4).collect();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let control = if i == 0 {
+            format!("a=T,f=24,s={pixels},v={pixels},q=2,m={more}")
+        } else {
+            format!("m={more}")
+        };
+        out += &format!("\x1b_G{control};{}\x1b\\", String::from_utf8_lossy(chunk));
+    }
+    out
+}
+
+// iTerm2 inline image of a BMP file, which needs no compression
+pub fn iterm2(dark: &[bool], size: usize, scale: usize) -> String {
+    let bmp = bmp(dark, size, scale);
+    format!(
+        "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{}\x07",
+        bmp.len(),
+        STANDARD.encode(&bmp)
+    )
+}
+
+// Pixels top to bottom, 3 bytes each
+fn rgb(dark: &[bool], size: usize, scale: usize) -> Vec<u8> {
+    let pixels = size * scale;
+    let mut data = Vec::with_capacity(pixels * pixels * 3);
+    for y in 0..pixels {
+        for x in 0..pixels {
+            let value = if dark[(y / scale) * size + x / scale] {
+                0
+            } else {
+                255
+            };
+            data.extend_from_slice(&[value; 3]);
+        }
+    }
+    data
+}
+
+// 24 bit BMP: rows bottom to top, padded to 4 bytes
+fn bmp(dark: &[bool], size: usize, scale: usize) -> Vec<u8> {
+    let pixels = size * scale;
+    let row_len = (pixels * 3).div_ceil(4) * 4;
+    let image_len = row_len * pixels;
+    let mut bmp = Vec::with_capacity(54 + image_len);
+    // File header
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&(54 + image_len as u32).to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+    bmp.extend_from_slice(&54u32.to_le_bytes());
+    // BITMAPINFOHEADER
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&(pixels as i32).to_le_bytes());
+    bmp.extend_from_slice(&(pixels as i32).to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes());
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+    bmp.extend_from_slice(&(image_len as u32).to_le_bytes());
+    // 72 DPI
+    bmp.extend_from_slice(&2835i32.to_le_bytes());
+    bmp.extend_from_slice(&2835i32.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+
+    let rgb = rgb(dark, size, scale);
+    for row in rgb.chunks(pixels * 3).rev() {
+        // BGR, all pixels are gray anyway
+        bmp.extend_from_slice(row);
+        bmp.resize(bmp.len() + row_len - row.len(), 0);
+    }
+    bmp
+}
@@ -0,0 +1,93 @@
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{Error as IOError, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+// Device flows started per key (a local user or a remote host) within the last `window`.
+// One file per key (named after the hash of the key), each line holding the time a device flow
+// was started.
+pub struct RateLimiter {
+    dir: PathBuf,
+    max: usize,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(dir: &Path, max: usize, window: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max,
+            window,
+        }
+    }
+
+    // Records a device flow for every key, unless one of them already reached the limit
+    pub fn allow(&self, keys: &[&str]) -> Result<bool, IOError> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        // Locked in a fixed order, so concurrent logins sharing keys can't deadlock
+        let mut paths: Vec<PathBuf> = keys.iter().map(|key| self.entry(key)).collect();
+        paths.sort();
+        paths.dedup();
+
+        let now = Utc::now().timestamp();
+        let oldest = now - self.window.as_secs() as i64;
+        let mut entries = Vec::new();
+        for path in paths {
+            let mut file = lock(&path)?;
+            let mut started = read_times(&mut file)?;
+            started.retain(|&time| time > oldest);
+            entries.push((file, started));
+        }
+
+        let allowed = entries.iter().all(|(_, started)| started.len() < self.max);
+        for (file, started) in &mut entries {
+            if allowed {
+                started.push(now);
+            }
+            write_times(file, started)?;
+        }
+        Ok(allowed)
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        let digest = Sha256::digest(key.as_bytes());
+        let name: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+}
+
+// Opens `path` and holds an exclusive lock on it until the file is closed
+fn lock(path: &Path) -> Result<File, IOError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(IOError::last_os_error());
+    }
+    Ok(file)
+}
+
+fn read_times(file: &mut File) -> Result<Vec<i64>, IOError> {
+    let mut buff = String::new();
+    file.read_to_string(&mut buff)?;
+    Ok(buff.lines().filter_map(|line| line.parse().ok()).collect())
+}
+
+fn write_times(file: &mut File, times: &[i64]) -> Result<(), IOError> {
+    let buff: String = times.iter().map(|time| format!("{time}\n")).collect();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(buff.as_bytes())
+}
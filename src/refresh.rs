@@ -0,0 +1,187 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use oauth2::RefreshToken;
+use serde::{Deserialize, Serialize};
+
+use crate::helper::HelperClient;
+use crate::logger::LogUser;
+use crate::seal;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Refresh token and the PKCS#8 DPoP key it is bound to, if any
+pub type BoundRefreshToken = (RefreshToken, Option<Vec<u8>>);
+
+// Plaintext of an entry
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    refresh_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dpop_key: Option<String>,
+    // Login context the token was granted in, see `load_for_context`
+    #[serde(default)]
+    context: Option<String>,
+}
+
+// Refresh tokens of every local user, encrypted with AES-256-GCM.
+// One file per local user holding the nonce followed by the ciphertext. The local username
+// is authenticated along with the token, so entries cannot be swapped between users.
+pub struct RefreshStore {
+    dir: PathBuf,
+    key_file: PathBuf,
+    // Tokens are kept by the helper daemon, which holds the key, instead of in `dir`
+    helper: Option<HelperClient>,
+}
+
+impl RefreshStore {
+    pub fn new(dir: &Path, key_file: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            key_file: key_file.to_path_buf(),
+            helper: None,
+        }
+    }
+
+    pub fn via_helper(mut self, socket: &Path) -> Self {
+        self.helper = Some(HelperClient::new(socket));
+        self
+    }
+
+    pub fn store(
+        &self,
+        local_user: &str,
+        context: &str,
+        token: &RefreshToken,
+    ) -> Result<(), DynErr> {
+        self.store_with_dpop_key(local_user, context, token, None)
+    }
+
+    // Stores the PKCS#8 DPoP key a DPoP-bound token was issued to along with it
+    pub fn store_with_dpop_key(
+        &self,
+        local_user: &str,
+        context: &str,
+        token: &RefreshToken,
+        dpop_key: Option<&[u8]>,
+    ) -> Result<(), DynErr> {
+        if let Some(helper) = &self.helper {
+            return Ok(helper.refresh_store(local_user, context, token, dpop_key)?);
+        }
+        let plaintext = serde_json::to_vec(&Sealed {
+            refresh_token: token.secret().to_string(),
+            dpop_key: dpop_key.map(|dpop_key| URL_SAFE_NO_PAD.encode(dpop_key)),
+            context: Some(context.to_string()),
+        })?;
+        let sealed = seal::seal(
+            &seal::load_key(&self.key_file)?,
+            local_user.as_bytes(),
+            &plaintext,
+        )?;
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let path = self.entry(local_user)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let tmp = path.with_file_name(format!(".{local_user}.{}.{nanos}", std::process::id()));
+        // Never follows or reuses a file someone else put there
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&sealed)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn load(&self, local_user: &str) -> Result<Option<RefreshToken>, DynErr> {
+        Ok(self.load_with_dpop_key(local_user)?.map(|(token, _)| token))
+    }
+
+    // The token of any context, e.g. to revoke it
+    pub fn load_with_dpop_key(
+        &self,
+        local_user: &str,
+    ) -> Result<Option<BoundRefreshToken>, DynErr> {
+        self.load_matching(local_user, None)
+    }
+
+    // The token only if it was granted in `context`, the service, tty, remote user and host
+    // of the login. Tokens of older versions have no context and are never returned.
+    pub fn load_for_context(
+        &self,
+        local_user: &str,
+        context: &str,
+    ) -> Result<Option<BoundRefreshToken>, DynErr> {
+        self.load_matching(local_user, Some(context))
+    }
+
+    fn load_matching(
+        &self,
+        local_user: &str,
+        context: Option<&str>,
+    ) -> Result<Option<BoundRefreshToken>, DynErr> {
+        if let Some(helper) = &self.helper {
+            return Ok(helper.refresh_load(local_user, context)?);
+        }
+        let data = match fs::read(self.entry(local_user)?) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let token = seal::open(
+            &seal::load_key(&self.key_file)?,
+            local_user.as_bytes(),
+            &data,
+        )
+        .map_err(|_| "Failed to decrypt refresh token")?;
+        // Entries of older versions hold just the token
+        let sealed = match serde_json::from_slice(&token) {
+            Ok(sealed) => sealed,
+            Err(_) => Sealed {
+                refresh_token: String::from_utf8(token)?,
+                dpop_key: None,
+                context: None,
+            },
+        };
+        if context.is_some_and(|context| sealed.context.as_deref() != Some(context)) {
+            return Ok(None);
+        }
+        let dpop_key = sealed
+            .dpop_key
+            .map(|dpop_key| URL_SAFE_NO_PAD.decode(dpop_key))
+            .transpose()?;
+        Ok(Some((RefreshToken::new(sealed.refresh_token), dpop_key)))
+    }
+
+    pub fn remove(&self, local_user: &str) -> Result<(), IOError> {
+        if let Some(helper) = &self.helper {
+            return helper.refresh_remove(local_user);
+        }
+        match fs::remove_file(self.entry(local_user)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn entry(&self, local_user: &str) -> Result<PathBuf, IOError> {
+        if local_user.is_empty() || local_user.starts_with('.') || local_user.contains('/') {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid local username: {}", LogUser(local_user)),
+            ));
+        }
+        Ok(self.dir.join(local_user))
+    }
+}
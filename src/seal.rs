@@ -0,0 +1,108 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::systemd_credential;
+
+const KEY_LEN: usize = 32;
+
+// Machine-local AES-256-GCM keys encrypting the files of the module at rest, so a backup or
+// a disk image doesn't expose them. Sealed data is the nonce followed by the ciphertext, `aad`
+// (e.g. the local username) is authenticated along with it.
+
+// Reads the key in `key_file`, generating it on first use. A systemd credential of the same
+// name (`LoadCredential=` or `LoadCredentialEncrypted=`) takes precedence, so the key can be
+// kept encrypted with `systemd-creds`.
+pub fn load_key(key_file: &Path) -> Result<LessSafeKey, IOError> {
+    let key = load_secret(key_file)?;
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| {
+        IOError::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Invalid key {}, expected {KEY_LEN} bytes",
+                key_file.display()
+            ),
+        )
+    })?;
+    Ok(LessSafeKey::new(key))
+}
+
+// Raw bytes of the key in `key_file`, e.g. to key an HMAC, found and generated the same way
+pub fn load_secret(key_file: &Path) -> Result<Vec<u8>, IOError> {
+    match systemd_credential(key_file) {
+        Some(credential) => fs::read(credential),
+        None => match fs::read(key_file) {
+            Err(e) if e.kind() == ErrorKind::NotFound => generate_key(key_file),
+            read => read,
+        },
+    }
+}
+
+pub fn seal(key: &LessSafeKey, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, IOError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| IOError::other("Failed to generate nonce"))?;
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut sealed,
+    )
+    .map_err(|_| IOError::other("Failed to encrypt"))?;
+    let mut data = nonce.to_vec();
+    data.append(&mut sealed);
+    Ok(data)
+}
+
+pub fn open(key: &LessSafeKey, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, IOError> {
+    let invalid = || IOError::new(ErrorKind::InvalidData, "Failed to decrypt");
+    if data.len() < NONCE_LEN {
+        return Err(invalid());
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?;
+    let mut sealed = sealed.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(aad), &mut sealed)
+        .map_err(|_| invalid())?;
+    Ok(plaintext.to_vec())
+}
+
+fn generate_key(key_file: &Path) -> Result<Vec<u8>, IOError> {
+    let mut key = vec![0u8; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| IOError::other("Failed to generate key"))?;
+    if let Some(parent) = key_file.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    // Hard link a complete key file into place, if another process was faster its key is used
+    let tmp = key_file.with_extension(format!("tmp.{}", std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    let linked = file
+        .write_all(&key)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::hard_link(&tmp, key_file));
+    fs::remove_file(&tmp)?;
+    match linked {
+        Ok(()) => {
+            log::info!("Generated key {}", key_file.display());
+            Ok(key)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => fs::read(key_file),
+        Err(e) => Err(e),
+    }
+}
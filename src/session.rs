@@ -0,0 +1,145 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{Error as IOError, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+// Open sessions of every remote identity on this host.
+// One file per identity (named after the hash of its key), each line holding
+// the pid owning the session, the time it was opened and the start time of the process.
+pub struct SessionStore {
+    dir: PathBuf,
+    stale_after: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+struct Session {
+    pid: u32,
+    opened: i64,
+    // In clock ticks since boot, tells a reused pid apart. None if unknown.
+    started: Option<u64>,
+}
+
+impl SessionStore {
+    pub fn new(dir: &Path, stale_after: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            stale_after,
+        }
+    }
+
+    // Registers a new session unless the identity already has `max` live sessions
+    pub fn open(&self, key: &str, pid: u32, max: usize) -> Result<bool, IOError> {
+        self.update(key, |sessions| {
+            if sessions.len() >= max {
+                return false;
+            }
+            sessions.push(Session {
+                pid,
+                opened: Utc::now().timestamp(),
+                started: process_start(pid),
+            });
+            true
+        })
+    }
+
+    pub fn close(&self, key: &str, pid: u32) -> Result<(), IOError> {
+        self.update(key, |sessions| sessions.retain(|s| s.pid != pid))
+    }
+
+    pub fn count(&self, key: &str) -> Result<usize, IOError> {
+        self.update(key, |sessions| sessions.len())
+    }
+
+    // Runs `f` on the live sessions of `key` while holding an exclusive lock on its file
+    fn update<T>(&self, key: &str, f: impl FnOnce(&mut Vec<Session>) -> T) -> Result<T, IOError> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(self.entry(key))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(IOError::last_os_error());
+        }
+
+        let mut sessions = read_sessions(&mut file)?;
+        let before = sessions.len();
+        sessions.retain(|s| !self.is_stale(s));
+        if sessions.len() != before {
+            log::info!("Dropped {} stale session(s)", before - sessions.len());
+        }
+
+        let result = f(&mut sessions);
+        write_sessions(&mut file, &sessions)?;
+        Ok(result)
+    }
+
+    // Sessions whose process is gone (e.g. crashed without closing) or was replaced by another
+    // one with the same pid. The timeout only applies when the start time of the process can't
+    // be compared.
+    fn is_stale(&self, session: &Session) -> bool {
+        let alive = unsafe { libc::kill(session.pid as libc::pid_t, 0) } == 0
+            || IOError::last_os_error().raw_os_error() == Some(libc::EPERM);
+        if !alive {
+            return true;
+        }
+        match (session.started, process_start(session.pid)) {
+            (Some(started), Some(current)) => started != current,
+            _ => Utc::now().timestamp() - session.opened > self.stale_after.as_secs() as i64,
+        }
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        let digest = Sha256::digest(key.as_bytes());
+        let name: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+}
+
+fn read_sessions(file: &mut File) -> Result<Vec<Session>, IOError> {
+    let mut buff = String::new();
+    file.read_to_string(&mut buff)?;
+    Ok(buff
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            Some(Session {
+                pid: fields.next()?.parse().ok()?,
+                opened: fields.next()?.parse().ok()?,
+                // Missing in the lines of older versions
+                started: fields.next().and_then(|started| started.parse().ok()),
+            })
+        })
+        .collect())
+}
+
+fn write_sessions(file: &mut File, sessions: &[Session]) -> Result<(), IOError> {
+    let buff: String = sessions
+        .iter()
+        .map(|s| match s.started {
+            Some(started) => format!("{} {} {started}\n", s.pid, s.opened),
+            None => format!("{} {}\n", s.pid, s.opened),
+        })
+        .collect();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(buff.as_bytes())
+}
+
+// Start time of the process, field 22 of /proc/<pid>/stat. The fields are counted after the
+// command name, which may contain spaces and parentheses.
+fn process_start(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
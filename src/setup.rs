@@ -0,0 +1,203 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::read_strict_config;
+use crate::oauth_device::OAuthClient;
+
+type DynErr = Box<dyn std::error::Error>;
+
+const GOOGLE_ISSUER: &str = "https://accounts.google.com";
+const GOOGLE_USERINFO: &str = "https://openidconnect.googleapis.com/v1/userinfo";
+
+// Kind of Authorization Server a config is generated for by `pam-oauth2-device-setup`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Keycloak,
+    Azuread,
+    Google,
+    #[default]
+    Oidc,
+}
+
+impl std::str::FromStr for Preset {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_string()))
+    }
+}
+
+impl Preset {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Preset::Keycloak => "Keycloak realm",
+            Preset::Azuread => "Azure AD (Microsoft Entra ID) tenant",
+            Preset::Google => "Google",
+            Preset::Oidc => "OpenID Connect provider",
+        }
+    }
+
+    // What the `issuer` of the setup is, if the preset needs one
+    pub fn issuer_prompt(&self) -> Option<&'static str> {
+        match self {
+            Preset::Keycloak => Some("Realm URL, e.g. https://sso.example.org/realms/hpc"),
+            Preset::Azuread => Some("Tenant id"),
+            Preset::Google => None,
+            Preset::Oidc => Some("Issuer URL"),
+        }
+    }
+}
+
+// Answers of `init` or `wizard` a config is rendered from
+#[derive(Debug, Clone, Default)]
+pub struct Setup {
+    pub preset: Preset,
+    // The tenant id of Azure AD, unused for Google
+    pub issuer: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+}
+
+impl Setup {
+    // TOML config of the preset, commented so it can be adjusted by hand afterwards
+    pub fn render(&self) -> Result<String, DynErr> {
+        if self.client_id.is_empty() {
+            return Err("No client id given".into());
+        }
+        let issuer = match (self.preset.issuer_prompt(), &self.issuer) {
+            (Some(_), Some(issuer)) if !issuer.is_empty() => issuer.as_str(),
+            (Some(what), _) => return Err(format!("No {what} given").into()),
+            (None, _) => GOOGLE_ISSUER,
+        };
+        let client_id = &self.client_id;
+
+        let mut text = format!(
+            "# Config of pam_oauth2_device for a {}, generated by pam-oauth2-device-setup.
+# Every option is described in the README, options left out keep their default.
+version = 2
+
+client_id = {}
+",
+            self.preset.description(),
+            quote(client_id)
+        );
+        text += &match &self.client_secret {
+            Some(secret) if !secret.is_empty() => format!(
+                "# Consider moving the secret to a file, see `client_secret_file`
+client_secret = {}
+",
+                quote(secret)
+            ),
+            _ => "# Public client, set `client_secret` for a confidential one\n".to_string(),
+        };
+        text += "\n";
+        text += &match self.preset {
+            Preset::Keycloak => format!(
+                "# Endpoints are discovered from the realm, tokens are introspected there
+issuer = {}
+scopes = \"openid profile\"
+validation_mode = \"introspection\"
+
+# Only users with one of these groups or realm roles may log in, anyone if empty
+allowed_groups = []
+
+[keycloak_roles]
+realm = true
+",
+                quote(issuer)
+            ),
+            Preset::Azuread => format!(
+                "# Endpoints are preset by the tenant. Azure AD doesn't introspect tokens, they are
+# verified with the tenant's keys.
+provider_type = \"azuread\"
+validation_mode = \"jwks\"
+# A scope of an API of your own app registration, tokens of Microsoft Graph can't be verified
+scopes = {}
+jwt_audience = {}
+
+[azuread]
+tenant = {}
+endpoint_version = \"v2\"
+",
+                quote(&format!("openid api://{client_id}/login")),
+                quote(&format!("api://{client_id}")),
+                quote(issuer)
+            ),
+            Preset::Google => format!(
+                "# Google's access tokens are opaque, the user is looked up at its userinfo endpoint.
+# Usernames are email addresses, map them to local users with a `user_map`.
+provider_type = \"rest\"
+issuer = {}
+scopes = \"openid email profile\"
+
+[identity_endpoint]
+url = {}
+username_pointer = \"/email\"
+subject_pointer = \"/sub\"
+",
+                quote(issuer),
+                quote(GOOGLE_USERINFO)
+            ),
+            Preset::Oidc => format!(
+                "# Endpoints are discovered from the issuer, tokens are introspected there
+issuer = {}
+scopes = \"openid profile\"
+validation_mode = \"introspection\"
+",
+                quote(issuer)
+            ),
+        };
+        Ok(text)
+    }
+}
+
+// Writes the config `text` to `path` once it loads in strict mode and, if `verify` is set,
+// the OAuth client of every provider can be built, which fetches the discovery document.
+// Nothing is written otherwise.
+pub fn install(path: &Path, text: &str, verify: bool) -> Result<(), DynErr> {
+    if path.extension().is_none_or(|ext| ext != "toml") {
+        return Err(format!(
+            "Config {} must end with .toml, JSON configs can't hold comments",
+            path.display()
+        )
+        .into());
+    }
+    // Next to the config so the `conf.d` fragments are merged like at login
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let staged = path.with_file_name(format!(".{file_name}.{}.toml", std::process::id()));
+    let res = stage(&staged, text, verify).and_then(|()| Ok(fs::rename(&staged, path)?));
+    if res.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    res
+}
+
+fn stage(staged: &Path, text: &str, verify: bool) -> Result<(), DynErr> {
+    // The config may hold the client secret
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(staged)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+
+    let config = read_strict_config(&staged.to_string_lossy())?;
+    if verify {
+        for provider in config.provider_configs(None)? {
+            OAuthClient::new(&provider)
+                .map_err(|e| format!("Provider {} isn't reachable: {e}", provider.provider_name))?;
+        }
+    }
+    Ok(())
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
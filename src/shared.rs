@@ -0,0 +1,92 @@
+use std::ffi::CString;
+
+use libc::{c_char, c_int, c_void};
+use oauth2::TokenResponse;
+use pam::constants::PamResultCode;
+use pam::module::PamHandle;
+use serde::{Deserialize, Serialize};
+
+use crate::oauth_device::{DeviceTokenResponse, ValidatedToken};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Version of the `SharedToken` layout, only increased on incompatible changes
+pub const SHARED_TOKEN_VERSION: u32 = 1;
+
+// Token response shared with other modules of the stack as NUL terminated JSON.
+// Fields are only ever added, never renamed or removed within a version.
+// The refresh token is deliberately left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SharedToken {
+    pub version: u32,
+    pub access_token: String,
+    pub token_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    // Unix timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+impl SharedToken {
+    pub fn new(token: &DeviceTokenResponse, validated: &ValidatedToken) -> Self {
+        Self {
+            version: SHARED_TOKEN_VERSION,
+            access_token: token.access_token().secret().clone(),
+            token_type: token.token_type().as_ref().to_string(),
+            id_token: token.extra_fields().id_token.clone(),
+            expires_at: validated.expires_at.map(|exp| exp.timestamp()),
+            scope: token.scopes().map(|scopes| {
+                scopes
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }),
+            username: validated.username.clone(),
+            subject: validated.subject.clone(),
+        }
+    }
+
+    pub fn to_c_string(&self) -> Result<CString, DynErr> {
+        Ok(CString::new(serde_json::to_string(self)?)?)
+    }
+}
+
+// pam-bindings stores boxed Rust values, other modules need a plain `char *`
+#[link(name = "pam")]
+extern "C" {
+    fn pam_set_data(
+        pamh: *mut PamHandle,
+        module_data_name: *const c_char,
+        data: *mut c_void,
+        cleanup: extern "C" fn(pamh: *mut PamHandle, data: *mut c_void, error_status: c_int),
+    ) -> c_int;
+}
+
+extern "C" fn free_c_string(_pamh: *mut PamHandle, data: *mut c_void, _error_status: c_int) {
+    if !data.is_null() {
+        drop(unsafe { CString::from_raw(data.cast::<c_char>()) });
+    }
+}
+
+// Stores `value` as a `char *` PAM data item, freed by PAM when the handle ends
+pub fn set_c_string_data(
+    pamh: &mut PamHandle,
+    key: &str,
+    value: CString,
+) -> Result<(), PamResultCode> {
+    let key = CString::new(key).map_err(|_| PamResultCode::PAM_BUF_ERR)?;
+    let data = value.into_raw();
+    let res = unsafe { pam_set_data(pamh, key.as_ptr(), data.cast::<c_void>(), free_c_string) };
+    if res != 0 {
+        drop(unsafe { CString::from_raw(data) });
+        return Err(PamResultCode::try_from(res).unwrap_or(PamResultCode::PAM_SYSTEM_ERR));
+    }
+    Ok(())
+}
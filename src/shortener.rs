@@ -0,0 +1,69 @@
+use oauth2::http::header::ACCEPT;
+use oauth2::http::{Method, Request};
+use oauth2::SyncHttpClient;
+use serde_json::Value;
+use url::{form_urlencoded, Url};
+
+use crate::config::{Config, UrlShortenerConfig};
+use crate::http::HttpClient;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Shortens the verification URI shown in the prompt with a URL shortener, so a long
+// `verification_uri_complete` can still be typed on a phone. The shortener is sent the whole
+// URI, user code included, so it should be one run by the site.
+pub struct UrlShortener<'a> {
+    config: &'a UrlShortenerConfig,
+    allow_insecure_http: bool,
+    http_client: HttpClient,
+}
+
+impl<'a> UrlShortener<'a> {
+    // None without `url_shortener`
+    pub fn new(config: &'a Config) -> Option<Self> {
+        Some(Self {
+            config: config.url_shortener.as_ref()?,
+            allow_insecure_http: config.allow_insecure_http,
+            http_client: HttpClient::new(config),
+        })
+    }
+
+    // Whether `url` is long enough to be shortened
+    pub fn applies(&self, url: &str) -> bool {
+        url.len() >= self.config.min_length
+    }
+
+    // Short URL redirecting to `url`, from a GET of the `endpoint` template with `{url}` replaced
+    // by the percent-encoded `url`
+    pub fn shorten(&self, url: &str) -> Result<String, DynErr> {
+        let encoded: String = form_urlencoded::byte_serialize(url.as_bytes()).collect();
+        let endpoint = Url::parse(&self.config.endpoint.replace("{url}", &encoded))?;
+        if endpoint.scheme() != "https" && !self.allow_insecure_http {
+            return Err(format!("URL shortener {endpoint} is not an https URL").into());
+        }
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(endpoint.as_str())
+            .header(ACCEPT, "application/json, text/plain")
+            .body(Vec::new())?;
+        let response = self.http_client.call(request)?;
+        if !response.status().is_success() {
+            return Err(format!("URL shortener failed with status {}", response.status()).into());
+        }
+
+        let body = String::from_utf8(response.into_body())?;
+        let short_url = match &self.config.response_field {
+            Some(field) => match serde_json::from_str::<Value>(&body)?.get(field) {
+                Some(Value::String(short_url)) => short_url.clone(),
+                _ => return Err(format!("URL shortener response has no {field} string").into()),
+            },
+            None => body.trim().to_string(),
+        };
+        // Shown to the user, so only a plain link is accepted
+        let parsed = Url::parse(&short_url)?;
+        if !matches!(parsed.scheme(), "https" | "http") || parsed.host().is_none() {
+            return Err(format!("URL shortener returned no http(s) URL: {short_url}").into());
+        }
+        Ok(short_url)
+    }
+}
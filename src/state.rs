@@ -0,0 +1,105 @@
+use std::fs::{self, DirBuilder};
+use std::io::{Error as IOError, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::Path;
+
+use crate::config::{Config, StateDirPermissions};
+
+// Present if SELinux is enabled
+const SELINUX_FS: &str = "/sys/fs/selinux";
+
+// Creates the `state_dir` with mode 0700 if it is missing, and checks that it is a directory
+// of root (or of the user running the module) no one else can access. The stores in it would
+// otherwise let other local users read or plant cached logins and lockouts.
+pub fn ensure_state_dir(config: &Config) -> Result<(), IOError> {
+    let Some(dir) = &config.state_dir else {
+        return Ok(());
+    };
+    let created = match fs::symlink_metadata(dir) {
+        Ok(_) => false,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+            log::info!("Created state dir {}", dir.display());
+            true
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(problem) = insecure(dir)? {
+        let msg = format!("State dir {} {problem}", dir.display());
+        match config.state_dir_permissions {
+            StateDirPermissions::Enforce => {
+                return Err(IOError::new(ErrorKind::PermissionDenied, msg))
+            }
+            StateDirPermissions::Warn => log::warn!("{msg}"),
+        }
+    }
+    if config.selinux_hints {
+        selinux_hint(dir, created);
+    }
+    Ok(())
+}
+
+fn insecure(dir: &Path) -> Result<Option<String>, IOError> {
+    let metadata = fs::symlink_metadata(dir)?;
+    let euid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() {
+        return Ok(Some("is not a directory".to_string()));
+    }
+    if metadata.uid() != 0 && metadata.uid() != euid {
+        return Ok(Some(format!("is owned by uid {}", metadata.uid())));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Ok(Some(format!(
+            "can be accessed by others (mode {:o}), run chmod 700 on it",
+            metadata.mode() & 0o777
+        )));
+    }
+    Ok(None)
+}
+
+// A directory created by the module inherits the label of its parent, which the policy of
+// e.g. `sshd` may not allow to write. Labels no policy allows are reported as well.
+fn selinux_hint(dir: &Path, created: bool) {
+    if !Path::new(SELINUX_FS).exists() {
+        return;
+    }
+    let context = selinux_context(dir);
+    let mislabeled = context
+        .as_deref()
+        .is_none_or(|c| c.contains(":default_t:") || c.contains(":unlabeled_t:"));
+    if created || mislabeled {
+        log::warn!(
+            "State dir {} has the SELinux context {}. If SELinux denies access to it, label it with: semanage fcontext -a -t var_lib_t '{}(/.*)?' && restorecon -Rv {}",
+            dir.display(),
+            context.as_deref().unwrap_or("unknown"),
+            dir.display(),
+            dir.display()
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn selinux_context(path: &Path) -> Option<String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut buff = [0u8; 256];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"security.selinux".as_ptr(),
+            buff.as_mut_ptr() as *mut libc::c_void,
+            buff.len(),
+        )
+    };
+    let len = usize::try_from(len).ok()?;
+    let context = String::from_utf8_lossy(&buff[..len]);
+    Some(context.trim_end_matches('\0').to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn selinux_context(_path: &Path) -> Option<String> {
+    None
+}
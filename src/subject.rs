@@ -0,0 +1,138 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::logger::LogUser;
+
+// Remembers the `sub` claim each local user authenticated with for the first time.
+// One file per local user, named after the user and containing the pinned subject.
+pub struct SubjectStore {
+    dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubjectCheck {
+    Pinned,
+    Matched,
+    Mismatch(String),
+}
+
+impl SubjectStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn check(&self, local_user: &str, subject: &str) -> Result<SubjectCheck, IOError> {
+        let entry = self.entry(local_user)?;
+        match fs::read_to_string(&entry) {
+            Ok(pinned) if pinned.trim() == subject => Ok(SubjectCheck::Matched),
+            Ok(pinned) => Ok(SubjectCheck::Mismatch(pinned.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => self.pin(&entry, subject),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Subject pinned for `local_user`, if any
+    pub fn pinned(&self, local_user: &str) -> Result<Option<String>, IOError> {
+        match fs::read_to_string(self.entry(local_user)?) {
+            Ok(pinned) => Ok(Some(pinned.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Admin path: forget the subject pinned for a local user, the next login pins a new one.
+    pub fn reset(&self, local_user: &str) -> Result<(), IOError> {
+        match fs::remove_file(self.entry(local_user)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn validate(&self, subject: Option<&str>, local_user: &str) -> bool {
+        let Some(subject) = subject else {
+            log::warn!("No subject provided in token, cannot verify pinned subject");
+            return false;
+        };
+
+        match self.check(local_user, subject) {
+            Ok(SubjectCheck::Matched) => true,
+            Ok(SubjectCheck::Pinned) => {
+                log::info!(
+                    "Pinned subject {} for local user {}",
+                    LogUser(subject),
+                    LogUser(local_user)
+                );
+                true
+            }
+            Ok(SubjectCheck::Mismatch(pinned)) => {
+                log::error!(
+                    "SUBJECT MISMATCH for local user {}: pinned: {} -> token: {}. Possible account reassignment or takeover!",
+                    LogUser(local_user),
+                    LogUser(&pinned),
+                    LogUser(subject)
+                );
+                false
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to verify pinned subject for {}: {}",
+                    LogUser(local_user),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    fn entry(&self, local_user: &str) -> Result<PathBuf, IOError> {
+        if local_user.is_empty() || local_user.starts_with('.') || local_user.contains('/') {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid local username: {}", LogUser(local_user)),
+            ));
+        }
+        Ok(self.dir.join(local_user))
+    }
+
+    fn pin(&self, entry: &Path, subject: &str) -> Result<SubjectCheck, IOError> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+
+        // Write to a temporary file first and hard link it into place, so concurrent
+        // first logins cannot both pin a subject.
+        let tmp = entry.with_file_name(format!(
+            ".{}.{}",
+            entry.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id()
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        let linked = file
+            .write_all(subject.as_bytes())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::hard_link(&tmp, entry));
+        fs::remove_file(&tmp)?;
+
+        match linked {
+            Ok(()) => Ok(SubjectCheck::Pinned),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let pinned = fs::read_to_string(entry)?;
+                if pinned.trim() == subject {
+                    Ok(SubjectCheck::Matched)
+                } else {
+                    Ok(SubjectCheck::Mismatch(pinned.trim().to_string()))
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error as IOError;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Remote identity (username or `sub`) -> local accounts it may log in as
+type Mapping = HashMap<String, Vec<String>>;
+
+struct Loaded {
+    path: PathBuf,
+    modified: SystemTime,
+    mapping: Mapping,
+}
+
+// Parsed map shared by all clients of the process, reloaded when the file changes
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+// Mapping file of remote identities to local accounts, e.g.
+// `{"alice@example.org": ["alice", "alice-admin"]}`
+#[derive(Debug, Clone)]
+pub struct UserMap {
+    path: PathBuf,
+}
+
+impl UserMap {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    // Local accounts allowed for the first of `remote_ids` found in the map,
+    // `None` if none of them is mapped
+    pub fn local_users(&self, remote_ids: &[&str]) -> Result<Option<Vec<String>>, DynErr> {
+        let mut loaded = LOADED.lock().map_err(|_| "User map lock poisoned")?;
+        let modified = modified(&self.path)?;
+        let stale = !matches!(&*loaded, Some(l) if l.path == self.path && l.modified == modified);
+        if stale {
+            log::debug!("Loading user map from {}", self.path.display());
+            let mapping: Mapping = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+            *loaded = Some(Loaded {
+                path: self.path.clone(),
+                modified,
+                mapping,
+            });
+        }
+
+        let mapping = &loaded.as_ref().expect("user map loaded above").mapping;
+        Ok(remote_ids.iter().find_map(|id| mapping.get(*id)).cloned())
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime, IOError> {
+    fs::metadata(path)?.modified()
+}
@@ -0,0 +1,131 @@
+use oauth2::http::header::{HeaderName, ACCEPT, CONTENT_TYPE};
+use oauth2::http::{Method, Request, StatusCode};
+use oauth2::SyncHttpClient;
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::config::{read_secret_file, SecretResolver, VaultAuth, VaultSecret};
+use crate::http::HttpClient;
+
+type DynErr = Box<dyn std::error::Error>;
+
+pub const VAULT_TOKEN: HeaderName = HeaderName::from_static("x-vault-token");
+const VAULT_NAMESPACE: HeaderName = HeaderName::from_static("x-vault-namespace");
+
+// Reads the field of a KV secret (https://developer.hashicorp.com/vault/api-docs/secret/kv),
+// logging in with the token of `token_file` or with an AppRole first. The token of the AppRole
+// login is revoked once the secret is read, as every login logs in again.
+impl SecretResolver for VaultSecret {
+    fn resolve(&self, http_client: &HttpClient) -> Result<String, DynErr> {
+        let (role_id, secret_id_file, mount) = match &self.auth {
+            VaultAuth::Token { token_file } => {
+                return self.read(http_client, &read_secret_file(token_file)?)
+            }
+            VaultAuth::Approle {
+                role_id,
+                secret_id_file,
+                mount,
+            } => (role_id, secret_id_file, mount),
+        };
+        let body = json!({
+            "role_id": role_id,
+            "secret_id": read_secret_file(secret_id_file)?,
+        });
+        let response = self.call(
+            http_client,
+            Method::POST,
+            &format!("auth/{}/login", mount.trim_matches('/')),
+            None,
+            Some(body),
+        )?;
+        let token = response
+            .pointer("/auth/client_token")
+            .and_then(Value::as_str)
+            .ok_or("Vault AppRole login returned no client_token")?;
+
+        let secret = self.read(http_client, token);
+        if let Err(e) = self.call(
+            http_client,
+            Method::POST,
+            "auth/token/revoke-self",
+            Some(token),
+            None,
+        ) {
+            log::warn!("Failed to revoke the Vault AppRole token: {e}");
+        }
+        secret
+    }
+}
+
+impl VaultSecret {
+    fn read(&self, http_client: &HttpClient, token: &str) -> Result<String, DynErr> {
+        let mount = self.mount.trim_matches('/');
+        let path = self.path.trim_matches('/');
+        let (api_path, data) = match self.kv_version {
+            1 => (format!("{mount}/{path}"), "/data"),
+            2 => (format!("{mount}/data/{path}"), "/data/data"),
+            other => return Err(format!("Unsupported Vault KV version {other}").into()),
+        };
+        let response = self.call(http_client, Method::GET, &api_path, Some(token), None)?;
+        response
+            .pointer(data)
+            .and_then(|data| data.get(&self.field))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                format!(
+                    "Vault secret {mount}/{path} has no string field {}",
+                    self.field
+                )
+                .into()
+            })
+    }
+
+    fn call(
+        &self,
+        http_client: &HttpClient,
+        method: Method,
+        api_path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> Result<Value, DynErr> {
+        let base = self.address.as_str().trim_end_matches('/');
+        let url = Url::parse(&format!("{base}/v1/{api_path}"))?;
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(url.as_str())
+            .header(ACCEPT, "application/json");
+        if let Some(token) = token {
+            request = request.header(VAULT_TOKEN, token);
+        }
+        if let Some(namespace) = &self.namespace {
+            request = request.header(VAULT_NAMESPACE, namespace);
+        }
+        let request = match body {
+            Some(body) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&body)?)?,
+            None => request.body(Vec::new())?,
+        };
+
+        let response = http_client.call(request)?;
+        // `revoke-self` answers without a body
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(Value::Null);
+        }
+        if response.status() != StatusCode::OK {
+            // Vault describes the failure in a list of `errors`
+            let errors = serde_json::from_slice::<Value>(response.body())
+                .ok()
+                .and_then(|body| body.get("errors").cloned())
+                .map(|errors| format!(": {errors}"))
+                .unwrap_or_default();
+            return Err(format!(
+                "Vault {method} {url} failed with status {}{errors}",
+                response.status()
+            )
+            .into());
+        }
+        Ok(serde_json::from_slice(response.body())?)
+    }
+}
@@ -0,0 +1,105 @@
+mod utils;
+
+use chrono::{Duration, Utc};
+use oauth2::TokenResponse;
+use pam_oauth2_device::oauth_device::AccountStatus;
+use utils::Mock;
+
+#[test]
+fn account_active() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .init(None);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert!(matches!(
+        oauth_client.check_account(token.access_token(), "test"),
+        AccountStatus::Active
+    ));
+}
+
+#[test]
+fn account_revoked() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(false)
+        .username(Some("test"))
+        .init(None);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert!(matches!(
+        oauth_client.check_account(token.access_token(), "test"),
+        AccountStatus::Expired
+    ));
+}
+
+#[test]
+fn account_expired() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .exp(Some(Utc::now() - Duration::seconds(60)))
+        .init(None);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert!(matches!(
+        oauth_client.check_account(token.access_token(), "test"),
+        AccountStatus::Expired
+    ));
+}
+
+#[test]
+fn account_removed_from_group() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .extra(Some(r#""groups": ["users"]"#))
+        .init_with(None, |c| c.allowed_groups = vec!["hpc".to_string()]);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert!(matches!(
+        oauth_client.check_account(token.access_token(), "test"),
+        AccountStatus::Denied
+    ));
+}
+
+#[test]
+fn account_check_unavailable() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(401);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert!(matches!(
+        oauth_client.check_account(token.access_token(), "test"),
+        AccountStatus::Unavailable(_)
+    ));
+}
@@ -0,0 +1,133 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use mockito::Matcher;
+use pam_oauth2_device::assertion::{ClientAssertion, ASSERTION_TYPE};
+use pam_oauth2_device::config::PrivateKeyJwt;
+use pam_oauth2_device::oauth_device::OAuthClient;
+use serde_json::Value;
+use utils::{mock_config, temp_dir};
+
+// Copy of the test key, git doesn't keep it private
+fn key_file(name: &str, mode: u32) -> PathBuf {
+    let dir = temp_dir(name);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("client.pem");
+    fs::write(&path, include_bytes!("keys/jwt_rs256.pem")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    path
+}
+
+fn private_key_jwt(key_file: PathBuf) -> PrivateKeyJwt {
+    PrivateKeyJwt {
+        key_file,
+        algorithm: Algorithm::RS256,
+        kid: Some("test-key".to_string()),
+        audience: None,
+    }
+}
+
+fn verify(assertion: &str, audience: &str) -> Value {
+    let jwks: JwkSet = serde_json::from_str(include_str!("keys/jwks.json")).unwrap();
+    let key = DecodingKey::from_jwk(&jwks.keys[0]).unwrap();
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&[audience]);
+    decode::<Value>(assertion, &key, &validation)
+        .unwrap()
+        .claims
+}
+
+#[test]
+fn signed_claims() {
+    let c = private_key_jwt(key_file("assertion_signed_claims", 0o600));
+    let client_assertion =
+        ClientAssertion::new("test", "https://idp.example.org/token", &c).unwrap();
+
+    let first = client_assertion.sign().unwrap();
+    assert_eq!(
+        decode_header(&first).unwrap().kid.as_deref(),
+        Some("test-key")
+    );
+    let claims = verify(&first, "https://idp.example.org/token");
+    assert_eq!(claims["iss"], "test");
+    assert_eq!(claims["sub"], "test");
+    assert_eq!(
+        claims["exp"].as_i64().unwrap() - claims["iat"].as_i64().unwrap(),
+        60
+    );
+
+    let second = verify(
+        &client_assertion.sign().unwrap(),
+        "https://idp.example.org/token",
+    );
+    assert_ne!(claims["jti"], second["jti"]);
+}
+
+#[test]
+fn configured_audience() {
+    let c = PrivateKeyJwt {
+        audience: Some("https://idp.example.org".to_string()),
+        ..private_key_jwt(key_file("assertion_audience", 0o600))
+    };
+    let client_assertion =
+        ClientAssertion::new("test", "https://idp.example.org/token", &c).unwrap();
+    verify(&client_assertion.sign().unwrap(), "https://idp.example.org");
+}
+
+#[test]
+fn invalid_keys_rejected() {
+    let c = private_key_jwt(key_file("assertion_world_readable", 0o644));
+    let err = ClientAssertion::new("test", "https://idp.example.org/token", &c).unwrap_err();
+    assert!(err.to_string().contains("world-readable"));
+
+    let c = PrivateKeyJwt {
+        algorithm: Algorithm::HS256,
+        ..private_key_jwt(key_file("assertion_hmac", 0o600))
+    };
+    assert!(ClientAssertion::new("test", "https://idp.example.org/token", &c).is_err());
+
+    let c = PrivateKeyJwt {
+        algorithm: Algorithm::ES256,
+        ..private_key_jwt(key_file("assertion_wrong_family", 0o600))
+    };
+    assert!(ClientAssertion::new("test", "https://idp.example.org/token", &c).is_err());
+}
+
+#[test]
+fn device_request_with_assertion() {
+    let mut server = mockito::Server::new();
+    let mut config = mock_config(&server.url(), None);
+    config.client_secret = String::new();
+    config.private_key_jwt = Some(private_key_jwt(key_file("assertion_device", 0o600)));
+    let device_request = server
+        .mock("POST", "/device")
+        .match_header("authorization", Matcher::Missing)
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("client_id".to_string(), "test".to_string()),
+            Matcher::UrlEncoded(
+                "client_assertion_type".to_string(),
+                ASSERTION_TYPE.to_string(),
+            ),
+            Matcher::Regex("client_assertion=[^&]+".to_string()),
+        ]))
+        .with_status(200)
+        .with_body(
+            r#"{
+            "device_code": "mocking_device_code",
+            "user_code": "mocking_user_code",
+            "verification_uri": "https://mocking.uri/",
+            "expires_in": 3600,
+            "interval": 5
+        }"#,
+        )
+        .create();
+
+    let oauth_client = OAuthClient::new(&config).unwrap();
+    oauth_client.device_code().unwrap();
+    device_request.assert();
+}
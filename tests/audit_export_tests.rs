@@ -0,0 +1,201 @@
+#![cfg(feature = "pam")]
+
+mod utils;
+
+use std::fs;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use mockito::{Matcher, Server};
+use pam_oauth2_device::audit::AuditEvent;
+use pam_oauth2_device::audit_export::AuditExport;
+use pam_oauth2_device::config::AuditExportConfig;
+use serde_json::Value;
+use url::Url;
+use utils::{mock_config, temp_dir};
+
+fn event(local_user: &str) -> AuditEvent {
+    let mut event = AuditEvent::new("sshd", local_user, "10.0.0.1", "ssh", "client");
+    event.finish(Ok(()));
+    event
+}
+
+fn export_config(dir: &Path) -> AuditExportConfig {
+    AuditExportConfig {
+        socket: None,
+        webhook: None,
+        spool_dir: Some(dir.join("spool")),
+        max_spooled: 1000,
+    }
+}
+
+fn spooled(dir: &Path, sink: &str) -> usize {
+    fs::read_dir(dir.join("spool").join(sink))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some())
+        .count()
+}
+
+// Local users of the events sent to the listener so far
+fn received(listener: &UnixListener) -> Vec<String> {
+    listener.set_nonblocking(true).unwrap();
+    let mut users = Vec::new();
+    while let Ok((mut stream, _)) = listener.accept() {
+        stream.set_nonblocking(false).unwrap();
+        let mut line = String::new();
+        stream.read_to_string(&mut line).unwrap();
+        assert!(line.ends_with('\n'));
+        let event: Value = serde_json::from_str(&line).unwrap();
+        users.push(event["local_user"].as_str().unwrap().to_string());
+    }
+    users
+}
+
+#[test]
+fn socket_spools_until_available() {
+    let dir = temp_dir("audit_export_socket");
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("audit.sock");
+    let mut config = mock_config(&"http://localhost".to_string(), None);
+    config.audit_export = Some(AuditExportConfig {
+        socket: Some(socket.clone()),
+        ..export_config(&dir)
+    });
+    let export = AuditExport::new(&config).unwrap();
+
+    // Nobody listens yet
+    assert!(export.export(&event("alice")).is_err());
+    assert!(export.export(&event("bob")).is_err());
+    assert_eq!(spooled(&dir, "socket"), 2);
+
+    let listener = UnixListener::bind(&socket).unwrap();
+    export.export(&event("carol")).unwrap();
+
+    assert_eq!(received(&listener), ["alice", "bob", "carol"]);
+    assert_eq!(spooled(&dir, "socket"), 0);
+}
+
+#[test]
+fn webhook_posts_events() {
+    let dir = temp_dir("audit_export_webhook");
+    let mut server = Server::new();
+    let unavailable = server
+        .mock("POST", "/hook")
+        .with_status(503)
+        .expect(1)
+        .create();
+    let mut config = mock_config(&server.url(), None);
+    config.audit_export = Some(AuditExportConfig {
+        webhook: Some(Url::parse(&format!("{}/hook", server.url())).unwrap()),
+        ..export_config(&dir)
+    });
+    let export = AuditExport::new(&config).unwrap();
+
+    // A single attempt, the spool retries with the next event
+    assert!(export.export(&event("alice")).is_err());
+    unavailable.assert();
+    unavailable.remove();
+    assert_eq!(spooled(&dir, "webhook"), 1);
+
+    let accepted = server
+        .mock("POST", "/hook")
+        .match_header("content-type", "application/json")
+        .match_body(Matcher::PartialJsonString(
+            r#"{"local_user": "alice", "result": "success"}"#.to_string(),
+        ))
+        .with_status(202)
+        .expect(1)
+        .create();
+    let bob = server
+        .mock("POST", "/hook")
+        .match_body(Matcher::PartialJsonString(
+            r#"{"local_user": "bob"}"#.to_string(),
+        ))
+        .with_status(200)
+        .expect(1)
+        .create();
+    export.export(&event("bob")).unwrap();
+
+    accepted.assert();
+    bob.assert();
+    assert_eq!(spooled(&dir, "webhook"), 0);
+}
+
+#[test]
+fn busy_spool_left_to_holder() {
+    let dir = temp_dir("audit_export_busy");
+    let socket = dir.join("audit.sock");
+    fs::create_dir_all(dir.join("spool/socket")).unwrap();
+    let listener = UnixListener::bind(&socket).unwrap();
+    let mut config = mock_config(&"http://localhost".to_string(), None);
+    config.audit_export = Some(AuditExportConfig {
+        socket: Some(socket),
+        ..export_config(&dir)
+    });
+    let export = AuditExport::new(&config).unwrap();
+
+    // Another login delivering the spool
+    let lock = fs::File::create(dir.join("spool/socket/.lock")).unwrap();
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) }, 0);
+    export.export(&event("alice")).unwrap();
+    assert!(received(&listener).is_empty());
+    assert_eq!(spooled(&dir, "socket"), 1);
+
+    drop(lock);
+    export.export(&event("bob")).unwrap();
+    assert_eq!(received(&listener), ["alice", "bob"]);
+    assert_eq!(spooled(&dir, "socket"), 0);
+}
+
+#[test]
+fn insecure_webhook_refused() {
+    let dir = temp_dir("audit_export_insecure");
+    let mut config = mock_config(&"http://localhost".to_string(), None);
+    config.allow_insecure_http = false;
+    config.audit_export = Some(AuditExportConfig {
+        webhook: Some(Url::parse("http://soc.example.org/hook").unwrap()),
+        ..export_config(&dir)
+    });
+    let export = AuditExport::new(&config).unwrap();
+
+    let err = export.export(&event("alice")).unwrap_err();
+    assert!(err.to_string().contains("not an https URL"));
+    assert_eq!(spooled(&dir, "webhook"), 1);
+}
+
+#[test]
+fn spool_drops_oldest() {
+    let dir = temp_dir("audit_export_max_spooled");
+    let mut config = mock_config(&"http://localhost".to_string(), None);
+    config.audit_export = Some(AuditExportConfig {
+        socket: Some(dir.join("missing.sock")),
+        max_spooled: 2,
+        ..export_config(&dir)
+    });
+    let export = AuditExport::new(&config).unwrap();
+
+    for user in ["alice", "bob", "carol"] {
+        assert!(export.export(&event(user)).is_err());
+    }
+
+    let mut users: Vec<String> = fs::read_dir(dir.join("spool").join("socket"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some())
+        .map(|path| {
+            let event: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+            event["local_user"].as_str().unwrap().to_string()
+        })
+        .collect();
+    users.sort();
+    assert_eq!(users, ["bob", "carol"]);
+}
+
+#[test]
+fn disabled_without_config() {
+    let config = mock_config(&"http://localhost".to_string(), None);
+
+    assert!(AuditExport::new(&config).is_none());
+}
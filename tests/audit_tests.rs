@@ -0,0 +1,88 @@
+#![cfg(feature = "pam")]
+
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use pam::constants::PamResultCode;
+use pam_oauth2_device::audit::{AuditEvent, AuditLog, AuditResult, ErrorClass};
+use serde_json::Value;
+use utils::temp_dir;
+
+#[test]
+fn record_events() {
+    let dir = temp_dir("audit_record_events");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+    let audit_log = AuditLog::new(&path);
+
+    let mut event = AuditEvent::new("sshd", "alice", "10.0.0.1", "ssh", "client");
+    event.set_provider("default", "client");
+    event.remote_user = Some("alice@example.org".to_string());
+    event.token_fingerprint = Some("3c469e9d6c5875d3".to_string());
+    assert_eq!(event.finish(Ok(())), PamResultCode::PAM_SUCCESS);
+    audit_log.record(&event).unwrap();
+
+    let mut event = AuditEvent::new("sudo", "bob", "", "pts/1", "client");
+    let code = event.finish(Err((PamResultCode::PAM_AUTH_ERR, ErrorClass::Token)));
+    assert_eq!(code, PamResultCode::PAM_AUTH_ERR);
+    audit_log.record(&event).unwrap();
+
+    let content = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+
+    let success: AuditEvent = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(success.result, AuditResult::Success);
+    assert_eq!(success.error_class, None);
+    assert_eq!(success.remote_user.as_deref(), Some("alice@example.org"));
+    assert_eq!(success.provider.as_deref(), Some("default"));
+    assert_eq!(
+        success.token_fingerprint.as_deref(),
+        Some("3c469e9d6c5875d3")
+    );
+    assert_eq!(success.correlation_id.len(), 16);
+    // SHA-256 of "client"
+    assert_eq!(
+        success.client_id_hash,
+        "948fe603f61dc036b5c596dc09fe3ce3f3d30dc90f024c85f3c82db2ccab679d"
+    );
+
+    let failure: Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(failure["result"], "failure");
+    assert_eq!(failure["error_class"], "token");
+    assert_eq!(failure["local_user"], "bob");
+    assert_eq!(failure["tty"], "pts/1");
+    assert!(failure["remote_user"].is_null());
+    assert!(failure["token_fingerprint"].is_null());
+    // Every attempt has its own correlation ID
+    assert_ne!(failure["correlation_id"], success.correlation_id.as_str());
+
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn ignored_event() {
+    let mut event = AuditEvent::new("sshd", "root", "10.0.0.1", "ssh", "client");
+    let code = event.finish(Err((PamResultCode::PAM_IGNORE, ErrorClass::ExemptUser)));
+
+    assert_eq!(code, PamResultCode::PAM_IGNORE);
+    let event = serde_json::to_value(&event).unwrap();
+    assert_eq!(event["result"], "ignored");
+    assert_eq!(event["error_class"], "exempt_user");
+}
+
+#[test]
+fn offline_event() {
+    let mut event = AuditEvent::new("sshd", "alice", "10.0.0.1", "ssh", "client");
+    event.offline = true;
+    let code = event.finish(Ok(()));
+
+    assert_eq!(code, PamResultCode::PAM_SUCCESS);
+    let event = serde_json::to_value(&event).unwrap();
+    assert_eq!(event["result"], "offline");
+    assert_eq!(event["offline"], true);
+    assert_eq!(event["remote_user"], serde_json::Value::Null);
+}
@@ -0,0 +1,222 @@
+mod utils;
+
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use oauth2::Scope;
+use pam_oauth2_device::azuread::{demangle, expected_scopes, metadata, username};
+use pam_oauth2_device::config::{
+    AzureAdOptions, AzureEndpointVersion, Config, ProviderType, ValidationMode,
+};
+use pam_oauth2_device::oauth_device::{OAuthClient, Validation};
+use serde_json::{json, Value};
+use url::Url;
+use utils::{mock_config, Mock};
+
+const TENANT: &str = "9188040d-6c67-4c5b-b112-36a304b66dad";
+
+fn options(endpoint_version: AzureEndpointVersion) -> AzureAdOptions {
+    AzureAdOptions {
+        tenant: TENANT.to_string(),
+        endpoint_version,
+        resource: None,
+    }
+}
+
+// Signs `claims` with the test key published in `keys/jwks.json`
+fn jwt(claims: &Value) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("test-key".to_string());
+    let key = EncodingKey::from_rsa_pem(include_bytes!("keys/jwt_rs256.pem")).unwrap();
+    encode(&header, claims, &key).unwrap()
+}
+
+fn azuread(c: &mut Config) {
+    c.provider_type = ProviderType::Azuread;
+    c.azuread = Some(options(AzureEndpointVersion::V2));
+    c.validation_mode = ValidationMode::Jwks;
+    c.oauth_token_introspect_url = None;
+    c.jwks_uri = c.oauth_token_url.as_ref().map(|u| u.join("/jwks").unwrap());
+}
+
+fn validate(claims: &Value, local_user: &str) -> Validation {
+    let (mut mock, oauth_client) =
+        Mock::builder().init_with(Some("openid api://pam/login"), azuread);
+    mock.http_device_complete();
+    mock.http_token_with_access_token(&jwt(claims));
+    mock.http_jwks();
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    oauth_client.validate(&token, local_user)
+}
+
+#[test]
+fn demangle_names() {
+    assert_eq!(
+        demangle("alice_contoso.com#EXT#@fabrikam.onmicrosoft.com"),
+        "alice@contoso.com"
+    );
+    assert_eq!(
+        demangle("alice_smith_contoso.com#EXT#@fabrikam.onmicrosoft.com"),
+        "alice_smith@contoso.com"
+    );
+    assert_eq!(demangle("live.com#alice@outlook.com"), "alice@outlook.com");
+    assert_eq!(demangle("alice@fabrikam.com"), "alice@fabrikam.com");
+}
+
+#[test]
+fn username_precedence() {
+    let claims = json!({"preferred_username": "v2", "unique_name": "v1", "upn": "member"});
+    assert_eq!(username(claims.as_object().unwrap()), Some("member"));
+    let claims = json!({"preferred_username": "v2", "unique_name": "v1"});
+    assert_eq!(username(claims.as_object().unwrap()), Some("v1"));
+    assert_eq!(username(json!({}).as_object().unwrap()), None);
+}
+
+#[test]
+fn scopes_of_access_token() {
+    let requested: Vec<Scope> = [
+        "openid",
+        "offline_access",
+        "api://pam/login",
+        "https://graph.microsoft.com/.default",
+    ]
+    .iter()
+    .map(|s| Scope::new(s.to_string()))
+    .collect();
+    assert_eq!(
+        expected_scopes(&requested),
+        vec![Scope::new("login".to_string())]
+    );
+}
+
+#[test]
+fn endpoint_versions() {
+    let v1 = metadata(&options(AzureEndpointVersion::V1));
+    assert_eq!(
+        v1.device_authorization_endpoint.unwrap().as_str(),
+        format!("https://login.microsoftonline.com/{TENANT}/oauth2/devicecode")
+    );
+    assert_eq!(
+        v1.jwks_uri.unwrap().as_str(),
+        format!("https://login.microsoftonline.com/{TENANT}/discovery/keys")
+    );
+
+    let v2 = metadata(&options(AzureEndpointVersion::V2));
+    assert_eq!(
+        v2.token_endpoint.unwrap().as_str(),
+        format!("https://login.microsoftonline.com/{TENANT}/oauth2/v2.0/token")
+    );
+}
+
+#[test]
+fn preset_endpoints() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    azuread(&mut config);
+    config.oauth_auth_url = None;
+    config.oauth_device_url = None;
+    config.oauth_token_url = None;
+    config.jwks_uri = None;
+
+    assert!(OAuthClient::new(&config).is_ok());
+}
+
+#[test]
+fn introspection_unsupported() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    azuread(&mut config);
+    config.validation_mode = ValidationMode::Introspection;
+
+    let err = OAuthClient::new(&config).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Azure AD doesn't support token introspection, set the validation_mode to jwks"
+    );
+}
+
+#[test]
+fn v1_token_of_guest() {
+    let claims = json!({
+        "iss": format!("https://sts.windows.net/{TENANT}/"),
+        "aud": "test",
+        "appid": "test",
+        "unique_name": "alice_contoso.com#EXT#@fabrikam.onmicrosoft.com",
+        "scp": "login",
+        "exp": chrono::Utc::now().timestamp() + 3600,
+    });
+
+    match validate(&claims, "alice@contoso.com") {
+        Validation::Valid(validated) => assert_eq!(validated.username, "alice@contoso.com"),
+        other => panic!("Unexpected validation result: {:?}", other),
+    }
+}
+
+#[test]
+fn v2_token_of_member() {
+    let claims = json!({
+        "iss": format!("https://login.microsoftonline.com/{TENANT}/v2.0"),
+        "aud": "test",
+        "azp": "test",
+        "upn": "bob@fabrikam.com",
+        "preferred_username": "bob.smith@fabrikam.com",
+        "scp": "login",
+        "exp": chrono::Utc::now().timestamp() + 3600,
+    });
+
+    match validate(&claims, "bob@fabrikam.com") {
+        Validation::Valid(validated) => assert_eq!(validated.username, "bob@fabrikam.com"),
+        other => panic!("Unexpected validation result: {:?}", other),
+    }
+}
+
+#[test]
+fn token_of_other_tenant() {
+    let claims = json!({
+        "iss": "https://login.microsoftonline.com/another-tenant/v2.0",
+        "aud": "test",
+        "upn": "bob@fabrikam.com",
+        "scp": "login",
+        "exp": chrono::Utc::now().timestamp() + 3600,
+    });
+
+    assert!(matches!(
+        validate(&claims, "bob@fabrikam.com"),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn v1_numeric_strings() {
+    let mut server = mockito::Server::new();
+    let mut config = mock_config(&server.url(), None);
+    azuread(&mut config);
+    config.azuread = Some(AzureAdOptions {
+        resource: Some("api://pam".to_string()),
+        ..options(AzureEndpointVersion::V1)
+    });
+    let device_request = server
+        .mock("POST", "/device")
+        .match_body(mockito::Matcher::UrlEncoded(
+            "resource".to_string(),
+            "api://pam".to_string(),
+        ))
+        .with_status(200)
+        .with_body(
+            r#"{
+            "device_code": "mocking_device_code",
+            "user_code": "mocking_user_code",
+            "verification_url": "https://microsoft.com/devicelogin",
+            "expires_in": "900",
+            "interval": "5"
+        }"#,
+        )
+        .create();
+
+    let oauth_client = OAuthClient::new(&config).unwrap();
+    let details = oauth_client.device_code().unwrap();
+    assert_eq!(details.expires_in().as_secs(), 900);
+    assert_eq!(
+        details.verification_uri().url(),
+        &Url::parse("https://microsoft.com/devicelogin").unwrap()
+    );
+    device_request.assert();
+}
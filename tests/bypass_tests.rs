@@ -0,0 +1,50 @@
+use pam_oauth2_device::bypass::Cidr;
+use pam_oauth2_device::config::BypassRule;
+use std::net::IpAddr;
+
+fn contains(cidr: &str, addr: &str) -> bool {
+    let cidr: Cidr = cidr.parse().unwrap();
+    cidr.contains(&addr.parse::<IpAddr>().unwrap())
+}
+
+#[test]
+fn networks() {
+    assert!(contains("10.20.0.0/16", "10.20.3.4"));
+    assert!(!contains("10.20.0.0/16", "10.21.3.4"));
+    assert!(contains("192.168.1.128/25", "192.168.1.200"));
+    assert!(!contains("192.168.1.128/25", "192.168.1.100"));
+    assert!(contains("0.0.0.0/0", "203.0.113.9"));
+    assert!(contains("203.0.113.9", "203.0.113.9"));
+    assert!(!contains("203.0.113.9", "203.0.113.10"));
+    assert!(contains("fd00::/8", "fd12:3456::1"));
+    assert!(!contains("fd00::/8", "fe80::1"));
+    // IPv4 clients of a dual-stack socket
+    assert!(contains("10.20.0.0/16", "::ffff:10.20.3.4"));
+    assert!(!contains("::/0", "10.20.3.4"));
+}
+
+#[test]
+fn invalid_networks() {
+    assert!("10.20.0.0/33".parse::<Cidr>().is_err());
+    assert!("fd00::/129".parse::<Cidr>().is_err());
+    assert!("mgmt.example.org/24".parse::<Cidr>().is_err());
+    assert!("10.20.0.0/".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn rules() {
+    let rule: BypassRule =
+        serde_json::from_str(r#"{"rhosts": ["10.20.0.0/16"], "services": ["sshd"]}"#).unwrap();
+    assert!(rule.applies("sshd", "ssh", "10.20.3.4"));
+    assert!(!rule.applies("sshd", "ssh", "198.51.100.7"));
+    assert!(!rule.applies("sudo", "pts/0", "10.20.3.4"));
+    // Host names are never trusted
+    assert!(!rule.applies("sshd", "ssh", "mgmt.example.org"));
+
+    let console: BypassRule = serde_json::from_str(r#"{"ttys": ["tty*", ":0"]}"#).unwrap();
+    assert!(console.applies("login", "tty1", ""));
+    assert!(console.applies("gdm-password", ":0", ""));
+    assert!(!console.applies("sshd", "ssh", "10.20.3.4"));
+
+    assert!(!BypassRule::default().applies("sshd", "ssh", "10.20.3.4"));
+}
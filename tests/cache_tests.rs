@@ -0,0 +1,179 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+use chrono::Utc;
+use pam_oauth2_device::cache::TokenCache;
+use pam_oauth2_device::oauth_device::ValidatedToken;
+use utils::temp_dir;
+
+const CONTEXT: &str = "sudo|pts/1|alice|";
+
+fn validated(expires_in: i64) -> ValidatedToken {
+    ValidatedToken {
+        username: "alice".to_string(),
+        subject: Some("sub-1".to_string()),
+        display_name: "Alice".to_string(),
+        expires_at: Some(Utc::now() + chrono::Duration::seconds(expires_in)),
+        groups: None,
+        email: None,
+        uid: None,
+    }
+}
+
+#[test]
+fn store_and_lookup() {
+    let dir = temp_dir("cache_store_and_lookup");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    let stored = cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+    let entry = cache.lookup("alice", CONTEXT).unwrap().unwrap();
+
+    assert_eq!(entry, stored);
+    assert_eq!(entry.validated().subject.as_deref(), Some("sub-1"));
+    // Only the hash of the token is stored
+    let content = fs::read_to_string(dir.join("alice")).unwrap();
+    assert!(!content.contains("\"access_token\""));
+    assert_eq!(
+        fs::metadata(dir.join("alice"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn policy_inputs_kept() {
+    let dir = temp_dir("cache_policy_inputs");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+    let mut validated = validated(3600);
+    validated.groups = Some(vec!["hpc".to_string()]);
+
+    cache
+        .store("alice", CONTEXT, "access_token", &validated, true)
+        .unwrap();
+
+    let entry = cache.lookup("alice", CONTEXT).unwrap().unwrap();
+    assert_eq!(entry.groups.as_deref(), Some(&["hpc".to_string()][..]));
+    assert!(entry.multi_factor);
+    assert_eq!(entry.validated().groups, validated.groups);
+
+    // Entries of older versions know neither
+    let mut old: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("alice")).unwrap()).unwrap();
+    old.as_object_mut().unwrap().remove("groups");
+    old.as_object_mut().unwrap().remove("multi_factor");
+    fs::write(dir.join("alice"), old.to_string()).unwrap();
+    let entry = cache.lookup("alice", CONTEXT).unwrap().unwrap();
+    assert_eq!(entry.groups, None);
+    assert!(!entry.multi_factor);
+}
+
+#[test]
+fn other_context_misses() {
+    let dir = temp_dir("cache_other_context");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+
+    assert_eq!(cache.lookup("alice", "su|pts/2|mallory|").unwrap(), None);
+    assert_eq!(cache.lookup("bob", CONTEXT).unwrap(), None);
+}
+
+#[test]
+fn any_context_hits() {
+    let dir = temp_dir("cache_any_context");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    let stored = cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+
+    assert_eq!(cache.lookup_any_context("alice").unwrap(), Some(stored));
+    assert_eq!(cache.lookup_any_context("bob").unwrap(), None);
+}
+
+#[test]
+fn capped_by_token_expiry() {
+    let dir = temp_dir("cache_token_expiry");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    let entry = cache
+        .store("alice", CONTEXT, "access_token", &validated(60), false)
+        .unwrap();
+    assert!(entry.expires_at <= Utc::now() + chrono::Duration::seconds(60));
+
+    cache
+        .store("alice", CONTEXT, "access_token", &validated(-1), false)
+        .unwrap();
+    assert_eq!(cache.lookup("alice", CONTEXT).unwrap(), None);
+    assert!(!dir.join("alice").exists());
+}
+
+#[test]
+fn insecure_entry_ignored() {
+    let dir = temp_dir("cache_insecure");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+    fs::set_permissions(dir.join("alice"), fs::Permissions::from_mode(0o644)).unwrap();
+
+    assert_eq!(cache.lookup("alice", CONTEXT).unwrap(), None);
+}
+
+#[test]
+fn expired_entries_cleaned_up() {
+    let dir = temp_dir("cache_cleanup");
+    let cache = TokenCache::new(&dir, Duration::from_secs(300));
+
+    cache
+        .store("bob", CONTEXT, "access_token", &validated(-1), false)
+        .unwrap();
+    cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+
+    assert!(!dir.join("bob").exists());
+    assert!(dir.join("alice").exists());
+}
+
+#[test]
+fn invalid_username() {
+    let cache = TokenCache::new(
+        &temp_dir("cache_invalid_username"),
+        Duration::from_secs(300),
+    );
+
+    assert!(cache.lookup("../etc", CONTEXT).is_err());
+}
+
+#[test]
+fn encrypted_entries() {
+    let dir = temp_dir("cache_encrypted");
+    let key_file = dir.join("cache.key");
+    let cache = TokenCache::new(&dir.join("cache"), Duration::from_secs(300)).encrypted(&key_file);
+
+    let stored = cache
+        .store("alice", CONTEXT, "access_token", &validated(3600), false)
+        .unwrap();
+
+    assert_eq!(cache.lookup("alice", CONTEXT).unwrap(), Some(stored));
+    let sealed = fs::read(dir.join("cache/alice")).unwrap();
+    assert!(!String::from_utf8_lossy(&sealed).contains("sub-1"));
+    // Entries can't be moved to another user
+    fs::copy(dir.join("cache/alice"), dir.join("cache/bob")).unwrap();
+    assert!(cache.lookup("bob", CONTEXT).is_err());
+    // Nor read without the key
+    let plain = TokenCache::new(&dir.join("cache"), Duration::from_secs(300));
+    assert!(plain.lookup("alice", CONTEXT).is_err());
+}
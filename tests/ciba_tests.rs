@@ -0,0 +1,144 @@
+mod utils;
+
+use mockito::Matcher;
+use oauth2::TokenResponse;
+use pam_oauth2_device::config::Flow;
+use pam_oauth2_device::error::ProtocolError;
+use pam_oauth2_device::oauth_device::{Authorization, OAuthClient};
+use utils::Mock;
+
+fn init(scopes: Option<&str>) -> (Mock, OAuthClient) {
+    Mock::builder().init_with(scopes, |c| {
+        c.flow = Flow::Ciba;
+        c.ciba.login_hint = "{username}@example.org".to_string();
+        // The device endpoint is not needed by the ciba flow
+        c.oauth_device_url = None;
+    })
+}
+
+fn http_backchannel(mock: &mut Mock, interval: u64, expires_in: u64) {
+    mock.server
+        .mock("POST", "/bc-authorize")
+        .with_status(200)
+        .with_body(format!(
+            r#"{{
+            "auth_req_id": "mocking_auth_req_id",
+            "expires_in": {expires_in},
+            "interval": {interval}
+        }}"#
+        ))
+        .create();
+}
+
+#[test]
+fn backchannel_request() {
+    let (mut mock, oauth_client) = init(Some("profile"));
+    let backchannel = mock
+        .server
+        .mock("POST", "/bc-authorize")
+        .match_header("authorization", "Basic dGVzdDp0ZXN0")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("scope".into(), "openid profile".into()),
+            Matcher::UrlEncoded("login_hint".into(), "alice@example.org".into()),
+            Matcher::Regex(r"binding_message=\d{6}".into()),
+        ]))
+        .with_status(200)
+        .with_body(r#"{"auth_req_id": "mocking_auth_req_id", "expires_in": 120}"#)
+        .create();
+
+    let Authorization::Ciba(ciba) = oauth_client.authorize("alice").unwrap() else {
+        panic!("Expected a backchannel authentication request");
+    };
+
+    backchannel.assert();
+    assert_eq!(ciba.auth_req_id, "mocking_auth_req_id");
+    assert_eq!(ciba.expires_in, 120);
+    assert_eq!(ciba.interval, None);
+    let binding_message = ciba.binding_message.unwrap();
+    assert_eq!(binding_message.len(), 6);
+    assert!(binding_message.chars().all(|c| c.is_ascii_digit()));
+}
+
+#[test]
+fn token_after_pending() {
+    let (mut mock, oauth_client) = init(None);
+    http_backchannel(&mut mock, 0, 120);
+    mock.http_token_error("authorization_pending", 1);
+    let granted = mock
+        .server
+        .mock("POST", "/token")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded(
+                "grant_type".into(),
+                "urn:openid:params:grant-type:ciba".into(),
+            ),
+            Matcher::UrlEncoded("auth_req_id".into(), "mocking_auth_req_id".into()),
+        ]))
+        .with_status(200)
+        .with_body(
+            r#"{
+            "access_token": "mocking_access_token",
+            "id_token": "mocking_id_token",
+            "token_type": "Bearer",
+            "expires_in": 3600
+        }"#,
+        )
+        .create();
+
+    let ciba = oauth_client.backchannel_authentication("alice").unwrap();
+    let token = oauth_client.ciba_token(&ciba, None).unwrap();
+
+    granted.assert();
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+    assert_eq!(
+        token.extra_fields().id_token.as_deref(),
+        Some("mocking_id_token")
+    );
+}
+
+#[test]
+fn token_expired() {
+    let (mut mock, oauth_client) = init(None);
+    http_backchannel(&mut mock, 0, 120);
+    mock.http_token_error("expired_token", 1);
+
+    let ciba = oauth_client.backchannel_authentication("alice").unwrap();
+    let err = oauth_client.ciba_token(&ciba, None).unwrap_err();
+    assert!(err.is_expired_token());
+}
+
+#[test]
+fn token_expires_in_caps_timeout() {
+    let (mut mock, oauth_client) = init(None);
+    http_backchannel(&mut mock, 1, 1);
+    mock.http_token_error("authorization_pending", 10);
+
+    let ciba = oauth_client.backchannel_authentication("alice").unwrap();
+    let err = oauth_client
+        .ciba_token(&ciba, Some(std::time::Duration::from_secs(600)))
+        .unwrap_err();
+    assert!(err.is_expired_token());
+}
+
+#[test]
+fn token_denied() {
+    let (mut mock, oauth_client) = init(None);
+    http_backchannel(&mut mock, 0, 120);
+    mock.http_token_error("access_denied", 1);
+
+    let ciba = oauth_client.backchannel_authentication("alice").unwrap();
+    let err = oauth_client.ciba_token(&ciba, None).unwrap_err();
+    assert_eq!(err.protocol_error(), Some(&ProtocolError::AccessDenied));
+    assert!(err.to_string().contains("access_denied"), "{err}");
+}
+
+#[test]
+fn device_flow_by_default() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    assert!(matches!(
+        oauth_client.authorize("alice").unwrap(),
+        Authorization::Device(_)
+    ));
+}
@@ -0,0 +1,87 @@
+#![allow(clippy::bool_assert_comparison)]
+
+use pam_oauth2_device::claims::required_claims_met;
+use pam_oauth2_device::config::ClaimRule;
+use serde_json::{json, Value};
+
+fn claims() -> Value {
+    json!({
+        "email_verified": true,
+        "acr": "mfa",
+        "amr": ["pwd", "otp"],
+        "scope": "openid profile",
+        "realm_access": {"roles": ["hpc"]},
+    })
+}
+
+fn met(rules: Value) -> bool {
+    let rules: Vec<ClaimRule> = serde_json::from_value(rules).unwrap();
+    required_claims_met(&rules, &claims(), "test")
+}
+
+#[test]
+fn equality() {
+    assert_eq!(
+        met(json!([{"claim": "email_verified", "operator": "==", "value": true}])),
+        true
+    );
+    assert_eq!(
+        met(json!([{"claim": "email_verified", "operator": "eq", "value": false}])),
+        false
+    );
+    assert_eq!(
+        met(json!([{"claim": "acr", "operator": "!=", "value": "pwd"}])),
+        true
+    );
+}
+
+#[test]
+fn membership() {
+    assert_eq!(
+        met(json!([{"claim": "acr", "operator": "in", "value": ["mfa", "hwk"]}])),
+        true
+    );
+    assert_eq!(
+        met(json!([{"claim": "amr", "operator": "in", "value": ["otp"]}])),
+        true
+    );
+    assert_eq!(
+        met(json!([{"claim": "amr", "operator": "not_in", "value": ["pwd"]}])),
+        false
+    );
+    assert_eq!(
+        met(json!([{"claim": "realm_access.roles", "operator": "contains", "value": "hpc"}])),
+        true
+    );
+    assert_eq!(
+        met(json!([{"claim": "scope", "operator": "contains", "value": "email"}])),
+        false
+    );
+}
+
+#[test]
+fn missing_claim() {
+    assert_eq!(
+        met(json!([{"claim": "groups", "operator": "ne", "value": "banned"}])),
+        false
+    );
+    assert_eq!(
+        met(json!([{"claim": "groups", "operator": "exists", "value": false}])),
+        true
+    );
+    assert_eq!(
+        met(json!([{"claim": "groups", "operator": "exists"}])),
+        false
+    );
+}
+
+#[test]
+fn every_rule_required() {
+    assert_eq!(
+        met(json!([
+            {"claim": "email_verified", "operator": "==", "value": true},
+            {"claim": "acr", "operator": "in", "value": ["hwk"]}
+        ])),
+        false
+    );
+}
@@ -0,0 +1,1116 @@
+mod utils;
+
+use pam_oauth2_device::config::{
+    cached_config, read_config, read_strict_config, AuthMode, Config, Flow, KerberosSource, NoConv,
+    OnUnreachable, PermissionCheck, PromptEcho, Reload, SubjectTokenType, ValidationMode,
+};
+use pam_oauth2_device::oauth_device::OAuthClient;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+use utils::{mock_config, temp_dir};
+
+#[test]
+fn https_endpoints() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.allow_insecure_http = false;
+
+    assert!(OAuthClient::new(&config).is_ok());
+}
+
+#[test]
+fn http_endpoint_rejected() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.allow_insecure_http = false;
+    config.oauth_token_url = Some(Url::parse("http://idp.example.org/token").unwrap());
+
+    let err = OAuthClient::new(&config).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Insecure plain http endpoint oauth_token_url: http://idp.example.org/token (set allow_insecure_http to allow it)"
+    );
+}
+
+#[test]
+fn http_localhost_rejected_without_flag() {
+    let mut config = mock_config(&"http://localhost:8080".to_string(), None);
+    config.allow_insecure_http = false;
+
+    assert!(OAuthClient::new(&config).is_err());
+}
+
+#[test]
+fn http_endpoint_allowed() {
+    let config = mock_config(&"http://localhost:8080".to_string(), None);
+
+    assert!(config.allow_insecure_http);
+    assert!(OAuthClient::new(&config).is_ok());
+}
+
+#[test]
+fn validation_mode_chains() {
+    let chain = |mode: &str| {
+        let config: Config = serde_json::from_str(&format!(
+            r#"{{"client_id": "test", "client_secret": "test", "validation_mode": "{mode}"}}"#
+        ))
+        .unwrap();
+        config.validation_mode.chain()
+    };
+
+    assert_eq!(chain("introspection"), [ValidationMode::Introspection]);
+    assert_eq!(chain("jwks"), [ValidationMode::Jwks]);
+    assert_eq!(
+        chain("jwks_then_introspection"),
+        [ValidationMode::Jwks, ValidationMode::Introspection]
+    );
+    assert_eq!(
+        chain("introspection_then_jwks"),
+        [ValidationMode::Introspection, ValidationMode::Jwks]
+    );
+}
+
+#[test]
+fn env_names_defaults() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "export_env": true,
+        "env_names": {"id_token": ""}
+    }"#,
+    )
+    .unwrap();
+
+    assert!(config.export_env);
+    assert_eq!(config.env_names.access_token, "OAUTH_ACCESS_TOKEN");
+    assert_eq!(config.env_names.id_token, "");
+    assert_eq!(config.env_names.expires_at, "OAUTH_TOKEN_EXPIRY");
+    assert_eq!(config.env_names.downstream_token, "OAUTH_DOWNSTREAM_TOKEN");
+    assert_eq!(config.env_names.ccache, "KRB5CCNAME");
+}
+
+#[test]
+fn token_exchange_options() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "token_exchange": {
+            "audience": "storage-gateway",
+            "scope": ["read", "write"],
+            "subject_token_type": "id_token"
+        }
+    }"#,
+    )
+    .unwrap();
+
+    let exchange = config.token_exchange.unwrap();
+    assert_eq!(exchange.audience, "storage-gateway");
+    assert_eq!(exchange.scopes.as_deref(), Some("read write"));
+    assert_eq!(exchange.subject_token_type, SubjectTokenType::IdToken);
+    assert!(exchange.endpoint.is_none());
+}
+
+#[test]
+fn kerberos_options() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "kerberos": {
+            "command": ["/usr/local/libexec/oidc-kinit", "--realm", "EXAMPLE.ORG"]
+        }
+    }"#,
+    )
+    .unwrap();
+
+    let kerberos = config.kerberos.unwrap();
+    assert_eq!(
+        kerberos.source,
+        KerberosSource::Command(vec![
+            "/usr/local/libexec/oidc-kinit".to_string(),
+            "--realm".to_string(),
+            "EXAMPLE.ORG".to_string()
+        ])
+    );
+    assert_eq!(kerberos.ccache_dir, std::path::PathBuf::from("/tmp"));
+    assert_eq!(kerberos.timeout, std::time::Duration::from_secs(30));
+
+    // Either an endpoint or a command is required
+    assert!(serde_json::from_str::<Config>(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "kerberos": {"ccache_dir": "/run/user"}
+    }"#,
+    )
+    .is_err());
+}
+
+#[test]
+fn provider_configs_in_order() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.providers = serde_json::from_str(
+        r#"[{
+            "name": "backup",
+            "client_secret": "backup_secret",
+            "oauth_device_url": "https://backup.example.org/device",
+            "oauth_token_url": "https://backup.example.org/token"
+        }]"#,
+    )
+    .unwrap();
+
+    let configs = config.provider_configs(None).unwrap();
+    assert_eq!(configs.len(), 2);
+    assert_eq!(configs[0].provider_name, "default");
+    assert_eq!(configs[1].provider_name, "backup");
+    // Client credentials are inherited, endpoints are not
+    assert_eq!(configs[1].client_id, "test");
+    assert_eq!(configs[1].client_secret, "backup_secret");
+    assert_eq!(
+        configs[1].oauth_token_url.as_ref().unwrap().as_str(),
+        "https://backup.example.org/token"
+    );
+    assert!(configs[1].oauth_token_introspect_url.is_none());
+    assert!(configs[1].providers.is_empty());
+}
+
+#[test]
+fn provider_flow() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.providers = serde_json::from_str(
+        r#"[{
+            "name": "push",
+            "flow": "ciba",
+            "backchannel_authentication_endpoint": "https://push.example.org/bc-authorize"
+        }, {
+            "name": "backup"
+        }]"#,
+    )
+    .unwrap();
+
+    let configs = config.provider_configs(None).unwrap();
+    assert_eq!(configs[0].flow, Flow::Device);
+    assert_eq!(configs[1].flow, Flow::Ciba);
+    assert_eq!(
+        configs[1]
+            .oauth_backchannel_auth_url
+            .as_ref()
+            .unwrap()
+            .as_str(),
+        "https://push.example.org/bc-authorize"
+    );
+    // The flow of the top level config is inherited
+    assert_eq!(configs[2].flow, Flow::Device);
+}
+
+#[test]
+fn provider_config_selected_by_name() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.providers = serde_json::from_str(r#"[{"name": "hpc"}, {"name": "backup"}]"#).unwrap();
+
+    let configs = config.provider_configs(Some("backup")).unwrap();
+    assert_eq!(configs.len(), 1);
+    assert_eq!(configs[0].provider_name, "backup");
+
+    let err = config.provider_configs(Some("missing")).err().unwrap();
+    assert_eq!(err.to_string(), "Unknown provider: missing");
+}
+
+#[test]
+fn service_account_config() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), Some("openid"));
+    config.required_scopes = Some(vec!["openid".to_string()]);
+    config.providers = serde_json::from_str(
+        r#"[{
+            "name": "machines",
+            "oauth_token_url": "https://machines.example.org/token"
+        }]"#,
+    )
+    .unwrap();
+    config.service_accounts = serde_json::from_str(
+        r#"{
+            "backup": {"client_id": "backup-job", "client_secret": "s3cret", "scopes": ["backup"]},
+            "report": {"client_id": "report-job", "client_secret": "r3port", "provider": "machines"}
+        }"#,
+    )
+    .unwrap();
+
+    let backup = config
+        .service_account_config(&config.service_accounts["backup"])
+        .unwrap();
+    assert_eq!(backup.provider_name, "default");
+    assert_eq!(backup.client_id, "backup-job");
+    assert_eq!(backup.client_secret, "s3cret");
+    assert_eq!(backup.scopes, "backup");
+    assert!(backup.required_scopes.is_none());
+
+    let report = config
+        .service_account_config(&config.service_accounts["report"])
+        .unwrap();
+    assert_eq!(report.provider_name, "machines");
+    assert_eq!(report.client_id, "report-job");
+    // The scopes of the provider are requested
+    assert_eq!(report.scopes, "openid");
+    assert_eq!(
+        report.oauth_token_url.as_ref().unwrap().as_str(),
+        "https://machines.example.org/token"
+    );
+}
+
+#[test]
+fn service_account_secret_missing() {
+    let dir = temp_dir("service_account_secret_missing");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        r#"{"client_id": "client-id", "client_secret": "test", "service_accounts": {"backup": {"client_id": "backup-job"}}}"#,
+    )
+    .unwrap();
+
+    let err = read_config(path.to_str().unwrap()).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Missing client_secret or client_secret_file (service account backup)"
+    );
+}
+
+#[test]
+fn read_toml_config() {
+    let dir = temp_dir("read_toml_config");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(
+        &path,
+        r#"
+# Comments are the reason to use TOML
+client_id = "client-id"
+client_secret = "client-secret"
+issuer = "https://idp.example.org/realms/test"
+oauth_device_token_polling_timeout = 120
+validation_mode = "jwks_then_introspection"
+allowed_groups = ["admins", "hpc"]
+
+[messages]
+success = "Welcome, {display_name}!"
+
+[[providers]]
+name = "backup"
+issuer = "https://backup.example.org/realms/test"
+"#,
+    )
+    .unwrap();
+
+    let config = read_config(path.to_str().unwrap()).unwrap();
+    assert_eq!(config.client_id, "client-id");
+    assert_eq!(
+        config.issuer.unwrap().as_str(),
+        "https://idp.example.org/realms/test"
+    );
+    assert_eq!(
+        config.oauth_device_token_polling_timeout,
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        config.validation_mode,
+        ValidationMode::JwksThenIntrospection
+    );
+    assert_eq!(config.allowed_groups, ["admins", "hpc"]);
+    assert_eq!(config.messages.success, "Welcome, {display_name}!");
+    // Defaults apply just like for JSON
+    assert_eq!(config.scopes, "openid profile");
+    assert!(config.messages.prompt_enter.starts_with("Press"));
+    assert_eq!(config.providers[0].name, "backup");
+}
+
+#[test]
+fn read_invalid_toml_config() {
+    let dir = temp_dir("read_invalid_toml_config");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(&path, "client_id = \n").unwrap();
+
+    assert!(read_config(path.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn expand_env_in_config() {
+    let dir = temp_dir("expand_env_in_config");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::env::set_var("PAM_OAUTH2_DEVICE_TEST_SECRET", "env-secret");
+    std::env::set_var("PAM_OAUTH2_DEVICE_TEST_REALM", "hpc");
+    std::fs::write(
+        &path,
+        r#"{
+        "client_id": "client-id",
+        "client_secret": "${PAM_OAUTH2_DEVICE_TEST_SECRET}",
+        "issuer": "https://idp.example.org/realms/${PAM_OAUTH2_DEVICE_TEST_REALM}"
+    }"#,
+    )
+    .unwrap();
+
+    let config = read_config(path.to_str().unwrap()).unwrap();
+    assert_eq!(config.client_secret, "env-secret");
+    assert_eq!(
+        config.issuer.unwrap().as_str(),
+        "https://idp.example.org/realms/hpc"
+    );
+}
+
+#[test]
+fn expand_missing_env_in_config() {
+    let dir = temp_dir("expand_missing_env_in_config");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        r#"{"client_id": "client-id", "client_secret": "${PAM_OAUTH2_DEVICE_TEST_UNSET}"}"#,
+    )
+    .unwrap();
+
+    let err = read_config(path.to_str().unwrap()).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Environment variable PAM_OAUTH2_DEVICE_TEST_UNSET referenced in the config is not set"
+    );
+}
+
+#[test]
+fn client_secret_file() {
+    let dir = temp_dir("client_secret_file");
+    std::fs::create_dir_all(&dir).unwrap();
+    let secret_path = dir.join("secret");
+    std::fs::write(&secret_path, "file-secret\n").unwrap();
+    std::fs::set_permissions(&secret_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        format!(
+            r#"{{"client_id": "client-id", "client_secret_file": "{}"}}"#,
+            secret_path.display()
+        ),
+    )
+    .unwrap();
+
+    let config = read_config(path.to_str().unwrap()).unwrap();
+    assert_eq!(config.client_secret, "file-secret");
+
+    // World-readable secret files are rejected
+    std::fs::set_permissions(&secret_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let err = read_config(path.to_str().unwrap()).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        format!("Secret file {} is world-readable", secret_path.display())
+    );
+
+    std::fs::remove_file(&secret_path).unwrap();
+    let err = read_config(path.to_str().unwrap()).err().unwrap();
+    assert!(err.to_string().starts_with(&format!(
+        "Secret file {}: No such file",
+        secret_path.display()
+    )));
+}
+
+#[test]
+fn client_secret_from_systemd_credential() {
+    let dir = temp_dir("client_secret_credential");
+    let credentials = dir.join("credentials");
+    std::fs::create_dir_all(&credentials).unwrap();
+    let credential = credentials.join("oauth-client.secret");
+    std::fs::write(&credential, "credential-secret\n").unwrap();
+    std::fs::set_permissions(&credential, std::fs::Permissions::from_mode(0o400)).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        r#"{"client_id": "client-id", "client_secret_file": "/etc/pam_oauth2_device/oauth-client.secret"}"#,
+    )
+    .unwrap();
+
+    // Passed by systemd, the file itself doesn't exist
+    std::env::set_var("CREDENTIALS_DIRECTORY", &credentials);
+    let res = read_config(path.to_str().unwrap());
+    std::env::remove_var("CREDENTIALS_DIRECTORY");
+
+    assert_eq!(res.unwrap().client_secret, "credential-secret");
+    assert!(read_config(path.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn client_secret_missing() {
+    let dir = temp_dir("client_secret_missing");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(&path, r#"{"client_id": "client-id"}"#).unwrap();
+
+    let err = read_config(path.to_str().unwrap()).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Missing client_secret or client_secret_file"
+    );
+
+    // No secret is needed along with private_key_jwt
+    std::fs::write(
+        &path,
+        r#"{"client_id": "client-id", "private_key_jwt": {"key_file": "/etc/pam_oauth2_device/client.pem", "algorithm": "ES256"}}"#,
+    )
+    .unwrap();
+    let config = read_config(path.to_str().unwrap()).unwrap();
+    assert!(config.client_secret.is_empty());
+    let private_key_jwt = config.private_key_jwt.unwrap();
+    assert_eq!(private_key_jwt.algorithm, jsonwebtoken::Algorithm::ES256);
+    assert_eq!(private_key_jwt.kid, None);
+}
+
+#[test]
+fn system_roots_disabled_without_bundle() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.tls_system_roots = false;
+
+    let err = OAuthClient::new(&config).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "tls_system_roots can only be disabled along with a ca_bundle_path"
+    );
+}
+
+#[test]
+fn on_unreachable_values() {
+    let config = mock_config(&"https://idp.example.org".to_string(), None);
+    assert_eq!(config.on_unreachable, OnUnreachable::Deny);
+    assert_eq!(
+        "ignore".parse::<OnUnreachable>().unwrap(),
+        OnUnreachable::Ignore
+    );
+    assert_eq!(
+        "cached".parse::<OnUnreachable>().unwrap(),
+        OnUnreachable::Cached
+    );
+    assert!("open".parse::<OnUnreachable>().is_err());
+}
+
+#[test]
+fn service_overrides() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "scopes": "openid profile",
+        "cache_ttl": 60,
+        "messages": {"success": "Welcome!", "prompt_enter": "Press Enter"},
+        "services": {
+            "sudo": {
+                "scopes": "openid sudo",
+                "oauth_device_token_polling_timeout": 30,
+                "cache_ttl": 900,
+                "prompt_echo": "info",
+                "messages": {"prompt_enter": "Press Enter to sudo"}
+            }
+        }
+    }"#,
+    )
+    .unwrap();
+
+    let sudo = config.for_service("sudo").unwrap();
+    assert_eq!(sudo.scopes, "openid sudo");
+    assert_eq!(
+        sudo.oauth_device_token_polling_timeout,
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(sudo.cache_ttl, Some(Duration::from_secs(900)));
+    assert_eq!(sudo.prompt_echo, PromptEcho::Info);
+    assert!(!sudo.prompt_echo.expects_input());
+    assert_eq!(sudo.messages.prompt_enter, "Press Enter to sudo");
+    // Messages not overridden are kept
+    assert_eq!(sudo.messages.success, "Welcome!");
+    assert_eq!(sudo.http_read_timeout, config.http_read_timeout);
+
+    let sshd = config.for_service("sshd").unwrap();
+    assert_eq!(sshd.scopes, "openid profile");
+    assert_eq!(sshd.cache_ttl, Some(Duration::from_secs(60)));
+    assert_eq!(sshd.prompt_echo, PromptEcho::Off);
+}
+
+#[test]
+fn homedir_mode() {
+    let config: Config = serde_json::from_str(
+        r#"{"client_id": "test", "client_secret": "test", "create_homedir": true, "homedir_mode": "0750"}"#,
+    )
+    .unwrap();
+    assert!(config.create_homedir);
+    assert_eq!(config.homedir_mode, 0o750);
+    assert_eq!(config.skel_dir, Path::new("/etc/skel"));
+    assert_eq!(
+        serde_json::to_value(&config).unwrap()["homedir_mode"],
+        "0750"
+    );
+
+    let default: Config =
+        serde_json::from_str(r#"{"client_id": "test", "client_secret": "test"}"#).unwrap();
+    assert_eq!(default.homedir_mode, 0o700);
+
+    for invalid in [r#""rwx""#, r#""0800""#, "488"] {
+        assert!(serde_json::from_str::<Config>(&format!(
+            r#"{{"client_id": "test", "client_secret": "test", "homedir_mode": {invalid}}}"#
+        ))
+        .is_err());
+    }
+}
+
+#[test]
+fn failure_messages() {
+    let config: Config = serde_json::from_str(
+        r#"{"client_id": "test", "client_secret": "test", "messages": {"denied": ""}}"#,
+    )
+    .unwrap();
+
+    // An empty message is not sent
+    assert_eq!(config.messages.denied, "");
+    assert!(config.messages.unavailable.contains("unavailable"));
+    assert!(config.messages.failed.contains("failed"));
+}
+
+#[test]
+fn invalid_service_overrides_rejected() {
+    let dir = temp_dir("invalid_service_overrides");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        r#"{"client_id": "test", "client_secret": "test", "services": {"sudo": {"messages": {"success": 42}}}}"#,
+    )
+    .unwrap();
+
+    assert!(read_config(path.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn translations() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "messages": {"success": "Welcome!"},
+        "translations": {
+            "de": {"prompt_enter": "Nach der Anmeldung \"ENTER\" drücken...", "success": "Willkommen!"},
+            "pt_BR": {"success": "Bem-vindo!"}
+        }
+    }"#,
+    )
+    .unwrap();
+
+    let de = config.for_locale("de_AT.UTF-8").unwrap();
+    assert_eq!(de.messages.success, "Willkommen!");
+    assert!(de.messages.prompt_enter.starts_with("Nach der Anmeldung"));
+    // Messages not translated are kept
+    assert_eq!(de.messages.expired, config.messages.expired);
+
+    let pt_br = config.for_locale("pt_BR.UTF-8@variant").unwrap();
+    assert_eq!(pt_br.messages.success, "Bem-vindo!");
+    assert_eq!(pt_br.messages.prompt_enter, config.messages.prompt_enter);
+
+    for locale in ["C.UTF-8", "POSIX", "fr_FR.UTF-8", "pt_PT", ""] {
+        assert_eq!(
+            config.for_locale(locale).unwrap().messages.success,
+            "Welcome!"
+        );
+    }
+}
+
+#[test]
+fn invalid_translations_rejected() {
+    let dir = temp_dir("invalid_translations");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(
+        &path,
+        r#"{"client_id": "test", "client_secret": "test", "translations": {"de": {"success": ["Willkommen!"]}}}"#,
+    )
+    .unwrap();
+
+    assert!(read_config(path.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn pam_arg_overrides() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), Some("openid"));
+    let args: HashMap<String, String> = [
+        ("client_id", "sudo-client"),
+        ("scope", "openid,profile,sudo"),
+        ("timeout", "45"),
+        ("qr", "off"),
+        ("mode", "mfa"),
+        ("no_conv", "terminals"),
+        ("prompt_echo", "on"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    config.apply_args(&args);
+    assert_eq!(config.client_id, "sudo-client");
+    assert_eq!(config.scopes, "openid profile sudo");
+    assert_eq!(
+        config.oauth_device_token_polling_timeout,
+        Some(Duration::from_secs(45))
+    );
+    assert!(!config.qr_enabled);
+    assert_eq!(config.mode, AuthMode::Mfa);
+    assert_eq!(config.no_conv, NoConv::Terminals);
+    assert_eq!(config.prompt_echo, PromptEcho::On);
+}
+
+#[test]
+fn invalid_pam_args_ignored() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), Some("openid"));
+    let timeout = config.oauth_device_token_polling_timeout;
+    let args: HashMap<String, String> = [
+        ("timeout", "soon"),
+        ("qr", "maybe"),
+        ("client_id", ""),
+        ("no_conv", "wall"),
+        ("prompt_echo", "silent"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    config.qr_enabled = true;
+    config.apply_args(&args);
+    assert_eq!(config.oauth_device_token_polling_timeout, timeout);
+    assert!(config.qr_enabled);
+    assert_eq!(config.client_id, "test");
+    assert_eq!(config.no_conv, NoConv::Fail);
+    assert_eq!(config.prompt_echo, PromptEcho::Off);
+}
+
+#[test]
+fn scopes_as_list() {
+    let config: Config = serde_json::from_str(
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "scopes": ["openid", "profile", "offline_access"],
+        "required_scopes": ["profile"],
+        "providers": [{"name": "backup", "scopes": ["openid"]}]
+    }"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.scopes, "openid profile offline_access");
+    assert_eq!(config.required_scopes, Some(vec!["profile".to_string()]));
+    assert_eq!(config.providers[0].scopes.as_deref(), Some("openid"));
+}
+
+fn write_config(name: &str, content: &str) -> String {
+    let dir = temp_dir(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn strict_unknown_keys() {
+    let path = write_config(
+        "strict_unknown_keys",
+        r#"{
+        "client_id": "test",
+        "client_secert": "test",
+        "client_secret": "test",
+        "providers": [{"name": "backup", "scpoes": "openid"}],
+        "http_retry": {"max_attempt": 2}
+    }"#,
+    );
+
+    // Ignored unless strict
+    assert!(read_config(&path).is_ok());
+    assert_eq!(
+        read_strict_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:
+  Unknown key `client_secert`
+  Unknown key `http_retry.max_attempt`
+  Unknown key `providers[0].scpoes`"
+    );
+}
+
+#[test]
+fn strict_enabled_in_file() {
+    let path = write_config(
+        "strict_enabled_in_file",
+        r#"{"strict_config": true, "client_id": "test", "client_secret": "test", "qr_enabeld": false}"#,
+    );
+
+    assert_eq!(
+        read_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:\n  Unknown key `qr_enabeld`"
+    );
+}
+
+#[test]
+fn strict_aliases() {
+    let path = write_config(
+        "strict_aliases",
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "scope": ["openid", "profile"],
+        "userinfo_endpoint": "https://idp.example.org/userinfo",
+        "providers": [{"name": "backup", "scope": "openid"}]
+    }"#,
+    );
+
+    assert!(read_strict_config(&path).is_ok());
+}
+
+#[test]
+fn strict_invalid_values() {
+    let path = write_config(
+        "strict_invalid_values",
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "oauth_token_url": "file:///etc/token",
+        "scopes": "openid \"profile\"",
+        "ca_bundle_path": "/nonexistent/ca.pem"
+    }"#,
+    );
+
+    assert_eq!(
+        read_strict_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:
+  `oauth_token_url` of provider `default` is not an http(s) URL with a host: file:///etc/token
+  Invalid scope \"\\\"profile\\\"\" in `scopes` of provider `default`
+  `ca_bundle_path` /nonexistent/ca.pem does not exist"
+    );
+}
+
+#[test]
+fn strict_url_shortener() {
+    let path = write_config(
+        "strict_url_shortener",
+        r#"{"version": 2, "client_id": "test", "client_secret": "test", "url_shortener": {"endpoint": "http://s.example.org/new?url={url}"}}"#,
+    );
+    assert_eq!(
+        read_strict_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:
+  `url_shortener.endpoint` must be an https URL unless allow_insecure_http is set: http://s.example.org/new?url="
+    );
+
+    let path = write_config(
+        "strict_url_shortener",
+        r#"{"client_id": "test", "client_secret": "test", "url_shortener": {"endpoint": "https://s.example.org/new"}}"#,
+    );
+    assert_eq!(
+        read_strict_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:\n  `url_shortener.endpoint` has no {url} placeholder"
+    );
+}
+
+#[test]
+fn strict_offline_needs_pinning() {
+    let path = write_config(
+        "strict_offline_needs_pinning",
+        r#"{"client_id": "test", "client_secret": "test", "offline_ttl": 604800}"#,
+    );
+    assert_eq!(
+        read_strict_config(&path).err().unwrap().to_string(),
+        "Strict config check failed:\n  `offline_ttl` requires `pin_subject`"
+    );
+
+    let path = write_config(
+        "strict_offline_needs_pinning",
+        r#"{"client_id": "test", "client_secret": "test", "offline_ttl": 604800, "pin_subject": true}"#,
+    );
+    assert!(read_strict_config(&path).is_ok());
+}
+
+#[test]
+fn strict_error_location() {
+    let path = write_config(
+        "strict_error_location",
+        r#"{"client_id": "test", "client_secret": "test", "providers": [{"name": "backup", "flow": "sideways"}]}"#,
+    );
+
+    let err = read_strict_config(&path).err().unwrap().to_string();
+    assert!(
+        err.starts_with("providers[0].flow: unknown variant `sideways`"),
+        "{err}"
+    );
+}
+
+#[test]
+fn cached_config_reload() {
+    let path = write_config(
+        "cached_config_reload",
+        r#"{"client_id": "first", "client_secret": "test"}"#,
+    );
+    let rewrite = |client_id: &str, modified: u64| {
+        std::fs::write(
+            &path,
+            format!(r#"{{"client_id": "{client_id}", "client_secret": "test"}}"#),
+        )
+        .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(modified))
+            .unwrap();
+    };
+    rewrite("first", 1000);
+    assert_eq!(
+        cached_config(&path, Reload::Mtime).unwrap().client_id,
+        "first"
+    );
+
+    // Same size and modification time, the cached config is used
+    rewrite("other", 1000);
+    assert_eq!(
+        cached_config(&path, Reload::Mtime).unwrap().client_id,
+        "first"
+    );
+    assert_eq!(
+        cached_config(&path, Reload::Always).unwrap().client_id,
+        "other"
+    );
+
+    rewrite("third", 2000);
+    assert_eq!(
+        cached_config(&path, Reload::Never).unwrap().client_id,
+        "first"
+    );
+    assert_eq!(
+        cached_config(&path, Reload::Mtime).unwrap().client_id,
+        "third"
+    );
+}
+
+#[test]
+fn config_fragments_merged() {
+    let path = write_config(
+        "config_fragments_merged",
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "messages": {"success": "Hello"},
+        "providers": [{"name": "backup", "client_id": "backup"}]
+    }"#,
+    );
+    let conf_d = std::path::Path::new(&path).with_file_name("conf.d");
+    std::fs::create_dir_all(&conf_d).unwrap();
+    std::fs::write(
+        conf_d.join("20-override.json"),
+        r#"{"client_id": "second", "qr_enabled": false}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        conf_d.join("10-providers.json"),
+        r#"{
+        "client_id": "first",
+        "messages": {"denied": "Go away"},
+        "providers": [{"name": "backup", "scopes": "openid"}, {"name": "third"}]
+    }"#,
+    )
+    .unwrap();
+    std::fs::write(conf_d.join("30-users.toml"), "allowed_users = [\"alice\"]").unwrap();
+    // Neither a fragment
+    std::fs::write(conf_d.join(".40-hidden.json"), "{").unwrap();
+    std::fs::write(conf_d.join("50-notes.txt"), "{").unwrap();
+
+    let config = read_config(&path).unwrap();
+
+    // Applied in lexical order
+    assert_eq!(config.client_id, "second");
+    assert!(!config.qr_enabled);
+    assert_eq!(config.messages.success, "Hello");
+    assert_eq!(config.messages.denied, "Go away");
+    assert_eq!(config.allowed_users, vec!["alice".to_string()]);
+    // Providers are merged by name
+    assert_eq!(config.providers.len(), 2);
+    assert_eq!(config.providers[0].client_id.as_deref(), Some("backup"));
+    assert_eq!(config.providers[0].scopes.as_deref(), Some("openid"));
+    assert_eq!(config.providers[1].name, "third");
+}
+
+#[test]
+fn config_fragment_invalid() {
+    let path = write_config(
+        "config_fragment_invalid",
+        r#"{"client_id": "test", "client_secret": "test"}"#,
+    );
+    let conf_d = std::path::Path::new(&path).with_file_name("conf.d");
+    std::fs::create_dir_all(&conf_d).unwrap();
+    std::fs::write(conf_d.join("10-broken.json"), "{").unwrap();
+
+    let err = read_config(&path).err().unwrap().to_string();
+    assert!(
+        err.starts_with(&format!(
+            "Invalid config fragment {}",
+            conf_d.join("10-broken.json").display()
+        )),
+        "{err}"
+    );
+}
+
+#[test]
+fn cached_config_fragment_changed() {
+    let path = write_config(
+        "cached_config_fragment_changed",
+        r#"{"client_id": "test", "client_secret": "test"}"#,
+    );
+    let conf_d = std::path::Path::new(&path).with_file_name("conf.d");
+    std::fs::create_dir_all(&conf_d).unwrap();
+
+    assert_eq!(
+        cached_config(&path, Reload::Mtime).unwrap().client_id,
+        "test"
+    );
+    std::fs::write(conf_d.join("10-client.json"), r#"{"client_id": "added"}"#).unwrap();
+    assert_eq!(
+        cached_config(&path, Reload::Mtime).unwrap().client_id,
+        "added"
+    );
+}
+
+#[test]
+fn config_version_1_migrated() {
+    let path = write_config(
+        "config_version_1_migrated",
+        r#"{
+        "client_id": "test",
+        "client_secret": "test",
+        "scope": "openid email",
+        "massages": {"prompt_code": "Enter the code:"},
+        "providers": [{"name": "backup", "userinfo_endpoint": "https://idp.example.org/userinfo"}]
+    }"#,
+    );
+
+    let config = read_config(&path).unwrap();
+
+    assert_eq!(config.version, 2);
+    assert_eq!(config.scopes, "openid email");
+    assert_eq!(config.messages.prompt_code, "Enter the code:");
+    assert_eq!(
+        config.providers[0]
+            .oauth_userinfo_url
+            .as_ref()
+            .unwrap()
+            .as_str(),
+        "https://idp.example.org/userinfo"
+    );
+    // The migrated layout passes the strict checks
+    assert!(read_strict_config(&path).is_ok());
+}
+
+#[test]
+fn config_version_1_allows_http() {
+    let path = write_config(
+        "config_version_1_allows_http",
+        r#"{"client_id": "test", "client_secret": "test", "oauth_auth_url": "https://idp.example.org/auth", "oauth_device_url": "https://idp.example.org/device", "oauth_token_url": "http://idp.example.org/token", "oauth_token_introspect_url": "https://idp.example.org/introspect"}"#,
+    );
+    let config = read_config(&path).unwrap();
+    assert!(config.allow_insecure_http);
+    OAuthClient::new(&config).unwrap();
+
+    let path = write_config(
+        "config_version_1_requires_https",
+        r#"{"client_id": "test", "client_secret": "test", "allow_insecure_http": false}"#,
+    );
+    assert!(!read_config(&path).unwrap().allow_insecure_http);
+
+    let path = write_config(
+        "config_version_2_requires_https",
+        r#"{"version": 2, "client_id": "test", "client_secret": "test", "oauth_auth_url": "https://idp.example.org/auth", "oauth_device_url": "https://idp.example.org/device", "oauth_token_url": "http://idp.example.org/token", "oauth_token_introspect_url": "https://idp.example.org/introspect"}"#,
+    );
+    let config = read_config(&path).unwrap();
+    assert!(!config.allow_insecure_http);
+    assert!(OAuthClient::new(&config)
+        .err()
+        .unwrap()
+        .to_string()
+        .starts_with("Insecure plain http endpoint oauth_token_url"));
+}
+
+#[test]
+fn config_version_2_not_migrated() {
+    let path = write_config(
+        "config_version_2_not_migrated",
+        r#"{"version": 2, "client_id": "test", "client_secret": "test", "massages": {"prompt_code": "Enter the code:"}}"#,
+    );
+
+    assert_ne!(
+        read_config(&path).unwrap().messages.prompt_code,
+        "Enter the code:"
+    );
+}
+
+#[test]
+fn config_version_newer() {
+    let path = write_config(
+        "config_version_newer",
+        r#"{"version": 3, "client_id": "test", "client_secret": "test"}"#,
+    );
+
+    assert_eq!(
+        read_config(&path).err().unwrap().to_string(),
+        "Config version 3 is newer than the supported 2"
+    );
+}
+
+#[test]
+fn state_dir_stores() {
+    let path = write_config(
+        "state_dir_stores",
+        r#"{"client_id": "test", "client_secret": "test", "state_dir": "/srv/pam", "lockout_store": "failed", "subject_store": "/var/lib/subjects", "audit_log": "audit.log"}"#,
+    );
+
+    let config = read_config(&path).unwrap();
+
+    assert_eq!(config.cache_dir, Path::new("/srv/pam/cache"));
+    assert_eq!(
+        config.refresh_token_store,
+        Path::new("/srv/pam/refresh_tokens")
+    );
+    assert_eq!(config.session_store, Path::new("/srv/pam/sessions"));
+    // Relative paths are resolved against the state dir, absolute ones are kept
+    assert_eq!(config.lockout_store, Path::new("/srv/pam/failed"));
+    assert_eq!(config.subject_store, Path::new("/var/lib/subjects"));
+    assert_eq!(config.audit_log.unwrap(), Path::new("/srv/pam/audit.log"));
+    // Unchanged without a state dir
+    let config = read_config(&write_config(
+        "state_dir_unset",
+        r#"{"client_id": "test", "client_secret": "test"}"#,
+    ))
+    .unwrap();
+    assert_eq!(config.cache_dir, Path::new("/var/cache/pam_oauth2_device"));
+}
+
+#[test]
+fn config_permission_check() {
+    let path = write_config(
+        "config_permission_check",
+        r#"{"client_id": "test", "client_secret": "test", "permission_check": "enforce"}"#,
+    );
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    let err = read_config(&path).err().unwrap();
+    assert!(err
+        .to_string()
+        .contains("holds a client_secret and can be read by others (mode 644)"));
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    assert!(read_config(&path).is_ok());
+
+    // A secret taken from the environment is not in the file
+    let path = write_config(
+        "config_permission_env",
+        r#"{"client_id": "test", "client_secret": "${PAM_OAUTH2_DEVICE_TEST_PERMISSION_SECRET}", "permission_check": "enforce"}"#,
+    );
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::env::set_var("PAM_OAUTH2_DEVICE_TEST_PERMISSION_SECRET", "test");
+    let config = read_config(&path).unwrap();
+    assert_eq!(config.permission_check, PermissionCheck::Enforce);
+}
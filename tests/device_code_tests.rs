@@ -1,11 +1,14 @@
 mod test_logger;
 mod utils;
-use pam_oauth2_device::config::Messages;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use pam_oauth2_device::config::{Messages, QrErrorCorrection, QrMode, QrOptions};
 use pam_oauth2_device::logger::Logger;
-use pam_oauth2_device::prompt::{qr_code, UserPrompt};
+use pam_oauth2_device::oauth_device::device_code_with_fallback;
+use pam_oauth2_device::prompt::{qr_code, supports_ansi, supports_images, UserPrompt};
 
 use test_logger::{TestLogger, LOGGER};
-use utils::Mock;
+use utils::{mock_config, Mock};
 
 #[test]
 fn device_basic_uri() {
@@ -26,14 +29,14 @@ fn device_basic_uri() {
     // No QR code generated
     assert_eq!(prompt.to_string(), "\nOpen the following link in your web browser:\nhttps://mocking.uri/\nOnce you're in, enter the following code:\nmocking_user_code\nPress \"ENTER\" after successful authentication...");
 
-    prompt.generate_qr();
+    prompt.generate_qr(&QrOptions::default());
 
     // With QR code generated
     assert_eq!(
         prompt.to_string(),
         format!(
             "\n{}\n{}",
-            qr_code(&"https://mocking.uri/".to_string()).unwrap(),
+            qr_code(&"https://mocking.uri/".to_string(), &QrOptions::default()).unwrap(),
             "Scan the QR code above or open the following link in your web browser:\nhttps://mocking.uri/\nOnce you're in, enter the following code:\nmocking_user_code\nPress \"ENTER\" after successful authentication..."
         )
     );
@@ -64,13 +67,13 @@ fn device_uri_complete() {
         "\nOpen the following link in your web browser:\nhttps://mocking.uri/mocking_user_code\nPress \"ENTER\" after successful authentication..."
     );
 
-    prompt.generate_qr();
+    prompt.generate_qr(&QrOptions::default());
     // With QR code generated
     assert_eq!(
         prompt.to_string(),
         format!(
             "\n{}\nScan the QR code above or open the following link in your web browser:\nhttps://mocking.uri/mocking_user_code\nPress \"ENTER\" after successful authentication...",
-            qr_code(&"https://mocking.uri/mocking_user_code".to_string()).unwrap()
+            qr_code(&"https://mocking.uri/mocking_user_code".to_string(), &QrOptions::default()).unwrap()
         )
     );
 }
@@ -93,9 +96,336 @@ fn err_500_device() {
 
     let resp = oauth_client.device_code();
     assert!(resp.is_err());
-    let _ = resp.map_err(|err| TestLogger::handle_error(err, "Failed to get device code"));
+    let _ = resp.map_err(|err| TestLogger::handle_error(err.into(), "Failed to get device code"));
     assert_eq!(
         logger.msg(),
         "Failed to get device code\n    caused by: Server returned error response: 500 Internal Server Error"
     );
 }
+
+#[test]
+fn device_uri_complete_ignored() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_complete();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let mut prompt = UserPrompt::new(&resp, &Messages::default());
+    prompt.ignore_verification_uri_complete();
+
+    assert_eq!(prompt.to_string(), "\nOpen the following link in your web browser:\nhttps://mocking.uri/\nOnce you're in, enter the following code:\nmocking_user_code\nPress \"ENTER\" after successful authentication...");
+
+    prompt.generate_qr(&QrOptions::default());
+    // The QR code doesn't embed the user code either
+    assert_eq!(
+        prompt.to_string(),
+        format!(
+            "\n{}\n{}",
+            qr_code(&"https://mocking.uri/".to_string(), &QrOptions::default()).unwrap(),
+            "Scan the QR code above or open the following link in your web browser:\nhttps://mocking.uri/\nOnce you're in, enter the following code:\nmocking_user_code\nPress \"ENTER\" after successful authentication..."
+        )
+    );
+}
+
+#[test]
+fn device_prompt_template() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let messages = Messages {
+        prompt_template: Some(
+            "{qr}|{username}: {verification_uri} {user_code} ({expires_in_minutes} min)"
+                .to_string(),
+        ),
+        ..Messages::default()
+    };
+    let mut prompt = UserPrompt::new(&resp, &messages);
+    prompt.set_username("test");
+
+    // No QR code generated
+    assert_eq!(
+        prompt.to_string(),
+        "|test: https://mocking.uri/ mocking_user_code (60 min)"
+    );
+
+    prompt.generate_qr(&QrOptions::default());
+    assert_eq!(
+        prompt.to_string(),
+        format!(
+            "{}|test: https://mocking.uri/ mocking_user_code (60 min)",
+            qr_code(&"https://mocking.uri/".to_string(), &QrOptions::default()).unwrap()
+        )
+    );
+}
+
+#[test]
+fn device_prompt_mfa() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let defaults = Messages::default();
+    let messages = Messages {
+        prompt_template: Some(defaults.prompt_mfa.clone()),
+        ..defaults
+    };
+    assert_eq!(
+        UserPrompt::new(&resp, &messages).to_string(),
+        "Second factor: open https://mocking.uri/ and enter the code mocking_user_code, then press \"ENTER\"..."
+    );
+}
+
+#[test]
+fn device_prompt_template_complete() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_complete();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let messages = Messages {
+        prompt_template: Some("Open {verification_uri}".to_string()),
+        ..Messages::default()
+    };
+    let mut prompt = UserPrompt::new(&resp, &messages);
+    assert_eq!(
+        prompt.to_string(),
+        "Open https://mocking.uri/mocking_user_code"
+    );
+
+    prompt.ignore_verification_uri_complete();
+    assert_eq!(prompt.to_string(), "Open https://mocking.uri/");
+}
+
+#[test]
+fn device_prompt_split() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let mut prompt = UserPrompt::new(&resp, &Messages::default());
+    let (info, input) = prompt.split();
+    assert_eq!(
+        info,
+        [
+            "Open the following link in your web browser:",
+            "https://mocking.uri/",
+            "Once you're in, enter the following code:",
+            "mocking_user_code"
+        ]
+    );
+    assert_eq!(input, "Press \"ENTER\" after successful authentication...");
+
+    prompt.generate_qr(&QrOptions::default());
+    let (info, _) = prompt.split();
+    assert_eq!(info.len(), 5);
+    assert_eq!(
+        info[0],
+        qr_code(&"https://mocking.uri/".to_string(), &QrOptions::default()).unwrap()
+    );
+}
+
+#[test]
+fn device_prompt_template_split() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_complete();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let messages = Messages {
+        prompt_template: Some("Open\n{verification_uri}\nPress Enter".to_string()),
+        ..Messages::default()
+    };
+    let prompt = UserPrompt::new(&resp, &messages);
+    let (info, input) = prompt.split();
+    assert_eq!(info, ["Open\nhttps://mocking.uri/mocking_user_code"]);
+    assert_eq!(input, "Press Enter");
+}
+
+#[test]
+fn device_prompt_notification() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    // Without the input prompt, nobody can press Enter
+    let prompt = UserPrompt::new(&resp, &Messages::default());
+    assert_eq!(
+        prompt.notification(),
+        "Open the following link in your web browser:\nhttps://mocking.uri/\nOnce you're in, enter the following code:\nmocking_user_code"
+    );
+
+    let messages = Messages {
+        prompt_template: Some("Open {verification_uri}\nand enter {user_code}".to_string()),
+        ..Messages::default()
+    };
+    let prompt = UserPrompt::new(&resp, &messages);
+    assert_eq!(
+        prompt.notification(),
+        "Open https://mocking.uri/\nand enter mocking_user_code"
+    );
+}
+
+#[test]
+fn device_prompt_styled() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+
+    let resp = oauth_client.device_code().unwrap();
+
+    let mut prompt = UserPrompt::new(&resp, &Messages::default());
+    prompt.enable_styling();
+    assert_eq!(
+        prompt.to_string(),
+        "\n\x1b[2mOpen the following link in your web browser:\x1b[0m\n\x1b[36mhttps://mocking.uri/\x1b[0m\n\x1b[2mOnce you're in, enter the following code:\x1b[0m\n\x1b[1mmocking_user_code\x1b[0m\n\x1b[2mPress \"ENTER\" after successful authentication...\x1b[0m"
+    );
+
+    // The QR code is left alone
+    prompt.generate_qr(&QrOptions::default());
+    let qr = qr_code(&"https://mocking.uri/".to_string(), &QrOptions::default()).unwrap();
+    assert!(prompt.to_string().starts_with(&format!("\n{qr}\n\x1b[2m")));
+    let (info, input) = prompt.split();
+    assert_eq!(info[0], qr);
+    assert_eq!(info[4], "\x1b[1mmocking_user_code\x1b[0m");
+    assert!(input.starts_with("\x1b[2m"));
+
+    let messages = Messages {
+        prompt_template: Some("{verification_uri} {user_code}".to_string()),
+        ..Messages::default()
+    };
+    let mut prompt = UserPrompt::new(&resp, &messages);
+    prompt.enable_styling();
+    assert_eq!(
+        prompt.to_string(),
+        "\x1b[36mhttps://mocking.uri/\x1b[0m \x1b[1mmocking_user_code\x1b[0m"
+    );
+}
+
+#[test]
+fn ansi_support() {
+    assert!(supports_ansi(Some("xterm-256color"), false));
+    // Unknown while authenticating
+    assert!(supports_ansi(None, false));
+    assert!(!supports_ansi(Some("dumb"), false));
+    assert!(!supports_ansi(Some("xterm"), true));
+}
+
+#[test]
+fn device_provider_fallback() {
+    let (mut primary, _) = Mock::builder().init(None);
+    let (mut backup, _) = Mock::builder().init(None);
+    primary
+        .server
+        .mock("POST", "/device")
+        .with_status(500)
+        .create();
+    backup.http_device_basic();
+
+    let mut backup_config = mock_config(&backup.server.url(), None);
+    backup_config.provider_name = "backup".to_string();
+    let providers = [mock_config(&primary.server.url(), None), backup_config];
+
+    let (provider, _, resp) = device_code_with_fallback(&providers).unwrap();
+    assert_eq!(provider.provider_name, "backup");
+    assert_eq!(resp.user_code().secret(), "mocking_user_code");
+
+    // Without a fallback the error is returned
+    assert!(device_code_with_fallback(&providers[..1]).is_err());
+}
+
+#[test]
+fn qr_code_options() {
+    let url = "https://mocking.uri/".to_string();
+    let options = QrOptions {
+        mode: QrMode::Ascii,
+        quiet_zone: 1,
+        ..QrOptions::default()
+    };
+    let ascii = qr_code(&url, &options).unwrap();
+    let rows: Vec<&str> = ascii.lines().collect();
+    // 25 modules of a version 2 code plus the quiet zone, two characters per module
+    assert_eq!(rows.len(), 27);
+    assert!(rows.iter().all(|row| row.len() == 54));
+    // The quiet zone is light, which is drawn unless inverted
+    assert_eq!(rows[0], "#".repeat(54));
+
+    let inverted = qr_code(
+        &url,
+        &QrOptions {
+            invert: true,
+            ..options
+        },
+    )
+    .unwrap();
+    assert_eq!(inverted.lines().next().unwrap(), " ".repeat(54));
+
+    let high = qr_code(
+        &url,
+        &QrOptions {
+            error_correction: QrErrorCorrection::High,
+            ..options
+        },
+    )
+    .unwrap();
+    assert!(high.lines().count() > rows.len());
+}
+
+#[test]
+fn qr_code_images() {
+    let url = "https://mocking.uri/".to_string();
+    let options = |mode| QrOptions {
+        mode,
+        quiet_zone: 1,
+        scale: 2,
+        ..QrOptions::default()
+    };
+    // 27 modules with the quiet zone, 2 pixels each
+    let pixels: usize = 54;
+
+    let sixel = qr_code(&url, &options(QrMode::Sixel)).unwrap();
+    assert!(sixel.starts_with(&format!("\x1bP0;1q\"1;1;{pixels};{pixels}")));
+    assert!(sixel.ends_with("\x1b\\"));
+    // One line of sixels per 6 rows of pixels
+    assert_eq!(sixel.matches('-').count(), pixels.div_ceil(6));
+
+    let kitty = qr_code(&url, &options(QrMode::Kitty)).unwrap();
+    assert!(kitty.starts_with(&format!("\x1b_Ga=T,f=24,s={pixels},v={pixels},q=2,m=1;")));
+    let data: String = kitty
+        .split("\x1b\\")
+        .filter(|escape| !escape.is_empty())
+        .map(|escape| escape.split_once(';').unwrap().1)
+        .collect();
+    let rgb = STANDARD.decode(data).unwrap();
+    assert_eq!(rgb.len(), pixels * pixels * 3);
+    // The quiet zone is white
+    assert_eq!(rgb[..6], [255; 6]);
+    assert!(kitty.contains(",m=0;") || kitty.contains("Gm=0;"));
+
+    let iterm2 = qr_code(&url, &options(QrMode::Iterm2)).unwrap();
+    let (header, data) = iterm2.split_once(':').unwrap();
+    let bmp = STANDARD.decode(data.strip_suffix('\x07').unwrap()).unwrap();
+    assert_eq!(
+        header,
+        format!(
+            "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1",
+            bmp.len()
+        )
+    );
+    assert_eq!(&bmp[..2], b"BM");
+    // Rows are padded to 4 bytes
+    assert_eq!(bmp.len(), 54 + (pixels * 3).div_ceil(4) * 4 * pixels);
+}
+
+#[test]
+fn image_support() {
+    assert!(supports_images(Some("xterm-kitty")));
+    // Unknown while authenticating
+    assert!(supports_images(None));
+    assert!(!supports_images(Some("linux")));
+    assert!(!supports_images(Some("screen-256color")));
+    assert!(!supports_images(Some("tmux-256color")));
+}
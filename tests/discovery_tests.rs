@@ -0,0 +1,115 @@
+mod utils;
+
+use mockito::Server;
+use pam_oauth2_device::oauth_device::OAuthClient;
+use url::Url;
+use utils::{discovery_body, mock_config};
+
+fn discovery_config(url: &String) -> pam_oauth2_device::config::Config {
+    let mut config = mock_config(url, None);
+    config.issuer = Some(Url::parse(url).unwrap());
+    config.oauth_auth_url = None;
+    config.oauth_device_url = None;
+    config.oauth_token_url = None;
+    config.oauth_token_introspect_url = None;
+    config
+}
+
+#[test]
+fn discovered_endpoints() {
+    let mut server = Server::new();
+    let discovery = server
+        .mock("GET", "/.well-known/openid-configuration")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(discovery_body(&server.url()))
+        .create();
+    let device = server
+        .mock("POST", "/device")
+        .with_status(200)
+        .with_body(
+            r#"{
+            "device_code": "mocking_device_code",
+            "user_code": "mocking_user_code",
+            "verification_uri": "https://mocking.uri/",
+            "expires_in": 3600
+        }"#,
+        )
+        .create();
+
+    let client = OAuthClient::new(&discovery_config(&server.url())).unwrap();
+    client.device_code().unwrap();
+
+    discovery.assert();
+    device.assert();
+}
+
+#[test]
+fn configured_endpoint_overrides_discovered() {
+    let mut server = Server::new();
+    server
+        .mock("GET", "/.well-known/openid-configuration")
+        .with_status(200)
+        .with_body(discovery_body(&server.url()))
+        .create();
+    let device = server
+        .mock("POST", "/custom/device")
+        .with_status(200)
+        .with_body(
+            r#"{
+            "device_code": "mocking_device_code",
+            "user_code": "mocking_user_code",
+            "verification_uri": "https://mocking.uri/",
+            "expires_in": 3600
+        }"#,
+        )
+        .create();
+
+    let mut config = discovery_config(&server.url());
+    config.oauth_device_url = Some(Url::parse(&format!("{}/custom/device", server.url())).unwrap());
+    let client = OAuthClient::new(&config).unwrap();
+    client.device_code().unwrap();
+
+    device.assert();
+}
+
+#[test]
+fn issuer_mismatch_rejected() {
+    let mut server = Server::new();
+    server
+        .mock("GET", "/.well-known/openid-configuration")
+        .with_status(200)
+        .with_body(discovery_body("https://evil.example.org"))
+        .create();
+
+    let err = OAuthClient::new(&discovery_config(&server.url())).unwrap_err();
+    assert!(err.to_string().starts_with("Discovered issuer"));
+}
+
+#[test]
+fn missing_endpoint_rejected() {
+    let mut server = Server::new();
+    server
+        .mock("GET", "/.well-known/openid-configuration")
+        .with_status(200)
+        .with_body(format!(r#"{{"issuer": "{}"}}"#, server.url()))
+        .create();
+
+    let err = OAuthClient::new(&discovery_config(&server.url())).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No oauth_auth_url configured or discovered"
+    );
+}
+
+#[test]
+fn no_issuer_and_no_endpoint() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.oauth_token_url = None;
+
+    let err = OAuthClient::new(&config).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No oauth_token_url configured or discovered"
+    );
+}
@@ -0,0 +1,102 @@
+mod utils;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use mockito::Request;
+use oauth2::{RefreshToken, TokenResponse};
+use pam_oauth2_device::dpop::DpopKey;
+use serde_json::Value;
+use utils::Mock;
+
+fn verify(proof: &str) -> Value {
+    let header = decode_header(proof).unwrap();
+    assert_eq!(header.typ.as_deref(), Some("dpop+jwt"));
+    let key = DecodingKey::from_jwk(&header.jwk.unwrap()).unwrap();
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    decode::<Value>(proof, &key, &validation).unwrap().claims
+}
+
+// Claims of the proof sent with `request`, without checking the signature
+fn proof_claims(request: &Request) -> Option<Value> {
+    let proof = request.header("dpop").first()?.to_str().ok()?.to_string();
+    let payload = URL_SAFE_NO_PAD.decode(proof.split('.').nth(1)?).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+#[test]
+fn proof_claims_signed() {
+    let dpop_key = DpopKey::generate().unwrap();
+
+    let first = verify(
+        &dpop_key
+            .proof("POST", "https://idp.example.org/token?a=b", Some("nonce"))
+            .unwrap(),
+    );
+    assert_eq!(first["htm"], "POST");
+    assert_eq!(first["htu"], "https://idp.example.org/token");
+    assert_eq!(first["nonce"], "nonce");
+    assert!(first["iat"].is_i64());
+
+    let second = verify(
+        &dpop_key
+            .proof("POST", "https://idp.example.org/token", None)
+            .unwrap(),
+    );
+    assert_ne!(first["jti"], second["jti"]);
+    assert!(second.get("nonce").is_none());
+}
+
+#[test]
+fn key_restored() {
+    let dpop_key = DpopKey::generate().unwrap();
+    let restored = DpopKey::from_pkcs8(dpop_key.pkcs8()).unwrap();
+
+    assert_eq!(restored.jwk(), dpop_key.jwk());
+    assert!(DpopKey::from_pkcs8(b"not a key").is_err());
+}
+
+#[test]
+fn token_request_with_nonce() {
+    let (mut mock, mut oauth_client) = Mock::builder().init_with(None, |c| c.dpop = true);
+    let dpop_key = DpopKey::generate().unwrap();
+    oauth_client.set_dpop_key(dpop_key.clone());
+    let jwk = dpop_key.jwk().clone();
+
+    let challenge = mock
+        .server
+        .mock("POST", "/token")
+        .match_request(|request| {
+            proof_claims(request).is_some_and(|claims| claims.get("nonce").is_none())
+        })
+        .with_status(400)
+        .with_header("DPoP-Nonce", "server-nonce")
+        .with_body(r#"{"error": "use_dpop_nonce"}"#)
+        .create();
+    let grant = mock
+        .server
+        .mock("POST", "/token")
+        .match_request(|request| {
+            proof_claims(request).is_some_and(|claims| claims["nonce"] == "server-nonce")
+        })
+        .with_status(200)
+        .with_body(
+            r#"{
+        "access_token": "dpop_bound_access_token",
+        "token_type": "DPoP",
+        "expires_in": 86400
+            }"#,
+        )
+        .create();
+
+    let token = oauth_client
+        .refresh_token(&RefreshToken::new("stored_refresh_token".to_string()))
+        .unwrap();
+
+    challenge.assert();
+    grant.assert();
+    assert_eq!(token.access_token().secret(), "dpop_bound_access_token");
+    assert_eq!(oauth_client.dpop_key().unwrap().jwk(), &jwk);
+}
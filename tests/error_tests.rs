@@ -0,0 +1,56 @@
+mod utils;
+
+use pam_oauth2_device::error::{OAuthDeviceError, ProtocolError};
+use pam_oauth2_device::oauth_device::OAuthClient;
+use utils::{mock_config, Mock};
+
+#[test]
+fn access_denied_is_protocol_error() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_complete();
+    mock.http_token_error("access_denied", 1);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let err = oauth_client.get_token(&device_details, None).unwrap_err();
+    assert_eq!(err.protocol_error(), Some(&ProtocolError::AccessDenied));
+    assert!(!err.is_expired_token());
+    // The server response is still displayed as before
+    assert!(err.to_string().contains("access_denied"), "{err}");
+}
+
+#[test]
+fn unknown_error_code_is_protocol_error() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_complete();
+    mock.http_token_error("invalid_client", 1);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let err = oauth_client.get_token(&device_details, None).unwrap_err();
+    assert_eq!(
+        err.protocol_error(),
+        Some(&ProtocolError::Other("invalid_client".to_string()))
+    );
+}
+
+#[test]
+fn invalid_provider_is_config_error() {
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.tls_system_roots = false;
+    config.ca_bundle_path = None;
+
+    let err = OAuthClient::new(&config).unwrap_err();
+    assert!(matches!(err, OAuthDeviceError::Config(_)), "{err}");
+}
+
+#[test]
+fn protocol_error_codes() {
+    assert_eq!(
+        ProtocolError::from("authorization_pending"),
+        ProtocolError::AuthorizationPending
+    );
+    assert_eq!(ProtocolError::from("slow_down"), ProtocolError::SlowDown);
+    assert_eq!(
+        ProtocolError::from("expired_token"),
+        ProtocolError::ExpiredToken
+    );
+}
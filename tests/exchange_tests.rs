@@ -0,0 +1,158 @@
+mod utils;
+
+use log::{LevelFilter, Log, Record};
+use mockito::Matcher;
+use oauth2::TokenResponse;
+use pam_oauth2_device::config::{SubjectTokenType, TokenExchange};
+use pam_oauth2_device::oauth_device::{DeviceTokenResponse, OAuthClient};
+use std::sync::{Arc, Mutex};
+use utils::Mock;
+
+fn exchange(subject_token_type: SubjectTokenType) -> TokenExchange {
+    TokenExchange {
+        endpoint: None,
+        audience: "storage-gateway".to_string(),
+        resource: None,
+        scopes: Some("read write".to_string()),
+        subject_token_type,
+        requested_token_type: None,
+    }
+}
+
+fn init(subject_token_type: SubjectTokenType) -> (Mock, OAuthClient) {
+    Mock::builder().init_with(None, |c| {
+        c.token_exchange = Some(exchange(subject_token_type))
+    })
+}
+
+fn token(id_token: Option<&str>) -> DeviceTokenResponse {
+    let mut token = serde_json::json!({
+        "access_token": "mocking_access_token",
+        "token_type": "Bearer",
+        "expires_in": 3600
+    });
+    if let Some(id_token) = id_token {
+        token["id_token"] = id_token.into();
+    }
+    serde_json::from_value(token).unwrap()
+}
+
+#[test]
+fn exchange_access_token() {
+    let (mut mock, oauth_client) = init(SubjectTokenType::AccessToken);
+    let exchange = mock
+        .server
+        .mock("POST", "/token")
+        .match_header("authorization", "Basic dGVzdDp0ZXN0")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded(
+                "grant_type".into(),
+                "urn:ietf:params:oauth:grant-type:token-exchange".into(),
+            ),
+            Matcher::UrlEncoded("subject_token".into(), "mocking_access_token".into()),
+            Matcher::UrlEncoded(
+                "subject_token_type".into(),
+                "urn:ietf:params:oauth:token-type:access_token".into(),
+            ),
+            Matcher::UrlEncoded("audience".into(), "storage-gateway".into()),
+            Matcher::UrlEncoded("scope".into(), "read write".into()),
+        ]))
+        .with_status(200)
+        .with_body(
+            r#"{
+        "access_token": "downstream_token",
+        "issued_token_type": "urn:ietf:params:oauth:token-type:access_token",
+        "token_type": "Bearer",
+        "expires_in": 300
+            }"#,
+        )
+        .create();
+
+    let exchanged = oauth_client.exchange_token(&token(None)).unwrap();
+
+    exchange.assert();
+    assert_eq!(exchanged.access_token.secret(), "downstream_token");
+    assert_eq!(exchanged.expires_in, Some(300));
+}
+
+#[test]
+fn exchange_id_token() {
+    let (mut mock, oauth_client) = init(SubjectTokenType::IdToken);
+    let exchange = mock
+        .server
+        .mock("POST", "/token")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("subject_token".into(), "mocking_id_token".into()),
+            Matcher::UrlEncoded(
+                "subject_token_type".into(),
+                "urn:ietf:params:oauth:token-type:id_token".into(),
+            ),
+        ]))
+        .with_status(200)
+        .with_body(r#"{"access_token": "downstream_token", "token_type": "N_A"}"#)
+        .create();
+
+    let token = token(Some("mocking_id_token"));
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+    oauth_client.exchange_token(&token).unwrap();
+    exchange.assert();
+
+    let err = oauth_client.exchange_token(&self::token(None)).unwrap_err();
+    assert_eq!(err.to_string(), "No id_token granted to exchange");
+}
+
+#[test]
+fn exchange_rejected() {
+    let (mut mock, oauth_client) = init(SubjectTokenType::AccessToken);
+    mock.server
+        .mock("POST", "/token")
+        .with_status(400)
+        .with_body(r#"{"error": "invalid_target"}"#)
+        .create();
+
+    let err = oauth_client.exchange_token(&token(None)).unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("failed with status 400 Bad Request: invalid_target"));
+}
+
+// Keeps the messages of the module, mockito logs the requests it receives as well
+#[derive(Default)]
+struct Lines(Mutex<Vec<String>>);
+
+impl Log for Lines {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("pam_oauth2_device")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn exchange_wire_debug_redacted() {
+    let lines = Arc::new(Lines::default());
+    log::set_boxed_logger(Box::new(lines.clone())).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+    let (mut mock, oauth_client) = Mock::builder().init_with(None, |c| {
+        c.token_exchange = Some(exchange(SubjectTokenType::AccessToken));
+        c.wire_debug = true;
+    });
+    mock.server
+        .mock("POST", "/token")
+        .with_status(200)
+        .with_body(r#"{"access_token": "downstream_token", "token_type": "Bearer"}"#)
+        .create();
+
+    oauth_client.exchange_token(&token(None)).unwrap();
+
+    let lines = lines.0.lock().unwrap().join("\n");
+    assert!(lines.contains("subject_token=[redacted]"), "{lines}");
+    assert!(!lines.contains("mocking_access_token"), "{lines}");
+    assert!(!lines.contains("downstream_token"), "{lines}");
+}
@@ -0,0 +1,49 @@
+mod utils;
+
+use pam_oauth2_device::error::OAuthDeviceError;
+use pam_oauth2_device::flow::DeviceFlow;
+use utils::{mock_config, Mock};
+
+fn init(username: &str) -> (Mock, DeviceFlow) {
+    let (mut mock, _) = Mock::builder()
+        .username(Some(username))
+        .scope(Some("openid profile"))
+        .init(Some("openid profile"));
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+    let config = mock_config(&mock.server.url(), Some("openid profile"));
+    (mock, DeviceFlow::new(config))
+}
+
+#[test]
+fn login() {
+    let (_mock, flow) = init("test");
+
+    let pending = flow.start("test").unwrap();
+    assert_eq!(pending.provider().provider_name, "default");
+    assert!(pending
+        .prompt()
+        .contains("https://mocking.uri/mocking_user_code"));
+    assert!(!pending.notification().contains("ENTER"));
+
+    let login = pending.finish().unwrap();
+    assert_eq!(login.provider, "default");
+    assert_eq!(login.validated.username, "test");
+}
+
+#[test]
+fn login_denied() {
+    let (_mock, flow) = init("mallory");
+
+    let err = flow.start("test").unwrap().finish().unwrap_err();
+    assert!(matches!(err, OAuthDeviceError::Denied), "{err}");
+}
+
+#[test]
+fn unknown_provider() {
+    let (_mock, flow) = init("test");
+
+    let err = flow.with_provider("missing").start("test").err().unwrap();
+    assert_eq!(err.to_string(), "Unknown provider: missing");
+}
@@ -0,0 +1,27 @@
+use pam_oauth2_device::glob::{matches, matches_any};
+
+#[test]
+fn wildcards() {
+    assert!(matches("root", "root"));
+    assert!(!matches("root", "rooted"));
+    assert!(matches("svc-*", "svc-backup"));
+    assert!(matches("svc-*", "svc-"));
+    assert!(!matches("svc-*", "admin"));
+    assert!(matches("*@example.org", "alice@example.org"));
+    assert!(!matches("*@example.org", "alice@example.org.evil"));
+    assert!(matches("user??", "user01"));
+    assert!(!matches("user??", "user1"));
+    assert!(matches("a*b*c", "aXbYbZc"));
+    assert!(matches("*", ""));
+    assert!(!matches("?", ""));
+    // No character classes
+    assert!(matches("[ab]", "[ab]"));
+}
+
+#[test]
+fn any_pattern() {
+    let patterns = vec!["root".to_string(), "svc-*".to_string()];
+    assert!(matches_any(&patterns, "svc-web"));
+    assert!(!matches_any(&patterns, "alice"));
+    assert!(!matches_any(&[], "alice"));
+}
@@ -0,0 +1,127 @@
+mod utils;
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use pam_oauth2_device::config::{Config, GroupSyncConfig};
+use pam_oauth2_device::group_sync::GroupSync;
+use utils::{mock_config, temp_dir};
+
+fn sync_config(gpasswd: PathBuf, remove: bool, dry_run: bool) -> Config {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.group_sync = Some(GroupSyncConfig {
+        map: HashMap::from([
+            (
+                "admins".to_string(),
+                vec!["wheel".to_string(), "docker".to_string()],
+            ),
+            ("devs".to_string(), vec!["docker".to_string()]),
+            ("hpc".to_string(), vec!["slurm".to_string()]),
+        ]),
+        remove,
+        dry_run,
+        gpasswd,
+    });
+    config
+}
+
+fn strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+// Stand-in for gpasswd appending its arguments to `calls`
+fn fake_gpasswd(dir: &Path) -> PathBuf {
+    fs::create_dir_all(dir).unwrap();
+    let path = dir.join("gpasswd");
+    fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\n",
+            dir.join("calls").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn plan_adds_mapped_groups() {
+    let config = sync_config(PathBuf::from("/bin/false"), false, false);
+    let sync = GroupSync::new(&config).unwrap();
+
+    let changes = sync.plan(
+        &strings(&["admins", "devs", "unmapped"]),
+        &strings(&["users", "docker", "slurm"]),
+    );
+    assert_eq!(changes.added, ["wheel"]);
+    // Only removed with `remove`
+    assert!(changes.removed.is_empty());
+    assert!(!changes.dry_run);
+
+    assert!(sync
+        .plan(&strings(&["devs"]), &strings(&["docker"]))
+        .is_empty());
+}
+
+#[test]
+fn plan_removes_left_groups() {
+    let config = sync_config(PathBuf::from("/bin/false"), true, false);
+    let sync = GroupSync::new(&config).unwrap();
+
+    let changes = sync.plan(
+        &strings(&["devs"]),
+        &strings(&["users", "wheel", "docker", "slurm"]),
+    );
+    assert!(changes.added.is_empty());
+    // Local groups not in the map are kept
+    assert_eq!(changes.removed, ["slurm", "wheel"]);
+}
+
+#[test]
+fn sync_runs_gpasswd() {
+    let dir = temp_dir("group_sync_runs_gpasswd");
+    let config = sync_config(fake_gpasswd(&dir), true, false);
+
+    let changes = GroupSync::new(&config)
+        .unwrap()
+        .sync("root", &strings(&["hpc"]))
+        .unwrap();
+    assert_eq!(changes.added, ["slurm"]);
+    assert_eq!(
+        fs::read_to_string(dir.join("calls")).unwrap(),
+        "-a root -- slurm\n"
+    );
+}
+
+#[test]
+fn sync_dry_run() {
+    let dir = temp_dir("group_sync_dry_run");
+    let config = sync_config(fake_gpasswd(&dir), false, true);
+
+    let changes = GroupSync::new(&config)
+        .unwrap()
+        .sync("root", &strings(&["admins"]))
+        .unwrap();
+    assert_eq!(changes.added, ["docker", "wheel"]);
+    assert!(changes.dry_run);
+    assert!(!dir.join("calls").exists());
+}
+
+#[test]
+fn sync_failed_change_left_out() {
+    let config = sync_config(PathBuf::from("/bin/false"), false, false);
+
+    let changes = GroupSync::new(&config)
+        .unwrap()
+        .sync("root", &strings(&["hpc"]))
+        .unwrap();
+    assert!(changes.is_empty());
+
+    assert!(GroupSync::new(&config)
+        .unwrap()
+        .sync("no-such-user-pam-oauth2", &strings(&["hpc"]))
+        .is_err());
+}
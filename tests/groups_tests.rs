@@ -0,0 +1,22 @@
+use pam_oauth2_device::groups::{home_dir, local_groups, member_of_any, user_name};
+
+#[test]
+fn groups_of_root() {
+    // Primary group of root on every Linux system
+    assert!(local_groups("root").unwrap().contains(&"root".to_string()));
+    assert!(member_of_any("root", &["legacyauth".to_string(), "root".to_string()]).unwrap());
+    assert!(!member_of_any("root", &["legacyauth".to_string()]).unwrap());
+}
+
+#[test]
+fn unknown_user() {
+    let err = local_groups("no-such-user-pam-oauth2").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn passwd_of_root() {
+    assert_eq!(home_dir("root").unwrap(), std::path::Path::new("/root"));
+    assert_eq!(user_name(0).unwrap().as_deref(), Some("root"));
+    assert_eq!(user_name(4_000_000_000).unwrap(), None);
+}
@@ -0,0 +1,245 @@
+mod utils;
+
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use oauth2::RefreshToken;
+use pam_oauth2_device::cache::{CacheEntry, TokenCache};
+use pam_oauth2_device::helper::{listen, HelperServer, Request, Response};
+use pam_oauth2_device::oauth_device::ValidatedToken;
+use pam_oauth2_device::refresh::RefreshStore;
+use utils::{mock_config, temp_dir};
+
+// Peers may only access the entries of their own user, unless they are root
+fn current_user() -> String {
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    unsafe { CStr::from_ptr((*passwd).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Starts a helper with its state in a temporary directory, returning its socket
+fn start_helper(name: &str) -> (PathBuf, PathBuf) {
+    let dir = temp_dir(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.cache_dir = dir.join("cache");
+    config.refresh_token_store = dir.join("refresh_tokens");
+    config.refresh_token_key = dir.join("refresh_token.key");
+    let socket = dir.join("helper.sock");
+
+    let listener = listen(&socket).unwrap();
+    std::thread::spawn(move || HelperServer::new(&config).serve(&listener));
+    (dir, socket)
+}
+
+#[test]
+fn cache_via_helper() {
+    let (dir, socket) = start_helper("helper_cache");
+    let cache = TokenCache::new(&dir.join("unused"), Duration::from_secs(60)).via_helper(&socket);
+    let user = current_user();
+    let validated = ValidatedToken {
+        username: "alice".to_string(),
+        subject: Some("sub-1".to_string()),
+        display_name: "Alice".to_string(),
+        expires_at: Some(Utc::now() + chrono::Duration::seconds(600)),
+        groups: None,
+        email: None,
+        uid: None,
+    };
+
+    let stored = cache
+        .store(&user, "sshd", "token", &validated, false)
+        .unwrap();
+
+    assert_eq!(cache.lookup(&user, "sshd").unwrap(), Some(stored.clone()));
+    assert_eq!(cache.lookup(&user, "sudo").unwrap(), None);
+    assert_eq!(cache.lookup_any_context(&user).unwrap(), Some(stored));
+    // Kept by the helper, not by the module
+    assert!(dir.join("cache").join(&user).exists());
+    assert!(!dir.join("unused").exists());
+
+    cache.remove(&user).unwrap();
+    assert_eq!(cache.lookup(&user, "sshd").unwrap(), None);
+}
+
+#[test]
+fn refresh_token_via_helper() {
+    let (dir, socket) = start_helper("helper_refresh");
+    let store = RefreshStore::new(&dir.join("unused"), &dir.join("unused.key")).via_helper(&socket);
+    let user = current_user();
+    let token = RefreshToken::new("mocking_refresh_token".to_string());
+
+    store
+        .store_with_dpop_key(&user, "sshd", &token, Some(b"dpop-key"))
+        .unwrap();
+
+    let (loaded, dpop_key) = store.load_with_dpop_key(&user).unwrap().unwrap();
+    assert_eq!(loaded.secret(), "mocking_refresh_token");
+    assert_eq!(dpop_key.as_deref(), Some(&b"dpop-key"[..]));
+    assert!(store.load_for_context(&user, "sshd").unwrap().is_some());
+    assert!(store.load_for_context(&user, "sudo").unwrap().is_none());
+    assert!(dir.join("refresh_token.key").exists());
+    assert!(!dir.join("unused.key").exists());
+
+    store.remove(&user).unwrap();
+    assert!(store.load(&user).unwrap().is_none());
+}
+
+#[test]
+fn invalid_request() {
+    let (_, socket) = start_helper("helper_invalid_request");
+    let mut stream = UnixStream::connect(&socket).unwrap();
+
+    stream
+        .write_all(b"{\"op\": \"drop_everything\"}\n")
+        .unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut buff = String::new();
+    stream.read_to_string(&mut buff).unwrap();
+
+    assert!(matches!(
+        serde_json::from_str(&buff).unwrap(),
+        Response::Error { .. }
+    ));
+}
+
+#[test]
+fn helper_unreachable() {
+    let dir = temp_dir("helper_unreachable");
+    let cache =
+        TokenCache::new(&dir, Duration::from_secs(60)).via_helper(&dir.join("missing.sock"));
+
+    assert!(cache.lookup(&current_user(), "sshd").is_err());
+}
+
+#[test]
+fn store_needs_root() {
+    let dir = temp_dir("helper_store_needs_root");
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.cache_dir = dir.join("cache");
+    config.refresh_token_store = dir.join("refresh_tokens");
+    config.refresh_token_key = dir.join("refresh_token.key");
+    let server = HelperServer::new(&config);
+    let nobody = unsafe { (*libc::getpwnam(c"nobody".as_ptr())).pw_uid };
+    let entry = CacheEntry {
+        token_hash: "hash".to_string(),
+        context: "sudo".to_string(),
+        username: "nobody".to_string(),
+        subject: None,
+        display_name: "nobody".to_string(),
+        groups: None,
+        multi_factor: false,
+        cached_at: Utc::now(),
+        expires_at: Utc::now() + chrono::Duration::seconds(600),
+    };
+
+    // Users could plant a cached login or a refresh token of their own choosing
+    let response = server.respond(
+        nobody,
+        Request::CacheStore {
+            local_user: "nobody".to_string(),
+            entry: entry.clone(),
+        },
+    );
+    assert!(matches!(response, Response::Error { .. }));
+    let response = server.respond(
+        nobody,
+        Request::RefreshStore {
+            local_user: "nobody".to_string(),
+            context: "sudo".to_string(),
+            refresh_token: "planted".to_string(),
+            dpop_key: None,
+        },
+    );
+    assert!(matches!(response, Response::Error { .. }));
+    assert!(!dir.join("cache").join("nobody").exists());
+
+    let response = server.respond(
+        0,
+        Request::CacheStore {
+            local_user: "nobody".to_string(),
+            entry,
+        },
+    );
+    assert_eq!(response, Response::Ok);
+    let response = server.respond(
+        nobody,
+        Request::CacheLookup {
+            local_user: "nobody".to_string(),
+            context: Some("sudo".to_string()),
+        },
+    );
+    assert!(matches!(response, Response::CacheEntry { entry: Some(_) }));
+    let response = server.respond(
+        nobody,
+        Request::CacheRemove {
+            local_user: "root".to_string(),
+        },
+    );
+    assert!(matches!(response, Response::Error { .. }));
+}
+
+#[test]
+fn refresh_load_needs_root() {
+    let dir = temp_dir("helper_refresh_load_needs_root");
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.cache_dir = dir.join("cache");
+    config.refresh_token_store = dir.join("refresh_tokens");
+    config.refresh_token_key = dir.join("refresh_token.key");
+    let server = HelperServer::new(&config);
+    let nobody = unsafe { (*libc::getpwnam(c"nobody".as_ptr())).pw_uid };
+
+    let response = server.respond(
+        0,
+        Request::RefreshStore {
+            local_user: "nobody".to_string(),
+            context: "sudo".to_string(),
+            refresh_token: "mocking_refresh_token".to_string(),
+            dpop_key: Some("ZHBvcC1rZXk".to_string()),
+        },
+    );
+    assert_eq!(response, Response::Ok);
+
+    // The refresh token and its DPoP key stay with the helper, even for their own user
+    for context in [Some("sudo".to_string()), None] {
+        let response = server.respond(
+            nobody,
+            Request::RefreshLoad {
+                local_user: "nobody".to_string(),
+                context,
+            },
+        );
+        assert!(matches!(response, Response::Error { .. }));
+    }
+    let response = server.respond(
+        0,
+        Request::RefreshLoad {
+            local_user: "nobody".to_string(),
+            context: None,
+        },
+    );
+    assert!(matches!(
+        response,
+        Response::RefreshToken {
+            refresh_token: Some(_),
+            dpop_key: Some(_),
+        }
+    ));
+}
+
+#[test]
+fn stalled_connection() {
+    let (dir, socket) = start_helper("helper_stalled_connection");
+    // Never sends its request
+    let _stalled = UnixStream::connect(&socket).unwrap();
+    let store = RefreshStore::new(&dir.join("unused"), &dir.join("unused.key")).via_helper(&socket);
+
+    let started = std::time::Instant::now();
+    assert!(store.load(&current_user()).unwrap().is_none());
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,107 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use pam_oauth2_device::homedir::create_home_at;
+use utils::temp_dir;
+
+fn mode(path: &Path) -> u32 {
+    fs::symlink_metadata(path).unwrap().mode() & 0o7777
+}
+
+#[test]
+fn home_from_skel() {
+    let dir = temp_dir("homedir_from_skel");
+    let skel = dir.join("skel");
+    fs::create_dir_all(skel.join(".config/app")).unwrap();
+    fs::write(skel.join(".bashrc"), "alias ll='ls -l'\n").unwrap();
+    fs::set_permissions(skel.join(".bashrc"), fs::Permissions::from_mode(0o644)).unwrap();
+    fs::write(skel.join(".config/app/secret"), "key").unwrap();
+    fs::set_permissions(
+        skel.join(".config/app/secret"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(".bashrc", skel.join(".profile")).unwrap();
+
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let home = dir.join("home/alice");
+    assert!(create_home_at(&home, &skel, uid, gid, 0o750).unwrap());
+
+    assert_eq!(mode(&home), 0o750);
+    assert_eq!(fs::metadata(&home).unwrap().uid(), uid);
+    assert_eq!(
+        fs::read_to_string(home.join(".bashrc")).unwrap(),
+        "alias ll='ls -l'\n"
+    );
+    assert_eq!(mode(&home.join(".bashrc")), 0o644);
+    assert_eq!(mode(&home.join(".config/app/secret")), 0o600);
+    assert_eq!(
+        fs::read_link(home.join(".profile")).unwrap(),
+        Path::new(".bashrc")
+    );
+    assert_eq!(mode(&dir.join("home")), 0o755);
+}
+
+#[test]
+fn existing_home_kept() {
+    let dir = temp_dir("homedir_existing");
+    let home = dir.join("alice");
+    fs::create_dir_all(&home).unwrap();
+    fs::write(home.join("notes"), "mine").unwrap();
+
+    assert!(!create_home_at(&home, Path::new("/etc/skel"), 0, 0, 0o700).unwrap());
+    assert_eq!(fs::read_to_string(home.join("notes")).unwrap(), "mine");
+}
+
+#[test]
+fn missing_skel() {
+    let dir = temp_dir("homedir_missing_skel");
+    let home = dir.join("alice");
+
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    assert!(create_home_at(&home, &dir.join("skel"), uid, gid, 0o700).unwrap());
+    assert_eq!(fs::read_dir(&home).unwrap().count(), 0);
+    assert_eq!(mode(&home), 0o700);
+}
+
+#[test]
+fn failed_copy_removed() {
+    let dir = temp_dir("homedir_failed_copy");
+    let skel = dir.join("skel");
+    fs::create_dir_all(skel.join(".config")).unwrap();
+    fs::write(skel.join(".config/large"), "x".repeat(4096)).unwrap();
+    let home = dir.join("home/alice");
+
+    // Fails the copy of the file, whoever runs the tests. In a child, as the limit is process wide.
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        let res = std::panic::catch_unwind(|| unsafe {
+            libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+            let limit = libc::rlimit {
+                rlim_cur: 1024,
+                rlim_max: libc::RLIM_INFINITY,
+            };
+            assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limit), 0);
+            let uid = libc::getuid();
+            let gid = libc::getgid();
+            assert!(create_home_at(&home, &skel, uid, gid, 0o700).is_err());
+        });
+        unsafe { libc::_exit(i32::from(res.is_err())) }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+    // Nothing left behind, the next session tries again
+    assert!(fs::symlink_metadata(&home).is_err());
+    assert_eq!(fs::read_dir(dir.join("home")).unwrap().count(), 0);
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    assert!(create_home_at(&home, &skel, uid, gid, 0o700).unwrap());
+    assert_eq!(fs::read(home.join(".config/large")).unwrap().len(), 4096);
+}
@@ -0,0 +1,284 @@
+mod utils;
+
+use mockito::Server;
+use pam_oauth2_device::config::{HttpEndpoint, RetryConfig};
+use pam_oauth2_device::error::OAuthDeviceError;
+use pam_oauth2_device::http::{
+    get_json, is_unreachable, redact_body, trusted_cas, HttpClient, Timeouts,
+};
+use pam_oauth2_device::oauth_device::OAuthClient;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+use url::Url;
+use utils::{mock_config, temp_dir};
+
+#[test]
+fn redact_json_body() {
+    let body =
+        br#"{"access_token":"secret","token_type":"Bearer","nested":{"refresh_token":"secret"}}"#;
+
+    assert_eq!(
+        redact_body(body),
+        r#"{"access_token":"[redacted]","nested":{"refresh_token":"[redacted]"},"token_type":"Bearer"}"#
+    );
+}
+
+#[test]
+fn redact_form_body() {
+    let body = b"token=secret&token_type_hint=access_token&client_secret=secret";
+
+    assert_eq!(
+        redact_body(body),
+        "token=[redacted]&token_type_hint=access_token&client_secret=[redacted]"
+    );
+}
+
+#[test]
+fn redact_unparsable_body() {
+    assert_eq!(
+        redact_body(b"not a form body"),
+        "[unparsable body, 15 bytes]"
+    );
+    assert_eq!(redact_body(b""), "");
+}
+
+#[test]
+fn retry_transient_status() {
+    let mut server = Server::new();
+    let unavailable = server
+        .mock("GET", "/doc")
+        .with_status(503)
+        .expect(2)
+        .create();
+    let ok = server
+        .mock("GET", "/doc")
+        .with_status(200)
+        .with_body(r#"{"ok": true}"#)
+        .expect(1)
+        .create();
+    let http_client = HttpClient::new(&mock_config(&server.url(), None));
+
+    let doc: Value = get_json(&http_client, &doc_url(&server)).unwrap();
+    assert_eq!(doc["ok"], true);
+    unavailable.assert();
+    ok.assert();
+}
+
+#[test]
+fn retry_gives_up() {
+    let mut server = Server::new();
+    let failing = server
+        .mock("GET", "/doc")
+        .with_status(429)
+        .expect(3)
+        .create();
+    let http_client = HttpClient::new(&mock_config(&server.url(), None));
+
+    assert!(get_json::<Value>(&http_client, &doc_url(&server)).is_err());
+    failing.assert();
+}
+
+#[test]
+fn no_retry_on_client_error() {
+    let mut server = Server::new();
+    let not_found = server
+        .mock("GET", "/doc")
+        .with_status(404)
+        .expect(1)
+        .create();
+    let http_client = HttpClient::new(&mock_config(&server.url(), None));
+
+    assert!(get_json::<Value>(&http_client, &doc_url(&server)).is_err());
+    not_found.assert();
+}
+
+#[test]
+fn retry_backoff() {
+    let retry = RetryConfig {
+        max_attempts: 5,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_secs(1),
+        jitter: false,
+    };
+    assert_eq!(retry.backoff(1, None), Duration::from_millis(100));
+    assert_eq!(retry.backoff(3, None), Duration::from_millis(400));
+    assert_eq!(retry.backoff(10, None), Duration::from_secs(1));
+    // `Retry-After` is honored up to the max backoff
+    assert_eq!(
+        retry.backoff(1, Some(Duration::from_millis(300))),
+        Duration::from_millis(300)
+    );
+    assert_eq!(
+        retry.backoff(1, Some(Duration::from_secs(60))),
+        Duration::from_secs(1)
+    );
+
+    let jittered = RetryConfig {
+        jitter: true,
+        ..retry
+    }
+    .backoff(1, None);
+    assert!(jittered >= Duration::from_millis(50) && jittered <= Duration::from_millis(100));
+}
+
+fn doc_url(server: &Server) -> Url {
+    Url::parse(&format!("{}/doc", server.url())).unwrap()
+}
+
+#[test]
+fn endpoint_timeouts() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.http_endpoint_timeouts = serde_json::from_str(r#"{"token": {"read": 120}}"#).unwrap();
+    let mut http_client = HttpClient::new(&config);
+    http_client.register_endpoint(
+        HttpEndpoint::Token,
+        &Url::parse("https://idp.example.org/token").unwrap(),
+    );
+    // No override configured
+    http_client.register_endpoint(
+        HttpEndpoint::Device,
+        &Url::parse("https://idp.example.org/device").unwrap(),
+    );
+
+    let defaults = Timeouts {
+        connect: Duration::from_secs(10),
+        read: Duration::from_secs(30),
+        total: Duration::from_secs(60),
+    };
+    assert_eq!(
+        http_client.timeouts("https://idp.example.org/token?x=1"),
+        Timeouts {
+            read: Duration::from_secs(120),
+            ..defaults
+        }
+    );
+    assert_eq!(
+        http_client.timeouts("https://idp.example.org/device"),
+        defaults
+    );
+}
+
+#[test]
+fn read_timeout() {
+    // Accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/doc", listener.local_addr().unwrap())).unwrap();
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.http_read_timeout = Duration::from_secs(1);
+    config.http_retry.max_attempts = 1;
+    let http_client = HttpClient::new(&config);
+
+    let start = Instant::now();
+    assert!(get_json::<Value>(&http_client, &url).is_err());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    drop(listener);
+}
+
+#[test]
+fn total_timeout() {
+    // Answers a byte every half second, never stalling long enough for the read timeout
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/doc", listener.local_addr().unwrap())).unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        while stream.write_all(b" ").is_ok() {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    });
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.http_read_timeout = Duration::from_secs(2);
+    config.http_total_timeout = Duration::from_secs(1);
+    config.http_retry.max_attempts = 1;
+    let http_client = HttpClient::new(&config);
+
+    let start = Instant::now();
+    assert!(get_json::<Value>(&http_client, &url).is_err());
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn unreachable_server() {
+    // Nothing listens on the port once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let mut config = mock_config(&url, None);
+    config.http_retry.max_attempts = 1;
+
+    let err = get_json::<Value>(&HttpClient::new(&config), &Url::parse(&url).unwrap()).unwrap_err();
+    assert!(is_unreachable(&*err));
+    let err = OAuthClient::new(&config)
+        .unwrap()
+        .device_code()
+        .unwrap_err();
+    assert!(matches!(err, OAuthDeviceError::Network(_)), "{err}");
+}
+
+#[test]
+fn error_status_not_unreachable() {
+    let mut server = Server::new();
+    server.mock("GET", "/doc").with_status(503).create();
+    let mut config = mock_config(&server.url(), None);
+    config.http_retry.max_attempts = 1;
+
+    let err = get_json::<Value>(&HttpClient::new(&config), &doc_url(&server)).unwrap_err();
+    assert!(!is_unreachable(&*err));
+}
+
+#[test]
+fn trusted_cas_bundle() {
+    let dir = temp_dir("trusted_cas_bundle");
+    std::fs::create_dir_all(&dir).unwrap();
+    let bundle = dir.join("ca.pem");
+    std::fs::write(&bundle, "-----BEGIN CERTIFICATE-----\ninternal\n").unwrap();
+
+    // curl's defaults are kept without a bundle
+    assert_eq!(trusted_cas(None, true).unwrap(), None);
+
+    let only_custom = trusted_cas(Some(&bundle), false).unwrap().unwrap();
+    assert_eq!(only_custom, std::fs::read(&bundle).unwrap());
+
+    let with_system = trusted_cas(Some(&bundle), true).unwrap().unwrap();
+    assert!(with_system.ends_with(&only_custom));
+
+    let err = trusted_cas(Some(&dir.join("missing.pem")), false).unwrap_err();
+    assert!(err.to_string().starts_with("Failed to read CA bundle"));
+}
+
+#[test]
+fn proxy_url() {
+    // Answers a single request as the proxy, handing its head back to the test
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            head.extend_from_slice(&buf[..n]);
+        }
+        let body = r#"{"proxied":true}"#;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        String::from_utf8(head).unwrap()
+    });
+
+    let mut config = mock_config(&"http://idp.example.org".to_string(), None);
+    config.proxy_url = Some(Url::parse(&format!("http://user:secret@{proxy}")).unwrap());
+    let http_client = HttpClient::new(&config);
+    let url = Url::parse("http://idp.example.org/doc").unwrap();
+
+    let doc = get_json::<Value>(&http_client, &url).unwrap();
+    assert_eq!(doc["proxied"], true);
+    let head = handle.join().unwrap();
+    assert!(head.starts_with("GET http://idp.example.org/doc HTTP/1.1\r\n"));
+    assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+}
@@ -0,0 +1,109 @@
+mod utils;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pam_oauth2_device::interrupt::{self, InterruptGuard};
+use utils::Mock;
+
+// The handlers are process wide, the tests must not install them concurrently
+static SIGNALS: Mutex<()> = Mutex::new(());
+
+fn terminate_after(delay: Duration) {
+    thread::spawn(move || {
+        thread::sleep(delay);
+        unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    });
+}
+
+// Runs `f` in a child process, as the caught signal terminates the process once the guard is
+// dropped. The wait status of the child, which exits with 1 if `f` panicked.
+fn in_child(f: impl FnOnce()) -> libc::c_int {
+    match unsafe { libc::fork() } {
+        0 => {
+            let res = panic::catch_unwind(AssertUnwindSafe(f));
+            unsafe { libc::_exit(i32::from(res.is_err())) }
+        }
+        pid => {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            status
+        }
+    }
+}
+
+fn terminated_by(status: libc::c_int, signal: libc::c_int) -> bool {
+    libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == signal
+}
+
+fn disposition(signal: libc::c_int) -> libc::sighandler_t {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        assert_eq!(libc::sigaction(signal, std::ptr::null(), &mut action), 0);
+        action.sa_sigaction
+    }
+}
+
+#[test]
+fn sleep_interrupted() {
+    let _lock = SIGNALS.lock().unwrap();
+    let status = in_child(|| {
+        let _guard = InterruptGuard::install();
+        assert!(interrupt::sleep(Duration::from_millis(10)));
+
+        let start = Instant::now();
+        terminate_after(Duration::from_millis(100));
+        assert!(!interrupt::sleep(Duration::from_secs(30)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(interrupt::interrupted());
+    });
+    // Raised again with the default disposition back once the guard is dropped
+    assert!(terminated_by(status, libc::SIGTERM), "status {status}");
+}
+
+#[test]
+fn dispositions_restored() {
+    let _lock = SIGNALS.lock().unwrap();
+    {
+        let _guard = InterruptGuard::install();
+        assert_ne!(disposition(libc::SIGTERM), libc::SIG_DFL);
+        assert!(interrupt::sleep(Duration::from_millis(10)));
+    }
+    assert_eq!(disposition(libc::SIGTERM), libc::SIG_DFL);
+    assert!(!interrupt::interrupted());
+}
+
+#[test]
+fn application_handlers_kept() {
+    let _lock = SIGNALS.lock().unwrap();
+    unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) };
+    {
+        let _guard = InterruptGuard::install();
+        assert_eq!(disposition(libc::SIGHUP), libc::SIG_IGN);
+    }
+    assert_eq!(disposition(libc::SIGHUP), libc::SIG_IGN);
+    unsafe { libc::signal(libc::SIGHUP, libc::SIG_DFL) };
+}
+
+#[test]
+fn token_polling_interrupted() {
+    let _lock = SIGNALS.lock().unwrap();
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_device_basic();
+    mock.http_token_error("authorization_pending", 10);
+
+    let device_code = oauth_client.device_code().unwrap();
+    let start = Instant::now();
+    let status = in_child(|| {
+        terminate_after(Duration::from_millis(300));
+        let err = oauth_client.get_token(&device_code, None).unwrap_err();
+        // The guard is dropped before the polling returns, terminating the child
+        unreachable!("Polling returned {err}");
+    });
+
+    assert!(terminated_by(status, libc::SIGTERM), "status {status}");
+    // Well before the 5 seconds interval of the device code
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
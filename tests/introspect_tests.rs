@@ -3,6 +3,7 @@ mod utils;
 
 use chrono::{Duration, Utc};
 use oauth2::{TokenIntrospectionResponse, TokenResponse};
+use pam_oauth2_device::config::{KeycloakRoles, Substitution, UsernameCase, UsernameNormalization};
 use pam_oauth2_device::logger::Logger;
 use utils::Mock;
 
@@ -339,3 +340,384 @@ fn token_expired() {
     assert_eq!(oauth_client.validate_token(&token, "test"), false);
     assert_eq!(logger.msg(), "Token has expired for user test");
 }
+
+#[test]
+fn introspect_with_refresh_token() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init(Some("openid profile"));
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let introspected = oauth_client.introspect_tokens(token.access_token(), token.refresh_token());
+
+    let access = introspected.access.unwrap();
+    assert_eq!(oauth_client.validate_token(&access, "test"), true);
+    assert_eq!(introspected.refresh.unwrap().unwrap().active(), true);
+}
+
+#[test]
+fn introspect_without_refresh_token() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init(Some("openid profile"));
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let introspected = oauth_client.introspect_tokens(token.access_token(), None);
+
+    assert!(introspected.access.is_ok());
+    assert!(introspected.refresh.is_none());
+}
+
+#[test]
+fn introspect_refresh_token_error() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init(Some("openid profile"));
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_hint_with_status("access_token", 200);
+    mock.http_introspect_hint_with_status("refresh_token", 401);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let introspected = oauth_client.introspect_tokens(token.access_token(), token.refresh_token());
+
+    // Failing refresh token introspection must not hide the valid access token
+    assert_eq!(introspected.access.unwrap().active(), true);
+    let _ = introspected
+        .refresh
+        .unwrap()
+        .map_err(|err| TestLogger::handle_error(err, "Failed to introspect refresh token"));
+    assert_eq!(
+        logger.msg(),
+        "Failed to introspect refresh token\n    caused by: Server returned error response: invalid_client: This client authentication was invalid"
+    );
+}
+
+#[test]
+fn allowed_group() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(r#""groups": ["users", "hpc"]"#))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["hpc".to_string(), "admins".to_string()]
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+}
+
+#[test]
+fn not_allowed_group() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(r#""groups": ["users"]"#))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["hpc".to_string()]
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    assert_eq!(
+        logger.msg(),
+        r#"User test is not a member of any allowed group: ["users"]"#
+    );
+}
+
+#[test]
+fn no_groups_claim() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["hpc".to_string()]
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    assert_eq!(logger.msg(), "No groups claim provided in token");
+}
+
+#[test]
+fn nested_roles_claim() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(
+            r#""realm_access": {"roles": ["offline_access", "hpc"]}"#,
+        ))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["hpc".to_string()];
+            c.groups_claim = "realm_access.roles".to_string();
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+}
+
+#[test]
+fn keycloak_client_roles() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(
+            r#""realm_access": {"roles": ["offline_access"]}, "resource_access": {"pam": {"roles": ["login"]}, "account": {"roles": ["manage-account"]}}"#,
+        ))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["pam:login".to_string()];
+            c.keycloak_roles = KeycloakRoles {
+                realm: true,
+                clients: vec!["pam".to_string()],
+            };
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+}
+
+#[test]
+fn keycloak_roles_not_allowed() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(
+            r#""groups": ["users"], "realm_access": {"roles": ["offline_access"]}, "resource_access": {"account": {"roles": ["login"]}}"#,
+        ))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_groups = vec!["hpc".to_string(), "pam:login".to_string()];
+            c.keycloak_roles = KeycloakRoles {
+                realm: true,
+                clients: vec!["pam".to_string(), "account".to_string()],
+            };
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    assert_eq!(
+        logger.msg(),
+        r#"User test is not a member of any allowed group: ["users", "offline_access", "account:login"]"#
+    );
+}
+
+#[test]
+fn allowed_audience() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_audiences = vec!["ssh".to_string(), "test".to_string()]
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+}
+
+#[test]
+fn not_allowed_audience() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_audiences = vec!["ssh".to_string()]
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    assert_eq!(
+        logger.msg(),
+        r#"Token of user test was not issued for an allowed audience: ["test"]"#
+    );
+}
+
+#[test]
+fn authorized_party_from_client_id() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_authorized_parties = vec!["test".to_string()]
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+}
+
+#[test]
+fn not_allowed_authorized_party() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(Some(r#""azp": "other-client""#))
+        .init_with(Some("openid profile"), |c| {
+            c.allowed_authorized_parties = vec!["test".to_string()]
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    assert_eq!(
+        logger.msg(),
+        "Token of user test was issued to a client that is not allowed: other-client"
+    );
+}
+
+#[test]
+fn normalized_username() {
+    let normalization = UsernameNormalization {
+        strip_domains: vec!["example.org".to_string()],
+        case: UsernameCase::Lower,
+        substitutions: vec![Substitution {
+            from: ".".to_string(),
+            to: "_".to_string(),
+        }],
+    };
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("Alice.Doe@Example.org"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.username_normalization = normalization.clone()
+        });
+    let logger = LOGGER.lock().unwrap();
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "alice_doe"), true);
+    // Logged as normalized
+    assert_eq!(oauth_client.validate_token(&token, "alice"), false);
+    assert_eq!(
+        logger.msg(),
+        "Invalid username: remote: alice_doe -> local: alice"
+    );
+}
+
+#[test]
+fn foreign_domain_kept() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("alice@example.com"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| {
+            c.username_normalization.strip_domains = vec!["*.example.org".to_string()]
+        });
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "alice"), false);
+}
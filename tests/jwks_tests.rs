@@ -0,0 +1,410 @@
+mod utils;
+
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use pam_oauth2_device::config::ValidationMode;
+use pam_oauth2_device::oauth_device::{DeviceTokenResponse, OAuthClient, Validation};
+use serde_json::{json, Value};
+use std::time::Duration;
+use url::Url;
+use utils::{mock_config, temp_dir, Mock};
+
+// Signs `claims` with the test key published in `keys/jwks.json`
+fn jwt(kid: &str, claims: &Value) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    let key = EncodingKey::from_rsa_pem(include_bytes!("keys/jwt_rs256.pem")).unwrap();
+    encode(&header, claims, &key).unwrap()
+}
+
+fn claims(mock: &Mock) -> Value {
+    json!({
+        "iss": mock.server.url(),
+        "aud": "test",
+        "sub": "f47ac10b-58cc",
+        "preferred_username": "test",
+        "scope": "openid profile",
+        "exp": chrono::Utc::now().timestamp() + 3600,
+    })
+}
+
+fn init(mode: ValidationMode) -> (Mock, OAuthClient) {
+    Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .discovery(true)
+        .init_with(Some("openid profile"), |c| c.validation_mode = mode)
+}
+
+fn validate(mock: &mut Mock, oauth_client: &OAuthClient, access_token: &str) -> Validation {
+    mock.http_jwks();
+    validate_without_jwks(mock, oauth_client, access_token)
+}
+
+fn validate_without_jwks(
+    mock: &mut Mock,
+    oauth_client: &OAuthClient,
+    access_token: &str,
+) -> Validation {
+    mock.http_device_complete();
+    mock.http_token_with_access_token(access_token);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    oauth_client.validate(&token, "test")
+}
+
+#[test]
+fn jwks_valid() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let token = jwt("test-key", &claims(&mock));
+
+    match validate(&mut mock, &oauth_client, &token) {
+        Validation::Valid(validated) => {
+            assert_eq!(validated.username, "test");
+            assert_eq!(validated.subject.as_deref(), Some("f47ac10b-58cc"));
+        }
+        other => panic!("Unexpected validation result: {:?}", other),
+    }
+}
+
+#[test]
+fn jwks_expired() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let mut claims = claims(&mock);
+    claims["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_not_yet_valid() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let mut claims = claims(&mock);
+    claims["nbf"] = json!(chrono::Utc::now().timestamp() + 3600);
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_clock_skew_tolerated() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let mut claims = claims(&mock);
+    // Issued by a server whose clock is a few seconds ahead
+    let ahead = chrono::Utc::now().timestamp() + 30;
+    claims["nbf"] = json!(ahead);
+    claims["iat"] = json!(ahead);
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn jwks_max_token_lifetime() {
+    for (max, valid) in [(7200, true), (600, false)] {
+        let (mut mock, oauth_client) =
+            Mock::builder()
+                .discovery(true)
+                .init_with(Some("openid profile"), |c| {
+                    c.validation_mode = ValidationMode::Jwks;
+                    c.max_token_lifetime = Some(std::time::Duration::from_secs(max));
+                });
+        let mut claims = claims(&mock);
+        claims["iat"] = json!(chrono::Utc::now().timestamp());
+        let token = jwt("test-key", &claims);
+
+        let validation = validate(&mut mock, &oauth_client, &token);
+        assert_eq!(
+            matches!(validation, Validation::Valid(_)),
+            valid,
+            "{validation:?}"
+        );
+    }
+}
+
+#[test]
+fn jwks_wrong_audience() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let mut claims = claims(&mock);
+    claims["aud"] = json!("another-client");
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_allowed_audiences() {
+    let (mut mock, oauth_client) =
+        Mock::builder()
+            .discovery(true)
+            .init_with(Some("openid profile"), |c| {
+                c.validation_mode = ValidationMode::Jwks;
+                c.allowed_audiences = vec!["ssh".to_string(), "sftp".to_string()];
+            });
+    let mut claims = claims(&mock);
+    claims["aud"] = json!(["account", "sftp"]);
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn jwks_wrong_issuer() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let mut claims = claims(&mock);
+    claims["iss"] = json!("https://evil.example.org");
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_tampered_signature() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let token = jwt("test-key", &claims(&mock));
+    // Swap the payload for one granting another user, keeping the original signature
+    let mut other = claims(&mock);
+    other["preferred_username"] = json!("root");
+    let forged = jwt("test-key", &other);
+    let parts: Vec<&str> = token.split('.').collect();
+    let forged_parts: Vec<&str> = forged.split('.').collect();
+    let token = format!("{}.{}.{}", parts[0], forged_parts[1], parts[2]);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_unknown_key() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    let token = jwt("rotated-key", &claims(&mock));
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Unavailable(_)
+    ));
+}
+
+#[test]
+fn jwks_algorithm_pinned() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+    // Signed with the published key, but with RS384 where the key is published for RS256
+    let mut header = Header::new(Algorithm::RS384);
+    header.kid = Some("test-key".to_string());
+    let key = EncodingKey::from_rsa_pem(include_bytes!("keys/jwt_rs256.pem")).unwrap();
+    let token = encode(&header, &claims(&mock), &key).unwrap();
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_cached() {
+    let dir = temp_dir("jwks_cached");
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .discovery(true)
+        .init_with(Some("openid profile"), |c| {
+            c.validation_mode = ValidationMode::Jwks;
+            c.cache_dir = dir.clone();
+            c.jwks_cache_ttl = Duration::from_secs(3600);
+        });
+    let token = |kid: &str, mock: &Mock| -> DeviceTokenResponse {
+        serde_json::from_value(json!({
+            "access_token": jwt(kid, &claims(mock)),
+            "token_type": "bearer",
+        }))
+        .unwrap()
+    };
+    let jwks = mock
+        .server
+        .mock("GET", "/jwks")
+        .with_status(200)
+        .with_body(include_str!("keys/jwks.json"))
+        .expect(1)
+        .create();
+
+    for _ in 0..2 {
+        assert!(matches!(
+            oauth_client.validate(&token("test-key", &mock), "test"),
+            Validation::Valid(_)
+        ));
+    }
+    jwks.assert();
+    jwks.remove();
+
+    // A key missing from the cached set is looked up in a fresh one
+    let refetched = mock
+        .server
+        .mock("GET", "/jwks")
+        .with_status(200)
+        .with_body(include_str!("keys/jwks.json"))
+        .expect(1)
+        .create();
+    assert!(matches!(
+        oauth_client.validate(&token("rotated-key", &mock), "test"),
+        Validation::Unavailable(_)
+    ));
+    refetched.assert();
+}
+
+#[test]
+fn jwks_opaque_token() {
+    let (mut mock, oauth_client) = init(ValidationMode::Jwks);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, "mocking_access_token"),
+        Validation::Unavailable(_)
+    ));
+}
+
+#[test]
+fn jwks_then_introspection_opaque_token() {
+    let (mut mock, oauth_client) = init(ValidationMode::JwksThenIntrospection);
+    mock.http_introspect_with_status(200);
+
+    // Not a JWT, falls back to introspection
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, "mocking_access_token"),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn jwks_then_introspection_jwt() {
+    let (mut mock, oauth_client) = init(ValidationMode::JwksThenIntrospection);
+    let introspect = mock.server.mock("POST", "/introspect").expect(0).create();
+    let token = jwt("test-key", &claims(&mock));
+
+    // Validated locally, without introspection
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Valid(_)
+    ));
+    introspect.assert();
+}
+
+#[test]
+fn jwks_then_introspection_unknown_key() {
+    let (mut mock, oauth_client) = init(ValidationMode::JwksThenIntrospection);
+    mock.http_introspect_with_status(200);
+    let token = jwt("rotated-key", &claims(&mock));
+
+    // A JWT signed with a key missing from the JWKS is left to introspection
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn jwks_then_introspection_denied_stops_chain() {
+    let (mut mock, oauth_client) = init(ValidationMode::JwksThenIntrospection);
+    let introspect = mock.server.mock("POST", "/introspect").expect(0).create();
+    let mut claims = claims(&mock);
+    claims["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+    let token = jwt("test-key", &claims);
+
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+    introspect.assert();
+}
+
+#[test]
+fn introspection_then_jwks_unavailable_introspection() {
+    let (mut mock, oauth_client) = init(ValidationMode::IntrospectionThenJwks);
+    mock.http_introspect_with_status(500);
+    let token = jwt("test-key", &claims(&mock));
+
+    // Introspection fails, the JWT is validated locally
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn introspection_then_jwks_opaque_token() {
+    let (mut mock, oauth_client) = init(ValidationMode::IntrospectionThenJwks);
+    mock.http_introspect_with_status(200);
+    let jwks = mock.server.mock("GET", "/jwks").expect(0).create();
+
+    // Introspection decides, the JWKS isn't fetched
+    assert!(matches!(
+        validate_without_jwks(&mut mock, &oauth_client, "mocking_access_token"),
+        Validation::Valid(_)
+    ));
+    jwks.assert();
+}
+
+#[test]
+fn introspection_then_jwks_unavailable_opaque_token() {
+    let (mut mock, oauth_client) = init(ValidationMode::IntrospectionThenJwks);
+    mock.http_introspect_with_status(500);
+
+    // Neither mode can decide on an opaque token while introspection is down
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, "mocking_access_token"),
+        Validation::Unavailable(_)
+    ));
+}
+
+#[test]
+fn introspection_then_jwks_denied_stops_chain() {
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(false)
+        .username(Some("test"))
+        .discovery(true)
+        .init_with(Some("openid profile"), |c| {
+            c.validation_mode = ValidationMode::IntrospectionThenJwks
+        });
+    mock.http_introspect_with_status(200);
+    let token = jwt("test-key", &claims(&mock));
+
+    // Inactive according to introspection, a valid JWT doesn't override it
+    assert!(matches!(
+        validate(&mut mock, &oauth_client, &token),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn jwks_requires_issuer() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.validation_mode = ValidationMode::Jwks;
+    config.jwks_uri = Some(Url::parse("https://idp.example.org/jwks").unwrap());
+
+    let err = OAuthClient::new(&config).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The jwks validation mode requires the issuer option"
+    );
+}
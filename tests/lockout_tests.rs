@@ -0,0 +1,82 @@
+mod utils;
+
+use pam_oauth2_device::lockout::LockoutStore;
+use std::time::Duration;
+use utils::temp_dir;
+
+#[test]
+fn lockout_after_threshold() {
+    let store = LockoutStore::new(
+        &temp_dir("lockout_after_threshold"),
+        3,
+        Duration::from_secs(60),
+    );
+
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    assert_eq!(store.locked_until("alice").unwrap(), None);
+    let until = store.record_failure("alice").unwrap().unwrap();
+    assert_eq!(store.locked_until("alice").unwrap(), Some(until));
+    // Other users are counted separately
+    assert_eq!(store.locked_until("bob").unwrap(), None);
+}
+
+#[test]
+fn lockout_reset() {
+    let store = LockoutStore::new(&temp_dir("lockout_reset"), 2, Duration::from_secs(60));
+
+    store.record_failure("alice").unwrap();
+    // A successful login starts the count over
+    store.reset("alice").unwrap();
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    assert!(store.record_failure("alice").unwrap().is_some());
+    // Lifted by an admin
+    store.reset("alice").unwrap();
+    assert_eq!(store.locked_until("alice").unwrap(), None);
+    store.reset("nobody").unwrap();
+}
+
+#[test]
+fn lockout_expires() {
+    let store = LockoutStore::new(&temp_dir("lockout_expires"), 1, Duration::from_secs(1));
+
+    assert!(store.record_failure("alice").unwrap().is_some());
+    assert!(store.locked_until("alice").unwrap().is_some());
+    std::thread::sleep(Duration::from_millis(2100));
+    assert_eq!(store.locked_until("alice").unwrap(), None);
+}
+
+#[test]
+fn failures_forgotten() {
+    let store = LockoutStore::new(
+        &temp_dir("lockout_failures_forgotten"),
+        2,
+        Duration::from_secs(1),
+    );
+
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    std::thread::sleep(Duration::from_millis(1100));
+    // No failure for the lockout duration, the count started over
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    assert!(store.record_failure("alice").unwrap().is_some());
+}
+
+#[test]
+fn invalid_entry_ignored() {
+    let dir = temp_dir("lockout_invalid_entry");
+    let store = LockoutStore::new(&dir, 2, Duration::from_secs(60));
+
+    store.record_failure("alice").unwrap();
+    let entry = std::fs::read_dir(&dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    // Doesn't fit the count, and must not wrap around to a count of 0
+    std::fs::write(&entry, "-1 0\n").unwrap();
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    std::fs::write(&entry, "4294967297 0\n").unwrap();
+    assert_eq!(store.record_failure("alice").unwrap(), None);
+    assert!(store.record_failure("alice").unwrap().is_some());
+}
@@ -0,0 +1,334 @@
+mod utils;
+
+use log::{Level, LevelFilter, Log, Record};
+use pam_oauth2_device::logger::{
+    correlation_id, insecure_log_path, mask_username, token_fingerprint, DefaultLogger,
+    JournaldLogger, LevelFilters, LogContext, LogUser, Logger, ModuleLogger, Redacted,
+    RotatingFile, Rotation,
+};
+use pam_oauth2_device::oauth_device::DeviceTokenResponse;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use utils::temp_dir;
+
+#[test]
+fn mask_long_username() {
+    let masked = mask_username("alice");
+
+    assert!(masked.starts_with("al***#"));
+    assert_eq!(masked.len(), "al***#".len() + 8);
+    assert!(!masked.contains("alice"));
+    // Deterministic, so the same user can be correlated across log lines
+    assert_eq!(masked, mask_username("alice"));
+    assert_ne!(masked, mask_username("alina"));
+}
+
+#[test]
+fn mask_username_keyed() {
+    // The unkeyed SHA-256 tag of `alice` could be looked up from a list of usernames
+    assert_ne!(mask_username("alice"), "al***#2bd806c9");
+}
+
+#[test]
+fn mask_short_username() {
+    assert!(mask_username("ab").starts_with("a***#"));
+    assert!(mask_username("a").starts_with("***#"));
+}
+
+#[test]
+fn log_user_unmasked_by_default() {
+    assert_eq!(LogUser("alice").to_string(), "alice");
+}
+
+#[test]
+fn rotate_log_file() {
+    let dir = temp_dir("rotate_log_file");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log");
+    let rotation = Rotation {
+        max_bytes: 10,
+        max_files: 2,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+
+    file.write_all(b"first 1\n").unwrap();
+    file.write_all(b"second\n").unwrap();
+    file.write_all(b"third\n").unwrap();
+    file.write_all(b"fourth\n").unwrap();
+
+    let read = |suffix: &str| std::fs::read_to_string(dir.join(format!("log{suffix}"))).unwrap();
+    assert_eq!(read(""), "fourth\n");
+    assert_eq!(read(".1"), "third\n");
+    assert_eq!(read(".2"), "second\n");
+    // Only `max_files` generations are kept
+    assert!(!dir.join("log.3").exists());
+}
+
+#[test]
+fn rotate_by_other_writer() {
+    let dir = temp_dir("rotate_by_other_writer");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log");
+    let rotation = Rotation {
+        max_bytes: 10,
+        max_files: 1,
+    };
+    let mut first = RotatingFile::open(&path, rotation).unwrap();
+    let mut second = RotatingFile::open(&path, rotation).unwrap();
+
+    first.write_all(b"first 1\n").unwrap();
+    first.write_all(b"first 2\n").unwrap();
+    // `second` still holds the rotated file and must not rotate again
+    second.write_all(b"second 1\n").unwrap();
+
+    let read = |suffix: &str| std::fs::read_to_string(dir.join(format!("log{suffix}"))).unwrap();
+    assert_eq!(read(".1"), "first 1\n");
+    assert_eq!(read(""), "first 2\nsecond 1\n");
+}
+
+#[test]
+fn reopen_after_logrotate() {
+    let dir = temp_dir("reopen_after_logrotate");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log");
+    let rotation = Rotation {
+        max_bytes: 0,
+        max_files: 0,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+
+    file.write_all(b"before\n").unwrap();
+    // What `logrotate` does without `copytruncate`
+    std::fs::rename(&path, dir.join("log.1")).unwrap();
+    file.write_all(b"after\n").unwrap();
+
+    let read = |suffix: &str| std::fs::read_to_string(dir.join(format!("log{suffix}"))).unwrap();
+    assert_eq!(read(".1"), "before\n");
+    assert_eq!(read(""), "after\n");
+}
+
+#[test]
+fn level_filters() {
+    let filters =
+        LevelFilters::parse("warn, pam_oauth2_device=info,pam_oauth2_device::oauth_device=debug");
+
+    assert_eq!(
+        filters.level("pam_oauth2_device::oauth_device"),
+        LevelFilter::Debug
+    );
+    assert_eq!(filters.level("pam_oauth2_device::http"), LevelFilter::Info);
+    assert_eq!(filters.level("pam_oauth2_device_other"), LevelFilter::Warn);
+    assert_eq!(filters.level("curl"), LevelFilter::Warn);
+    assert_eq!(filters.max(), LevelFilter::Debug);
+    // A plain level, as before
+    assert_eq!(
+        LevelFilters::parse("error").level("curl"),
+        LevelFilter::Error
+    );
+    assert_eq!(LevelFilters::parse("none").max(), LevelFilter::Off);
+}
+
+// Keeps the messages it is given
+#[derive(Default)]
+struct Lines(Mutex<Vec<String>>);
+
+impl Log for Lines {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn module_logger_context() {
+    let lines = Arc::new(Lines::default());
+    let logger = ModuleLogger::new(
+        LevelFilters::parse("info,curl=warn"),
+        Box::new(lines.clone()),
+    );
+    let record = |target: &str, msg| {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{msg}"))
+                .level(Level::Info)
+                .target(target)
+                .build(),
+        )
+    };
+
+    DefaultLogger::set_context(LogContext {
+        service: "sshd".to_string(),
+        rhost: "10.0.0.1".to_string(),
+        tty: String::new(),
+        correlation_id: "3f2a9c1e5b7d0846".to_string(),
+    });
+    record("pam_oauth2_device", "Device flow started");
+    record("curl", "Connected");
+
+    let pid = std::process::id();
+    assert_eq!(
+        *lines.0.lock().unwrap(),
+        [format!(
+            "[service=sshd rhost=10.0.0.1 id=3f2a9c1e5b7d0846 pid={pid}] Device flow started"
+        )]
+    );
+}
+
+#[test]
+fn token_fingerprints() {
+    let fingerprint = token_fingerprint("eyJhbGciOiJSUzI1NiJ9.payload.signature");
+
+    assert_eq!(fingerprint.len(), 16);
+    assert_eq!(
+        fingerprint,
+        token_fingerprint("eyJhbGciOiJSUzI1NiJ9.payload.signature")
+    );
+    assert_ne!(fingerprint, token_fingerprint("other"));
+    // First 8 bytes of the SHA-256 of "token"
+    assert_eq!(token_fingerprint("token"), "3c469e9d6c5875d3");
+}
+
+#[test]
+fn correlation_ids() {
+    let id = correlation_id();
+
+    assert_eq!(id.len(), 16);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(id, correlation_id());
+}
+
+#[test]
+fn journald_entry() {
+    let dir = temp_dir("journald_entry");
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket_path = dir.join("socket");
+    let _ = std::fs::remove_file(&socket_path);
+    let journal = UnixDatagram::bind(&socket_path).unwrap();
+    let logger = JournaldLogger::new(LevelFilter::Info, &socket_path);
+
+    logger.log(
+        &Record::builder()
+            .level(Level::Debug)
+            .args(format_args!("filtered"))
+            .build(),
+    );
+    logger.log(
+        &Record::builder()
+            .level(Level::Error)
+            .args(format_args!("Failed\n    caused by: timeout"))
+            .build(),
+    );
+
+    let mut buf = [0u8; 1024];
+    let len = journal.recv(&mut buf).unwrap();
+    let entry = &buf[..len];
+    let msg = "Failed\n    caused by: timeout";
+    let mut expected = b"MESSAGE\n".to_vec();
+    expected.extend_from_slice(&(msg.len() as u64).to_le_bytes());
+    expected.extend_from_slice(msg.as_bytes());
+    expected.extend_from_slice(
+        b"\nPRIORITY=3\nSYSLOG_FACILITY=4\nSYSLOG_IDENTIFIER=pam_oauth2_device\n",
+    );
+    assert!(entry.starts_with(&expected));
+    assert!(entry.ends_with(format!("SYSLOG_PID={}\n", std::process::id()).as_bytes()));
+
+    // The debug record was not sent
+    journal.set_nonblocking(true).unwrap();
+    assert!(journal.recv(&mut buf).is_err());
+}
+
+#[test]
+fn redacted_token_response() {
+    let token: DeviceTokenResponse = serde_json::from_str(
+        r#"{
+        "access_token": "secret_access_token",
+        "refresh_token": "secret_refresh_token",
+        "id_token": "secret_id_token",
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "scope": "openid profile"
+    }"#,
+    )
+    .unwrap();
+
+    let debug = format!("{:?}", Redacted(&token));
+    assert!(!debug.contains("secret"));
+    assert!(debug.contains(r#""access_token":"[redacted]""#));
+    assert!(debug.contains(r#""refresh_token":"[redacted]""#));
+    assert!(debug.contains(r#""id_token":"[redacted]""#));
+    // The structure is still logged
+    assert!(debug.contains(r#""expires_in":300"#));
+    assert!(debug.contains(r#""scope":"openid profile""#));
+
+    // Plain Debug doesn't leak the id_token either
+    assert!(!format!("{:?}", token).contains("secret"));
+}
+
+#[test]
+fn insecure_log_paths() {
+    let dir = temp_dir("insecure_log_paths");
+    fs::create_dir_all(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let log = dir.join("pam.log");
+    fs::write(&log, "").unwrap();
+    fs::set_permissions(&log, fs::Permissions::from_mode(0o600)).unwrap();
+
+    assert_eq!(insecure_log_path(log.to_str().unwrap()), None);
+    assert_eq!(insecure_log_path("syslog"), None);
+    assert!(insecure_log_path("/tmp/pam_oauth2_device.log")
+        .unwrap()
+        .contains("is in /tmp, which everyone can write"));
+    fs::set_permissions(&log, fs::Permissions::from_mode(0o666)).unwrap();
+    assert!(insecure_log_path(log.to_str().unwrap())
+        .unwrap()
+        .ends_with("can be written by everyone, run chmod 600 on it"));
+}
+
+#[test]
+fn oversize_record_not_rotated_empty() {
+    let dir = temp_dir("oversize_record_not_rotated_empty");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log");
+    let rotation = Rotation {
+        max_bytes: 4,
+        max_files: 1,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+
+    file.write_all(b"oversize\n").unwrap();
+    assert!(!dir.join("log.1").exists());
+    file.write_all(b"next\n").unwrap();
+
+    let read = |suffix: &str| std::fs::read_to_string(dir.join(format!("log{suffix}"))).unwrap();
+    assert_eq!(read(".1"), "oversize\n");
+    assert_eq!(read(""), "next\n");
+}
+
+#[test]
+fn rotate_zero_max_files_keeps_log() {
+    let dir = temp_dir("rotate_zero_max_files_keeps_log");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log");
+    let rotation = Rotation {
+        max_bytes: 10,
+        max_files: 0,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+
+    file.write_all(b"first 1\n").unwrap();
+    file.write_all(b"second\n").unwrap();
+    file.write_all(b"third\n").unwrap();
+
+    let read = |suffix: &str| std::fs::read_to_string(dir.join(format!("log{suffix}"))).unwrap();
+    assert_eq!(read(""), "third\n");
+    assert_eq!(read(".1"), "second\n");
+    assert!(!dir.join("log.2").exists());
+}
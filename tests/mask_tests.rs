@@ -0,0 +1,62 @@
+// Masking is process wide, so it is enabled for every test of this file
+use oauth2::StandardDeviceAuthorizationResponse;
+use pam_oauth2_device::config::Messages;
+use pam_oauth2_device::logger::{mask_username, DefaultLogger, LogUser, Redacted};
+use pam_oauth2_device::prompt::UserPrompt;
+
+fn enable_masking() {
+    DefaultLogger::mask_usernames(true, Some(b"mask-key"));
+}
+
+#[test]
+fn user_claims_masked() {
+    enable_masking();
+    let claims = serde_json::json!({
+        "sub": "248289761001",
+        "preferred_username": "alice",
+        "email": "alice@example.org",
+        "upn": "alice@example.org",
+        "unique_name": "EXAMPLE\\alice",
+        "login": "alice",
+        "name": "Alice Liddell",
+        "username": "alice",
+        "active": true,
+        "access_token": "mocking_access_token"
+    });
+
+    let debug = format!("{:?}", Redacted(&claims));
+
+    assert!(!debug.contains("alice"), "{debug}");
+    assert!(!debug.contains("Alice"), "{debug}");
+    assert!(!debug.contains("248289761001"), "{debug}");
+    assert!(!debug.contains("mocking_access_token"), "{debug}");
+    assert!(debug.contains(&mask_username("alice")), "{debug}");
+    assert!(debug.contains("\"active\":true"), "{debug}");
+}
+
+#[test]
+fn log_user_masked() {
+    enable_masking();
+
+    assert_eq!(LogUser("alice").to_string(), mask_username("alice"));
+}
+
+#[test]
+fn user_prompt_debug_masked() {
+    enable_masking();
+    let resp: StandardDeviceAuthorizationResponse = serde_json::from_value(serde_json::json!({
+        "device_code": "mocking_device_code",
+        "user_code": "mocking_user_code",
+        "verification_uri": "https://mocking.uri/",
+        "expires_in": 3600,
+        "interval": 5
+    }))
+    .unwrap();
+    let mut prompt = UserPrompt::new(&resp, &Messages::default());
+    prompt.set_username("alice");
+
+    let debug = format!("{prompt:?}");
+
+    assert!(!debug.contains("alice"), "{debug}");
+    assert!(debug.contains(&mask_username("alice")), "{debug}");
+}
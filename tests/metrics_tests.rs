@@ -0,0 +1,87 @@
+#![cfg(feature = "pam")]
+
+mod utils;
+
+use std::fs;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use pam::constants::PamResultCode;
+use pam_oauth2_device::audit::{AuditEvent, ErrorClass};
+use pam_oauth2_device::config::MetricsConfig;
+use pam_oauth2_device::metrics::Metrics;
+use utils::temp_dir;
+
+fn success() -> AuditEvent {
+    let mut event = AuditEvent::new("sshd", "alice", "10.0.0.1", "ssh", "client");
+    event.device_flow = Some(Duration::from_millis(1500));
+    event.finish(Ok(()));
+    event
+}
+
+fn denied() -> AuditEvent {
+    let mut event = AuditEvent::new("sshd", "bob", "10.0.0.1", "ssh", "client");
+    event.finish(Err((PamResultCode::PAM_PERM_DENIED, ErrorClass::Denied)));
+    event
+}
+
+#[test]
+fn textfile_counters() {
+    let dir = temp_dir("metrics_textfile");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("pam_oauth2_device.prom");
+    let config = MetricsConfig {
+        statsd: None,
+        textfile: Some(path.clone()),
+        prefix: "pam".to_string(),
+    };
+    let metrics = Metrics::new(&config);
+
+    metrics.record(&success()).unwrap();
+    metrics.record(&denied()).unwrap();
+    metrics.record(&success()).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "# TYPE pam_attempts_total counter
+pam_attempts_total 3
+# TYPE pam_device_flow_seconds summary
+pam_device_flow_seconds_count 2
+pam_device_flow_seconds_sum 3
+# TYPE pam_errors_total counter
+pam_errors_total{error_class=\"denied\"} 1
+# TYPE pam_results_total counter
+pam_results_total{result=\"failure\"} 1
+pam_results_total{result=\"success\"} 2
+"
+    );
+}
+
+#[test]
+fn statsd_datagram() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let config = MetricsConfig {
+        statsd: Some(server.local_addr().unwrap().to_string()),
+        textfile: None,
+        prefix: "pam".to_string(),
+    };
+    let metrics = Metrics::new(&config);
+    let mut buff = [0; 512];
+
+    metrics.record(&success()).unwrap();
+    let len = server.recv(&mut buff).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buff[..len]).unwrap(),
+        "pam.attempts:1|c\npam.result.success:1|c\npam.device_flow:1500|ms"
+    );
+
+    metrics.record(&denied()).unwrap();
+    let len = server.recv(&mut buff).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buff[..len]).unwrap(),
+        "pam.attempts:1|c\npam.result.failure:1|c\npam.error_class.denied:1|c"
+    );
+}
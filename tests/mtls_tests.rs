@@ -0,0 +1,95 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use pam_oauth2_device::config::Config;
+use pam_oauth2_device::mtls::thumbprint;
+use pam_oauth2_device::oauth_device::{OAuthClient, Validation};
+use utils::{mock_config, temp_dir, Mock};
+
+const THUMBPRINT: &str = "oOsxbx_2bQhDtmkvb0PwPw95OVEOeRVzpduyunfzyTE";
+
+// Copies of the test certificate and key, git doesn't keep the key private
+fn client_certificate(c: &mut Config, name: &str) {
+    let dir = temp_dir(name);
+    fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("client.crt"), dir.join("client.key"));
+    fs::write(&cert, include_bytes!("keys/client.crt")).unwrap();
+    fs::write(&key, include_bytes!("keys/client.key")).unwrap();
+    fs::set_permissions(&key, fs::Permissions::from_mode(0o600)).unwrap();
+    c.tls_client_cert = Some(cert);
+    c.tls_client_key = Some(key);
+}
+
+fn validate(cnf: Option<&str>, name: Option<&str>) -> Validation {
+    let extra = cnf.map(|thumbprint| format!(r#""cnf": {{"x5t#S256": "{thumbprint}"}}"#));
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .extra(extra.as_deref())
+        .init_with(Some("openid profile"), |c| {
+            if let Some(name) = name {
+                client_certificate(c, name);
+            }
+        });
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    oauth_client.validate(&token, "test")
+}
+
+#[test]
+fn certificate_thumbprint() {
+    assert_eq!(
+        thumbprint(include_bytes!("keys/client.crt")).unwrap(),
+        THUMBPRINT
+    );
+    assert!(thumbprint(include_bytes!("keys/client.key")).is_err());
+}
+
+#[test]
+fn bound_token() {
+    assert!(matches!(
+        validate(Some(THUMBPRINT), Some("mtls_bound")),
+        Validation::Valid(_)
+    ));
+    // Unbound tokens are accepted as before
+    assert!(matches!(
+        validate(None, Some("mtls_unbound")),
+        Validation::Valid(_)
+    ));
+}
+
+#[test]
+fn token_bound_to_other_certificate() {
+    assert!(matches!(
+        validate(
+            Some("47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU"),
+            Some("mtls_other")
+        ),
+        Validation::Denied
+    ));
+    // Without a client certificate, no bound token is ours
+    assert!(matches!(
+        validate(Some(THUMBPRINT), None),
+        Validation::Denied
+    ));
+}
+
+#[test]
+fn incomplete_client_certificate() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    client_certificate(&mut config, "mtls_incomplete");
+    config.tls_client_key = None;
+
+    let err = OAuthClient::new(&config).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "tls_client_cert and tls_client_key must be set together"
+    );
+}
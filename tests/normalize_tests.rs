@@ -0,0 +1,59 @@
+use pam_oauth2_device::config::{Substitution, UsernameCase, UsernameNormalization};
+use pam_oauth2_device::normalize::normalize_username;
+
+fn substitution(from: &str, to: &str) -> Substitution {
+    Substitution {
+        from: from.to_string(),
+        to: to.to_string(),
+    }
+}
+
+#[test]
+fn default_keeps_username() {
+    let rules = UsernameNormalization::default();
+    assert_eq!(
+        normalize_username(&rules, "Alice@example.org"),
+        "Alice@example.org"
+    );
+}
+
+#[test]
+fn strip_listed_domains() {
+    let rules = UsernameNormalization {
+        strip_domains: vec!["example.org".to_string(), "*.example.net".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(normalize_username(&rules, "alice@example.org"), "alice");
+    assert_eq!(normalize_username(&rules, "alice@EXAMPLE.ORG"), "alice");
+    assert_eq!(normalize_username(&rules, "bob@corp.example.net"), "bob");
+    // Other domains could name other people
+    assert_eq!(
+        normalize_username(&rules, "alice@example.com"),
+        "alice@example.com"
+    );
+    // Only the last `@` separates the domain
+    assert_eq!(normalize_username(&rules, "a@b@example.org"), "a@b");
+}
+
+#[test]
+fn case_and_substitutions() {
+    let rules = UsernameNormalization {
+        strip_domains: vec!["*".to_string()],
+        case: UsernameCase::Lower,
+        substitutions: vec![
+            substitution(".", "_"),
+            substitution(" ", ""),
+            substitution("", "x"),
+        ],
+    };
+    assert_eq!(
+        normalize_username(&rules, "Alice.van Doe@Example.org"),
+        "alice_vandoe"
+    );
+
+    let rules = UsernameNormalization {
+        case: UsernameCase::Upper,
+        ..Default::default()
+    };
+    assert_eq!(normalize_username(&rules, "alice"), "ALICE");
+}
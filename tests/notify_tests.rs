@@ -0,0 +1,54 @@
+mod utils;
+
+use std::ffi::CStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use pam_oauth2_device::config::NoConv;
+use pam_oauth2_device::notify::Notifier;
+use utils::temp_dir;
+
+// The notification file is handed to the user running the tests, who may not be root
+fn current_user() -> String {
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    assert!(!passwd.is_null());
+    unsafe { CStr::from_ptr((*passwd).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[test]
+fn no_fallback() {
+    assert!(Notifier::new(NoConv::Fail, &temp_dir("notify_none"), "alice").is_none());
+}
+
+#[test]
+fn notification_file() {
+    let dir = temp_dir("notify_file").join("notify");
+    let user = current_user();
+    let notifier = Notifier::new(NoConv::File, &dir, &user).unwrap();
+
+    notifier.send("Open https://mocking.uri/").unwrap();
+    let path = dir.join(&user);
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "Open https://mocking.uri/\n"
+    );
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o600);
+    assert_eq!(metadata.uid(), unsafe { libc::geteuid() });
+
+    // The latest message replaces the previous one
+    notifier.send("The code has expired").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "The code has expired\n");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+#[test]
+fn unknown_user() {
+    let dir = temp_dir("notify_unknown");
+    let notifier = Notifier::new(NoConv::File, &dir, "pam-oauth2-no-such-user").unwrap();
+
+    assert!(notifier.send("Open https://mocking.uri/").is_err());
+    assert!(!dir.exists());
+}
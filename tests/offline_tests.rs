@@ -0,0 +1,66 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+use pam_oauth2_device::offline::OfflineStore;
+use utils::temp_dir;
+
+#[test]
+fn store_and_lookup() {
+    let dir = temp_dir("offline_store_and_lookup");
+    let store = OfflineStore::new(&dir, Duration::from_secs(3600));
+
+    let stored = store.store("alice", "sub-1").unwrap();
+    assert_eq!(store.lookup("alice", "sub-1").unwrap(), Some(stored));
+    assert_eq!(store.lookup("bob", "sub-1").unwrap(), None);
+
+    // Neither the subject nor the remote user are stored
+    let content = fs::read_to_string(dir.join("alice")).unwrap();
+    assert!(!content.contains("sub-1"));
+    assert_eq!(
+        fs::metadata(dir.join("alice"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn bound_to_user_and_subject() {
+    let dir = temp_dir("offline_bound");
+    let store = OfflineStore::new(&dir, Duration::from_secs(3600));
+
+    let entry = store.store("alice", "sub-1").unwrap();
+    assert!(entry.matches("alice", "sub-1"));
+    assert!(!entry.matches("bob", "sub-1"));
+    assert!(!entry.matches("alice", "sub-2"));
+    // Another pinned subject, e.g. after a reset
+    assert_eq!(store.lookup("alice", "sub-2").unwrap(), None);
+
+    // Salted, the same login stores another hash
+    assert_ne!(store.store("alice", "sub-1").unwrap().hash, entry.hash);
+}
+
+#[test]
+fn expired_entry_removed() {
+    let dir = temp_dir("offline_expired");
+    let store = OfflineStore::new(&dir, Duration::ZERO);
+
+    store.store("alice", "sub-1").unwrap();
+    assert_eq!(store.lookup("alice", "sub-1").unwrap(), None);
+    assert!(!dir.join("alice").exists());
+}
+
+#[test]
+fn insecure_entry_ignored() {
+    let dir = temp_dir("offline_insecure");
+    let store = OfflineStore::new(&dir, Duration::from_secs(3600));
+
+    store.store("alice", "sub-1").unwrap();
+    fs::set_permissions(dir.join("alice"), fs::Permissions::from_mode(0o644)).unwrap();
+    assert_eq!(store.lookup("alice", "sub-1").unwrap(), None);
+}
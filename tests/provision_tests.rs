@@ -0,0 +1,130 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use pam_oauth2_device::config::{Config, ProvisioningConfig};
+use pam_oauth2_device::oauth_device::ExtraClaims;
+use pam_oauth2_device::provision::{gecos, NewAccount, Provisioner};
+use utils::{mock_config, temp_dir};
+
+fn provisioning_config(uid_range: (u32, u32), useradd: PathBuf) -> Config {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.provisioning = Some(ProvisioningConfig {
+        uid_claim: Some("uidNumber".to_string()),
+        uid_range,
+        shell: PathBuf::from("/bin/zsh"),
+        home_base: PathBuf::from("/home/sso"),
+        primary_group: None,
+        useradd,
+    });
+    config
+}
+
+// Stand-in for useradd writing its arguments to `args`
+fn fake_useradd(dir: &Path) -> PathBuf {
+    fs::create_dir_all(dir).unwrap();
+    let path = dir.join("useradd");
+    fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\nfor arg; do echo \"$arg\"; done > {}\n",
+            dir.join("args").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn plan_claimed_uid() {
+    let config = provisioning_config((4_000_000_000, 4_000_000_100), PathBuf::new());
+    let provisioner = Provisioner::new(&config).unwrap();
+
+    let account = provisioner
+        .plan(
+            "alice",
+            "Alice Doe",
+            Some("alice@example.org"),
+            Some(4_000_000_042),
+        )
+        .unwrap();
+    assert_eq!(
+        account,
+        NewAccount {
+            uid: 4_000_000_042,
+            gecos: "Alice Doe,,,,alice@example.org".to_string(),
+            home: PathBuf::from("/home/sso/alice"),
+            shell: PathBuf::from("/bin/zsh"),
+        }
+    );
+}
+
+#[test]
+fn plan_free_uid() {
+    let config = provisioning_config((4_000_000_000, 4_000_000_100), PathBuf::new());
+    let provisioner = Provisioner::new(&config).unwrap();
+
+    let account = provisioner.plan("alice", "Alice", None, None).unwrap();
+    assert_eq!(account.uid, 4_000_000_000);
+    assert_eq!(account.gecos, "Alice");
+}
+
+#[test]
+fn plan_claimed_uid_out_of_range() {
+    let config = provisioning_config((0, 100), PathBuf::new());
+    let provisioner = Provisioner::new(&config).unwrap();
+
+    // Never root, even within the range
+    assert!(provisioner.plan("alice", "Alice", None, Some(0)).is_err());
+    assert!(provisioner
+        .plan("alice", "Alice", None, Some(1000))
+        .is_err());
+}
+
+#[test]
+fn create_runs_useradd() {
+    let dir = temp_dir("provision_create");
+    let config = provisioning_config((4_000_000_000, 4_000_000_100), fake_useradd(&dir));
+    let provisioner = Provisioner::new(&config).unwrap();
+
+    let account = provisioner
+        .plan("alice", "Alice Doe", Some("alice@example.org"), None)
+        .unwrap();
+    provisioner.create("alice", &account).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("args")).unwrap(),
+        "-M\n-u\n4000000000\n-c\nAlice Doe,,,,alice@example.org\n-d\n/home/sso/alice\n-s\n/bin/zsh\n--\nalice\n"
+    );
+
+    let config = provisioning_config((4_000_000_000, 4_000_000_100), PathBuf::from("/bin/false"));
+    let err = Provisioner::new(&config)
+        .unwrap()
+        .create("alice", &account)
+        .unwrap_err();
+    assert!(err.to_string().contains("/bin/false"), "{err}");
+}
+
+#[test]
+fn gecos_separators_removed() {
+    assert_eq!(
+        gecos("Doe, Alice: admin\n", Some("alice@example.org")),
+        "Doe Alice admin,,,,alice@example.org"
+    );
+    assert_eq!(gecos("Alice", None), "Alice");
+}
+
+#[test]
+fn uid_claim() {
+    let claims: ExtraClaims = serde_json::from_str(
+        r#"{"uidNumber": 100042, "posix": {"uid": "100043"}, "name": "alice", "big": 5000000000}"#,
+    )
+    .unwrap();
+    assert_eq!(claims.uid("uidNumber"), Some(100042));
+    assert_eq!(claims.uid("posix.uid"), Some(100043));
+    assert_eq!(claims.uid("name"), None);
+    assert_eq!(claims.uid("big"), None);
+    assert_eq!(claims.uid("missing"), None);
+}
@@ -0,0 +1,38 @@
+mod utils;
+
+use pam_oauth2_device::ratelimit::RateLimiter;
+use std::time::Duration;
+use utils::temp_dir;
+
+#[test]
+fn rate_limit() {
+    let limiter = RateLimiter::new(&temp_dir("rate_limit"), 2, Duration::from_secs(60));
+
+    assert!(limiter.allow(&["user:alice"]).unwrap());
+    assert!(limiter.allow(&["user:alice"]).unwrap());
+    assert!(!limiter.allow(&["user:alice"]).unwrap());
+    // Other users are counted separately
+    assert!(limiter.allow(&["user:bob"]).unwrap());
+}
+
+#[test]
+fn rate_limit_any_key() {
+    let limiter = RateLimiter::new(&temp_dir("rate_limit_any_key"), 2, Duration::from_secs(60));
+
+    assert!(limiter.allow(&["user:alice", "rhost:10.0.0.1"]).unwrap());
+    assert!(limiter.allow(&["user:bob", "rhost:10.0.0.1"]).unwrap());
+    // The remote host reached the limit, whoever logs in from it
+    assert!(!limiter.allow(&["user:carol", "rhost:10.0.0.1"]).unwrap());
+    // Refused device flows are not counted
+    assert!(limiter.allow(&["user:carol", "rhost:10.0.0.2"]).unwrap());
+}
+
+#[test]
+fn rate_limit_window() {
+    let limiter = RateLimiter::new(&temp_dir("rate_limit_window"), 1, Duration::from_secs(1));
+
+    assert!(limiter.allow(&["user:alice"]).unwrap());
+    assert!(!limiter.allow(&["user:alice"]).unwrap());
+    std::thread::sleep(Duration::from_millis(2100));
+    assert!(limiter.allow(&["user:alice"]).unwrap());
+}
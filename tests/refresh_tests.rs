@@ -0,0 +1,177 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use mockito::Matcher;
+use oauth2::{RefreshToken, TokenResponse};
+use pam_oauth2_device::refresh::RefreshStore;
+use utils::{temp_dir, Mock};
+
+fn store(name: &str) -> (std::path::PathBuf, RefreshStore) {
+    let dir = temp_dir(name);
+    let store = RefreshStore::new(&dir.join("tokens"), &dir.join("refresh_token.key"));
+    (dir, store)
+}
+
+#[test]
+fn store_and_load() {
+    let (dir, store) = store("refresh_store_and_load");
+    let token = RefreshToken::new("mocking_refresh_token".to_string());
+
+    store.store("alice", "sshd", &token).unwrap();
+
+    assert_eq!(
+        store.load("alice").unwrap().unwrap().secret(),
+        "mocking_refresh_token"
+    );
+    assert!(store.load("bob").unwrap().is_none());
+    // Stored encrypted, with a private key
+    let sealed = fs::read(dir.join("tokens/alice")).unwrap();
+    assert!(!String::from_utf8_lossy(&sealed).contains("mocking_refresh_token"));
+    assert_eq!(
+        fs::metadata(dir.join("refresh_token.key"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn bound_to_context() {
+    let (_, store) = store("refresh_bound_to_context");
+    let token = RefreshToken::new("mocking_refresh_token".to_string());
+
+    store
+        .store("alice", "sshd||alice|10.0.0.1", &token)
+        .unwrap();
+
+    let (loaded, _) = store
+        .load_for_context("alice", "sshd||alice|10.0.0.1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(loaded.secret(), "mocking_refresh_token");
+    // Another host or service can't redeem it
+    assert!(store
+        .load_for_context("alice", "sshd||alice|10.0.0.2")
+        .unwrap()
+        .is_none());
+    assert!(store
+        .load_for_context("alice", "sudo|pts/0|alice|")
+        .unwrap()
+        .is_none());
+    // Still available to revoke it
+    assert!(store.load("alice").unwrap().is_some());
+}
+
+#[test]
+fn swapped_entry_rejected() {
+    let (dir, store) = store("refresh_swapped");
+    store
+        .store(
+            "mallory",
+            "sshd",
+            &RefreshToken::new("mallory_token".to_string()),
+        )
+        .unwrap();
+
+    fs::copy(dir.join("tokens/mallory"), dir.join("tokens/alice")).unwrap();
+
+    assert_eq!(
+        store.load("alice").unwrap_err().to_string(),
+        "Failed to decrypt refresh token"
+    );
+}
+
+#[test]
+fn removed() {
+    let (_, store) = store("refresh_removed");
+    store
+        .store(
+            "alice",
+            "sshd",
+            &RefreshToken::new("mocking_refresh_token".to_string()),
+        )
+        .unwrap();
+
+    store.remove("alice").unwrap();
+
+    assert!(store.load("alice").unwrap().is_none());
+}
+
+#[test]
+fn invalid_key() {
+    let (dir, store) = store("refresh_invalid_key");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("refresh_token.key"), b"too short").unwrap();
+
+    assert!(store
+        .store(
+            "alice",
+            "sshd",
+            &RefreshToken::new("mocking_refresh_token".to_string())
+        )
+        .is_err());
+}
+
+#[test]
+fn refresh_token_grant() {
+    let (mut mock, oauth_client) = Mock::builder().init(Some("openid profile"));
+    let grant = mock
+        .server
+        .mock("POST", "/token")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("grant_type".into(), "refresh_token".into()),
+            Matcher::UrlEncoded("refresh_token".into(), "stored_refresh_token".into()),
+        ]))
+        .with_status(200)
+        .with_body(
+            r#"{
+        "access_token": "refreshed_access_token",
+        "refresh_token": "rotated_refresh_token",
+        "token_type": "Bearer",
+        "expires_in": 86400
+            }"#,
+        )
+        .create();
+
+    let token = oauth_client
+        .refresh_token(&RefreshToken::new("stored_refresh_token".to_string()))
+        .unwrap();
+
+    grant.assert();
+    assert_eq!(token.access_token().secret(), "refreshed_access_token");
+    assert_eq!(
+        token.refresh_token().unwrap().secret(),
+        "rotated_refresh_token"
+    );
+}
+
+#[test]
+fn refresh_token_grant_rejected() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+    mock.http_token_with_status(400);
+
+    assert!(oauth_client
+        .refresh_token(&RefreshToken::new("revoked_refresh_token".to_string()))
+        .is_err());
+}
+
+#[test]
+fn dpop_key_stored() {
+    let (_, store) = store("refresh_dpop_key");
+    let token = RefreshToken::new("mocking_refresh_token".to_string());
+
+    store
+        .store_with_dpop_key("alice", "sshd", &token, Some(b"pkcs8 key"))
+        .unwrap();
+    let (loaded, dpop_key) = store.load_with_dpop_key("alice").unwrap().unwrap();
+    assert_eq!(loaded.secret(), "mocking_refresh_token");
+    assert_eq!(dpop_key.as_deref(), Some(&b"pkcs8 key"[..]));
+
+    store.store("alice", "sshd", &token).unwrap();
+    let (_, dpop_key) = store.load_with_dpop_key("alice").unwrap().unwrap();
+    assert!(dpop_key.is_none());
+}
@@ -0,0 +1,85 @@
+mod utils;
+
+use mockito::Matcher;
+use oauth2::{AccessToken, RefreshToken, StandardRevocableToken};
+use pam_oauth2_device::oauth_device::OAuthClient;
+use utils::Mock;
+
+fn init() -> (Mock, OAuthClient) {
+    Mock::builder().init_with(None, |c| {
+        c.oauth_revocation_url = c
+            .oauth_token_url
+            .as_ref()
+            .map(|u| u.join("/revoke").unwrap());
+    })
+}
+
+#[test]
+fn revoke_tokens() {
+    let (mut mock, oauth_client) = init();
+    let refresh = mock
+        .server
+        .mock("POST", "/revoke")
+        // `test:test`
+        .match_header("authorization", "Basic dGVzdDp0ZXN0")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("token".into(), "mocking_refresh_token".into()),
+            Matcher::UrlEncoded("token_type_hint".into(), "refresh_token".into()),
+        ]))
+        .with_status(200)
+        .create();
+    let access = mock
+        .server
+        .mock("POST", "/revoke")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("token".into(), "mocking_access_token".into()),
+            Matcher::UrlEncoded("token_type_hint".into(), "access_token".into()),
+        ]))
+        .with_status(200)
+        .create();
+
+    oauth_client
+        .revoke(StandardRevocableToken::RefreshToken(RefreshToken::new(
+            "mocking_refresh_token".to_string(),
+        )))
+        .unwrap();
+    oauth_client
+        .revoke(StandardRevocableToken::AccessToken(AccessToken::new(
+            "mocking_access_token".to_string(),
+        )))
+        .unwrap();
+
+    refresh.assert();
+    access.assert();
+}
+
+#[test]
+fn revocation_rejected() {
+    let (mut mock, oauth_client) = init();
+    mock.server
+        .mock("POST", "/revoke")
+        .with_status(400)
+        .with_body(r#"{"error": "unsupported_token_type"}"#)
+        .create();
+
+    assert!(oauth_client
+        .revoke(StandardRevocableToken::AccessToken(AccessToken::new(
+            "mocking_access_token".to_string(),
+        )))
+        .is_err());
+}
+
+#[test]
+fn no_revocation_endpoint() {
+    let (_, oauth_client) = Mock::builder().init(None);
+
+    let err = oauth_client
+        .revoke(StandardRevocableToken::AccessToken(AccessToken::new(
+            "mocking_access_token".to_string(),
+        )))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No oauth_revocation_url configured or discovered"
+    );
+}
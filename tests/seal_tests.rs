@@ -0,0 +1,38 @@
+mod utils;
+
+use std::fs;
+
+use pam_oauth2_device::seal::{load_key, load_secret, open, seal};
+use utils::temp_dir;
+
+#[test]
+fn systemd_credential() {
+    let dir = temp_dir("seal_credential");
+    let credentials = dir.join("credentials");
+    fs::create_dir_all(&credentials).unwrap();
+    fs::write(credentials.join("cache.key"), [7u8; 32]).unwrap();
+    let key_file = dir.join("etc/cache.key");
+
+    std::env::set_var("CREDENTIALS_DIRECTORY", &credentials);
+    let sealed = seal(&load_key(&key_file).unwrap(), b"alice", b"secret").unwrap();
+    std::env::remove_var("CREDENTIALS_DIRECTORY");
+
+    // The credential is used instead of generating the key file
+    assert!(!key_file.exists());
+    fs::create_dir_all(key_file.parent().unwrap()).unwrap();
+    fs::write(&key_file, [7u8; 32]).unwrap();
+    assert_eq!(
+        open(&load_key(&key_file).unwrap(), b"alice", &sealed).unwrap(),
+        b"secret"
+    );
+}
+
+#[test]
+fn secret_generated_once() {
+    let dir = temp_dir("seal_secret_generated");
+    let key_file = dir.join("mask_username.key");
+
+    let secret = load_secret(&key_file).unwrap();
+    assert_eq!(secret.len(), 32);
+    assert_eq!(load_secret(&key_file).unwrap(), secret);
+}
@@ -0,0 +1,89 @@
+mod utils;
+
+use pam_oauth2_device::session::SessionStore;
+use std::fs;
+use std::time::Duration;
+use utils::temp_dir;
+
+// No process can have a pid above the kernel maximum (4194304)
+const DEAD_PID: u32 = 4_194_305;
+
+#[test]
+fn session_limit() {
+    let store = SessionStore::new(&temp_dir("session_limit"), Duration::from_secs(3600));
+    let pid = std::process::id();
+
+    assert!(store.open("sub-1", pid, 2).unwrap());
+    assert!(store.open("sub-1", pid, 2).unwrap());
+    assert!(!store.open("sub-1", pid, 2).unwrap());
+    // Other identities are counted separately
+    assert!(store.open("sub-2", pid, 2).unwrap());
+    assert_eq!(store.count("sub-1").unwrap(), 2);
+}
+
+#[test]
+fn session_close() {
+    let store = SessionStore::new(&temp_dir("session_close"), Duration::from_secs(3600));
+    let pid = std::process::id();
+
+    assert!(store.open("sub-1", pid, 1).unwrap());
+    store.close("sub-1", pid).unwrap();
+    assert_eq!(store.count("sub-1").unwrap(), 0);
+    assert!(store.open("sub-1", pid, 1).unwrap());
+}
+
+#[test]
+fn stale_dead_process() {
+    let store = SessionStore::new(&temp_dir("stale_dead_process"), Duration::from_secs(3600));
+
+    assert!(store.open("sub-1", DEAD_PID, 1).unwrap());
+    // The crashed session doesn't count against the limit
+    assert!(store.open("sub-1", std::process::id(), 1).unwrap());
+    assert_eq!(store.count("sub-1").unwrap(), 1);
+}
+
+#[test]
+fn live_session_kept() {
+    let store = SessionStore::new(&temp_dir("live_session_kept"), Duration::from_secs(0));
+    let pid = std::process::id();
+
+    assert!(store.open("sub-1", pid, 1).unwrap());
+    std::thread::sleep(Duration::from_millis(1100));
+    // Still running, whatever its age
+    assert_eq!(store.count("sub-1").unwrap(), 1);
+}
+
+#[test]
+fn stale_reused_pid() {
+    let dir = temp_dir("stale_reused_pid");
+    let store = SessionStore::new(&dir, Duration::from_secs(3600));
+    let pid = std::process::id();
+
+    assert!(store.open("sub-1", pid, 1).unwrap());
+    // A session of an earlier process with the same pid
+    let entry = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let line = fs::read_to_string(&entry).unwrap();
+    let (recorded, _) = line.trim_end().rsplit_once(' ').unwrap();
+    fs::write(&entry, format!("{recorded} 1\n")).unwrap();
+
+    assert_eq!(store.count("sub-1").unwrap(), 0);
+}
+
+#[test]
+fn stale_timeout_without_start_time() {
+    let dir = temp_dir("stale_timeout");
+    let store = SessionStore::new(&dir, Duration::from_secs(3600));
+    let pid = std::process::id();
+
+    assert!(store.open("sub-1", pid, 1).unwrap());
+    // Written by an older version
+    let entry = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    fs::write(
+        &entry,
+        format!("{pid} {}\n", chrono::Utc::now().timestamp()),
+    )
+    .unwrap();
+    assert_eq!(store.count("sub-1").unwrap(), 1);
+    fs::write(&entry, format!("{pid} 0\n")).unwrap();
+    assert_eq!(store.count("sub-1").unwrap(), 0);
+}
@@ -0,0 +1,112 @@
+mod utils;
+
+use std::fs;
+
+use pam_oauth2_device::config::{read_config, ProviderType, ValidationMode};
+use pam_oauth2_device::setup::{install, Preset, Setup};
+use utils::temp_dir;
+
+fn setup(preset: Preset, issuer: Option<&str>) -> Setup {
+    Setup {
+        preset,
+        issuer: issuer.map(str::to_string),
+        client_id: "pam-login".to_string(),
+        client_secret: Some("s3cr\"et".to_string()),
+    }
+}
+
+#[test]
+fn generated_configs_load() {
+    let dir = temp_dir("setup_generated");
+    fs::create_dir_all(&dir).unwrap();
+    let presets = [
+        (Preset::Keycloak, Some("https://sso.example.org/realms/hpc")),
+        (
+            Preset::Azuread,
+            Some("9188040d-6c67-4c5b-b112-36a304b66dad"),
+        ),
+        (Preset::Google, None),
+        (Preset::Oidc, Some("https://idp.example.org")),
+    ];
+
+    for (preset, issuer) in presets {
+        let path = dir.join(format!("{preset:?}.toml"));
+        let text = setup(preset, issuer).render().unwrap();
+        install(&path, &text, false).unwrap();
+
+        let config = read_config(&path.to_string_lossy()).unwrap();
+        assert_eq!(config.client_id, "pam-login");
+        assert_eq!(config.client_secret, "s3cr\"et");
+        match preset {
+            Preset::Keycloak => {
+                assert_eq!(config.issuer.unwrap().as_str(), issuer.unwrap());
+                assert!(config.keycloak_roles.realm);
+            }
+            Preset::Azuread => {
+                assert_eq!(config.provider_type, ProviderType::Azuread);
+                assert_eq!(config.validation_mode, ValidationMode::Jwks);
+                assert_eq!(config.azuread.unwrap().tenant, issuer.unwrap());
+                assert_eq!(config.jwt_audience.as_deref(), Some("api://pam-login"));
+            }
+            Preset::Google => {
+                assert_eq!(config.provider_type, ProviderType::Rest);
+                assert_eq!(
+                    config.issuer.unwrap().as_str(),
+                    "https://accounts.google.com/"
+                );
+                assert_eq!(config.identity_endpoint.unwrap().username_pointer, "/email");
+            }
+            Preset::Oidc => {
+                assert_eq!(config.validation_mode, ValidationMode::Introspection);
+            }
+        }
+    }
+}
+
+#[test]
+fn public_client() {
+    let mut public = setup(Preset::Oidc, Some("https://idp.example.org"));
+    public.client_secret = None;
+
+    let text = public.render().unwrap();
+
+    assert!(!text.contains("\nclient_secret"));
+}
+
+#[test]
+fn missing_issuer() {
+    assert!(setup(Preset::Keycloak, None).render().is_err());
+    assert!(setup(Preset::Oidc, Some("")).render().is_err());
+    assert!(setup(Preset::Google, None).render().is_ok());
+}
+
+#[test]
+fn unreachable_provider_not_written() {
+    let dir = temp_dir("setup_unreachable");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    // Nothing listens on port 1
+    let text = setup(Preset::Oidc, Some("https://127.0.0.1:1"))
+        .render()
+        .unwrap();
+
+    assert!(install(&path, &text, true).is_err());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn json_output_rejected() {
+    let dir = temp_dir("setup_json");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    let text = setup(Preset::Google, None).render().unwrap();
+
+    assert!(install(&path, &text, false).is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn preset_names() {
+    assert_eq!("azuread".parse::<Preset>().unwrap(), Preset::Azuread);
+    assert!("okta".parse::<Preset>().is_err());
+}
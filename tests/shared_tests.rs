@@ -0,0 +1,61 @@
+#![cfg(feature = "pam")]
+
+use chrono::DateTime;
+use pam_oauth2_device::oauth_device::{DeviceTokenResponse, ValidatedToken};
+use pam_oauth2_device::shared::SharedToken;
+
+fn validated() -> ValidatedToken {
+    ValidatedToken {
+        username: "alice".to_string(),
+        subject: Some("sub-1".to_string()),
+        display_name: "Alice".to_string(),
+        expires_at: DateTime::from_timestamp(1713953169, 0),
+        groups: None,
+        email: None,
+        uid: None,
+    }
+}
+
+#[test]
+fn shared_token_layout() {
+    let token: DeviceTokenResponse = serde_json::from_str(
+        r#"{
+        "access_token": "mocking_access_token",
+        "refresh_token": "mocking_refresh_token",
+        "id_token": "mocking_id_token",
+        "token_type": "Bearer",
+        "expires_in": 86400,
+        "scope": "openid profile"
+    }"#,
+    )
+    .unwrap();
+
+    let shared = SharedToken::new(&token, &validated());
+
+    // Refresh tokens are never shared
+    assert_eq!(
+        shared.to_c_string().unwrap().to_str().unwrap(),
+        r#"{"version":1,"access_token":"mocking_access_token","token_type":"bearer","id_token":"mocking_id_token","expires_at":1713953169,"scope":"openid profile","username":"alice","subject":"sub-1"}"#
+    );
+}
+
+#[test]
+fn shared_token_optional_fields() {
+    let token: DeviceTokenResponse =
+        serde_json::from_str(r#"{"access_token": "mocking_access_token", "token_type": "Bearer"}"#)
+            .unwrap();
+    let validated = ValidatedToken {
+        subject: None,
+        expires_at: None,
+        ..validated()
+    };
+
+    assert_eq!(
+        SharedToken::new(&token, &validated)
+            .to_c_string()
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        r#"{"version":1,"access_token":"mocking_access_token","token_type":"bearer","username":"alice"}"#
+    );
+}
@@ -0,0 +1,125 @@
+mod utils;
+
+use mockito::{Matcher, Server};
+use pam_oauth2_device::config::UrlShortenerConfig;
+use pam_oauth2_device::shortener::UrlShortener;
+use utils::mock_config;
+
+const LONG_URL: &str =
+    "https://login.example.org/device?code=ABCD-EFGH&tenant=00000000-0000-0000-0000-000000000000";
+
+fn shortener_config(endpoint: String, response_field: Option<&str>) -> UrlShortenerConfig {
+    UrlShortenerConfig {
+        endpoint,
+        response_field: response_field.map(str::to_string),
+        min_length: 60,
+    }
+}
+
+#[test]
+fn shorten_plain_text() {
+    let mut server = Server::new();
+    let mock = server
+        .mock("GET", "/new")
+        .match_query(Matcher::UrlEncoded("url".into(), LONG_URL.into()))
+        .with_body("https://s.example.org/x7\n")
+        .create();
+    let mut config = mock_config(&server.url(), None);
+    config.url_shortener = Some(shortener_config(
+        format!("{}/new?url={{url}}", server.url()),
+        None,
+    ));
+
+    let shortener = UrlShortener::new(&config).unwrap();
+    assert!(shortener.applies(LONG_URL));
+    assert_eq!(
+        shortener.shorten(LONG_URL).unwrap(),
+        "https://s.example.org/x7"
+    );
+    mock.assert();
+}
+
+#[test]
+fn shorten_json_field() {
+    let mut server = Server::new();
+    server
+        .mock("GET", "/api/shorten")
+        .match_query(Matcher::Any)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"short_url": "https://s.example.org/x7", "hits": 0}"#)
+        .create();
+    let mut config = mock_config(&server.url(), None);
+    config.url_shortener = Some(shortener_config(
+        format!("{}/api/shorten?long={{url}}", server.url()),
+        Some("short_url"),
+    ));
+
+    let shortener = UrlShortener::new(&config).unwrap();
+    assert_eq!(
+        shortener.shorten(LONG_URL).unwrap(),
+        "https://s.example.org/x7"
+    );
+
+    config.url_shortener = Some(shortener_config(
+        format!("{}/api/shorten?long={{url}}", server.url()),
+        Some("link"),
+    ));
+    let shortener = UrlShortener::new(&config).unwrap();
+    assert!(shortener.shorten(LONG_URL).is_err());
+}
+
+#[test]
+fn shorten_failures() {
+    let mut server = Server::new();
+    server
+        .mock("GET", "/down")
+        .match_query(Matcher::Any)
+        .with_status(503)
+        .create();
+    server
+        .mock("GET", "/html")
+        .match_query(Matcher::Any)
+        .with_body("<html>short</html>")
+        .create();
+    let mut config = mock_config(&server.url(), None);
+
+    config.url_shortener = Some(shortener_config(
+        format!("{}/down?url={{url}}", server.url()),
+        None,
+    ));
+    assert!(UrlShortener::new(&config)
+        .unwrap()
+        .shorten(LONG_URL)
+        .is_err());
+
+    // Only links are shown to the user
+    config.url_shortener = Some(shortener_config(
+        format!("{}/html?url={{url}}", server.url()),
+        None,
+    ));
+    assert!(UrlShortener::new(&config)
+        .unwrap()
+        .shorten(LONG_URL)
+        .is_err());
+
+    config.allow_insecure_http = false;
+    let err = UrlShortener::new(&config)
+        .unwrap()
+        .shorten(LONG_URL)
+        .unwrap_err();
+    assert!(err.to_string().contains("not an https URL"), "{err}");
+}
+
+#[test]
+fn shorten_short_url_skipped() {
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    assert!(UrlShortener::new(&config).is_none());
+
+    config.url_shortener = Some(shortener_config(
+        "https://s.example.org/new?url={url}".to_string(),
+        None,
+    ));
+    let shortener = UrlShortener::new(&config).unwrap();
+    assert!(!shortener.applies("https://idp.example.org/device"));
+    assert!(shortener.applies(LONG_URL));
+}
@@ -0,0 +1,53 @@
+mod utils;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use pam_oauth2_device::config::StateDirPermissions;
+use pam_oauth2_device::state::ensure_state_dir;
+use utils::{mock_config, temp_dir};
+
+#[test]
+fn state_dir_created() {
+    let dir = temp_dir("state_dir_created").join("state");
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.state_dir = Some(dir.clone());
+
+    ensure_state_dir(&config).unwrap();
+
+    let mode = fs::metadata(&dir).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+}
+
+#[test]
+fn state_dir_too_open() {
+    let dir = temp_dir("state_dir_too_open");
+    fs::create_dir_all(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.state_dir = Some(dir.clone());
+
+    let err = ensure_state_dir(&config).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "State dir {} can be accessed by others (mode 755), run chmod 700 on it",
+            dir.display()
+        )
+    );
+
+    config.state_dir_permissions = StateDirPermissions::Warn;
+    assert!(ensure_state_dir(&config).is_ok());
+}
+
+#[test]
+fn state_dir_not_a_directory() {
+    let dir = temp_dir("state_dir_not_a_directory");
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("state");
+    fs::write(&file, "").unwrap();
+    let mut config = mock_config(&"http://127.0.0.1".to_string(), None);
+    config.state_dir = Some(file);
+
+    assert!(ensure_state_dir(&config).is_err());
+}
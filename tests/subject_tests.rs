@@ -0,0 +1,73 @@
+mod test_logger;
+mod utils;
+
+use pam_oauth2_device::subject::{SubjectCheck, SubjectStore};
+use std::os::unix::fs::PermissionsExt;
+
+use test_logger::LOGGER;
+use utils::temp_dir;
+
+#[test]
+fn pin_and_match() {
+    let dir = temp_dir("pin_and_match");
+    let store = SubjectStore::new(&dir);
+
+    assert_eq!(store.check("test", "sub-1").unwrap(), SubjectCheck::Pinned);
+    assert_eq!(store.check("test", "sub-1").unwrap(), SubjectCheck::Matched);
+    assert_eq!(
+        std::fs::metadata(dir.join("test"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn mismatch() {
+    let dir = temp_dir("mismatch");
+    let store = SubjectStore::new(&dir);
+    let logger = LOGGER.lock().unwrap();
+
+    assert!(store.validate(Some("sub-1"), "test"));
+    assert!(!store.validate(Some("sub-2"), "test"));
+    assert_eq!(
+        logger.msg(),
+        "SUBJECT MISMATCH for local user test: pinned: sub-1 -> token: sub-2. Possible account reassignment or takeover!"
+    );
+}
+
+#[test]
+fn reset() {
+    let dir = temp_dir("reset");
+    let store = SubjectStore::new(&dir);
+
+    store.check("test", "sub-1").unwrap();
+    store.reset("test").unwrap();
+    // Resetting an unknown user is not an error
+    store.reset("test").unwrap();
+    assert_eq!(store.check("test", "sub-2").unwrap(), SubjectCheck::Pinned);
+    assert_eq!(store.pinned("test").unwrap().as_deref(), Some("sub-2"));
+    assert_eq!(store.pinned("other").unwrap(), None);
+}
+
+#[test]
+fn empty_subject() {
+    let store = SubjectStore::new(&temp_dir("empty_subject"));
+    let logger = LOGGER.lock().unwrap();
+
+    assert!(!store.validate(None, "test"));
+    assert_eq!(
+        logger.msg(),
+        "No subject provided in token, cannot verify pinned subject"
+    );
+}
+
+#[test]
+fn invalid_local_user() {
+    let store = SubjectStore::new(&temp_dir("invalid_local_user"));
+
+    assert!(store.check("../etc/passwd", "sub-1").is_err());
+    assert!(store.check(".hidden", "sub-1").is_err());
+}
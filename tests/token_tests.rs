@@ -2,7 +2,10 @@ mod test_logger;
 mod utils;
 
 use oauth2::{basic::BasicTokenType, TokenResponse};
+use pam_oauth2_device::config::Messages;
 use pam_oauth2_device::logger::Logger;
+use pam_oauth2_device::prompt::waiting_message;
+use std::time::{Duration, Instant};
 use utils::Mock;
 
 use test_logger::{TestLogger, LOGGER};
@@ -39,7 +42,8 @@ fn token_basic_err() {
     let token = oauth_client.get_token(&device_details, None);
     assert!(token.is_err());
 
-    let _ = token.map_err(|err| TestLogger::handle_error(err, "Failed to recive user token"));
+    let _ =
+        token.map_err(|err| TestLogger::handle_error(err.into(), "Failed to recive user token"));
 
     assert_eq!(
         logger.msg(),
@@ -59,10 +63,178 @@ fn token_other_err() {
     let token = oauth_client.get_token(&device_details, None);
     assert!(token.is_err());
 
-    let _ = token.map_err(|err| TestLogger::handle_error(err, "Failed to recive user token"));
+    let _ =
+        token.map_err(|err| TestLogger::handle_error(err.into(), "Failed to recive user token"));
 
     assert_eq!(
         logger.msg(),
         "Failed to recive user token\n    caused by: Server returned error response: access_denied: Authorization for user is still pending."
     );
 }
+
+#[test]
+fn token_form_encoded() {
+    let (mut mock, oauth_client) =
+        Mock::builder().init_with(None, |c| c.tolerate_form_encoded_token = true);
+
+    mock.http_device_complete();
+    mock.http_token_form_encoded();
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+    assert_eq!(token.token_type(), &BasicTokenType::Bearer);
+    assert_eq!(token.expires_in().unwrap().as_secs(), 86400);
+    assert_eq!(token.scopes().unwrap().len(), 2);
+}
+
+#[test]
+fn token_form_encoded_strict() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_complete();
+    mock.http_token_form_encoded();
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None);
+
+    assert!(token.is_err());
+}
+
+#[test]
+fn token_slow_down() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_with_interval(0, 3600);
+    mock.http_token_error("authorization_pending", 1);
+    mock.http_token_error("slow_down", 1);
+    mock.http_token_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let start = Instant::now();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+    // Pending is polled again right away with the interval of 0, slow_down waits 5 seconds
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(5), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(10), "{elapsed:?}");
+}
+
+#[test]
+fn token_expired() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_complete();
+    mock.http_token_error("expired_token", 1);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let err = oauth_client.get_token(&device_details, None).err().unwrap();
+    assert!(err.is_expired_token());
+}
+
+#[test]
+fn token_expires_in_caps_timeout() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_with_interval(1, 1);
+    mock.http_token_error("authorization_pending", 10);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let start = Instant::now();
+    let err = oauth_client
+        .get_token(&device_details, Some(Duration::from_secs(600)))
+        .err()
+        .unwrap();
+    assert!(err.is_expired_token());
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn token_denied_not_expired() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_complete();
+    mock.http_token_with_status(403);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let err = oauth_client.get_token(&device_details, None).err().unwrap();
+    assert!(!err.is_expired_token());
+}
+
+#[test]
+fn token_poll_limited() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_with_interval(0, 3600);
+    mock.http_token_error("authorization_pending", 10);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client
+        .poll_token(&device_details, None, Some(2))
+        .unwrap();
+    assert!(token.is_none());
+}
+
+#[test]
+fn token_poll_limited_authorized() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_with_interval(0, 3600);
+    mock.http_token_error("authorization_pending", 1);
+    mock.http_token_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client
+        .poll_token(&device_details, None, Some(3))
+        .unwrap()
+        .unwrap();
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+}
+
+#[test]
+fn token_progress() {
+    let (mut mock, oauth_client) = Mock::builder().init(None);
+
+    mock.http_device_with_interval(0, 3600);
+    mock.http_token_error("authorization_pending", 2);
+    mock.http_token_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let remaining = std::cell::RefCell::new(Vec::new());
+    let token = oauth_client
+        .get_token_with_progress(&device_details, Some(Duration::from_secs(120)), &|left| {
+            remaining.borrow_mut().push(left)
+        })
+        .unwrap();
+
+    assert_eq!(token.access_token().secret(), "mocking_access_token");
+    // Called before every wait for the next poll
+    let remaining = remaining.into_inner();
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining[0] <= Duration::from_secs(120));
+    assert!(remaining[1] <= remaining[0]);
+}
+
+#[test]
+fn token_waiting_message() {
+    let messages = Messages::default();
+    assert_eq!(
+        waiting_message(&messages, Duration::from_secs(605), "alice", "Alice"),
+        "Waiting for approval... 10:05 remaining"
+    );
+    assert_eq!(
+        waiting_message(&messages, Duration::from_millis(59_900), "alice", "Alice"),
+        "Waiting for approval... 0:59 remaining"
+    );
+
+    let messages = Messages {
+        waiting: "Hang on, {display_name} ({username}), {remaining} left".to_string(),
+        ..Messages::default()
+    };
+    assert_eq!(
+        waiting_message(&messages, Duration::from_secs(90), "alice", "Alice"),
+        "Hang on, Alice (alice), 1:30 left"
+    );
+}
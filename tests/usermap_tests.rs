@@ -0,0 +1,140 @@
+#![allow(clippy::bool_assert_comparison)]
+
+mod utils;
+
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
+
+use oauth2::TokenResponse;
+use pam_oauth2_device::oauth_device::UserMatch;
+use pam_oauth2_device::usermap::UserMap;
+use utils::{mock_config, temp_dir, Mock};
+
+fn write_map(name: &str, content: &str) -> std::path::PathBuf {
+    let dir = temp_dir(name);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("usermap.json");
+    fs::write(&path, content).unwrap();
+    path
+}
+
+#[test]
+fn mapped_by_username_or_sub() {
+    let path = write_map(
+        "usermap-lookup",
+        r#"{"alice@example.org": ["alice", "alice-admin"], "f47ac10b-58cc": ["bob"]}"#,
+    );
+    let user_map = UserMap::new(&path);
+
+    assert_eq!(
+        user_map.local_users(&["alice@example.org"]).unwrap(),
+        Some(vec!["alice".to_string(), "alice-admin".to_string()])
+    );
+    assert_eq!(
+        user_map
+            .local_users(&["bob@example.org", "f47ac10b-58cc"])
+            .unwrap(),
+        Some(vec!["bob".to_string()])
+    );
+    assert_eq!(user_map.local_users(&["carol"]).unwrap(), None);
+}
+
+#[test]
+fn reloaded_on_change() {
+    let path = write_map("usermap-reload", r#"{"alice": ["alice"]}"#);
+    let user_map = UserMap::new(&path);
+    assert_eq!(
+        user_map.local_users(&["alice"]).unwrap(),
+        Some(vec!["alice".to_string()])
+    );
+
+    fs::write(&path, r#"{"alice": ["alice-admin"]}"#).unwrap();
+    // Make sure the modification time differs even on coarse grained filesystems
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+
+    assert_eq!(
+        user_map.local_users(&["alice"]).unwrap(),
+        Some(vec!["alice-admin".to_string()])
+    );
+}
+
+#[test]
+fn missing_map_is_an_error() {
+    let user_map = UserMap::new(&temp_dir("usermap-missing").join("usermap.json"));
+
+    assert!(user_map.local_users(&["alice"]).is_err());
+}
+
+#[test]
+fn mapped_local_user_valid() {
+    let path = write_map("usermap-valid", r#"{"test": ["alice", "root"]}"#);
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| c.user_map = Some(path));
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "alice"), true);
+    // A mapped user doesn't keep access to the local account of the same name
+    assert_eq!(oauth_client.validate_token(&token, "test"), false);
+    // Root is never allowed, even if mapped
+    assert_eq!(oauth_client.validate_token(&token, "root"), false);
+}
+
+#[test]
+fn unmapped_user_falls_back_to_same_name() {
+    let path = write_map("usermap-unmapped", r#"{"other": ["alice"]}"#);
+    let (mut mock, oauth_client) = Mock::builder()
+        .active(true)
+        .username(Some("test"))
+        .scope(Some("openid profile"))
+        .init_with(Some("openid profile"), |c| c.user_map = Some(path));
+
+    mock.http_device_complete();
+    mock.http_token_with_status(200);
+    mock.http_introspect_with_status(200);
+
+    let device_details = oauth_client.device_code().unwrap();
+    let token = oauth_client.get_token(&device_details, None).unwrap();
+    let token = oauth_client.introspect(token.access_token()).unwrap();
+
+    assert_eq!(oauth_client.validate_token(&token, "test"), true);
+    assert_eq!(oauth_client.validate_token(&token, "alice"), false);
+}
+
+// Also checked again when a cached login is reused, the map may have changed meanwhile
+#[test]
+fn user_match() {
+    let path = write_map("usermap-user-match", r#"{"alice": ["alice-admin"]}"#);
+    let mut config = mock_config(&"https://idp.example.org".to_string(), None);
+    config.user_map = Some(path.clone());
+    config.allowed_remote_users = vec!["alice".to_string(), "bob".to_string()];
+    let users = UserMatch::new(&config);
+
+    assert_eq!(users.allows("alice", None, "alice-admin"), true);
+    assert_eq!(users.allows("alice", None, "alice"), false);
+    assert_eq!(users.allows("bob", None, "bob"), true);
+    assert_eq!(users.allows("carol", None, "carol"), false);
+
+    fs::write(&path, r#"{"alice": ["alice"]}"#).unwrap();
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(users.allows("alice", None, "alice-admin"), false);
+}
@@ -1,6 +1,10 @@
 use chrono::{DateTime, Duration, Utc};
-use mockito::{Server, ServerGuard};
-use pam_oauth2_device::config::{Config, Messages};
+use mockito::{Matcher, Server, ServerGuard};
+use pam_oauth2_device::config::{
+    AccountCheck, CibaOptions, Config, EnvNames, Flow, KeycloakRoles, Messages, NoConv,
+    PermissionCheck, PromptEcho, PromptMode, ProviderType, QrOptions, RetryConfig,
+    StateDirPermissions, UsernameNormalization, ValidationMode,
+};
 use pam_oauth2_device::oauth_device::OAuthClient;
 use url::Url;
 
@@ -25,6 +29,10 @@ pub(crate) struct Mock {
     scope: Option<String>,
     active: bool,
     exp: Option<DateTime<Utc>>,
+    // Additional raw JSON members of the introspection response, e.g. `"name": "Alice"`
+    extra: Option<String>,
+    // Serve a discovery document and configure the mock server as the `issuer`
+    discovery: bool,
 }
 
 #[allow(dead_code)]
@@ -36,6 +44,8 @@ impl Mock {
             scope: None,
             active: true,
             exp: Some(chrono::Utc::now() + Duration::seconds(3600)),
+            extra: None,
+            discovery: false,
         })
     }
 }
@@ -48,36 +58,195 @@ impl MockBuilder {
     builder_setter!(username, optional & str);
     builder_setter!(scope, optional & str);
     builder_setter!(exp, optional DateTime<Utc>);
+    builder_setter!(extra, optional & str);
+    builder_setter!(discovery, bool);
 
     pub(crate) fn init(self, pam_scopes: Option<&str>) -> (Mock, OAuthClient) {
-        let config = mock_config(&self.0.server.url(), pam_scopes);
+        self.init_with(pam_scopes, |_| ())
+    }
+
+    // Same as `init` but lets the test adjust the config before the client is built
+    pub(crate) fn init_with(
+        self,
+        pam_scopes: Option<&str>,
+        configure: impl FnOnce(&mut Config),
+    ) -> (Mock, OAuthClient) {
+        let mut server = self.0.server;
+        let mut config = mock_config(&server.url(), pam_scopes);
+        if self.0.discovery {
+            server
+                .mock("GET", "/.well-known/openid-configuration")
+                .with_status(200)
+                .with_body(discovery_body(&server.url()))
+                .create();
+            config.issuer = Some(Url::parse(&server.url()).unwrap());
+        }
+        configure(&mut config);
         let oauth_client = OAuthClient::new(&config)
             .unwrap_or_else(|err| panic!("Failed to create OAuth client: {}", err));
         let mock = Mock {
-            server: self.0.server,
+            server,
             username: self.0.username,
             scope: self.0.scope,
             active: self.0.active,
             exp: self.0.exp,
+            extra: self.0.extra,
+            discovery: self.0.discovery,
         };
         (mock, oauth_client)
     }
 }
 
+#[allow(dead_code)]
+pub(crate) fn discovery_body(issuer: &str) -> String {
+    format!(
+        r#"{{
+        "issuer": "{issuer}",
+        "authorization_endpoint": "{issuer}/auth",
+        "device_authorization_endpoint": "{issuer}/device",
+        "token_endpoint": "{issuer}/token",
+        "introspection_endpoint": "{issuer}/introspect",
+        "jwks_uri": "{issuer}/jwks"
+    }}"#
+    )
+}
+
 #[allow(dead_code)]
 pub(crate) fn mock_config(url: &String, scope: Option<&str>) -> Config {
     let scope = scope.map(|s| s.to_owned());
     Config {
+        version: 2,
+        strict_config: false,
+        provider_name: "default".to_string(),
         client_id: "test".to_string(),
         client_secret: "test".to_string(),
-        oauth_auth_url: Url::parse(&format!("{}/{}", url, "auth")).unwrap(),
-        oauth_device_url: Url::parse(&format!("{}/{}", url, "device")).unwrap(),
-        oauth_token_url: Url::parse(&format!("{}/{}", url, "token")).unwrap(),
-        oauth_token_introspect_url: Url::parse(&format!("{}/{}", url, "introspect")).unwrap(),
+        client_secret_file: None,
+        client_secret_source: None,
+        issuer: None,
+        provider_type: ProviderType::Oidc,
+        azuread: None,
+        oauth_auth_url: Some(Url::parse(&format!("{}/{}", url, "auth")).unwrap()),
+        oauth_device_url: Some(Url::parse(&format!("{}/{}", url, "device")).unwrap()),
+        oauth_token_url: Some(Url::parse(&format!("{}/{}", url, "token")).unwrap()),
+        oauth_token_introspect_url: Some(Url::parse(&format!("{}/{}", url, "introspect")).unwrap()),
+        oauth_userinfo_url: None,
+        oauth_revocation_url: None,
+        oauth_backchannel_auth_url: Some(
+            Url::parse(&format!("{}/{}", url, "bc-authorize")).unwrap(),
+        ),
+        jwks_uri: None,
+        identity_endpoint: None,
         oauth_device_token_polling_timeout: None,
+        max_prompt_retries: 0,
+        flow: Flow::Device,
+        ciba: CibaOptions::default(),
         scopes: scope.unwrap_or_default(),
+        required_scopes: None,
+        private_key_jwt: None,
+        providers: Vec::new(),
         qr_enabled: false,
+        qr: QrOptions::default(),
+        prefer_verification_uri_complete: true,
+        url_shortener: None,
         messages: Messages::default(),
+        split_prompt: false,
+        prompt_colors: false,
+        prompt_mode: PromptMode::default(),
+        prompt_echo: PromptEcho::default(),
+        enter_polls: 3,
+        progress_interval: None,
+        no_conv: NoConv::Fail,
+        notify_dir: std::env::temp_dir(),
+        validation_mode: ValidationMode::default(),
+        introspect_refresh_token: false,
+        jwt_audience: None,
+        display_name_claim: "name".to_string(),
+        username_claim: "username".to_string(),
+        username_normalization: UsernameNormalization::default(),
+        merge_userinfo: false,
+        allowed_groups: Vec::new(),
+        groups_claim: "groups".to_string(),
+        keycloak_roles: KeycloakRoles::default(),
+        group_sync: None,
+        provisioning: None,
+        allowed_audiences: Vec::new(),
+        allowed_authorized_parties: Vec::new(),
+        allowed_clock_skew_secs: std::time::Duration::from_secs(60),
+        // Every mock server publishes its own key set
+        jwks_cache_ttl: std::time::Duration::ZERO,
+        max_token_lifetime: None,
+        required_claims: Vec::new(),
+        require_mfa: false,
+        mfa_acr_values: vec!["mfa".to_string()],
+        mfa_amr_values: vec!["mfa".to_string()],
+        user_map: None,
+        allowed_users: Vec::new(),
+        denied_users: Vec::new(),
+        allowed_remote_users: Vec::new(),
+        service_accounts: Default::default(),
+        skip_if_local_groups: Vec::new(),
+        bypass: Vec::new(),
+        on_unreachable: Default::default(),
+        mode: Default::default(),
+        services: Default::default(),
+        translations: Default::default(),
+        offline_ttl: None,
+        offline_store: std::env::temp_dir(),
+        account_check: AccountCheck::default(),
+        pin_subject: false,
+        subject_store: std::env::temp_dir(),
+        lockout_threshold: None,
+        lockout_duration: std::time::Duration::from_secs(900),
+        lockout_store: std::env::temp_dir(),
+        wire_debug: false,
+        // mockito serves plain http on localhost
+        allow_insecure_http: true,
+        ca_bundle_path: None,
+        tls_system_roots: true,
+        tls_pinned_public_key: None,
+        tls_client_cert: None,
+        tls_client_key: None,
+        dpop: false,
+        revoke_on_logout: false,
+        token_exchange: None,
+        kerberos: None,
+        proxy_url: None,
+        mask_username: false,
+        mask_username_key: std::env::temp_dir().join("mask_username.key"),
+        audit_log: None,
+        audit_export: None,
+        metrics: None,
+        tolerate_form_encoded_token: false,
+        http_connect_timeout: std::time::Duration::from_secs(10),
+        http_read_timeout: std::time::Duration::from_secs(30),
+        http_total_timeout: std::time::Duration::from_secs(60),
+        http_endpoint_timeouts: Default::default(),
+        http_retry: RetryConfig {
+            max_attempts: 3,
+            initial_backoff: std::time::Duration::ZERO,
+            max_backoff: std::time::Duration::ZERO,
+            jitter: false,
+        },
+        export_env: false,
+        env_names: EnvNames::default(),
+        max_sessions_per_user: None,
+        create_homedir: false,
+        skel_dir: std::path::PathBuf::from("/etc/skel"),
+        homedir_mode: 0o700,
+        rate_limit_per_minute: None,
+        cache_ttl: None,
+        permission_check: PermissionCheck::Warn,
+        state_dir: None,
+        state_dir_permissions: StateDirPermissions::Enforce,
+        selinux_hints: false,
+        cache_dir: std::env::temp_dir(),
+        cache_key: None,
+        refresh_token_reauth: false,
+        refresh_token_store: std::env::temp_dir(),
+        refresh_token_key: std::env::temp_dir().join("refresh_token.key"),
+        session_store: std::env::temp_dir(),
+        helper_socket: None,
+        session_stale_timeout: std::time::Duration::from_secs(24 * 60 * 60),
     }
 }
 
@@ -116,6 +285,35 @@ impl Mock {
             .create();
     }
 
+    // Device authorization response polled every `interval` seconds, expiring after `expires_in`
+    #[allow(dead_code)]
+    pub(crate) fn http_device_with_interval(&mut self, interval: u64, expires_in: u64) {
+        self.server
+            .mock("POST", "/device")
+            .with_status(200)
+            .with_body(format!(
+                r#"{{
+            "device_code": "mocking_device_code",
+            "user_code": "mocking_user_code",
+            "verification_uri": "https://mocking.uri/",
+            "expires_in": {expires_in},
+            "interval": {interval}
+        }}"#
+            ))
+            .create();
+    }
+
+    // Token error response of RFC 8628, section 3.5, returned `hits` times
+    #[allow(dead_code)]
+    pub(crate) fn http_token_error(&mut self, error: &str, hits: usize) {
+        self.server
+            .mock("POST", "/token")
+            .with_status(400)
+            .with_body(format!(r#"{{"error": "{error}"}}"#))
+            .expect(hits)
+            .create();
+    }
+
     #[allow(dead_code)]
     pub(crate) fn http_token_with_status(&mut self, status: usize) {
         let body = match status {
@@ -142,8 +340,80 @@ impl Mock {
             .create();
     }
 
+    // Token response with the given (e.g. JWT) access token
+    #[allow(dead_code)]
+    pub(crate) fn http_token_with_access_token(&mut self, access_token: &str) {
+        self.server
+            .mock("POST", "/token")
+            .with_status(200)
+            .with_body(format!(
+                r#"{{
+        "access_token": "{access_token}",
+        "token_type": "Bearer",
+        "expires_in": 86400
+            }}"#
+            ))
+            .create();
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn http_token_with_id_token(&mut self, id_token: &str) {
+        self.server
+            .mock("POST", "/token")
+            .with_status(200)
+            .with_body(format!(
+                r#"{{
+        "access_token": "mocking_access_token",
+        "id_token": "{id_token}",
+        "token_type": "Bearer",
+        "expires_in": 86400
+            }}"#
+            ))
+            .create();
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn http_jwks(&mut self) {
+        self.server
+            .mock("GET", "/jwks")
+            .with_status(200)
+            .with_body(include_str!("keys/jwks.json"))
+            .create();
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn http_token_form_encoded(&mut self) {
+        self.server
+            .mock("POST", "/token")
+            .with_status(200)
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("access_token=mocking_access_token&token_type=bearer&expires_in=86400&scope=openid+profile")
+            .create();
+    }
+
     #[allow(dead_code)]
     pub(crate) fn http_introspect_with_status(&mut self, status: usize) {
+        let body = self.introspect_body(status);
+        self.server
+            .mock("POST", "/introspect")
+            .with_status(status)
+            .with_body(body)
+            .create();
+    }
+
+    // Introspection mock answering only requests with the given token_type_hint
+    #[allow(dead_code)]
+    pub(crate) fn http_introspect_hint_with_status(&mut self, hint: &str, status: usize) {
+        let body = self.introspect_body(status);
+        self.server
+            .mock("POST", "/introspect")
+            .match_body(Matcher::Regex(format!("token_type_hint={hint}")))
+            .with_status(status)
+            .with_body(body)
+            .create();
+    }
+
+    fn introspect_body(&self, status: usize) -> String {
         let username = self
             .username
             .as_ref()
@@ -159,7 +429,7 @@ impl Mock {
             .as_ref()
             .map(|e| format!("{}", e.timestamp()))
             .unwrap_or("null".to_string());
-        let body = match status {
+        match status {
             200..=299 => format!(
                 r#"{{
         "active": {},
@@ -171,23 +441,31 @@ impl Mock {
         "iat": 1713949569,
         "nbf": 1713949569,
         "aud": "test",
-        "iss": "test"
+        "iss": "test"{}
             }}"#,
-                self.active, scope, username, exp
+                self.active,
+                scope,
+                username,
+                exp,
+                self.extra
+                    .as_ref()
+                    .map(|e| format!(",\n        {e}"))
+                    .unwrap_or_default()
             ),
-            _ => {
-                format!(
-                    r#"{{
+            _ => r#"{
         "error": "invalid_client",
         "error_description": "This client authentication was invalid"
-            }}"#
-                )
-            }
-        };
-        self.server
-            .mock("POST", "/introspect")
-            .with_status(status)
-            .with_body(body)
-            .create();
+            }"#
+            .to_string(),
+        }
     }
 }
+
+// Fresh, empty directory under the system temp dir, unique per test
+#[allow(dead_code)]
+pub(crate) fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("pam_oauth2_device-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}